@@ -26,8 +26,11 @@ public class Blaze4DCore implements AutoCloseable {
         Natives.b4dSetDebugMode(this.handle, mode.raw);
     }
 
-    public long createShader(B4DVertexFormat vertexFormat, long usedUniforms) {
-        return Natives.b4dCreateShader(this.handle, vertexFormat.getAddress(), usedUniforms);
+    /**
+     * Creates a new shader. Fragments with an alpha value below the alpha cutoff are discarded. A cutoff of 0 disables discarding.
+     */
+    public long createShader(B4DVertexFormat vertexFormat, long usedUniforms, float alphaCutoff) {
+        return Natives.b4dCreateShader(this.handle, vertexFormat.getAddress(), usedUniforms, alphaCutoff);
     }
 
     public void destroyShader(long shaderId) {
@@ -67,7 +70,8 @@ public class Blaze4DCore implements AutoCloseable {
         UV2(7),
         TEXTURED0(8),
         TEXTURED1(9),
-        TEXTURED2(10);
+        TEXTURED2(10),
+        FOG(11);
 
         final int raw;
 
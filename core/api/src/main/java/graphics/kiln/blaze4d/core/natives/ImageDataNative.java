@@ -13,12 +13,16 @@ public class ImageDataNative {
     public static final MemoryLayout.PathElement ROW_STRIDE_PATH;
     public static final MemoryLayout.PathElement OFFSET_PATH;
     public static final MemoryLayout.PathElement EXTENT_PATH;
+    public static final MemoryLayout.PathElement MIP_LEVEL_PATH;
+    public static final MemoryLayout.PathElement ARRAY_LAYER_PATH;
 
     public static final VarHandle DATA_PTR_HANDLE;
     public static final VarHandle DATA_LEN_HANDLE;
     public static final VarHandle ROW_STRIDE_HANDLE;
     public static final VarHandle OFFSET_HANDLE;
     public static final VarHandle EXTENT_HANDLE;
+    public static final VarHandle MIP_LEVEL_HANDLE;
+    public static final VarHandle ARRAY_LAYER_HANDLE;
 
     static {
         LAYOUT = MemoryLayout.structLayout(
@@ -26,7 +30,9 @@ public class ImageDataNative {
                 Natives.getSizeLayout().withName("data_len"),
                 ValueLayout.JAVA_INT.withName("row_stride"),
                 MemoryLayout.sequenceLayout(2, ValueLayout.JAVA_INT).withName("offset"),
-                MemoryLayout.sequenceLayout(2, ValueLayout.JAVA_INT).withName("extent")
+                MemoryLayout.sequenceLayout(2, ValueLayout.JAVA_INT).withName("extent"),
+                ValueLayout.JAVA_INT.withName("mip_level"),
+                ValueLayout.JAVA_INT.withName("array_layer")
         );
 
         DATA_PTR_PATH = MemoryLayout.PathElement.groupElement("data_ptr");
@@ -34,11 +40,15 @@ public class ImageDataNative {
         ROW_STRIDE_PATH = MemoryLayout.PathElement.groupElement("row_stride");
         OFFSET_PATH = MemoryLayout.PathElement.groupElement("offset");
         EXTENT_PATH = MemoryLayout.PathElement.groupElement("extent");
+        MIP_LEVEL_PATH = MemoryLayout.PathElement.groupElement("mip_level");
+        ARRAY_LAYER_PATH = MemoryLayout.PathElement.groupElement("array_layer");
 
         DATA_PTR_HANDLE = LAYOUT.varHandle(DATA_PTR_PATH);
         DATA_LEN_HANDLE = LAYOUT.varHandle(DATA_LEN_PATH);
         ROW_STRIDE_HANDLE = LAYOUT.varHandle(ROW_STRIDE_PATH);
         OFFSET_HANDLE = LAYOUT.varHandle(OFFSET_PATH, MemoryLayout.PathElement.sequenceElement());
         EXTENT_HANDLE = LAYOUT.varHandle(EXTENT_PATH, MemoryLayout.PathElement.sequenceElement());
+        MIP_LEVEL_HANDLE = LAYOUT.varHandle(MIP_LEVEL_PATH);
+        ARRAY_LAYER_HANDLE = LAYOUT.varHandle(ARRAY_LAYER_PATH);
     }
 }
@@ -89,7 +89,7 @@ public class Natives {
         );
 
         B4D_CREATE_SHADER_HANDLE = lookupFunction("b4d_create_shader",
-                FunctionDescriptor.of(JAVA_LONG, ADDRESS, ADDRESS, JAVA_LONG)
+                FunctionDescriptor.of(JAVA_LONG, ADDRESS, ADDRESS, JAVA_LONG, JAVA_FLOAT)
         );
 
         B4D_DESTROY_SHADER_HANDLE = lookupFunction("b4d_destroy_shader",
@@ -196,9 +196,9 @@ public class Natives {
         }
     }
 
-    public static long b4dCreateShader(MemoryAddress b4d, MemoryAddress vertexFormat, long usedUniforms) {
+    public static long b4dCreateShader(MemoryAddress b4d, MemoryAddress vertexFormat, long usedUniforms, float alphaCutoff) {
         try {
-            return (long) B4D_CREATE_SHADER_HANDLE.invoke(b4d, vertexFormat, usedUniforms);
+            return (long) B4D_CREATE_SHADER_HANDLE.invoke(b4d, vertexFormat, usedUniforms, alphaCutoff);
         } catch (Throwable e) {
             throw new RuntimeException("Failed to invoke b4d_create_shader", e);
         }
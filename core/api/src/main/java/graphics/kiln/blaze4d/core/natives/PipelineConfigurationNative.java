@@ -18,6 +18,9 @@ public class PipelineConfigurationNative {
     public static final MemoryLayout.PathElement BLEND_ALPHA_OP_PATH;
     public static final MemoryLayout.PathElement BLEND_ALPHA_SRC_FACTOR_PATH;
     public static final MemoryLayout.PathElement BLEND_ALPHA_DST_FACTOR_PATH;
+    public static final MemoryLayout.PathElement CULL_MODE_PATH;
+    public static final MemoryLayout.PathElement FRONT_FACE_PATH;
+    public static final MemoryLayout.PathElement POLYGON_MODE_PATH;
 
     public static final VarHandle DEPTH_TEST_ENABLE_HANDLE;
     public static final VarHandle DEPTH_COMPARE_OP_HANDLE;
@@ -29,6 +32,9 @@ public class PipelineConfigurationNative {
     public static final VarHandle BLEND_ALPHA_OP_HANDLE;
     public static final VarHandle BLEND_ALPHA_SRC_FACTOR_HANDLE;
     public static final VarHandle BLEND_ALPHA_DST_FACTOR_HANDLE;
+    public static final VarHandle CULL_MODE_HANDLE;
+    public static final VarHandle FRONT_FACE_HANDLE;
+    public static final VarHandle POLYGON_MODE_HANDLE;
 
     static {
         LAYOUT = MemoryLayout.structLayout(
@@ -41,7 +47,10 @@ public class PipelineConfigurationNative {
                 ValueLayout.JAVA_INT.withName("blend_color_dst_factor"),
                 ValueLayout.JAVA_INT.withName("blend_alpha_op"),
                 ValueLayout.JAVA_INT.withName("blend_alpha_src_factor"),
-                ValueLayout.JAVA_INT.withName("blend_alpha_dst_factor")
+                ValueLayout.JAVA_INT.withName("blend_alpha_dst_factor"),
+                ValueLayout.JAVA_INT.withName("cull_mode"),
+                ValueLayout.JAVA_INT.withName("front_face"),
+                ValueLayout.JAVA_INT.withName("polygon_mode")
         );
 
         DEPTH_TEST_ENABLE_PATH = MemoryLayout.PathElement.groupElement("depth_test_enable");
@@ -54,6 +63,9 @@ public class PipelineConfigurationNative {
         BLEND_ALPHA_OP_PATH = MemoryLayout.PathElement.groupElement("blend_alpha_op");
         BLEND_ALPHA_SRC_FACTOR_PATH = MemoryLayout.PathElement.groupElement("blend_alpha_src_factor");
         BLEND_ALPHA_DST_FACTOR_PATH = MemoryLayout.PathElement.groupElement("blend_alpha_dst_factor");
+        CULL_MODE_PATH = MemoryLayout.PathElement.groupElement("cull_mode");
+        FRONT_FACE_PATH = MemoryLayout.PathElement.groupElement("front_face");
+        POLYGON_MODE_PATH = MemoryLayout.PathElement.groupElement("polygon_mode");
 
         DEPTH_TEST_ENABLE_HANDLE = LAYOUT.varHandle(DEPTH_TEST_ENABLE_PATH);
         DEPTH_COMPARE_OP_HANDLE = LAYOUT.varHandle(DEPTH_COMPARE_OP_PATH);
@@ -65,5 +77,8 @@ public class PipelineConfigurationNative {
         BLEND_ALPHA_OP_HANDLE = LAYOUT.varHandle(BLEND_ALPHA_OP_PATH);
         BLEND_ALPHA_SRC_FACTOR_HANDLE = LAYOUT.varHandle(BLEND_ALPHA_SRC_FACTOR_PATH);
         BLEND_ALPHA_DST_FACTOR_HANDLE = LAYOUT.varHandle(BLEND_ALPHA_DST_FACTOR_PATH);
+        CULL_MODE_HANDLE = LAYOUT.varHandle(CULL_MODE_PATH);
+        FRONT_FACE_HANDLE = LAYOUT.varHandle(FRONT_FACE_PATH);
+        POLYGON_MODE_HANDLE = LAYOUT.varHandle(POLYGON_MODE_PATH);
     }
 }
\ No newline at end of file
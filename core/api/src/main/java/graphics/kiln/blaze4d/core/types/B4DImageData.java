@@ -38,6 +38,14 @@ public class B4DImageData implements AutoCloseable {
         ImageDataNative.EXTENT_HANDLE.set(this.memory, 1, y);
     }
 
+    public void setMipLevel(int mipLevel) {
+        ImageDataNative.MIP_LEVEL_HANDLE.set(this.memory, mipLevel);
+    }
+
+    public void setArrayLayer(int arrayLayer) {
+        ImageDataNative.ARRAY_LAYER_HANDLE.set(this.memory, arrayLayer);
+    }
+
     public MemoryAddress getAddress() {
         return this.memory.address();
     }
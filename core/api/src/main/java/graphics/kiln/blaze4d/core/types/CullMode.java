@@ -0,0 +1,37 @@
+package graphics.kiln.blaze4d.core.types;
+
+public enum CullMode {
+    NONE(0),
+    FRONT(1),
+    BACK(2),
+    FRONT_AND_BACK(3);
+
+    private final int value;
+
+    CullMode(int value) {
+        this.value = value;
+    }
+
+    public int getValue() {
+        return this.value;
+    }
+
+    public static CullMode fromValue(int value) {
+        return switch (value) {
+            case 0 -> NONE;
+            case 1 -> FRONT;
+            case 2 -> BACK;
+            case 3 -> FRONT_AND_BACK;
+            default -> throw new IllegalStateException("Invalid cull mode value: " + value);
+        };
+    }
+
+    public static CullMode fromGlCullFace(int glMode) {
+        return switch (glMode) {
+            case 0x0404 -> FRONT;
+            case 0x0405 -> BACK;
+            case 0x0408 -> FRONT_AND_BACK;
+            default -> throw new IllegalStateException("Invalid cull face value: " + glMode);
+        };
+    }
+}
@@ -0,0 +1,32 @@
+package graphics.kiln.blaze4d.core.types;
+
+public enum FrontFace {
+    COUNTER_CLOCKWISE(0),
+    CLOCKWISE(1);
+
+    private final int value;
+
+    FrontFace(int value) {
+        this.value = value;
+    }
+
+    public int getValue() {
+        return this.value;
+    }
+
+    public static FrontFace fromValue(int value) {
+        return switch (value) {
+            case 0 -> COUNTER_CLOCKWISE;
+            case 1 -> CLOCKWISE;
+            default -> throw new IllegalStateException("Invalid front face value: " + value);
+        };
+    }
+
+    public static FrontFace fromGlFrontFace(int glMode) {
+        return switch (glMode) {
+            case 0x0900 -> CLOCKWISE;
+            case 0x0901 -> COUNTER_CLOCKWISE;
+            default -> throw new IllegalStateException("Invalid front face value: " + glMode);
+        };
+    }
+}
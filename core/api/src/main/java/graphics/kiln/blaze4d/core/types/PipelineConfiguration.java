@@ -95,6 +95,30 @@ public class PipelineConfiguration implements AutoCloseable {
         return BlendFactor.fromValue((int) PipelineConfigurationNative.BLEND_ALPHA_DST_FACTOR_HANDLE.get(this.memory));
     }
 
+    public void setCullMode(CullMode mode) {
+        PipelineConfigurationNative.CULL_MODE_HANDLE.set(this.memory, mode.getValue());
+    }
+
+    public CullMode getCullMode() {
+        return CullMode.fromValue((int) PipelineConfigurationNative.CULL_MODE_HANDLE.get(this.memory));
+    }
+
+    public void setFrontFace(FrontFace face) {
+        PipelineConfigurationNative.FRONT_FACE_HANDLE.set(this.memory, face.getValue());
+    }
+
+    public FrontFace getFrontFace() {
+        return FrontFace.fromValue((int) PipelineConfigurationNative.FRONT_FACE_HANDLE.get(this.memory));
+    }
+
+    public void setPolygonMode(PolygonMode mode) {
+        PipelineConfigurationNative.POLYGON_MODE_HANDLE.set(this.memory, mode.getValue());
+    }
+
+    public PolygonMode getPolygonMode() {
+        return PolygonMode.fromValue((int) PipelineConfigurationNative.POLYGON_MODE_HANDLE.get(this.memory));
+    }
+
     public MemoryAddress getAddress() {
         return this.memory.address();
     }
@@ -0,0 +1,35 @@
+package graphics.kiln.blaze4d.core.types;
+
+public enum PolygonMode {
+    FILL(0),
+    LINE(1),
+    POINT(2);
+
+    private final int value;
+
+    PolygonMode(int value) {
+        this.value = value;
+    }
+
+    public int getValue() {
+        return this.value;
+    }
+
+    public static PolygonMode fromValue(int value) {
+        return switch (value) {
+            case 0 -> FILL;
+            case 1 -> LINE;
+            case 2 -> POINT;
+            default -> throw new IllegalStateException("Invalid polygon mode value: " + value);
+        };
+    }
+
+    public static PolygonMode fromGlPolygonMode(int glMode) {
+        return switch (glMode) {
+            case 0x1B00 -> POINT;
+            case 0x1B01 -> LINE;
+            case 0x1B02 -> FILL;
+            default -> throw new IllegalStateException("Invalid polygon mode value: " + glMode);
+        };
+    }
+}
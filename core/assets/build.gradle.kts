@@ -13,8 +13,13 @@ configure<graphics.kiln.blaze4d.build.assets.AssetsPluginExtension> {
             addModule("debug/null.vert")
             addModule("debug/debug.frag")
             addModule("debug/textured.frag")
+            addModule("debug/fog.vert")
+            addModule("debug/fog.frag")
             addModule("debug/background.vert")
             addModule("debug/background.frag")
+            addModule("main/main.vert")
+            addModule("main/main.frag")
+            addModule("cull/frustum_cull.comp")
         }
 
         addProject("Utils") {
@@ -4,6 +4,7 @@
  */
 
 #include <mc_uniforms.glsl>
+#include "transform.glsl"
 
 layout(location=0) in vec3 in_position;
 layout(location=1) in vec4 in_color;
@@ -11,6 +12,6 @@ layout(location=1) in vec4 in_color;
 layout(location=0) out vec4 out_color;
 
 void main() {
-    gl_Position = mc_transform_position(in_position);
+    gl_Position = debug_transform_position(in_position);
     out_color = in_color;
 }
\ No newline at end of file
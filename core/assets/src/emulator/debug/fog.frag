@@ -0,0 +1,16 @@
+#version 450
+/**
+ * Outputs the fog value of the pixel. Black if there is no fog and white if the fog color fully
+ * replaces the color.
+ */
+
+#include <mc_uniforms.glsl>
+#include <fog.glsl>
+
+layout(location=0) in float in_fog_distance;
+
+layout(location=0) out vec4 out_color;
+
+void main() {
+    out_color = vec4(vec3(mc_fog_value(in_fog_distance, mc_fog_start(), mc_fog_end())), 1.0);
+}
\ No newline at end of file
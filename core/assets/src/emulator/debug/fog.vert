@@ -0,0 +1,17 @@
+#version 450
+/**
+ * A debug shader passing the fog distance to the fragment shader.
+ */
+
+#include <mc_uniforms.glsl>
+#include <fog.glsl>
+#include "transform.glsl"
+
+layout(location=0) in vec3 in_position;
+
+layout(location=0) out float out_fog_distance;
+
+void main() {
+    gl_Position = debug_transform_position(in_position);
+    out_fog_distance = mc_fog_distance(debug_model_view_matrix(), debug_model_position(in_position) + mc_chunk_offset(), mc_fog_shape());
+}
@@ -4,12 +4,13 @@
  */
 
 #include <mc_uniforms.glsl>
+#include "transform.glsl"
 
 layout(location=0) in vec3 in_position;
 
 layout(location=0) out vec4 out_color;
 
 void main() {
-    gl_Position = mc_transform_position(in_position);
+    gl_Position = debug_transform_position(in_position);
     out_color = vec4(0.0, 0.0, 0.0, 0.0);
 }
\ No newline at end of file
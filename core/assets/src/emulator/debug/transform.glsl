@@ -0,0 +1,43 @@
+/**
+ * Vertex transformation shared by all debug vertex shaders.
+ *
+ * Requires mc_uniforms.glsl to be included first.
+ */
+
+// Set if the draw uses wide lines which must be expanded into screen space quads
+layout(constant_id=0) const bool WIDE_LINES = false;
+
+// Set if the draw is part of a batch which provides the chunk offset through in_batch_chunk_offset
+layout(constant_id=1) const bool BATCHED = false;
+
+// Set if the draw is instanced and provides the model view matrix through in_instance_model_view_matrix
+layout(constant_id=2) const bool INSTANCED = false;
+
+// The line direction if WIDE_LINES is set. Otherwise contains garbage and must not be used.
+layout(location=2) in vec3 in_line_direction;
+
+// The chunk offset if BATCHED is set. Otherwise contains garbage and must not be used.
+layout(location=3) in vec3 in_batch_chunk_offset;
+
+// The model view matrix if INSTANCED is set. Otherwise contains garbage and must not be used.
+layout(location=4) in mat4 in_instance_model_view_matrix;
+
+// Returns the position including the chunk offset of batched draws. The chunk offset uniform is not included.
+vec3 debug_model_position(vec3 position) {
+    return BATCHED ? position + in_batch_chunk_offset : position;
+}
+
+mat4 debug_model_view_matrix() {
+    return INSTANCED ? in_instance_model_view_matrix : mc_model_view_matrix();
+}
+
+vec4 debug_transform_position(vec3 position) {
+    position = debug_model_position(position);
+    mat4 model_view_matrix = debug_model_view_matrix();
+
+    if (WIDE_LINES) {
+        return mc_transform_line(model_view_matrix, position, in_line_direction, gl_VertexIndex);
+    } else {
+        return mc_transform_position(model_view_matrix, position);
+    }
+}
@@ -0,0 +1,27 @@
+/**
+ * Implementation of minecrafts fog functions.
+ */
+
+float mc_fog_distance(mat4 model_view_matrix, vec3 position, uint shape) {
+    if (shape == 0) {
+        return length((model_view_matrix * vec4(position, 1.0)).xyz);
+    } else {
+        float dist_xz = length((model_view_matrix * vec4(position.x, 0.0, position.z, 1.0)).xyz);
+        float dist_y = length((model_view_matrix * vec4(0.0, position.y, 0.0, 1.0)).xyz);
+        return max(dist_xz, dist_y);
+    }
+}
+
+// Returns how strongly the fog color is applied ignoring the alpha of the fog color. 0 if there is no fog.
+float mc_fog_value(float vertex_distance, float fog_start, float fog_end) {
+    if (vertex_distance <= fog_start) {
+        return 0.0;
+    }
+
+    return vertex_distance < fog_end ? smoothstep(fog_start, fog_end, vertex_distance) : 1.0;
+}
+
+vec4 mc_linear_fog(vec4 color, float vertex_distance, float fog_start, float fog_end, vec4 fog_color) {
+    float fog_value = mc_fog_value(vertex_distance, fog_start, fog_end);
+    return vec4(mix(color.rgb, fog_color.rgb, fog_value * fog_color.a), color.a);
+}
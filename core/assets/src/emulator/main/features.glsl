@@ -0,0 +1,26 @@
+/**
+ * Specialization constants used to configure the main shaders for a specific minecraft shader.
+ */
+
+layout(constant_id=0) const uint FEATURES = 0;
+
+// The factor needed to convert the uv1 and uv2 attributes back to texel coordinates
+layout(constant_id=1) const float UV1_SCALE = 1.0;
+layout(constant_id=2) const float UV2_SCALE = 1.0;
+
+// Fragments with an alpha value below the cutoff are discarded. The default never discards
+layout(constant_id=3) const float ALPHA_CUTOFF = 0.0;
+
+const uint FEATURE_COLOR = 1;
+const uint FEATURE_UV0 = 2;
+const uint FEATURE_UV1 = 4;
+const uint FEATURE_UV2 = 8;
+const uint FEATURE_LIGHTING = 16;
+const uint FEATURE_FOG = 32;
+const uint FEATURE_WIDE_LINES = 64;
+const uint FEATURE_BATCHED = 128;
+const uint FEATURE_INSTANCED = 256;
+
+bool has_feature(uint feature) {
+    return (FEATURES & feature) != 0;
+}
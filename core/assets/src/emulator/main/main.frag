@@ -0,0 +1,35 @@
+#version 450
+/**
+ * The fragment shader used to emulate minecrafts core shaders.
+ */
+
+#include <mc_uniforms.glsl>
+#include <fog.glsl>
+#include "features.glsl"
+
+layout(location=0) in vec4 in_color;
+layout(location=1) in vec2 in_uv0;
+layout(location=2) in vec4 in_overlay;
+layout(location=3) in float in_fog_distance;
+
+layout(location=0) out vec4 out_color;
+
+void main() {
+    vec4 color = in_color;
+    if (has_feature(FEATURE_UV0)) {
+        color *= mc_image_0(in_uv0);
+    }
+    color *= mc_color_modulator();
+
+    if (color.a < ALPHA_CUTOFF) {
+        discard;
+    }
+
+    color.rgb = mix(in_overlay.rgb, color.rgb, in_overlay.a);
+
+    if (has_feature(FEATURE_FOG)) {
+        color = mc_linear_fog(color, in_fog_distance, mc_fog_start(), mc_fog_end(), mc_fog_color());
+    }
+
+    out_color = color;
+}
@@ -0,0 +1,78 @@
+#version 450
+/**
+ * The vertex shader used to emulate minecrafts core shaders.
+ *
+ * Attributes which are not part of the vertex format are still bound by the pipeline but contain
+ * garbage and must not be used.
+ */
+
+#include <mc_uniforms.glsl>
+#include <fog.glsl>
+#include "features.glsl"
+
+layout(location=0) in vec3 in_position;
+layout(location=1) in vec4 in_color;
+layout(location=2) in vec2 in_uv0;
+layout(location=3) in vec2 in_uv1;
+layout(location=4) in vec2 in_uv2;
+layout(location=5) in vec3 in_normal;
+// The per draw chunk offset of batched draws. The chunk offset push constant is 0 for batched draws.
+layout(location=6) in vec3 in_batch_chunk_offset;
+// The per instance model view matrix and color of instanced draws.
+layout(location=7) in mat4 in_instance_model_view_matrix;
+layout(location=11) in vec4 in_instance_color;
+
+layout(location=0) out vec4 out_color;
+layout(location=1) out vec2 out_uv0;
+layout(location=2) out vec4 out_overlay;
+layout(location=3) out float out_fog_distance;
+
+const float LIGHT_POWER = 0.6;
+const float AMBIENT_LIGHT = 0.4;
+
+vec4 mix_light(vec3 light_0, vec3 light_1, vec3 normal, vec4 color) {
+    float light = max(0.0, dot(normalize(light_0), normal)) + max(0.0, dot(normalize(light_1), normal));
+    return vec4(color.rgb * min(1.0, light * LIGHT_POWER + AMBIENT_LIGHT), color.a);
+}
+
+void main() {
+    vec3 position = has_feature(FEATURE_BATCHED) ? in_position + in_batch_chunk_offset : in_position;
+    mat4 model_view_matrix = has_feature(FEATURE_INSTANCED) ? in_instance_model_view_matrix : mc_model_view_matrix();
+
+    if (has_feature(FEATURE_WIDE_LINES)) {
+        gl_Position = mc_transform_line(model_view_matrix, position, in_normal, gl_VertexIndex);
+    } else {
+        gl_Position = mc_transform_position(model_view_matrix, position);
+    }
+
+    vec4 color = has_feature(FEATURE_COLOR) ? in_color : vec4(1.0);
+    if (has_feature(FEATURE_INSTANCED)) {
+        color *= in_instance_color;
+    }
+    if (has_feature(FEATURE_LIGHTING)) {
+        color = mix_light(mc_light_0_direction(), mc_light_1_direction(), in_normal, color);
+    }
+    if (has_feature(FEATURE_UV2)) {
+        vec2 lightmap_coord = clamp((in_uv2 * UV2_SCALE) / 256.0, vec2(0.5 / 16.0), vec2(15.5 / 16.0));
+        color *= mc_image_2(lightmap_coord);
+    }
+    out_color = color;
+
+    if (has_feature(FEATURE_UV0)) {
+        out_uv0 = (mc_texture_matrix() * vec4(in_uv0, 0.0, 1.0)).xy;
+    } else {
+        out_uv0 = vec2(0.0);
+    }
+
+    if (has_feature(FEATURE_UV1)) {
+        out_overlay = texelFetch(_mc_image[1], ivec2(in_uv1 * UV1_SCALE), 0);
+    } else {
+        out_overlay = vec4(0.0, 0.0, 0.0, 1.0);
+    }
+
+    if (has_feature(FEATURE_FOG)) {
+        out_fog_distance = mc_fog_distance(model_view_matrix, position + mc_chunk_offset(), mc_fog_shape());
+    } else {
+        out_fog_distance = 0.0;
+    }
+}
@@ -7,22 +7,17 @@ layout(set=0, binding=1) uniform sampler2D[3] _mc_image;
 layout(set=0, binding=0, std140)
 uniform _McStaticUniforms {
     mat4 projection_matrix;
+    mat4 inverse_view_rotation_matrix;
+    mat4 texture_matrix;
     vec4 fog_color;
+    vec4 color_modulator;
     vec3 fog_range_and_game_time;
     uint fog_shape;
-    vec2 screen_size;
-} _mc_static_uniforms;
-
-/*
-layout(set=1, binding=0, std140)
-uniform McSet1Binding0 {
-    mat4 inverse_view_rotation_matrix;
-    mat4 texture_matrix;
     vec3 light_0_direction;
-    vec3 light_1_direction;
-    vec4 color_modulator;
     float line_width;
-} mc_set_1_binding_0;*/
+    vec3 light_1_direction;
+    vec2 screen_size;
+} _mc_static_uniforms;
 
 layout(push_constant)
 uniform _PushConstant {
@@ -38,13 +33,12 @@ mat4 mc_projection_matrix() {
     return _mc_static_uniforms.projection_matrix;
 }
 
-/*
 mat4 mc_inverse_view_rotation_matrix() {
-    return mc_set_1_binding_0.inverse_view_rotation_matrix;
+    return _mc_static_uniforms.inverse_view_rotation_matrix;
 }
 
 mat4 mc_texture_matrix() {
-    return mc_set_1_binding_0.texture_matrix;
+    return _mc_static_uniforms.texture_matrix;
 }
 
 vec2 mc_screen_size() {
@@ -52,15 +46,15 @@ vec2 mc_screen_size() {
 }
 
 vec4 mc_color_modulator() {
-    return mc_set_1_binding_0.color_modulator;
+    return _mc_static_uniforms.color_modulator;
 }
 
 vec3 mc_light_0_direction() {
-    return mc_set_1_binding_0.light_0_direction;
+    return _mc_static_uniforms.light_0_direction;
 }
 
 vec3 mc_light_1_direction() {
-    return mc_set_1_binding_0.light_1_direction;
+    return _mc_static_uniforms.light_1_direction;
 }
 
 vec4 mc_fog_color() {
@@ -80,22 +74,69 @@ uint mc_fog_shape() {
 }
 
 float mc_line_width() {
-    return mc_set_1_binding_0.line_width;
+    return _mc_static_uniforms.line_width;
 }
 
 float mc_game_time() {
     return _mc_static_uniforms.fog_range_and_game_time.z;
-}*/
+}
 
 vec3 mc_chunk_offset() {
     return _push_constant.chunk_offset;
 }
 
+// Converts opengl clip space coordinates to vulkan clip space coordinates
+vec4 _mc_to_vulkan_clip(vec4 position) {
+    position.z = (position.z + position.w) / 2.0;
+    position.y *= -1.0;
+    return position;
+}
+
+vec4 mc_transform_position(mat4 model_view_matrix, vec3 position) {
+    return _mc_to_vulkan_clip(mc_projection_matrix() * (model_view_matrix * vec4(position + mc_chunk_offset(), 1.0)));
+}
+
 vec4 mc_transform_position(vec3 position) {
-    vec4 tmp = mc_projection_matrix() * (mc_model_view_matrix() * vec4(position + mc_chunk_offset(), 1.0));
-    tmp.z = (tmp.z + tmp.w) / 2.0;
-    tmp.y *= -1.0;
-    return tmp;
+    return mc_transform_position(mc_model_view_matrix(), position);
+}
+
+// Slightly shrinks lines towards the camera to avoid z fighting. Matches minecrafts rendertype_lines shader.
+const mat4 _MC_LINE_VIEW_SCALE = mat4(
+    0.99609375, 0.0, 0.0, 0.0,
+    0.0, 0.99609375, 0.0, 0.0,
+    0.0, 0.0, 0.99609375, 0.0,
+    0.0, 0.0, 0.0, 1.0
+);
+
+/**
+ * Transforms a vertex of a wide line. Every line vertex must be submitted twice with the copies
+ * placed at an even and the following odd vertex index (gl_VertexIndex). The copies are offset in
+ * opposite directions perpendicular to the line in screen space to form a quad of mc_line_width()
+ * pixels.
+ */
+vec4 mc_transform_line(mat4 model_view_matrix, vec3 position, vec3 line_direction, int vertex_index) {
+    vec3 world_position = position + mc_chunk_offset();
+    vec4 line_start = mc_projection_matrix() * _MC_LINE_VIEW_SCALE * model_view_matrix * vec4(world_position, 1.0);
+    vec4 line_end = mc_projection_matrix() * _MC_LINE_VIEW_SCALE * model_view_matrix * vec4(world_position + line_direction, 1.0);
+
+    vec3 ndc_start = line_start.xyz / line_start.w;
+    vec3 ndc_end = line_end.xyz / line_end.w;
+
+    vec2 screen_direction = normalize((ndc_end.xy - ndc_start.xy) * mc_screen_size());
+    vec2 offset = vec2(-screen_direction.y, screen_direction.x) * mc_line_width() / mc_screen_size();
+    if (offset.x < 0.0) {
+        offset *= -1.0;
+    }
+
+    if (vertex_index % 2 == 0) {
+        return _mc_to_vulkan_clip(vec4((ndc_start + vec3(offset, 0.0)) * line_start.w, line_start.w));
+    } else {
+        return _mc_to_vulkan_clip(vec4((ndc_start - vec3(offset, 0.0)) * line_start.w, line_start.w));
+    }
+}
+
+vec4 mc_transform_line(vec3 position, vec3 line_direction, int vertex_index) {
+    return mc_transform_line(mc_model_view_matrix(), position, line_direction, vertex_index);
 }
 
 vec4 mc_image(uint index, vec2 coord) {
@@ -8,7 +8,8 @@ use winit::event_loop::{ControlFlow, EventLoop};
 use b4d_core::prelude::*;
 use b4d_core::renderer::emulator::debug_pipeline::DebugPipelineMode;
 use b4d_core::renderer::emulator::mc_shaders::{McUniform, McUniformData, VertexFormat, VertexFormatEntry};
-use b4d_core::renderer::emulator::MeshData;
+use b4d_core::renderer::emulator::{MeshData, MeshInstance, PrimitiveTopology};
+use b4d_core::renderer::emulator::pipeline::DrawConfig;
 
 use b4d_core::window::WinitWindow;
 
@@ -21,18 +22,19 @@ fn main() {
     let b4d = b4d_core::b4d::Blaze4D::new(window, true);
     b4d.set_debug_mode(Some(DebugPipelineMode::Textured0));
     let vertex_format = Vertex::make_b4d_vertex_format();
-    let mut shader = b4d.create_shader(&vertex_format, McUniform::MODEL_VIEW_MATRIX | McUniform::PROJECTION_MATRIX);
+    let mut shader = b4d.create_shader(&vertex_format, McUniform::MODEL_VIEW_MATRIX | McUniform::PROJECTION_MATRIX, 0f32);
 
     let data = MeshData {
         vertex_data: cast_slice(&CUBE_VERTICES),
-        index_data: cast_slice(&CUBE_INDICES),
+        index_data: Some(cast_slice(&CUBE_INDICES)),
         vertex_stride: std::mem::size_of::<Vertex>() as u32,
         index_count: CUBE_INDICES.len() as u32,
         index_type: vk::IndexType::UINT32,
-        primitive_topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+        primitive_topology: PrimitiveTopology::TriangleList,
     };
 
     let mut mesh = b4d.create_global_mesh(&data);
+    let mut instances = Vec::with_capacity(11 * 11 * 11);
 
     let mut draw_times = Vec::with_capacity(1000);
     let mut last_update = std::time::Instant::now();
@@ -61,7 +63,7 @@ fn main() {
             Event::MainEventsCleared => {
                 let now = std::time::Instant::now();
 
-                mesh = b4d.create_global_mesh(&data);
+                mesh = mesh.update_resized(&data);
 
                 if let Some(mut recorder) = b4d.try_start_frame(current_size) {
 
@@ -70,6 +72,7 @@ fn main() {
                     let elapsed = start.elapsed().as_secs_f32();
                     let rotation = Mat4f32::new_rotation(Vec3f32::new(elapsed / 2.34f32, elapsed / 2.783f32, elapsed / 2.593f32));
 
+                    instances.clear();
                     for x in -5i32..=5i32 {
                         for y in -5i32..=5i32 {
                             for z in 1i32..=11i32 {
@@ -78,20 +81,17 @@ fn main() {
                                     0f32 + ((y as f32) / 1f32),
                                     5f32 + ((z as f32) / 1f32)
                                 ));
-                                recorder.update_uniform(&McUniformData::ModelViewMatrix(translation * rotation), shader);
-
-                                let id = recorder.upload_immediate(&data);
-                                recorder.draw_immediate(id, shader, true);
-                                // recorder.draw_global(mesh.clone(), shader, true);
+                                instances.push(MeshInstance::new(translation * rotation, None));
                             }
                         }
                     }
+                    recorder.draw_global_instanced(mesh.clone(), shader, &instances, &DrawConfig::default());
 
                     drop(recorder);
 
                     // Stress test the shader stuff
                     b4d.drop_shader(shader);
-                    shader = b4d.create_shader(&vertex_format, McUniform::MODEL_VIEW_MATRIX | McUniform::PROJECTION_MATRIX);
+                    shader = b4d.create_shader(&vertex_format, McUniform::MODEL_VIEW_MATRIX | McUniform::PROJECTION_MATRIX, 0f32);
                 }
                 draw_times.push(now.elapsed());
 
@@ -220,6 +220,17 @@ impl Allocator {
         self.vma_allocator.destroy_image(image, allocation.vma_allocation)
     }
 
+    /// Invalidates a range of host visible memory making writes by the device visible to the host.
+    /// Does nothing if the memory is host coherent.
+    ///
+    /// # Safety
+    ///
+    /// `allocation` must have been previously allocated from this allocator and not yet freed.
+    /// `offset` and `size` are relative to the start of the allocation and must be inside of it.
+    pub unsafe fn invalidate_allocation(&self, allocation: Allocation, offset: vk::DeviceSize, size: vk::DeviceSize) -> Result<(), vk::Result> {
+        self.vma_allocator.invalidate_allocation(allocation.vma_allocation, offset, size)
+    }
+
     unsafe fn set_allocation_name(&self, allocation: vma::Allocation, name: &fmt::Arguments) {
         if let Some(str) = name.as_str() {
             self.vma_allocator.set_allocation_name(allocation, CString::new(str).unwrap().as_c_str())
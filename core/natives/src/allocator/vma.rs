@@ -344,6 +344,15 @@ impl Allocator {
         sys::vmaGetAllocationInfo(self.handle, allocation, info)
     }
 
+    pub unsafe fn invalidate_allocation(&self, allocation: Allocation, offset: vk::DeviceSize, size: vk::DeviceSize) -> Result<(), vk::Result> {
+        let result = sys::vmaInvalidateAllocation(self.handle, allocation, offset, size);
+        if result == vk::Result::SUCCESS {
+            Ok(())
+        } else {
+            Err(result)
+        }
+    }
+
     pub unsafe fn set_allocation_name(&self, allocation: Allocation, name: &CStr) {
         sys::vmaSetAllocationName(self.handle, allocation, name.as_ptr())
     }
@@ -463,6 +472,13 @@ mod sys {
             p_allocation_info: *mut AllocationInfo,
         );
 
+        pub(super) fn vmaInvalidateAllocation(
+            allocator: AllocatorHandle,
+            allocation: Allocation,
+            offset: vk::DeviceSize,
+            size: vk::DeviceSize,
+        ) -> vk::Result;
+
         pub(super) fn vmaSetAllocationName(
             allocator: AllocatorHandle,
             allocation: Allocation,
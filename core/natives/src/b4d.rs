@@ -12,9 +12,10 @@ use crate::instance::init::{create_instance, InstanceCreateConfig};
 use crate::vk::objects::surface::SurfaceProvider;
 
 use crate::prelude::*;
-use crate::renderer::emulator::{EmulatorRenderer, GlobalImage, GlobalMesh, MeshData};
+use crate::renderer::emulator::{EmulatorRenderer, GlobalImage, GlobalMesh, MeshData, RenderTarget};
 use crate::renderer::emulator::debug_pipeline::{DebugPipeline, DebugPipelineMode};
-use crate::renderer::emulator::mc_shaders::{McUniform, ShaderId, VertexFormat};
+use crate::renderer::emulator::mc_pipeline::McPipeline;
+use crate::renderer::emulator::mc_shaders::{McUniform, ShaderId, VertexFormat, VertexFormatEntry};
 use crate::renderer::emulator::PassRecorder;
 use crate::renderer::emulator::pipeline::{EmulatorPipeline, SwapchainOutput};
 use crate::util::format::Format;
@@ -86,12 +87,37 @@ impl Blaze4D {
         self.emulator.create_global_mesh(data)
     }
 
+    pub fn create_sortable_global_mesh(&self, data: &MeshData, position: &VertexFormatEntry) -> Arc<GlobalMesh> {
+        self.emulator.create_sortable_global_mesh(data, position)
+    }
+
     pub fn create_global_image(&self, size:Vec2u32, format: &'static Format) -> Arc<GlobalImage> {
         self.emulator.create_global_image(size, format)
     }
 
-    pub fn create_shader(&self, vertex_format: &VertexFormat, used_uniforms: McUniform) -> ShaderId {
-        self.emulator.create_shader(vertex_format, used_uniforms)
+    pub fn create_global_image_mips(&self, size: Vec2u32, mip_levels: u32, format: &'static Format) -> Arc<GlobalImage> {
+        self.emulator.create_global_image_mips(size, mip_levels, format)
+    }
+
+    pub fn create_global_image_array(&self, size: Vec2u32, array_layers: u32, mip_levels: u32, format: &'static Format) -> Arc<GlobalImage> {
+        self.emulator.create_global_image_array(size, array_layers, mip_levels, format)
+    }
+
+    pub fn create_global_image_3d(&self, size: Vec3u32, mip_levels: u32, format: &'static Format) -> Arc<GlobalImage> {
+        self.emulator.create_global_image_3d(size, mip_levels, format)
+    }
+
+    pub fn create_global_image_cube(&self, size: u32, mip_levels: u32, format: &'static Format) -> Arc<GlobalImage> {
+        self.emulator.create_global_image_cube(size, mip_levels, format)
+    }
+
+    pub fn create_render_target(&self, size: Vec2u32) -> Arc<RenderTarget> {
+        self.emulator.create_render_target(size)
+    }
+
+    /// See [`EmulatorRenderer::create_shader`].
+    pub fn create_shader(&self, vertex_format: &VertexFormat, used_uniforms: McUniform, alpha_cutoff: f32) -> ShaderId {
+        self.emulator.create_shader(vertex_format, used_uniforms, alpha_cutoff)
     }
 
     pub fn drop_shader(&self, id: ShaderId) {
@@ -105,6 +131,15 @@ impl Blaze4D {
             None
         }
     }
+
+    /// Starts a pass rendering into the provided render target. The debug mode is ignored for
+    /// these passes.
+    ///
+    /// Only one pass may be active at any time so the returned pass must be dropped before the next
+    /// frame is started.
+    pub fn start_target_pass(&self, render_target: Arc<RenderTarget>) -> PassRecorder {
+        self.render_config.lock().unwrap().start_target_pass(&self.emulator, render_target)
+    }
 }
 
 struct RenderConfig {
@@ -118,6 +153,10 @@ struct RenderConfig {
 
     debug_mode: Option<DebugPipelineMode>,
     debug_pipeline: Option<(Arc<dyn EmulatorPipeline>, Arc<SwapchainOutput>)>,
+
+    /// Pipeline used for render target passes. Its internal images are never rendered to so they
+    /// are created with the smallest possible size.
+    target_pipeline: Option<Arc<dyn EmulatorPipeline>>,
 }
 
 impl RenderConfig {
@@ -132,7 +171,9 @@ impl RenderConfig {
             current_pipeline: None,
 
             debug_mode: Some(DebugPipelineMode::Color),
-            debug_pipeline: None
+            debug_pipeline: None,
+
+            target_pipeline: None,
         }
     }
 
@@ -185,6 +226,15 @@ impl RenderConfig {
         Some(recorder)
     }
 
+    fn start_target_pass(&mut self, renderer: &EmulatorRenderer, render_target: Arc<RenderTarget>) -> PassRecorder {
+        let emulator = &self.emulator;
+        let pipeline = self.target_pipeline.get_or_insert_with(|| {
+            McPipeline::new(emulator.clone(), Vec2u32::new(1, 1)).unwrap()
+        });
+
+        renderer.start_target_pass(pipeline.clone(), render_target)
+    }
+
     fn prepare_pipeline(&mut self, output_size: Vec2u32) -> (Arc<dyn EmulatorPipeline>, &Arc<SwapchainOutput>) {
         if let Some(debug_mode) = &self.debug_mode {
             if self.debug_pipeline.is_none() {
@@ -199,7 +249,17 @@ impl RenderConfig {
             let (pipeline, output) = self.debug_pipeline.as_ref().unwrap();
             (pipeline.clone(), output)
         } else {
-            todo!()
+            if self.current_pipeline.is_none() {
+                log::info!("No pipeline present. Rebuilding for size {:?}", output_size);
+
+                let pipeline = McPipeline::new(self.emulator.clone(), output_size).unwrap();
+                let swapchain_output = SwapchainOutput::new(&self.device, pipeline.clone(), self.current_swapchain.as_ref().cloned().unwrap());
+
+                self.current_pipeline = Some((pipeline, swapchain_output));
+            }
+
+            let (pipeline, output) = self.current_pipeline.as_ref().unwrap();
+            (pipeline.clone(), output)
         }
     }
 
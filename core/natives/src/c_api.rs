@@ -4,11 +4,12 @@ use std::sync::Arc;
 use ash::vk;
 use crate::b4d::Blaze4D;
 use crate::glfw_surface::GLFWSurfaceProvider;
-use crate::prelude::{Mat4f32, UUID, Vec2f32, Vec2u32, Vec3f32, Vec4f32};
+use crate::prelude::{Mat4f32, UUID, Vec2f32, Vec2u32, Vec3f32, Vec3u32, Vec4f32};
 
-use crate::renderer::emulator::{MeshData, PassRecorder, ImmediateMeshId, GlobalMesh, ImageData, GlobalImage, SamplerInfo};
+use crate::renderer::emulator::{MeshData, PassRecorder, ImmediateMeshId, GlobalMesh, MeshBounds, MeshDataRange, MeshInstance, ImageData, GlobalImage, SamplerInfo, PrimitiveTopology, RenderTarget, ReadbackHandle, ImageRegion};
 use crate::renderer::emulator::debug_pipeline::DebugPipelineMode;
 use crate::renderer::emulator::mc_shaders::{McUniform, McUniformData, ShaderId, VertexFormat, VertexFormatEntry};
+use crate::renderer::emulator::pipeline::{BlendState, DrawConfig};
 use crate::util::format::Format;
 use crate::vk::objects::surface::SurfaceProvider;
 
@@ -38,6 +39,7 @@ impl CDebugMode {
     pub const TEXTURED0: CDebugMode = CDebugMode(8);
     pub const TEXTURED1: CDebugMode = CDebugMode(9);
     pub const TEXTURED2: CDebugMode = CDebugMode(10);
+    pub const FOG: CDebugMode = CDebugMode(11);
 
     pub fn to_debug_pipeline_mode(&self) -> Option<DebugPipelineMode> {
         match *self {
@@ -52,6 +54,7 @@ impl CDebugMode {
             Self::TEXTURED0 => Some(DebugPipelineMode::Textured0),
             Self::TEXTURED1 => Some(DebugPipelineMode::Textured1),
             Self::TEXTURED2 => Some(DebugPipelineMode::Textured2),
+            Self::FOG => Some(DebugPipelineMode::Fog),
             _ => panic!()
         }
     }
@@ -70,6 +73,37 @@ struct CPipelineConfiguration {
     blend_alpha_op: i32,
     blend_alpha_src_factor: i32,
     blend_alpha_dst_factor: i32,
+    cull_mode: u32,
+    front_face: i32,
+    polygon_mode: i32,
+}
+
+impl CPipelineConfiguration {
+    fn to_draw_config(&self) -> DrawConfig {
+        let blend_state = if self.blend_enable != 0 {
+            Some(BlendState {
+                color_op: vk::BlendOp::from_raw(self.blend_color_op),
+                color_src_factor: vk::BlendFactor::from_raw(self.blend_color_src_factor),
+                color_dst_factor: vk::BlendFactor::from_raw(self.blend_color_dst_factor),
+                alpha_op: vk::BlendOp::from_raw(self.blend_alpha_op),
+                alpha_src_factor: vk::BlendFactor::from_raw(self.blend_alpha_src_factor),
+                alpha_dst_factor: vk::BlendFactor::from_raw(self.blend_alpha_dst_factor),
+            })
+        } else {
+            None
+        };
+
+        DrawConfig {
+            depth_test_enable: self.depth_test_enable != 0,
+            depth_compare_op: vk::CompareOp::from_raw(self.depth_compare_op),
+            depth_write_enable: self.depth_write_enable != 0,
+            blend_state,
+            cull_mode: vk::CullModeFlags::from_raw(self.cull_mode),
+            front_face: vk::FrontFace::from_raw(self.front_face),
+            polygon_mode: vk::PolygonMode::from_raw(self.polygon_mode),
+            ..Default::default()
+        }
+    }
 }
 
 #[repr(C)]
@@ -77,6 +111,7 @@ struct CPipelineConfiguration {
 struct CMeshData {
     vertex_data_ptr: *const u8,
     vertex_data_len: usize,
+    /// May be null for non indexed meshes. In that case index_count is the number of vertices.
     index_data_ptr: *const u8,
     index_data_len: usize,
     vertex_stride: u32,
@@ -91,18 +126,21 @@ impl CMeshData {
             log::error!("Vertex data pointer is null");
             panic!();
         }
-        if self.index_data_ptr.is_null() {
-            log::error!("Index data pointer is null");
-            panic!();
-        }
 
         MeshData {
             vertex_data: std::slice::from_raw_parts(self.vertex_data_ptr, self.vertex_data_len as usize),
-            index_data: std::slice::from_raw_parts(self.index_data_ptr, self.index_data_len as usize),
+            index_data: if self.index_data_ptr.is_null() {
+                None
+            } else {
+                Some(std::slice::from_raw_parts(self.index_data_ptr, self.index_data_len as usize))
+            },
             vertex_stride: self.vertex_stride,
             index_count: self.index_count,
             index_type: vk::IndexType::from_raw(self.index_type),
-            primitive_topology: vk::PrimitiveTopology::from_raw(self.primitive_topology),
+            primitive_topology: PrimitiveTopology::from_raw(self.primitive_topology).unwrap_or_else(|| {
+                log::error!("Invalid primitive topology {:?}", self.primitive_topology);
+                panic!();
+            }),
         }
     }
 }
@@ -199,6 +237,8 @@ struct CImageData {
     row_stride: u32,
     offset: [u32; 2],
     extent: [u32; 2],
+    mip_level: u32,
+    array_layer: u32,
 }
 
 impl CImageData {
@@ -210,9 +250,11 @@ impl CImageData {
 
         ImageData {
             data: std::slice::from_raw_parts(self.data_ptr, self.data_ptr_len),
-            row_stride: 0,
+            row_stride: self.row_stride,
             offset: Vec2u32::new(self.offset[0], self.offset[1]),
-            extent: Vec2u32::new(self.extent[0], self.extent[1])
+            extent: Vec2u32::new(self.extent[0], self.extent[1]),
+            mip_level: self.mip_level,
+            array_layer: self.array_layer,
         }
     }
 }
@@ -289,6 +331,20 @@ impl CMcUniformData {
     }
 }
 
+#[repr(C)]
+struct CMeshInstance {
+    model_view_matrix: Mat4f32,
+    color: Vec4f32,
+    /// If 0 the color is ignored.
+    color_enable: u32,
+}
+
+impl CMeshInstance {
+    fn to_mesh_instance(&self) -> MeshInstance {
+        MeshInstance::new(self.model_view_matrix, if self.color_enable != 0 { Some(self.color) } else { None })
+    }
+}
+
 #[repr(C)]
 struct CSamplerInfo {
     mag_filter: i32,
@@ -296,7 +352,15 @@ struct CSamplerInfo {
     mipmap_mode: i32,
     address_mode_u: i32,
     address_mode_v: i32,
+    address_mode_w: i32,
     anisotropy_enable: u32,
+    max_anisotropy: f32,
+    mip_lod_bias: f32,
+    min_lod: f32,
+    max_lod: f32,
+    border_color: i32,
+    compare_enable: u32,
+    compare_op: i32,
 }
 
 impl CSamplerInfo {
@@ -307,7 +371,14 @@ impl CSamplerInfo {
             mipmap_mode: vk::SamplerMipmapMode::from_raw(self.mipmap_mode),
             address_mode_u: vk::SamplerAddressMode::from_raw(self.address_mode_u),
             address_mode_v: vk::SamplerAddressMode::from_raw(self.address_mode_v),
+            address_mode_w: vk::SamplerAddressMode::from_raw(self.address_mode_w),
             anisotropy_enable: self.anisotropy_enable != 0,
+            max_anisotropy: self.max_anisotropy,
+            mip_lod_bias: self.mip_lod_bias,
+            min_lod: self.min_lod,
+            max_lod: self.max_lod,
+            border_color: vk::BorderColor::from_raw(self.border_color),
+            compare_op: if self.compare_enable != 0 { Some(vk::CompareOp::from_raw(self.compare_op)) } else { None },
         }
     }
 }
@@ -392,6 +463,33 @@ unsafe extern "C" fn b4d_create_global_mesh(b4d: *const Blaze4D, data: *const CM
     })
 }
 
+/// Creates a quads mesh which can be depth sorted using `b4d_global_mesh_sort_quads`. The
+/// position offset and format define where the vertex positions are stored in the vertex data.
+#[no_mangle]
+unsafe extern "C" fn b4d_create_sortable_global_mesh(b4d: *const Blaze4D, data: *const CMeshData, position_offset: u32, position_format: i32) -> *mut Arc<GlobalMesh> {
+    catch_unwind(|| {
+        let b4d = b4d.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null b4d to b4d_create_sortable_global_mesh");
+            exit(1);
+        });
+        let data = data.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null mesh data to b4d_create_sortable_global_mesh");
+            exit(1);
+        });
+
+        let mesh_data = data.to_mesh_data();
+        let position = VertexFormatEntry {
+            offset: position_offset,
+            format: vk::Format::from_raw(position_format),
+        };
+
+        Box::leak(Box::new(b4d.create_sortable_global_mesh(&mesh_data, &position)))
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_create_sortable_global_mesh");
+        exit(1);
+    })
+}
+
 #[no_mangle]
 unsafe extern "C" fn b4d_destroy_global_mesh(mesh: *mut Arc<GlobalMesh>) {
     catch_unwind(|| {
@@ -406,6 +504,103 @@ unsafe extern "C" fn b4d_destroy_global_mesh(mesh: *mut Arc<GlobalMesh>) {
     })
 }
 
+/// Updates parts of the vertex and index data of a global mesh. The data pointers may be null if
+/// the respective data should not be updated.
+#[no_mangle]
+unsafe extern "C" fn b4d_global_mesh_update(mesh: *const Arc<GlobalMesh>, vertex_offset: u64, vertex_data_ptr: *const u8, vertex_data_len: usize, index_offset: u64, index_data_ptr: *const u8, index_data_len: usize) {
+    catch_unwind(|| {
+        let mesh = mesh.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null mesh to b4d_global_mesh_update");
+            exit(1);
+        });
+
+        let vertex_range = if vertex_data_ptr.is_null() {
+            None
+        } else {
+            Some(MeshDataRange::new(vertex_offset, std::slice::from_raw_parts(vertex_data_ptr, vertex_data_len)))
+        };
+        let index_range = if index_data_ptr.is_null() {
+            None
+        } else {
+            Some(MeshDataRange::new(index_offset, std::slice::from_raw_parts(index_data_ptr, index_data_len)))
+        };
+
+        mesh.update(vertex_range, index_range);
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_global_mesh_update");
+        exit(1);
+    })
+}
+
+/// Replaces the content of a global mesh. Returns a new handle which may refer to the same mesh or
+/// to a newly created one. The passed handle remains valid and must still be destroyed.
+#[no_mangle]
+unsafe extern "C" fn b4d_global_mesh_update_resized(mesh: *const Arc<GlobalMesh>, data: *const CMeshData) -> *mut Arc<GlobalMesh> {
+    catch_unwind(|| {
+        let mesh = mesh.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null mesh to b4d_global_mesh_update_resized");
+            exit(1);
+        });
+        let data = data.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null mesh data to b4d_global_mesh_update_resized");
+            exit(1);
+        });
+
+        let mesh_data = data.to_mesh_data();
+
+        Box::leak(Box::new(mesh.update_resized(&mesh_data)))
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_global_mesh_update_resized");
+        exit(1);
+    })
+}
+
+/// Sets the bounding box of a global mesh used for frustum culling. `bounds` must point to the min
+/// xyz followed by the max xyz coordinates or be null to remove the bounds.
+#[no_mangle]
+unsafe extern "C" fn b4d_global_mesh_set_bounds(mesh: *const Arc<GlobalMesh>, bounds: *const f32) {
+    catch_unwind(|| {
+        let mesh = mesh.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null mesh to b4d_global_mesh_set_bounds");
+            exit(1);
+        });
+
+        let bounds = if bounds.is_null() {
+            None
+        } else {
+            let bounds = std::slice::from_raw_parts(bounds, 6);
+            Some(MeshBounds::new(Vec3f32::new(bounds[0], bounds[1], bounds[2]), Vec3f32::new(bounds[3], bounds[4], bounds[5])))
+        };
+
+        mesh.set_bounds(bounds);
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_global_mesh_set_bounds");
+        exit(1);
+    })
+}
+
+/// Sorts the quads of a sortable mesh back to front. The camera position must point to 3 floats and
+/// be relative to the origin of the mesh.
+#[no_mangle]
+unsafe extern "C" fn b4d_global_mesh_sort_quads(mesh: *const Arc<GlobalMesh>, camera_position: *const f32) {
+    catch_unwind(|| {
+        let mesh = mesh.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null mesh to b4d_global_mesh_sort_quads");
+            exit(1);
+        });
+        if camera_position.is_null() {
+            log::error!("Passed null camera position to b4d_global_mesh_sort_quads");
+            exit(1);
+        }
+
+        let camera_position = std::slice::from_raw_parts(camera_position, 3);
+        mesh.sort_quads(Vec3f32::new(camera_position[0], camera_position[1], camera_position[2]));
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_global_mesh_sort_quads");
+        exit(1);
+    })
+}
+
 #[no_mangle]
 unsafe extern "C" fn b4d_create_global_image(b4d: *const Blaze4D, width: u32, height: u32, format: i32) -> *mut Arc<GlobalImage> {
     catch_unwind(|| {
@@ -424,6 +619,77 @@ unsafe extern "C" fn b4d_create_global_image(b4d: *const Blaze4D, width: u32, he
     })
 }
 
+#[no_mangle]
+unsafe extern "C" fn b4d_create_global_image_mips(b4d: *const Blaze4D, width: u32, height: u32, mip_levels: u32, format: i32) -> *mut Arc<GlobalImage> {
+    catch_unwind(|| {
+        let b4d = b4d.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null b4d to b4d_create_global_image_mips");
+            exit(1);
+        });
+
+        let size = Vec2u32::new(width, height);
+        let format = Format::format_for(vk::Format::from_raw(format));
+
+        Box::leak(Box::new(b4d.create_global_image_mips(size, mip_levels, format)))
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_create_global_image_mips");
+        exit(1);
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn b4d_create_global_image_array(b4d: *const Blaze4D, width: u32, height: u32, array_layers: u32, mip_levels: u32, format: i32) -> *mut Arc<GlobalImage> {
+    catch_unwind(|| {
+        let b4d = b4d.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null b4d to b4d_create_global_image_array");
+            exit(1);
+        });
+
+        let size = Vec2u32::new(width, height);
+        let format = Format::format_for(vk::Format::from_raw(format));
+
+        Box::leak(Box::new(b4d.create_global_image_array(size, array_layers, mip_levels, format)))
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_create_global_image_array");
+        exit(1);
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn b4d_create_global_image_3d(b4d: *const Blaze4D, width: u32, height: u32, depth: u32, mip_levels: u32, format: i32) -> *mut Arc<GlobalImage> {
+    catch_unwind(|| {
+        let b4d = b4d.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null b4d to b4d_create_global_image_3d");
+            exit(1);
+        });
+
+        let size = Vec3u32::new(width, height, depth);
+        let format = Format::format_for(vk::Format::from_raw(format));
+
+        Box::leak(Box::new(b4d.create_global_image_3d(size, mip_levels, format)))
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_create_global_image_3d");
+        exit(1);
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn b4d_create_global_image_cube(b4d: *const Blaze4D, size: u32, mip_levels: u32, format: i32) -> *mut Arc<GlobalImage> {
+    catch_unwind(|| {
+        let b4d = b4d.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null b4d to b4d_create_global_image_cube");
+            exit(1);
+        });
+
+        let format = Format::format_for(vk::Format::from_raw(format));
+
+        Box::leak(Box::new(b4d.create_global_image_cube(size, mip_levels, format)))
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_create_global_image_cube");
+        exit(1);
+    })
+}
+
 #[no_mangle]
 unsafe extern "C" fn b4d_update_global_image(image: *mut Arc<GlobalImage>, writes: *const CImageData, count: u32) {
     catch_unwind(|| {
@@ -446,6 +712,144 @@ unsafe extern "C" fn b4d_update_global_image(image: *mut Arc<GlobalImage>, write
     })
 }
 
+#[no_mangle]
+unsafe extern "C" fn b4d_global_image_generate_mipmaps(image: *const Arc<GlobalImage>) {
+    catch_unwind(|| {
+        let image = image.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null image to b4d_global_image_generate_mipmaps");
+            exit(1);
+        });
+
+        image.generate_mipmaps();
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_global_image_generate_mipmaps");
+        exit(1);
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn b4d_global_image_copy(dst: *const Arc<GlobalImage>, src: *const Arc<GlobalImage>, src_x: u32, src_y: u32, width: u32, height: u32, src_mip_level: u32, src_array_layer: u32, dst_x: u32, dst_y: u32, dst_mip_level: u32, dst_array_layer: u32) {
+    catch_unwind(|| {
+        let dst = dst.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null dst to b4d_global_image_copy");
+            exit(1);
+        });
+        let src = src.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null src to b4d_global_image_copy");
+            exit(1);
+        });
+
+        let src_region = ImageRegion::new(Vec2u32::new(src_x, src_y), Vec2u32::new(width, height), src_mip_level, src_array_layer);
+
+        dst.copy_from(src, &src_region, Vec2u32::new(dst_x, dst_y), dst_mip_level, dst_array_layer);
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_global_image_copy");
+        exit(1);
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn b4d_global_image_blit(dst: *const Arc<GlobalImage>, src: *const Arc<GlobalImage>, src_x: u32, src_y: u32, src_width: u32, src_height: u32, src_mip_level: u32, src_array_layer: u32, dst_x: u32, dst_y: u32, dst_width: u32, dst_height: u32, dst_mip_level: u32, dst_array_layer: u32, filter: i32) {
+    catch_unwind(|| {
+        let dst = dst.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null dst to b4d_global_image_blit");
+            exit(1);
+        });
+        let src = src.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null src to b4d_global_image_blit");
+            exit(1);
+        });
+
+        let src_region = ImageRegion::new(Vec2u32::new(src_x, src_y), Vec2u32::new(src_width, src_height), src_mip_level, src_array_layer);
+        let dst_region = ImageRegion::new(Vec2u32::new(dst_x, dst_y), Vec2u32::new(dst_width, dst_height), dst_mip_level, dst_array_layer);
+
+        dst.blit_from(src, &src_region, &dst_region, vk::Filter::from_raw(filter));
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_global_image_blit");
+        exit(1);
+    })
+}
+
+/// Calls [`GlobalImage::read_back`]. The returned handle must be destroyed by calling
+/// [`b4d_destroy_readback`].
+#[no_mangle]
+unsafe extern "C" fn b4d_global_image_read_back(image: *const Arc<GlobalImage>, x: u32, y: u32, width: u32, height: u32, mip_level: u32, array_layer: u32) -> *mut ReadbackHandle {
+    catch_unwind(|| {
+        let image = image.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null image to b4d_global_image_read_back");
+            exit(1);
+        });
+
+        Box::leak(Box::new(image.read_back(Vec2u32::new(x, y), Vec2u32::new(width, height), mip_level, array_layer)))
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_global_image_read_back");
+        exit(1);
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn b4d_readback_get_size(readback: *const ReadbackHandle) -> usize {
+    catch_unwind(|| {
+        let readback = readback.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null readback to b4d_readback_get_size");
+            exit(1);
+        });
+
+        readback.get_size()
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_readback_get_size");
+        exit(1);
+    })
+}
+
+/// Returns 1 if the data of the readback is available. 0 otherwise.
+#[no_mangle]
+unsafe extern "C" fn b4d_readback_is_ready(readback: *const ReadbackHandle) -> u32 {
+    catch_unwind(|| {
+        let readback = readback.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null readback to b4d_readback_is_ready");
+            exit(1);
+        });
+
+        if readback.is_ready() { 1 } else { 0 }
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_readback_is_ready");
+        exit(1);
+    })
+}
+
+/// Blocks until the data of the readback is available and returns a pointer to it. The pointer
+/// is valid until the readback is destroyed.
+#[no_mangle]
+unsafe extern "C" fn b4d_readback_wait(readback: *const ReadbackHandle) -> *const u8 {
+    catch_unwind(|| {
+        let readback = readback.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null readback to b4d_readback_wait");
+            exit(1);
+        });
+
+        readback.wait().as_ptr()
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_readback_wait");
+        exit(1);
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn b4d_destroy_readback(readback: *mut ReadbackHandle) {
+    catch_unwind(|| {
+        if readback.is_null() {
+            log::error!("Passed null readback to b4d_destroy_readback");
+            exit(1);
+        }
+
+        Box::from_raw(readback);
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_destroy_readback");
+        exit(1);
+    })
+}
+
 #[no_mangle]
 unsafe extern "C" fn b4d_destroy_global_image(image: *mut Arc<GlobalImage>) {
     catch_unwind(|| {
@@ -461,7 +865,41 @@ unsafe extern "C" fn b4d_destroy_global_image(image: *mut Arc<GlobalImage>) {
 }
 
 #[no_mangle]
-unsafe extern "C" fn b4d_create_shader(b4d: *const Blaze4D, vertex_format: *const CVertexFormat, used_uniforms: u64) -> u64 {
+unsafe extern "C" fn b4d_create_render_target(b4d: *const Blaze4D, width: u32, height: u32) -> *mut Arc<RenderTarget> {
+    catch_unwind(|| {
+        let b4d = b4d.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null b4d to b4d_create_render_target");
+            exit(1);
+        });
+
+        let size = Vec2u32::new(width, height);
+
+        Box::leak(Box::new(b4d.create_render_target(size)))
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_create_render_target");
+        exit(1);
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn b4d_destroy_render_target(render_target: *mut Arc<RenderTarget>) {
+    catch_unwind(|| {
+        if render_target.is_null() {
+            log::error!("Passed null render_target to b4d_destroy_render_target");
+            exit(1);
+        }
+
+        Box::from_raw(render_target);
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_destroy_render_target");
+        exit(1);
+    })
+}
+
+/// Creates a new shader. Fragments with an alpha value below `alpha_cutoff` are discarded. A cutoff
+/// of 0 disables discarding.
+#[no_mangle]
+unsafe extern "C" fn b4d_create_shader(b4d: *const Blaze4D, vertex_format: *const CVertexFormat, used_uniforms: u64, alpha_cutoff: f32) -> u64 {
     catch_unwind(|| {
         let b4d = b4d.as_ref().unwrap_or_else(|| {
             log::error!("Passed null b4d to b4d_create_shader");
@@ -475,7 +913,7 @@ unsafe extern "C" fn b4d_create_shader(b4d: *const Blaze4D, vertex_format: *cons
         let vertex_format = vertex_format.to_vertex_format();
         let mc_uniform = McUniform::from_raw(used_uniforms);
 
-        b4d.create_shader(&vertex_format, mc_uniform).as_uuid().get_raw()
+        b4d.create_shader(&vertex_format, mc_uniform, alpha_cutoff).as_uuid().get_raw()
     }).unwrap_or_else(|_| {
         log::error!("panic in b4d_create_shader");
         exit(1);
@@ -518,6 +956,110 @@ unsafe extern "C" fn b4d_start_frame(b4d: *mut Blaze4D, window_width: u32, windo
     })
 }
 
+/// Calls [`Blaze4D::start_target_pass`].
+///
+/// The returned pass must be ended by calling [`b4d_end_frame`].
+#[no_mangle]
+unsafe extern "C" fn b4d_start_target_pass(b4d: *const Blaze4D, render_target: *const Arc<RenderTarget>) -> *mut PassRecorder {
+    catch_unwind(|| {
+        let b4d = b4d.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null b4d to b4d_start_target_pass");
+            exit(1);
+        });
+        let render_target = render_target.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null render_target to b4d_start_target_pass");
+            exit(1);
+        });
+
+        Box::leak(Box::new(b4d.start_target_pass(render_target.clone())))
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_start_target_pass");
+        exit(1);
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn b4d_pass_set_viewport(pass: *mut PassRecorder, x: f32, y: f32, width: f32, height: f32, min_depth: f32, max_depth: f32) {
+    catch_unwind(|| {
+        let pass = pass.as_mut().unwrap_or_else(|| {
+            log::error!("Passed null pass to b4d_pass_set_viewport");
+            exit(1);
+        });
+
+        pass.set_viewport(Some(vk::Viewport {
+            x,
+            y,
+            width,
+            height,
+            min_depth,
+            max_depth
+        }));
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_pass_set_viewport");
+        exit(1);
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn b4d_pass_reset_viewport(pass: *mut PassRecorder) {
+    catch_unwind(|| {
+        let pass = pass.as_mut().unwrap_or_else(|| {
+            log::error!("Passed null pass to b4d_pass_reset_viewport");
+            exit(1);
+        });
+
+        pass.set_viewport(None);
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_pass_reset_viewport");
+        exit(1);
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn b4d_pass_set_scissor(pass: *mut PassRecorder, enable: u32, x: i32, y: i32, width: u32, height: u32) {
+    catch_unwind(|| {
+        let pass = pass.as_mut().unwrap_or_else(|| {
+            log::error!("Passed null pass to b4d_pass_set_scissor");
+            exit(1);
+        });
+
+        let scissor = if enable != 0 {
+            Some(vk::Rect2D {
+                offset: vk::Offset2D { x, y },
+                extent: vk::Extent2D { width, height }
+            })
+        } else {
+            None
+        };
+
+        pass.set_scissor(scissor);
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_pass_set_scissor");
+        exit(1);
+    })
+}
+
+/// Calls [`PassRecorder::clear`]. A value is only cleared if its corresponding `clear_*` argument
+/// is not 0.
+#[no_mangle]
+unsafe extern "C" fn b4d_pass_clear(pass: *mut PassRecorder, clear_color: u32, r: f32, g: f32, b: f32, a: f32, clear_depth: u32, depth: f32, clear_stencil: u32, stencil: u32) {
+    catch_unwind(|| {
+        let pass = pass.as_mut().unwrap_or_else(|| {
+            log::error!("Passed null pass to b4d_pass_clear");
+            exit(1);
+        });
+
+        let color = if clear_color != 0 { Some(Vec4f32::new(r, g, b, a)) } else { None };
+        let depth = if clear_depth != 0 { Some(depth) } else { None };
+        let stencil = if clear_stencil != 0 { Some(stencil) } else { None };
+
+        pass.clear(color, depth, stencil);
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_pass_clear");
+        exit(1);
+    })
+}
+
 #[no_mangle]
 unsafe extern "C" fn b4d_pass_update_uniform(pass: *mut PassRecorder, data: *const CMcUniformData, shader_id: u64) {
     catch_unwind(|| {
@@ -555,6 +1097,10 @@ unsafe extern "C" fn b4d_pass_update_texture(pass: *mut PassRecorder, index: u32
             log::error!("Passed null sampler_info to b4d_pass_update_texture");
             exit(1);
         });
+        if image.get_view_type() != vk::ImageViewType::TYPE_2D {
+            log::error!("Passed image with view type {:?} to b4d_pass_update_texture. Only 2D images can be bound", image.get_view_type());
+            exit(1);
+        }
 
         let sampler_info = sampler_info.to_sampler_info();
         let shader_id = ShaderId::from_uuid(UUID::from_raw(shader_id));
@@ -566,6 +1112,35 @@ unsafe extern "C" fn b4d_pass_update_texture(pass: *mut PassRecorder, index: u32
     })
 }
 
+/// Binds the color image of a render target. Equivalent to calling [`b4d_pass_update_texture`]
+/// with the color image of the render target. The render target must not be the one the pass is
+/// rendering into.
+#[no_mangle]
+unsafe extern "C" fn b4d_pass_update_texture_render_target(pass: *mut PassRecorder, index: u32, render_target: *const Arc<RenderTarget>, sampler_info: *const CSamplerInfo, shader_id: u64) {
+    catch_unwind(|| {
+        let pass = pass.as_mut().unwrap_or_else(|| {
+            log::error!("Passed null pass to b4d_pass_update_texture_render_target");
+            exit(1);
+        });
+        let render_target = render_target.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null render_target to b4d_pass_update_texture_render_target");
+            exit(1);
+        });
+        let sampler_info = sampler_info.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null sampler_info to b4d_pass_update_texture_render_target");
+            exit(1);
+        });
+
+        let sampler_info = sampler_info.to_sampler_info();
+        let shader_id = ShaderId::from_uuid(UUID::from_raw(shader_id));
+
+        pass.update_texture(index, render_target.get_color_image(), &sampler_info, shader_id);
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_pass_update_texture_render_target");
+        exit(1);
+    })
+}
+
 #[no_mangle]
 unsafe extern "C" fn b4d_pass_draw_global(pass: *mut PassRecorder, mesh: *const Arc<GlobalMesh>, shader_id: u64, depth_write_enable: u32) {
     catch_unwind(|| {
@@ -581,13 +1156,144 @@ unsafe extern "C" fn b4d_pass_draw_global(pass: *mut PassRecorder, mesh: *const
 
         let depth_write_enable = if depth_write_enable == 1 { true } else { false };
 
-        pass.draw_global(mesh.clone(), shader_id, depth_write_enable);
+        pass.draw_global(mesh.clone(), shader_id, &DrawConfig {
+            depth_compare_op: vk::CompareOp::LESS,
+            depth_write_enable,
+            blend_state: Some(BlendState::ALPHA),
+            ..Default::default()
+        });
     }).unwrap_or_else(|_| {
         log::error!("panic in b4d_pass_draw_global");
         exit(1);
     })
 }
 
+#[no_mangle]
+unsafe extern "C" fn b4d_pass_draw_global_with_config(pass: *mut PassRecorder, mesh: *const Arc<GlobalMesh>, shader_id: u64, config: *const CPipelineConfiguration) {
+    catch_unwind(|| {
+        let pass = pass.as_mut().unwrap_or_else(|| {
+            log::error!("Passed null pass to b4d_pass_draw_global_with_config");
+            exit(1);
+        });
+        let mesh = mesh.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null mesh to b4d_pass_draw_global_with_config");
+            exit(1);
+        });
+        let config = config.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null pipeline configuration to b4d_pass_draw_global_with_config");
+            exit(1);
+        });
+        let shader_id = ShaderId::from_uuid(UUID::from_raw(shader_id));
+
+        pass.draw_global(mesh.clone(), shader_id, &config.to_draw_config());
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_pass_draw_global_with_config");
+        exit(1);
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn b4d_pass_draw_global_instanced(pass: *mut PassRecorder, mesh: *const Arc<GlobalMesh>, shader_id: u64, instances: *const CMeshInstance, count: u32, config: *const CPipelineConfiguration) {
+    catch_unwind(|| {
+        let pass = pass.as_mut().unwrap_or_else(|| {
+            log::error!("Passed null pass to b4d_pass_draw_global_instanced");
+            exit(1);
+        });
+        let mesh = mesh.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null mesh to b4d_pass_draw_global_instanced");
+            exit(1);
+        });
+        if instances.is_null() {
+            log::error!("Passed null instances to b4d_pass_draw_global_instanced");
+            exit(1);
+        }
+        let config = config.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null pipeline configuration to b4d_pass_draw_global_instanced");
+            exit(1);
+        });
+        let shader_id = ShaderId::from_uuid(UUID::from_raw(shader_id));
+
+        let instances = std::slice::from_raw_parts(instances, count as usize);
+        let instances: Box<_> = instances.iter().map(CMeshInstance::to_mesh_instance).collect();
+
+        pass.draw_global_instanced(mesh.clone(), shader_id, instances.as_ref(), &config.to_draw_config());
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_pass_draw_global_instanced");
+        exit(1);
+    })
+}
+
+/// Reads the draws passed to the batch draw functions. `chunk_offsets` must point to `count`
+/// tightly packed xyz chunk offsets.
+unsafe fn read_batch_draws(meshes: *const *const Arc<GlobalMesh>, chunk_offsets: *const f32, count: u32, function: &str) -> Box<[(Arc<GlobalMesh>, Vec3f32)]> {
+    if meshes.is_null() {
+        log::error!("Passed null meshes to {}", function);
+        exit(1);
+    }
+    if chunk_offsets.is_null() {
+        log::error!("Passed null chunk offsets to {}", function);
+        exit(1);
+    }
+
+    let meshes = std::slice::from_raw_parts(meshes, count as usize);
+    let chunk_offsets = std::slice::from_raw_parts(chunk_offsets, (count as usize) * 3);
+    meshes.iter().zip(chunk_offsets.chunks_exact(3)).map(|(mesh, offset)| {
+        let mesh = mesh.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null mesh to {}", function);
+            exit(1);
+        });
+        (mesh.clone(), Vec3f32::new(offset[0], offset[1], offset[2]))
+    }).collect()
+}
+
+/// Draws `count` global meshes in a batch. `chunk_offsets` must point to `count` tightly packed
+/// xyz chunk offsets.
+#[no_mangle]
+unsafe extern "C" fn b4d_pass_draw_global_batch(pass: *mut PassRecorder, meshes: *const *const Arc<GlobalMesh>, chunk_offsets: *const f32, count: u32, shader_id: u64, config: *const CPipelineConfiguration) {
+    catch_unwind(|| {
+        let pass = pass.as_mut().unwrap_or_else(|| {
+            log::error!("Passed null pass to b4d_pass_draw_global_batch");
+            exit(1);
+        });
+        let config = config.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null pipeline configuration to b4d_pass_draw_global_batch");
+            exit(1);
+        });
+        let shader_id = ShaderId::from_uuid(UUID::from_raw(shader_id));
+
+        let draws = read_batch_draws(meshes, chunk_offsets, count, "b4d_pass_draw_global_batch");
+
+        pass.draw_global_batch(draws.as_ref(), shader_id, &config.to_draw_config());
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_pass_draw_global_batch");
+        exit(1);
+    })
+}
+
+/// Same as [`b4d_pass_draw_global_batch`] but meshes outside of the view frustum are culled on the
+/// gpu.
+#[no_mangle]
+unsafe extern "C" fn b4d_pass_draw_global_batch_culled(pass: *mut PassRecorder, meshes: *const *const Arc<GlobalMesh>, chunk_offsets: *const f32, count: u32, shader_id: u64, config: *const CPipelineConfiguration) {
+    catch_unwind(|| {
+        let pass = pass.as_mut().unwrap_or_else(|| {
+            log::error!("Passed null pass to b4d_pass_draw_global_batch_culled");
+            exit(1);
+        });
+        let config = config.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null pipeline configuration to b4d_pass_draw_global_batch_culled");
+            exit(1);
+        });
+        let shader_id = ShaderId::from_uuid(UUID::from_raw(shader_id));
+
+        let draws = read_batch_draws(meshes, chunk_offsets, count, "b4d_pass_draw_global_batch_culled");
+
+        pass.draw_global_batch_culled(draws.as_ref(), shader_id, &config.to_draw_config());
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_pass_draw_global_batch_culled");
+        exit(1);
+    })
+}
+
 #[no_mangle]
 unsafe extern "C" fn b4d_pass_upload_immediate(pass: *mut PassRecorder, data: *const CMeshData) -> u32 {
     catch_unwind(|| {
@@ -620,13 +1326,38 @@ unsafe extern "C" fn b4d_pass_draw_immediate(pass: *mut PassRecorder, id: u32, s
 
         let depth_write_enable = if depth_write_enable == 1 { true } else { false };
 
-        pass.draw_immediate(ImmediateMeshId::form_raw(id), shader_id, depth_write_enable);
+        pass.draw_immediate(ImmediateMeshId::form_raw(id), shader_id, &DrawConfig {
+            depth_compare_op: vk::CompareOp::LESS,
+            depth_write_enable,
+            blend_state: Some(BlendState::ALPHA),
+            ..Default::default()
+        });
     }).unwrap_or_else(|_| {
         log::error!("panic in b4d_pass_draw_immediate");
         exit(1);
     })
 }
 
+#[no_mangle]
+unsafe extern "C" fn b4d_pass_draw_immediate_with_config(pass: *mut PassRecorder, id: u32, shader_id: u64, config: *const CPipelineConfiguration) {
+    catch_unwind(|| {
+        let pass = pass.as_mut().unwrap_or_else(|| {
+            log::error!("Passed null pass to b4d_pass_draw_immediate_with_config");
+            exit(1);
+        });
+        let config = config.as_ref().unwrap_or_else(|| {
+            log::error!("Passed null pipeline configuration to b4d_pass_draw_immediate_with_config");
+            exit(1);
+        });
+        let shader_id = ShaderId::from_uuid(UUID::from_raw(shader_id));
+
+        pass.draw_immediate(ImmediateMeshId::form_raw(id), shader_id, &config.to_draw_config());
+    }).unwrap_or_else(|_| {
+        log::error!("panic in b4d_pass_draw_immediate_with_config");
+        exit(1);
+    })
+}
+
 #[no_mangle]
 unsafe extern "C" fn b4d_end_frame(recorder: *mut PassRecorder) {
     catch_unwind(|| {
@@ -15,6 +15,8 @@ use crate::prelude::*;
 pub struct DeviceFunctions {
     pub instance: Arc<InstanceContext>,
     pub physical_device: vk::PhysicalDevice,
+    pub enabled_features: vk::PhysicalDeviceFeatures,
+    pub limits: vk::PhysicalDeviceLimits,
     pub vk: ash::Device,
     pub synchronization_2_khr: ash::extensions::khr::Synchronization2,
     pub timeline_semaphore_khr: ash::extensions::khr::TimelineSemaphore,
@@ -82,6 +84,16 @@ impl DeviceContext {
         &self.functions.vk
     }
 
+    /// Returns the core features enabled on this device.
+    pub fn get_enabled_features(&self) -> &vk::PhysicalDeviceFeatures {
+        &self.functions.enabled_features
+    }
+
+    /// Returns the limits of the physical device.
+    pub fn get_limits(&self) -> &vk::PhysicalDeviceLimits {
+        &self.functions.limits
+    }
+
     pub fn synchronization_2_khr(&self) -> &ash::extensions::khr::Synchronization2 {
         &self.functions.synchronization_2_khr
     }
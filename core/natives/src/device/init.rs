@@ -133,6 +133,8 @@ pub fn create_device(config: DeviceCreateConfig, instance: Arc<InstanceContext>)
     let functions = Arc::new(DeviceFunctions {
         instance,
         physical_device,
+        enabled_features: device_config.enabled_features,
+        limits: selected_properties.limits,
         vk: device,
         synchronization_2_khr,
         timeline_semaphore_khr,
@@ -342,6 +344,9 @@ struct DeviceConfigInfo {
     rating: f32,
     has_maintenance4: bool,
 
+    /// The core features enabled on the device.
+    enabled_features: vk::PhysicalDeviceFeatures,
+
     /// The main queue family. It is guaranteed to support presentation to all surfaces as well as
     /// graphics, compute and transfer operations.
     main_queue_family: u32,
@@ -401,7 +406,7 @@ fn configure_device(device: &mut DeviceConfigurator) -> Result<Option<DeviceConf
     properties = properties.push_next(&mut push_descriptor_properties);
 
     // Read supported features and properties
-    device.get_features(features);
+    let supported_features = device.get_features(features);
     device.get_properties(properties);
     let timeline_features = timeline_features.build();
     let timeline_properties = timeline_properties.build();
@@ -453,6 +458,24 @@ fn configure_device(device: &mut DeviceConfigurator) -> Result<Option<DeviceConf
         has_maintenance4 = false;
     }
 
+    // Optional core features. These are only enabled if supported and must be checked before use.
+    let mut enabled_features = vk::PhysicalDeviceFeatures::default();
+    if supported_features.fill_mode_non_solid == vk::TRUE {
+        enabled_features.fill_mode_non_solid = vk::TRUE;
+    }
+    if supported_features.sampler_anisotropy == vk::TRUE {
+        enabled_features.sampler_anisotropy = vk::TRUE;
+    }
+    if supported_features.multi_draw_indirect == vk::TRUE {
+        enabled_features.multi_draw_indirect = vk::TRUE;
+    }
+    if supported_features.draw_indirect_first_instance == vk::TRUE {
+        enabled_features.draw_indirect_first_instance = vk::TRUE;
+    }
+    device.push_next(vk::PhysicalDeviceFeatures2::builder()
+        .features(enabled_features)
+    );
+
     // Calculate queue family assignments
     let main_families = device.filter_sort_queues(|family, properties, surface_support| {
         Some(family)
@@ -468,6 +491,7 @@ fn configure_device(device: &mut DeviceConfigurator) -> Result<Option<DeviceConf
     Ok(Some(DeviceConfigInfo {
         rating: 0.0,
         has_maintenance4,
+        enabled_features,
         main_queue_family,
         async_compute_family: None,
         async_transfer_family: None
@@ -0,0 +1,286 @@
+//! Gpu frustum culling of batched draws.
+//!
+//! Culling is performed by a compute shader which modifies the indirect draw commands of a batch
+//! in place before the graphics commands of the pass execute. Draws outside of the frustum get
+//! their instance count set to 0.
+
+use std::sync::Arc;
+
+use ash::vk;
+use bytemuck::{bytes_of, Pod, Zeroable};
+use include_bytes_aligned::include_bytes_aligned;
+
+use crate::renderer::emulator::pipeline_common::{SHADER_ENTRY, try_create_shader_module};
+
+use crate::prelude::*;
+
+/// The bounding box of a mesh in model space before the chunk offset is applied.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct MeshBounds {
+    pub min: Vec3f32,
+    pub max: Vec3f32,
+}
+
+impl MeshBounds {
+    pub fn new(min: Vec3f32, max: Vec3f32) -> Self {
+        Self {
+            min,
+            max
+        }
+    }
+
+    /// Returns the bounds in the layout used by the cull shader. If [`None`] the returned bounds
+    /// disable culling for the draw.
+    pub(super) fn to_shader_data(bounds: Option<&MeshBounds>) -> [f32; 6] {
+        match bounds {
+            Some(bounds) => [bounds.min[0], bounds.min[1], bounds.min[2], bounds.max[0], bounds.max[1], bounds.max[2]],
+            None => [1f32, 0f32, 0f32, 0f32, 0f32, 0f32],
+        }
+    }
+}
+
+/// A batch of indirect draws which should be culled before the pass executes.
+///
+/// All ranges must be part of buffers with the `STORAGE_BUFFER` usage and the offsets must be
+/// multiples of `minStorageBufferOffsetAlignment`.
+pub(super) struct CullTask {
+    pub(super) indirect_buffer: vk::Buffer,
+    pub(super) indirect_offset: vk::DeviceSize,
+
+    /// True if the draw commands are [`vk::DrawIndexedIndirectCommand`].
+    pub(super) indexed: bool,
+    pub(super) instance_buffer: vk::Buffer,
+    pub(super) instance_offset: vk::DeviceSize,
+    pub(super) bounds_buffer: vk::Buffer,
+    pub(super) bounds_offset: vk::DeviceSize,
+    pub(super) draw_count: u32,
+    pub(super) planes: [Vec4f32; 6],
+}
+
+pub(super) struct FrustumCullPipeline {
+    device: Arc<DeviceContext>,
+    module: vk::ShaderModule,
+    set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+}
+
+impl FrustumCullPipeline {
+    const WORKGROUP_SIZE: u32 = 64;
+
+    pub(super) fn new(device: Arc<DeviceContext>) -> Self {
+        let module = try_create_shader_module(&device, FRUSTUM_CULL_BIN, "frustum_cull").unwrap_or_else(|_| {
+            log::error!("Failed to create frustum cull shader module");
+            panic!()
+        });
+
+        let bindings: [_; 3] = std::array::from_fn(|binding| {
+            vk::DescriptorSetLayoutBinding {
+                binding: binding as u32,
+                descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                descriptor_count: 1,
+                stage_flags: vk::ShaderStageFlags::COMPUTE,
+                p_immutable_samplers: std::ptr::null(),
+            }
+        });
+
+        let info = vk::DescriptorSetLayoutCreateInfo::builder()
+            .flags(vk::DescriptorSetLayoutCreateFlags::PUSH_DESCRIPTOR_KHR)
+            .bindings(&bindings);
+
+        let set_layout = unsafe {
+            device.vk().create_descriptor_set_layout(&info, None)
+        }.unwrap_or_else(|err| {
+            log::error!("vkCreateDescriptorSetLayout returned {:?} in FrustumCullPipeline::new", err);
+            panic!()
+        });
+
+        let push_constant_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: std::mem::size_of::<PushConstants>() as u32,
+        };
+
+        let info = vk::PipelineLayoutCreateInfo::builder()
+            .push_constant_ranges(std::slice::from_ref(&push_constant_range))
+            .set_layouts(std::slice::from_ref(&set_layout));
+
+        let pipeline_layout = unsafe {
+            device.vk().create_pipeline_layout(&info, None)
+        }.unwrap_or_else(|err| {
+            log::error!("vkCreatePipelineLayout returned {:?} in FrustumCullPipeline::new", err);
+            panic!()
+        });
+
+        let stage = vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .module(module)
+            .name(SHADER_ENTRY);
+
+        let info = vk::ComputePipelineCreateInfo::builder()
+            .stage(*stage)
+            .layout(pipeline_layout);
+
+        let pipeline = *unsafe {
+            device.vk().create_compute_pipelines(vk::PipelineCache::null(), std::slice::from_ref(&info), None)
+        }.unwrap_or_else(|(_, err)| {
+            log::error!("vkCreateComputePipelines returned {:?} in FrustumCullPipeline::new", err);
+            panic!()
+        }).get(0).unwrap();
+
+        Self {
+            device,
+            module,
+            set_layout,
+            pipeline_layout,
+            pipeline,
+        }
+    }
+
+    /// Records the dispatches for a list of cull tasks.
+    ///
+    /// Writes of the input data must be made visible to compute shader reads and the modified draw
+    /// commands must be made visible to indirect command reads by the caller.
+    pub(super) fn record(&self, cmd: vk::CommandBuffer, tasks: &[CullTask]) {
+        if tasks.is_empty() {
+            return;
+        }
+
+        unsafe {
+            self.device.vk().cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.pipeline);
+        }
+
+        for task in tasks {
+            let command_stride = if task.indexed {
+                std::mem::size_of::<vk::DrawIndexedIndirectCommand>()
+            } else {
+                std::mem::size_of::<vk::DrawIndirectCommand>()
+            } as vk::DeviceSize;
+
+            let buffer_infos = [
+                vk::DescriptorBufferInfo {
+                    buffer: task.indirect_buffer,
+                    offset: task.indirect_offset,
+                    range: command_stride * (task.draw_count as vk::DeviceSize)
+                },
+                vk::DescriptorBufferInfo {
+                    buffer: task.instance_buffer,
+                    offset: task.instance_offset,
+                    range: 12 * (task.draw_count as vk::DeviceSize)
+                },
+                vk::DescriptorBufferInfo {
+                    buffer: task.bounds_buffer,
+                    offset: task.bounds_offset,
+                    range: 24 * (task.draw_count as vk::DeviceSize)
+                },
+            ];
+            let writes: [_; 3] = std::array::from_fn(|binding| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_binding(binding as u32)
+                    .dst_array_element(0)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(std::slice::from_ref(&buffer_infos[binding]))
+                    .build()
+            });
+
+            let push_constants = PushConstants {
+                planes: task.planes,
+                draw_count: task.draw_count,
+                command_stride: (command_stride / 4) as u32,
+            };
+
+            unsafe {
+                self.device.push_descriptor_khr().cmd_push_descriptor_set(
+                    cmd,
+                    vk::PipelineBindPoint::COMPUTE,
+                    self.pipeline_layout,
+                    0,
+                    &writes
+                );
+                self.device.vk().cmd_push_constants(
+                    cmd,
+                    self.pipeline_layout,
+                    vk::ShaderStageFlags::COMPUTE,
+                    0,
+                    bytes_of(&push_constants)
+                );
+                self.device.vk().cmd_dispatch(cmd, (task.draw_count + Self::WORKGROUP_SIZE - 1) / Self::WORKGROUP_SIZE, 1, 1);
+            }
+        }
+    }
+}
+
+impl Drop for FrustumCullPipeline {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.vk().destroy_pipeline(self.pipeline, None);
+            self.device.vk().destroy_pipeline_layout(self.pipeline_layout, None);
+            self.device.vk().destroy_descriptor_set_layout(self.set_layout, None);
+            self.device.vk().destroy_shader_module(self.module, None);
+        }
+    }
+}
+
+/// Extracts the 6 frustum planes from a opengl style `projection * model_view` matrix.
+///
+/// A point p is inside the frustum if `dot(plane.xyz, p) + plane.w >= 0` for all planes. The planes
+/// are not normalized.
+pub(super) fn extract_frustum_planes(matrix: &Mat4f32) -> [Vec4f32; 6] {
+    let row = |index: usize| -> Vec4f32 {
+        matrix.row(index).transpose()
+    };
+
+    [
+        row(3) + row(0),
+        row(3) - row(0),
+        row(3) + row(1),
+        row(3) - row(1),
+        row(3) + row(2),
+        row(3) - row(2),
+    ]
+}
+
+/// Must match the push constant block in `frustum_cull.comp`.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct PushConstants {
+    #[allow(unused)]
+    planes: [Vec4f32; 6],
+
+    #[allow(unused)]
+    draw_count: u32,
+
+    #[allow(unused)]
+    command_stride: u32,
+}
+const_assert_eq!(std::mem::size_of::<PushConstants>(), 104);
+
+unsafe impl Zeroable for PushConstants {}
+unsafe impl Pod for PushConstants {}
+
+static FRUSTUM_CULL_BIN: &'static [u8] = include_bytes_aligned!(4, concat!(env!("B4D_RESOURCE_DIR"), "emulator/cull/frustum_cull_comp.spv"));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_inside(planes: &[Vec4f32; 6], point: Vec3f32) -> bool {
+        planes.iter().all(|plane| plane.xyz().dot(&point) + plane[3] >= 0f32)
+    }
+
+    #[test]
+    fn test_frustum_planes() {
+        let projection = Mat4f32::new_perspective(1f32, std::f32::consts::FRAC_PI_2, 0.1f32, 100f32);
+        let planes = extract_frustum_planes(&projection);
+
+        assert!(is_inside(&planes, Vec3f32::new(0f32, 0f32, -10f32)));
+        assert!(is_inside(&planes, Vec3f32::new(5f32, -5f32, -10f32)));
+        assert!(!is_inside(&planes, Vec3f32::new(0f32, 0f32, 10f32)));
+        assert!(!is_inside(&planes, Vec3f32::new(0f32, 0f32, -200f32)));
+        assert!(!is_inside(&planes, Vec3f32::new(20f32, 0f32, -10f32)));
+
+        let translated = projection * Mat4f32::new_translation(&Vec3f32::new(0f32, 0f32, -20f32));
+        let planes = extract_frustum_planes(&translated);
+        assert!(is_inside(&planes, Vec3f32::new(0f32, 0f32, 10f32)));
+    }
+}
@@ -1,22 +1,20 @@
 //! Provides a [`EmulatorPipeline`] implementation useful for debugging.
 
-use std::collections::HashMap;
-use std::ffi::CStr;
-use std::sync::{Arc, Mutex, Weak};
+use std::sync::{Arc, Weak};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::Instant;
 use ash::vk;
 use bumpalo::Bump;
-use bytemuck::{bytes_of, cast_slice, Pod, Zeroable};
+use bytemuck::{bytes_of, cast_slice};
 use include_bytes_aligned::include_bytes_aligned;
 use crate::allocator::Allocation;
 use crate::device::device::Queue;
-use crate::device::device_utils::create_shader_from_bytes;
 
 use crate::prelude::*;
-use crate::renderer::emulator::EmulatorRenderer;
-use crate::renderer::emulator::mc_shaders::{McUniform, McUniformData, ShaderDropListener, ShaderId, ShaderListener, VertexFormat, VertexFormatEntry};
-use crate::renderer::emulator::pipeline::{DrawTask, EmulatorPipeline, EmulatorPipelinePass, PipelineTask, PooledObjectProvider, SubmitRecorder};
+use crate::renderer::emulator::{EmulatorRenderer, RenderTarget};
+use crate::renderer::emulator::mc_shaders::{ShaderDropListener, ShaderId, VertexFormat, VertexFormatEntry};
+use crate::renderer::emulator::pipeline::{DrawBatchTask, DrawTask, EmulatorPipeline, InstancedDrawTask, EmulatorPipelinePass, ObjectCreateError, PipelineTask, PooledObjectProvider, SubmitRecorder};
+use crate::renderer::emulator::pipeline_common::{DRAW_DYNAMIC_STATES, DrawPipeline, DrawState, InstanceInput, PipelineCache, PipelineConfig, SHADER_ENTRY, try_create_shader_module};
 use crate::util::vk::{make_full_rect, make_full_viewport};
 
 pub struct DepthTypeInfo {
@@ -28,18 +26,6 @@ pub struct DepthTypeInfo {
     pub discard: bool,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
-pub enum ObjectCreateError {
-    Vulkan(vk::Result),
-    Allocation,
-}
-
-impl From<vk::Result> for ObjectCreateError {
-    fn from(result: vk::Result) -> Self {
-        Self::Vulkan(result)
-    }
-}
-
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub enum DebugPipelineMode {
     Depth,
@@ -52,6 +38,7 @@ pub enum DebugPipelineMode {
     Textured0,
     Textured1,
     Textured2,
+    Fog,
 }
 
 /// A [`EmulatorPipeline`] which provides debug information.
@@ -65,6 +52,8 @@ pub enum DebugPipelineMode {
 /// - UV1: The uv1 vertex attribute
 /// - UV2: The uv2 vertex attribute
 /// - Textured0: The textured result from uv0 (Not implemented yet)
+/// - Fog: The fog value computed from the fog uniforms. Black if there is no fog and white if the
+///   fog color fully replaces the color
 pub struct DebugPipeline {
     emulator: Arc<EmulatorRenderer>,
     weak: Weak<Self>,
@@ -77,7 +66,7 @@ pub struct DebugPipeline {
     background_pipeline: BackgroundPipeline,
     descriptor_pool: vk::DescriptorPool,
 
-    pipelines: Mutex<HashMap<ShaderId, ShaderPipelines>>,
+    pipelines: PipelineCache,
     next_index: AtomicUsize,
     pass_objects: Box<[PassObjects]>,
     output_views: Box<[vk::ImageView]>,
@@ -189,7 +178,7 @@ impl DebugPipeline {
                 background_pipeline,
                 descriptor_pool,
 
-                pipelines: Mutex::new(HashMap::new()),
+                pipelines: PipelineCache::new(),
                 next_index: AtomicUsize::new(0),
                 pass_objects,
                 output_views
@@ -208,66 +197,40 @@ impl DebugPipeline {
         }
     }
 
-    /// Returns the pipeline to be used for a specific configuration. If the pipeline doesnt exits
-    /// yet a new one is created.
-    fn get_pipeline(&self, shader: ShaderId, config: &PipelineConfig) -> vk::Pipeline {
-        let mut guard = self.pipelines.lock().unwrap();
-        let pipelines = guard.get_mut(&shader).unwrap_or_else(|| {
-            log::error!("Called get_pipeline for unregistered shader {:?}", shader);
-            panic!()
-        });
-
-        pipelines.get_or_create_pipeline(config, |format| self.create_pipeline(config, format))
-    }
-
     fn create_pipeline(&self, config: &PipelineConfig, vertex_format: &VertexFormat) -> vk::Pipeline {
         let alloc = Bump::new();
-        let (shader_stages, input_state) = self.shader_modules.configure_pipeline(vertex_format, &alloc);
-
-        let viewport = make_full_viewport(self.framebuffer_size);
-        let scissor = make_full_rect(self.framebuffer_size);
+        let (shader_stages, input_state) = self.shader_modules.configure_pipeline(config, vertex_format, &alloc);
 
+        // Viewport and scissor are dynamic state set by the DrawState
         let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
-            .viewports(std::slice::from_ref(&viewport))
-            .scissors(std::slice::from_ref(&scissor));
+            .viewport_count(1)
+            .scissor_count(1);
 
-        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
-            .polygon_mode(vk::PolygonMode::FILL)
-            .cull_mode(vk::CullModeFlags::BACK)
-            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
-            .line_width(1f32);
+        let rasterization_state = config.get_rasterization_state(self.emulator.get_device());
 
         let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
             .rasterization_samples(vk::SampleCountFlags::TYPE_1)
             .sample_shading_enable(false);
 
         let attachment_blend_state = [
-            vk::PipelineColorBlendAttachmentState::builder()
-                .blend_enable(true)
-                .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
-                .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
-                .color_blend_op(vk::BlendOp::ADD)
-                .src_alpha_blend_factor(vk::BlendFactor::ONE)
-                .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
-                .color_blend_op(vk::BlendOp::ADD)
-                .color_write_mask(vk::ColorComponentFlags::RGBA)
-                .build(),
+            config.get_attachment_blend_state()
         ];
 
         let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
             .logic_op_enable(false)
             .attachments(&attachment_blend_state);
 
-        let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder();
+        let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
+            .dynamic_states(&DRAW_DYNAMIC_STATES);
 
         let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
-            .topology(config.primitive_topology)
+            .topology(config.primitive_topology.get_native())
             .primitive_restart_enable(false);
 
         let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
             .depth_test_enable(config.depth_test_enable)
             .depth_write_enable(config.depth_write_enable)
-            .depth_compare_op(vk::CompareOp::LESS);
+            .depth_compare_op(config.depth_compare_op);
 
         let info = vk::GraphicsPipelineCreateInfo::builder()
             .stages(shader_stages)
@@ -430,51 +393,17 @@ impl EmulatorPipeline for DebugPipeline {
     }
 
     fn inc_shader_used(&self, shader: ShaderId) {
-        let mut guard = self.pipelines.lock().unwrap();
-        if let Some(pipelines) = guard.get_mut(&shader) {
-            pipelines.inc_used();
-        } else {
-            let listener = self.emulator.get_shader(shader).unwrap_or_else(|| {
-                log::error!("Called inc_shader_used for nonexistent shader {:?}", shader);
-                panic!()
-            }).register_drop_listener(&(self.weak.upgrade().unwrap() as Arc<dyn ShaderDropListener + Send + Sync>));
-
-            let shader_obj = self.emulator.get_shader(shader).unwrap();
-            let vertex_format = shader_obj.get_vertex_format().clone();
-            let used_uniforms = shader_obj.get_used_uniforms();
-
-            let mut  pipelines = ShaderPipelines::new(self.emulator.get_device().clone(), vertex_format, used_uniforms, listener);
-            pipelines.inc_used();
-
-            guard.insert(shader, pipelines);
-        }
+        self.pipelines.inc_shader_used(&self.emulator, shader, self.weak.upgrade().unwrap());
     }
 
     fn dec_shader_used(&self, shader: ShaderId) {
-        let mut guard = self.pipelines.lock().unwrap();
-        let pipelines = guard.get_mut(&shader).unwrap_or_else(|| {
-            log::error!("Called dec_shader_used for shader which is not registered {:?}", shader);
-            panic!();
-        });
-        pipelines.dec_used();
-        let drop = pipelines.can_drop();
-        if drop {
-            guard.remove(&shader);
-        }
+        self.pipelines.dec_shader_used(shader);
     }
 }
 
 impl ShaderDropListener for DebugPipeline {
     fn on_shader_drop(&self, id: ShaderId) {
-        let mut drop = false;
-        let mut guard = self.pipelines.lock().unwrap();
-        if let Some(pipeline) = guard.get_mut(&id) {
-            pipeline.mark();
-            drop = pipeline.can_drop();
-        }
-        if drop {
-            guard.remove(&id);
-        }
+        self.pipelines.on_shader_drop(id);
     }
 }
 
@@ -484,7 +413,7 @@ impl Drop for DebugPipeline {
         for objects in self.pass_objects.iter_mut() {
             objects.destroy(device);
         }
-        self.pipelines.get_mut().unwrap().clear();
+        self.pipelines.clear();
         unsafe {
             device.vk().destroy_descriptor_pool(self.descriptor_pool, None);
         }
@@ -503,7 +432,9 @@ struct ShaderModules {
     vertex_module: vk::ShaderModule,
     null_module: vk::ShaderModule,
     fragment_module: vk::ShaderModule,
-    texture_module: Option<vk::ShaderModule>,
+
+    /// The fragment module used instead of `fragment_module` by modes with their own fragment shader.
+    mode_fragment_module: Option<vk::ShaderModule>,
 }
 
 impl ShaderModules {
@@ -526,6 +457,7 @@ impl ShaderModules {
             DebugPipelineMode::Textured0 |
             DebugPipelineMode::Textured1 |
             DebugPipelineMode::Textured2 => try_create_shader_module(device, DEBUG_UV_VERTEX_BIN, "uv_vertex"),
+            DebugPipelineMode::Fog => try_create_shader_module(device, DEBUG_FOG_VERTEX_BIN, "fog_vertex"),
         }.map_err(|err| {
             unsafe {
                 device.vk().destroy_shader_module(null_module, None);
@@ -534,8 +466,9 @@ impl ShaderModules {
             err
         })?;
 
-        let texture_module = match mode {
+        let mode_fragment_module = match mode {
             DebugPipelineMode::Textured0 => try_create_shader_module(device, TEXTURED_FRAGMENT_BIN, "textured_fragment").map(|val| Some(val)),
+            DebugPipelineMode::Fog => try_create_shader_module(device, FOG_FRAGMENT_BIN, "fog_fragment").map(|val| Some(val)),
             _ => Ok(None),
         }.map_err(|err| {
             unsafe {
@@ -551,18 +484,38 @@ impl ShaderModules {
             vertex_module,
             null_module,
             fragment_module,
-            texture_module,
+            mode_fragment_module,
         })
     }
 
-    fn configure_pipeline<'s, 'a: 's>(&'s self, vertex_format: &VertexFormat, alloc: &'a Bump) -> (&'a [vk::PipelineShaderStageCreateInfo], &'a vk::PipelineVertexInputStateCreateInfo) {
-        let input_bindings: &[_] = alloc.alloc([
-            vk::VertexInputBindingDescription {
-                binding: 0,
-                stride: vertex_format.stride,
-                input_rate: vk::VertexInputRate::VERTEX
-            }
-        ]);
+    fn configure_pipeline<'s, 'a: 's>(&'s self, config: &PipelineConfig, vertex_format: &VertexFormat, alloc: &'a Bump) -> (&'a [vk::PipelineShaderStageCreateInfo], &'a vk::PipelineVertexInputStateCreateInfo) {
+        let (instance_binding, instance_attributes) = config.get_instance_inputs(vertex_format, 3);
+
+        let vertex_binding = vk::VertexInputBindingDescription {
+            binding: 0,
+            stride: vertex_format.stride,
+            input_rate: vk::VertexInputRate::VERTEX
+        };
+        let input_bindings: &[_] = match instance_binding {
+            Some(instance_binding) => alloc.alloc([vertex_binding, instance_binding]),
+            None => alloc.alloc([vertex_binding]),
+        };
+
+        // The line direction is always consumed by the vertex shaders
+        let wide_lines = config.primitive_topology.is_wide_line() && vertex_format.normal.is_some();
+        if config.primitive_topology.is_wide_line() && !wide_lines {
+            log::warn!("Vertex format used with {:?} has no normal attribute. Lines will not be visible", config.primitive_topology);
+        }
+        let line_direction = vertex_format.normal.as_ref().unwrap_or(&vertex_format.position);
+        let line_direction_attribute = vk::VertexInputAttributeDescription {
+            location: 2,
+            binding: 0,
+            format: line_direction.format,
+            offset: line_direction.offset,
+        };
+
+        // The debug shaders ignore the instance color
+        let [chunk_offset, model_view_0, model_view_1, model_view_2, model_view_3, _] = instance_attributes;
 
         let vertex_module;
         let input_attributes: &[_];
@@ -583,7 +536,13 @@ impl ShaderModules {
                     binding: 0,
                     format: entry.format,
                     offset: entry.offset
-                }
+                },
+                line_direction_attribute,
+                chunk_offset,
+                model_view_0,
+                model_view_1,
+                model_view_2,
+                model_view_3,
             ]);
         } else {
             vertex_format_supported = false;
@@ -596,9 +555,42 @@ impl ShaderModules {
                     format: vertex_format.position.format,
                     offset: vertex_format.position.offset,
                 },
+                line_direction_attribute,
+                chunk_offset,
+                model_view_0,
+                model_view_1,
+                model_view_2,
+                model_view_3,
             ]);
         }
 
+        let vertex_specialization_data: &[vk::Bool32] = alloc.alloc([
+            if wide_lines { vk::TRUE } else { vk::FALSE },
+            if config.instance_input == InstanceInput::ChunkOffset { vk::TRUE } else { vk::FALSE },
+            if config.instance_input == InstanceInput::Transform { vk::TRUE } else { vk::FALSE },
+        ]);
+        let vertex_specialization_entries = alloc.alloc([
+            vk::SpecializationMapEntry {
+                constant_id: 0,
+                offset: 0,
+                size: 4
+            },
+            vk::SpecializationMapEntry {
+                constant_id: 1,
+                offset: 4,
+                size: 4
+            },
+            vk::SpecializationMapEntry {
+                constant_id: 2,
+                offset: 8,
+                size: 4
+            },
+        ]);
+        let vertex_specialization = alloc.alloc(vk::SpecializationInfo::builder()
+            .map_entries(vertex_specialization_entries)
+            .data(cast_slice(vertex_specialization_data))
+        );
+
         let (fragment_module, fragment_specialization) = match (self.mode, vertex_format_supported) {
             (DebugPipelineMode::Textured0, true) |
             (DebugPipelineMode::Textured1, true) |
@@ -616,11 +608,14 @@ impl ShaderModules {
                         size: 4
                     }
                 ]);
-                (*self.texture_module.as_ref().unwrap(), alloc.alloc(vk::SpecializationInfo::builder()
+                (*self.mode_fragment_module.as_ref().unwrap(), alloc.alloc(vk::SpecializationInfo::builder()
                     .map_entries(entries)
                     .data(bytes_of(data))
                 ))
             }
+            (DebugPipelineMode::Fog, true) => {
+                (*self.mode_fragment_module.as_ref().unwrap(), alloc.alloc(vk::SpecializationInfo::builder()))
+            }
             _ => {
                 (self.fragment_module, alloc.alloc(vk::SpecializationInfo::builder()))
             }
@@ -631,6 +626,7 @@ impl ShaderModules {
                 .stage(vk::ShaderStageFlags::VERTEX)
                 .module(vertex_module)
                 .name(SHADER_ENTRY)
+                .specialization_info(vertex_specialization)
                 .build(),
             vk::PipelineShaderStageCreateInfo::builder()
                 .stage(vk::ShaderStageFlags::FRAGMENT)
@@ -652,7 +648,8 @@ impl ShaderModules {
     fn process_vertex_format<'a>(&self, vertex_format: &'a VertexFormat) -> Option<&'a VertexFormatEntry> {
         match self.mode {
             DebugPipelineMode::Depth |
-            DebugPipelineMode::Position => Some(&vertex_format.position),
+            DebugPipelineMode::Position |
+            DebugPipelineMode::Fog => Some(&vertex_format.position),
             DebugPipelineMode::Color => vertex_format.color.as_ref(),
             DebugPipelineMode::Normal => vertex_format.normal.as_ref(),
             DebugPipelineMode::UV0 |
@@ -669,84 +666,13 @@ impl ShaderModules {
             device.vk().destroy_shader_module(self.vertex_module, None);
             device.vk().destroy_shader_module(self.null_module, None);
             device.vk().destroy_shader_module(self.fragment_module, None);
-            if let Some(texture_module) = self.texture_module.take() {
-                device.vk().destroy_shader_module(texture_module, None);
+            if let Some(mode_fragment_module) = self.mode_fragment_module.take() {
+                device.vk().destroy_shader_module(mode_fragment_module, None);
             }
         }
     }
 }
 
-struct DrawPipeline {
-    set0_layout: vk::DescriptorSetLayout,
-    pipeline_layout: vk::PipelineLayout,
-}
-
-impl DrawPipeline {
-    fn new(device: &DeviceContext) -> Result<Self, ObjectCreateError> {
-        let bindings = [
-            vk::DescriptorSetLayoutBinding {
-                binding: 0,
-                descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
-                descriptor_count: 1,
-                stage_flags: vk::ShaderStageFlags::ALL,
-                p_immutable_samplers: std::ptr::null(),
-            },
-            vk::DescriptorSetLayoutBinding {
-                binding: 1,
-                descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                descriptor_count: 3,
-                stage_flags: vk::ShaderStageFlags::ALL_GRAPHICS,
-                p_immutable_samplers: std::ptr::null(),
-            },
-        ];
-
-        let info = vk::DescriptorSetLayoutCreateInfo::builder()
-            .flags(vk::DescriptorSetLayoutCreateFlags::PUSH_DESCRIPTOR_KHR)
-            .bindings(&bindings);
-
-        let set0_layout = unsafe {
-            device.vk().create_descriptor_set_layout(&info, None)
-        }.map_err(|err| {
-            log::error!("vkCreateDescriptorSetLayout returned {:?} in DrawPipeline::new when creating set 0 layout", err);
-            err
-        })?;
-
-        let push_constant_range = vk::PushConstantRange {
-            stage_flags: vk::ShaderStageFlags::ALL_GRAPHICS,
-            offset: 0,
-            size: std::mem::size_of::<PushConstants>() as u32,
-        };
-
-        let layouts = [
-            set0_layout
-        ];
-
-        let info = vk::PipelineLayoutCreateInfo::builder()
-            .push_constant_ranges(std::slice::from_ref(&push_constant_range))
-            .set_layouts(&layouts);
-
-        let pipeline_layout = unsafe {
-            device.vk().create_pipeline_layout(&info, None)
-        }.map_err(|err| {
-            log::error!("vkCreatePipelineLayout returned {:?} in DrawPipeline::new", err);
-            unsafe { device.vk().destroy_descriptor_set_layout(set0_layout, None) };
-            err
-        })?;
-
-        Ok(Self {
-            set0_layout,
-            pipeline_layout
-        })
-    }
-
-    fn destroy(&mut self, device: &DeviceContext) {
-        unsafe {
-            device.vk().destroy_pipeline_layout(self.pipeline_layout, None);
-            device.vk().destroy_descriptor_set_layout(self.set0_layout, None);
-        }
-    }
-}
-
 struct BackgroundPipeline {
     descriptor_set_layout: vk::DescriptorSetLayout,
     pipeline_layout: vk::PipelineLayout,
@@ -1161,262 +1087,79 @@ impl PassObjects {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
-struct PipelineConfig {
-    primitive_topology: vk::PrimitiveTopology,
-    depth_test_enable: bool,
-    depth_write_enable: bool,
-}
-
-struct ShaderPipelines {
-    device: Arc<DeviceContext>,
-    vertex_format: VertexFormat,
-    used_uniforms: McUniform,
-    pipelines: HashMap<PipelineConfig, vk::Pipeline>,
-    #[allow(unused)]
-    listener: ShaderListener,
-    used_counter: u32,
-    marked: bool,
-}
-
-impl ShaderPipelines {
-    fn new(device: Arc<DeviceContext>, vertex_format: VertexFormat, used_uniforms: McUniform, listener: ShaderListener) -> Self {
-        Self {
-            device,
-            vertex_format,
-            used_uniforms,
-            pipelines: HashMap::new(),
-            listener,
-            used_counter: 0,
-            marked: false,
-        }
-    }
-
-    fn get_or_create_pipeline<T: FnOnce(&VertexFormat) -> vk::Pipeline>(&mut self, config: &PipelineConfig, create_fn: T) -> vk::Pipeline {
-        if let Some(pipeline) = self.pipelines.get(config) {
-            *pipeline
-        } else {
-            let pipeline = create_fn(&self.vertex_format);
-            self.pipelines.insert(*config, pipeline);
-            pipeline
-        }
-    }
-
-    fn inc_used(&mut self) {
-        self.used_counter += 1;
-    }
-
-    fn dec_used(&mut self) {
-        self.used_counter -= 1;
-    }
-
-    fn mark(&mut self) {
-        self.marked = true;
-    }
-
-    fn can_drop(&self) -> bool {
-        self.marked && self.used_counter == 0
-    }
-}
-
-impl Drop for ShaderPipelines {
-    fn drop(&mut self) {
-        for pipeline in self.pipelines.values() {
-            unsafe {
-                self.device.vk().destroy_pipeline(*pipeline, None);
-            }
-        }
-    }
-}
-
 struct DebugPipelinePass {
     parent: Arc<DebugPipeline>,
     index: usize,
 
-    placeholder_texture: vk::ImageView,
-    placeholder_sampler: vk::Sampler,
-    shader_uniforms: HashMap<ShaderId, UniformStateTracker>,
-
     command_buffer: Option<vk::CommandBuffer>,
-    current_pipeline: Option<(ShaderId, PipelineConfig)>,
-    current_vertex_buffer: Option<vk::Buffer>,
-    current_index_buffer: Option<vk::Buffer>,
+    draw_state: DrawState,
 }
 
 impl DebugPipelinePass {
     fn new(parent: Arc<DebugPipeline>, index: usize) -> Self {
+        let draw_state = DrawState::new(parent.framebuffer_size);
+
         Self {
             parent,
             index,
 
-            placeholder_texture: vk::ImageView::null(),
-            placeholder_sampler: vk::Sampler::null(),
-            shader_uniforms: HashMap::new(),
-
             command_buffer: None,
-            current_pipeline: None,
-            current_vertex_buffer: None,
-            current_index_buffer: None
+            draw_state,
         }
     }
 
-    fn update_uniform(&mut self, shader: ShaderId, data: &McUniformData) {
-        if !self.shader_uniforms.contains_key(&shader) {
-            let uniforms = self.parent.pipelines.lock().unwrap().get(&shader).unwrap().used_uniforms;
-            self.shader_uniforms.insert(shader, UniformStateTracker::new(uniforms, self.placeholder_texture, self.placeholder_sampler));
-        }
-        let tracker = self.shader_uniforms.get_mut(&shader).unwrap();
-        tracker.update_uniform(data);
-    }
-
-    fn update_texture(&mut self, shader: ShaderId, index: u32, view: vk::ImageView, sampler: vk::Sampler) {
-        if !self.shader_uniforms.contains_key(&shader) {
-            let uniforms = self.parent.pipelines.lock().unwrap().get(&shader).unwrap().used_uniforms;
-            self.shader_uniforms.insert(shader, UniformStateTracker::new(uniforms, self.placeholder_texture, self.placeholder_sampler));
-        }
-        let tracker = self.shader_uniforms.get_mut(&shader).unwrap();
-        tracker.update_texture(index, view, sampler);
+    fn flush_draws(&mut self) {
+        let parent = &self.parent;
+        self.draw_state.flush(parent.emulator.get_device(), *self.command_buffer.as_ref().unwrap(), parent.draw_pipeline.pipeline_layout);
     }
 
     fn draw(&mut self, task: &DrawTask, obj: &mut PooledObjectProvider) {
-        let device = self.parent.emulator.get_device();
-        let cmd = *self.command_buffer.as_ref().unwrap();
-
-        let pipeline_config = PipelineConfig {
-            primitive_topology: task.primitive_topology,
-            depth_test_enable: true,
-            depth_write_enable: task.depth_write_enable
-        };
-
-        if self.current_pipeline != Some((task.shader, pipeline_config)) {
-            self.current_pipeline = Some((task.shader, pipeline_config));
-
-            let new_pipeline = self.parent.get_pipeline(task.shader, &pipeline_config);
-            unsafe {
-                device.vk().cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, new_pipeline);
-            }
-        }
-
-        if !self.shader_uniforms.contains_key(&task.shader) {
-            log::warn!("Called draw without any shader uniforms. Using default values!");
-            let uniforms = self.parent.pipelines.lock().unwrap().get(&task.shader).unwrap().used_uniforms;
-            self.shader_uniforms.insert(task.shader, UniformStateTracker::new(uniforms, self.placeholder_texture, self.placeholder_sampler));
-        }
-        if let Some(tracker) = self.shader_uniforms.get_mut(&task.shader) {
-            if let Some(push_constants) = tracker.validate_push_constants() {
-                unsafe {
-                    device.vk().cmd_push_constants(
-                        self.command_buffer.unwrap(),
-                        self.parent.draw_pipeline.pipeline_layout,
-                        vk::ShaderStageFlags::ALL_GRAPHICS,
-                        0,
-                        bytes_of(push_constants)
-                    );
-                }
-            }
-
-            if let Some(static_uniforms) = tracker.validate_static_uniforms() {
-                let (buffer, offset) = obj.allocate_uniform(bytes_of(static_uniforms));
-                let buffer_info = vk::DescriptorBufferInfo {
-                    buffer,
-                    offset,
-                    range: std::mem::size_of::<StaticUniforms>() as vk::DeviceSize
-                };
-                let write = vk::WriteDescriptorSet::builder()
-                    .dst_binding(0)
-                    .dst_array_element(0)
-                    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-                    .buffer_info(std::slice::from_ref(&buffer_info));
-
-                unsafe {
-                    device.push_descriptor_khr().cmd_push_descriptor_set(
-                        self.command_buffer.unwrap(),
-                        vk::PipelineBindPoint::GRAPHICS,
-                        self.parent.draw_pipeline.pipeline_layout,
-                        0,
-                        std::slice::from_ref(&write)
-                    );
-                }
-            }
-
-            if let Some(textures) = tracker.validate_textures() {
-                let image_info0 = vk::DescriptorImageInfo {
-                    sampler: textures[0].1,
-                    image_view: textures[0].0,
-                    image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
-                };
-                let image_info1 = vk::DescriptorImageInfo {
-                    sampler: textures[1].1,
-                    image_view: textures[1].0,
-                    image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
-                };
-                let image_info2 = vk::DescriptorImageInfo {
-                    sampler: textures[2].1,
-                    image_view: textures[2].0,
-                    image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
-                };
-                let writes = [
-                    vk::WriteDescriptorSet::builder()
-                        .dst_binding(1)
-                        .dst_array_element(0)
-                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                        .image_info(std::slice::from_ref(&image_info0))
-                        .build(),
-                    vk::WriteDescriptorSet::builder()
-                        .dst_binding(1)
-                        .dst_array_element(1)
-                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                        .image_info(std::slice::from_ref(&image_info1))
-                        .build(),
-                    vk::WriteDescriptorSet::builder()
-                        .dst_binding(1)
-                        .dst_array_element(2)
-                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                        .image_info(std::slice::from_ref(&image_info2))
-                        .build(),
-                ];
-
-                unsafe {
-                    device.push_descriptor_khr().cmd_push_descriptor_set(
-                        self.command_buffer.unwrap(),
-                        vk::PipelineBindPoint::GRAPHICS,
-                        self.parent.draw_pipeline.pipeline_layout,
-                        0,
-                        &writes
-                    );
-                }
-            }
-        }
-
-        if self.current_vertex_buffer != Some(task.vertex_buffer) {
-            unsafe {
-                device.vk().cmd_bind_vertex_buffers(
-                    cmd,
-                    0,
-                    std::slice::from_ref(&task.vertex_buffer),
-                    std::slice::from_ref(&0)
-                );
-            }
-            self.current_vertex_buffer = Some(task.vertex_buffer);
-        }
+        let parent = &self.parent;
+        self.draw_state.draw(
+            parent.emulator.get_device(),
+            *self.command_buffer.as_ref().unwrap(),
+            parent.draw_pipeline.pipeline_layout,
+            &parent.pipelines,
+            task,
+            obj,
+            |config, format, _, _| parent.create_pipeline(config, format)
+        );
+    }
 
-        if self.current_index_buffer != Some(task.index_buffer) {
-            unsafe {
-                device.vk().cmd_bind_index_buffer(cmd, task.index_buffer, 0, task.index_type);
-            }
-            self.current_index_buffer = Some(task.index_buffer);
-        }
+    fn draw_batch(&mut self, task: &DrawBatchTask, obj: &mut PooledObjectProvider) {
+        let parent = &self.parent;
+        self.draw_state.draw_batch(
+            parent.emulator.get_device(),
+            *self.command_buffer.as_ref().unwrap(),
+            parent.draw_pipeline.pipeline_layout,
+            &parent.pipelines,
+            task,
+            obj,
+            |config, format, _, _| parent.create_pipeline(config, format)
+        );
+    }
 
-        unsafe {
-            device.vk().cmd_draw_indexed(cmd, task.index_count, 1, task.first_index, task.vertex_offset, 0);
-        }
+    fn draw_instanced(&mut self, task: &InstancedDrawTask, obj: &mut PooledObjectProvider) {
+        let parent = &self.parent;
+        self.draw_state.draw_instanced(
+            parent.emulator.get_device(),
+            *self.command_buffer.as_ref().unwrap(),
+            parent.draw_pipeline.pipeline_layout,
+            &parent.pipelines,
+            task,
+            obj,
+            |config, format, _, _| parent.create_pipeline(config, format)
+        );
     }
 }
 
 impl EmulatorPipelinePass for DebugPipelinePass {
-    fn init(&mut self, _: &Queue, obj: &mut PooledObjectProvider, placeholder_texture: vk::ImageView, placeholder_sampler: vk::Sampler) {
-        self.placeholder_texture = placeholder_texture;
-        self.placeholder_sampler = placeholder_sampler;
+    fn init(&mut self, _: &Queue, obj: &mut PooledObjectProvider, render_target: Option<Arc<RenderTarget>>, placeholder_texture: vk::ImageView, placeholder_sampler: vk::Sampler) {
+        if render_target.is_some() {
+            log::warn!("DebugPipeline does not support render targets. Rendering into internal images instead");
+        }
+
+        self.draw_state.set_placeholder(placeholder_texture, placeholder_sampler);
 
         let cmd = obj.get_begin_command_buffer().unwrap();
         self.command_buffer = Some(cmd);
@@ -1455,18 +1198,39 @@ impl EmulatorPipelinePass for DebugPipelinePass {
     fn process_task(&mut self, task: &PipelineTask, obj: &mut PooledObjectProvider) {
         match task {
             PipelineTask::UpdateUniform(shader, data) => {
-                self.update_uniform(*shader, data);
+                self.draw_state.update_uniform(&self.parent.pipelines, *shader, data);
             }
             PipelineTask::UpdateTexture(shader, index, view, sampler) => {
-                self.update_texture(*shader, *index, *view, *sampler);
+                self.draw_state.update_texture(&self.parent.pipelines, *shader, *index, *view, *sampler);
+            }
+            PipelineTask::SetViewport(viewport) => {
+                self.flush_draws();
+                self.draw_state.set_viewport(*viewport);
+            }
+            PipelineTask::SetScissor(scissor) => {
+                self.flush_draws();
+                self.draw_state.set_scissor(*scissor);
+            }
+            PipelineTask::Clear(color, depth, _) => {
+                self.flush_draws();
+                // There is no stencil attachment so the stencil value is ignored
+                self.draw_state.clear(self.parent.emulator.get_device(), *self.command_buffer.as_ref().unwrap(), *color, *depth);
             }
             PipelineTask::Draw(task) => {
                 self.draw(task, obj);
             }
+            PipelineTask::DrawBatch(task) => {
+                self.draw_batch(task, obj);
+            }
+            PipelineTask::DrawInstanced(task) => {
+                self.draw_instanced(task, obj);
+            }
         }
     }
 
     fn record<'a>(&mut self, _: &mut PooledObjectProvider, submits: &mut SubmitRecorder<'a>, alloc: &'a Bump) {
+        self.flush_draws();
+
         let device = self.parent.emulator.get_device();
         let cmd = self.command_buffer.take().unwrap();
 
@@ -1553,216 +1317,14 @@ impl Drop for DebugPipelinePass {
     }
 }
 
-struct UniformStateTracker {
-    used_uniforms: McUniform,
-    push_constants_dirty: bool,
-    static_uniforms_dirty: bool,
-    textures_dirty: bool,
-    push_constant_cache: PushConstants,
-    static_uniform_cache: StaticUniforms,
-    textures: [(vk::ImageView, vk::Sampler); 3],
-}
-
-impl UniformStateTracker {
-    fn new(used_uniforms: McUniform, initial_texture: vk::ImageView, initial_sampler: vk::Sampler) -> Self {
-        Self {
-            used_uniforms,
-            push_constants_dirty: true,
-            static_uniforms_dirty: true,
-            textures_dirty: true,
-            push_constant_cache: PushConstants {
-                model_view_matrix: Mat4f32::identity(),
-                chunk_offset: Vec3f32::zeros(),
-                _padding0: Default::default(),
-            },
-            static_uniform_cache: StaticUniforms {
-                projection_matrix: Mat4f32::identity(),
-                screen_size: Vec2f32::zeros(),
-                _padding0: Default::default(),
-                fog_color: Vec4f32::zeros(),
-                fog_range_and_game_time: Vec3f32::zeros(),
-                _padding1: Default::default(),
-                fog_shape: 0,
-                _padding2: Default::default(),
-            },
-            textures: [(initial_texture, initial_sampler); 3],
-        }
-    }
-
-    fn update_uniform(&mut self, data: &McUniformData) {
-        match data {
-            McUniformData::ModelViewMatrix(mat) => {
-                if self.used_uniforms.contains(&McUniform::MODEL_VIEW_MATRIX) {
-                    self.push_constant_cache.model_view_matrix = *mat;
-                    self.push_constants_dirty = true;
-                }
-            }
-            McUniformData::ProjectionMatrix(mat) => {
-                if self.used_uniforms.contains(&McUniform::PROJECTION_MATRIX) {
-                    self.static_uniform_cache.projection_matrix = *mat;
-                    self.static_uniforms_dirty = true;
-                }
-            }
-            McUniformData::InverseViewRotationMatrix(_) => {}
-            McUniformData::TextureMatrix(_) => {}
-            McUniformData::ScreenSize(size) => {
-                if self.used_uniforms.contains(&McUniform::SCREEN_SIZE) {
-                    self.static_uniform_cache.screen_size = *size;
-                    self.static_uniforms_dirty = true;
-                }
-            }
-            McUniformData::ColorModulator(_) => {}
-            McUniformData::Light0Direction(_) => {}
-            McUniformData::Light1Direction(_) => {}
-            McUniformData::FogStart(start) => {
-                if self.used_uniforms.contains(&McUniform::FOG_START) {
-                    self.static_uniform_cache.fog_range_and_game_time[0] = *start;
-                    self.static_uniforms_dirty = true;
-                }
-            }
-            McUniformData::FogEnd(end) => {
-                if self.used_uniforms.contains(&McUniform::FOG_END) {
-                    self.static_uniform_cache.fog_range_and_game_time[1] = *end;
-                    self.static_uniforms_dirty = true;
-                }
-            }
-            McUniformData::FogColor(color) => {
-                if self.used_uniforms.contains(&McUniform::FOG_COLOR) {
-                    self.static_uniform_cache.fog_color = *color;
-                    self.static_uniforms_dirty = true;
-                }
-            }
-            McUniformData::FogShape(shape) => {
-                if self.used_uniforms.contains(&McUniform::FOG_SHAPE) {
-                    self.static_uniform_cache.fog_shape = *shape;
-                    self.static_uniforms_dirty = true;
-                }
-            }
-            McUniformData::LineWidth(_) => {}
-            McUniformData::GameTime(time) => {
-                if self.used_uniforms.contains(&McUniform::GAME_TIME) {
-                    self.static_uniform_cache.fog_range_and_game_time[2] = *time;
-                    self.static_uniforms_dirty = true;
-                }
-            }
-            McUniformData::ChunkOffset(offset) => {
-                if self.used_uniforms.contains(&McUniform::CHUNK_OFFSET) {
-                    self.push_constant_cache.chunk_offset = *offset;
-                    self.push_constants_dirty = true;
-                }
-            }
-        }
-    }
-
-    fn update_texture(&mut self, index: u32, view: vk::ImageView, sampler: vk::Sampler) {
-        match index {
-            0 => {
-                self.textures[0] = (view, sampler);
-                self.textures_dirty = true;
-            },
-            1 => {
-                self.textures[1] = (view, sampler);
-                self.textures_dirty = true;
-            },
-            2 => {
-                self.textures[2] = (view, sampler);
-                self.textures_dirty = true;
-            },
-            _ => log::warn!("Called updated texture on index {:?} which is out of bounds", index),
-        }
-    }
-
-    fn validate_push_constants(&mut self) -> Option<&PushConstants> {
-        if self.push_constants_dirty {
-            self.push_constants_dirty = false;
-            Some(&self.push_constant_cache)
-        } else {
-            None
-        }
-    }
-
-    fn validate_static_uniforms(&mut self) -> Option<&StaticUniforms> {
-        if self.static_uniforms_dirty {
-            self.static_uniforms_dirty = false;
-            Some(&self.static_uniform_cache)
-        } else {
-            None
-        }
-    }
-
-    fn validate_textures(&mut self) -> Option<&[(vk::ImageView, vk::Sampler); 3]> {
-        if self.textures_dirty {
-            self.textures_dirty = false;
-            Some(&self.textures)
-        } else {
-            None
-        }
-    }
-}
-
-#[repr(C)]
-#[derive(Copy, Clone, Default)]
-struct PushConstants {
-    #[allow(unused)]
-    model_view_matrix: Mat4f32,
-
-    #[allow(unused)]
-    chunk_offset: Vec3f32,
-
-    _padding0: [u8; 4],
-}
-const_assert_eq!(std::mem::size_of::<PushConstants>(), 80);
-const_assert_eq!(std::mem::size_of::<PushConstants>() % 16, 0);
-
-unsafe impl Zeroable for PushConstants {}
-unsafe impl Pod for PushConstants {}
-
-#[repr(C)]
-#[derive(Copy, Clone, Default)]
-struct StaticUniforms {
-    #[allow(unused)]
-    projection_matrix: Mat4f32,
-
-    #[allow(unused)]
-    screen_size: Vec2f32,
-
-    _padding0: [u8; 8],
-
-    #[allow(unused)]
-    fog_color: Vec4f32,
-
-    #[allow(unused)]
-    fog_range_and_game_time: Vec3f32,
-
-    _padding1: [u8; 4],
-
-    #[allow(unused)]
-    fog_shape: u32,
-
-    _padding2: [u8; 12],
-}
-const_assert_eq!(std::mem::size_of::<StaticUniforms>(), 128);
-const_assert_eq!(std::mem::size_of::<StaticUniforms>() % 16, 0);
-
-unsafe impl Zeroable for StaticUniforms {}
-unsafe impl Pod for StaticUniforms {}
-
-fn try_create_shader_module(device: &DeviceContext, data: &[u8], name: &str) -> Result<vk::ShaderModule, vk::Result> {
-    unsafe {
-        create_shader_from_bytes(device.get_functions(), data)
-    }.map_err(|err| {
-        log::error!("vkCreateShaderModule returned {:?} when creating module {:?}", err, name);
-        err
-    })
-}
-
-const SHADER_ENTRY: &'static CStr = unsafe { CStr::from_bytes_with_nul_unchecked(b"main\0") }; // GOD I LOVE RUSTS FFI API IT IS SO NICE AND DEFINITELY NOT STUPID WITH WHICH FUNCTIONS ARE CONST AND WHICH AREN'T
 static DEBUG_POSITION_VERTEX_BIN: &'static [u8] = include_bytes_aligned!(4, concat!(env!("B4D_RESOURCE_DIR"), "emulator/debug/position_vert.spv"));
 static DEBUG_COLOR_VERTEX_BIN: &'static [u8] = include_bytes_aligned!(4, concat!(env!("B4D_RESOURCE_DIR"), "emulator/debug/color_vert.spv"));
 static DEBUG_UV_VERTEX_BIN: &'static [u8] = include_bytes_aligned!(4, concat!(env!("B4D_RESOURCE_DIR"), "emulator/debug/uv_vert.spv"));
 static DEBUG_NULL_VERTEX_BIN: &'static [u8] = include_bytes_aligned!(4, concat!(env!("B4D_RESOURCE_DIR"), "emulator/debug/null_vert.spv"));
 static DEBUG_FRAGMENT_BIN: &'static [u8] = include_bytes_aligned!(4, concat!(env!("B4D_RESOURCE_DIR"), "emulator/debug/debug_frag.spv"));
 static TEXTURED_FRAGMENT_BIN: &'static [u8] = include_bytes_aligned!(4, concat!(env!("B4D_RESOURCE_DIR"), "emulator/debug/textured_frag.spv"));
+static DEBUG_FOG_VERTEX_BIN: &'static [u8] = include_bytes_aligned!(4, concat!(env!("B4D_RESOURCE_DIR"), "emulator/debug/fog_vert.spv"));
+static FOG_FRAGMENT_BIN: &'static [u8] = include_bytes_aligned!(4, concat!(env!("B4D_RESOURCE_DIR"), "emulator/debug/fog_frag.spv"));
 
 static BACKGROUND_VERTEX_BIN: &'static [u8] = include_bytes_aligned!(4, concat!(env!("B4D_RESOURCE_DIR"), "emulator/debug/background_vert.spv"));
 static BACKGROUND_FRAGMENT_BIN: &'static [u8] = include_bytes_aligned!(4, concat!(env!("B4D_RESOURCE_DIR"), "emulator/debug/background_frag.spv"));
\ No newline at end of file
@@ -0,0 +1,140 @@
+//! Deferred destruction of global objects.
+//!
+//! Global objects may be dropped on any thread while passes using them are still executing on the
+//! gpu. Instead of destroying their vulkan objects immediately they are pushed into a
+//! [`DeferredQueue`] keyed on the last pass they were used in and destroyed once the worker reports
+//! that pass as complete.
+
+use std::sync::Mutex;
+
+use ash::vk;
+
+use crate::allocator::Allocation;
+use crate::renderer::emulator::mesh_arena::{MeshArena, MeshArenaAllocation};
+use crate::renderer::emulator::PassId;
+
+use crate::prelude::*;
+
+/// Keeps track of objects which must not be destroyed until some pass has completed.
+///
+/// Passes are submitted to a single queue and therefore always complete in order. Completing a pass
+/// thus implies that all previous passes have completed as well.
+pub(super) struct DeferredQueue<T> {
+    completed_pass: PassId,
+    pending: Vec<(PassId, T)>,
+}
+
+impl<T> DeferredQueue<T> {
+    pub(super) fn new() -> Self {
+        Self {
+            completed_pass: PassId::from_raw(0),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Queues a object to be released once the pass `used_in` has completed. If the pass has
+    /// already completed the object is returned immediately and can be destroyed by the caller.
+    pub(super) fn push(&mut self, used_in: PassId, object: T) -> Option<T> {
+        if used_in <= self.completed_pass {
+            Some(object)
+        } else {
+            self.pending.push((used_in, object));
+            None
+        }
+    }
+
+    /// Marks all passes up to and including `pass` as complete and returns all objects which are no
+    /// longer in use.
+    pub(super) fn complete_pass(&mut self, pass: PassId) -> Vec<T> {
+        if pass <= self.completed_pass {
+            return Vec::new();
+        }
+        self.completed_pass = pass;
+
+        let (released, pending) = std::mem::take(&mut self.pending).into_iter().partition(|(used_in, _)| *used_in <= pass);
+        self.pending = pending;
+
+        released.into_iter().map(|(_, object)| object).collect()
+    }
+
+    #[cfg(any(test, feature = "__internal_doc_test"))]
+    pub(super) fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Returns all pending objects regardless of the passes they were used in.
+    pub(super) fn drain(&mut self) -> Vec<T> {
+        std::mem::take(&mut self.pending).into_iter().map(|(_, object)| object).collect()
+    }
+}
+
+/// The vulkan objects owned by a global object.
+pub(super) enum DeferredObject {
+    MeshAllocation(MeshArenaAllocation),
+    Image(vk::Image, vk::ImageView, Allocation),
+}
+
+impl DeferredObject {
+    /// Destroys the vulkan objects.
+    ///
+    /// # Safety
+    /// The objects must not be in use by the device anymore.
+    pub(super) unsafe fn destroy(self, device: &DeviceContext, mesh_arena: &Mutex<MeshArena>) {
+        match self {
+            DeferredObject::MeshAllocation(allocation) => {
+                mesh_arena.lock().unwrap().free(allocation);
+            }
+            DeferredObject::Image(image, view, allocation) => {
+                device.vk().destroy_image_view(view, None);
+                device.get_allocator().destroy_image(image, allocation);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pass(id: u64) -> PassId {
+        PassId::from_raw(id)
+    }
+
+    #[test]
+    fn test_unused_is_released_immediately() {
+        let mut queue = DeferredQueue::new();
+        assert_eq!(queue.push(pass(0), 1u32), Some(1));
+
+        assert_eq!(queue.complete_pass(pass(2)), Vec::<u32>::new());
+        assert_eq!(queue.push(pass(1), 2u32), Some(2));
+        assert_eq!(queue.push(pass(2), 3u32), Some(3));
+    }
+
+    #[test]
+    fn test_release_after_used_pass() {
+        // An object used in pass 1 is released while the pass is still executing
+        let mut queue = DeferredQueue::new();
+        assert_eq!(queue.push(pass(1), 1u32), None);
+
+        // Completing passes before the one the object was used in must not release it
+        assert_eq!(queue.complete_pass(pass(0)), Vec::<u32>::new());
+
+        assert_eq!(queue.complete_pass(pass(1)), vec![1u32]);
+        assert_eq!(queue.drain(), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_out_of_order_release() {
+        let mut queue = DeferredQueue::new();
+        assert_eq!(queue.push(pass(3), 1u32), None);
+        assert_eq!(queue.push(pass(1), 2u32), None);
+        assert_eq!(queue.push(pass(2), 3u32), None);
+
+        assert_eq!(queue.complete_pass(pass(2)), vec![2u32, 3u32]);
+
+        // Completing a older pass must not release anything
+        assert_eq!(queue.complete_pass(pass(1)), Vec::<u32>::new());
+
+        assert_eq!(queue.drain(), vec![1u32]);
+    }
+}
@@ -1,20 +1,29 @@
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
-use std::sync::{Arc, Mutex, Weak};
+use std::ptr::NonNull;
+use std::sync::{Arc, Condvar, Mutex, Weak};
 use std::sync::atomic::AtomicU64;
 
 use ash::vk;
 use crate::allocator::Allocation;
 use crate::define_uuid_type;
 
-use crate::renderer::emulator::{MeshData, PassId};
+use crate::renderer::emulator::{MeshData, PassId, PrimitiveTopology};
 
 use crate::prelude::*;
+use crate::renderer::emulator::cull::MeshBounds;
+use crate::renderer::emulator::deferred::DeferredObject;
+use crate::renderer::emulator::mc_shaders::VertexFormatEntry;
+use crate::renderer::emulator::mesh_arena::MeshArenaAllocation;
+use crate::renderer::emulator::quad_sort::QuadSortState;
 use crate::renderer::emulator::share::Share;
-use crate::renderer::emulator::worker::{GlobalImageClear, GlobalImageWrite, GlobalMeshWrite, WorkerTask};
+use crate::renderer::emulator::topology::{EmulatedIndices, get_index_type_size, QuadIndexCache};
+use crate::renderer::emulator::staging::{StagingAllocationId, StagingMemoryPool};
+use crate::renderer::emulator::worker::{GlobalImageClear, GlobalImageCopy, GlobalImageCopyRegion, GlobalImageRead, GlobalImageWrite, GlobalMeshRelocation, GlobalMeshWrite, WorkerTask};
 use crate::util::alloc::next_aligned;
 use crate::util::format::Format;
+use crate::vk::objects::ImageSize;
 
 define_uuid_type!(pub, GlobalMeshId);
 
@@ -31,72 +40,404 @@ impl From<vk::Result> for GlobalObjectCreateError {
 }
 
 pub struct GlobalMesh {
+    weak: Weak<Self>,
     share: Arc<Share>,
     id: GlobalMeshId,
 
     last_used_pass: AtomicU64,
 
-    buffer: vk::Buffer,
-    allocation: Allocation,
-    buffer_size: vk::DeviceSize,
+    /// The arena range storing the mesh data and the draw parameters using it. Both change if the
+    /// mesh is relocated by the mesh arena compaction.
+    placement: Mutex<MeshPlacement>,
+    vertex_data_size: vk::DeviceSize,
 
-    draw_info: GlobalMeshDrawInfo,
+    /// The offset and size of the index data relative to the start of the mesh if it can be updated.
+    index_data_range: Option<(vk::DeviceSize, vk::DeviceSize)>,
+
+    /// The offset of the index data stored by the mesh relative to the start of the mesh.
+    index_offset: vk::DeviceSize,
+
+    /// The vertex stride and index size used to calculate the draw parameters after a relocation.
+    vertex_stride: vk::DeviceSize,
+    index_size: vk::DeviceSize,
+
+    /// The bounding box used to cull the mesh in batched draws.
+    bounds: Mutex<Option<MeshBounds>>,
+
+    /// The data used to sort the quads of the mesh if it is sortable.
+    sort_state: Option<Mutex<QuadSortState>>,
+
+    /// The shared index buffer used if this is a sequential quad mesh.
+    quad_indices: Option<Arc<GlobalMesh>>,
 }
 
 impl GlobalMesh {
     pub(super) fn new(share: Arc<Share>, data: &MeshData) -> Result<Arc<Self>, GlobalObjectCreateError> {
-        let index_offset = next_aligned(data.vertex_data.len() as vk::DeviceSize, data.get_index_size() as vk::DeviceSize);
-        let required_size = index_offset + (data.index_data.len() as vk::DeviceSize);
+        Self::new_with_sort_state(share, data, None, true)
+    }
 
-        let (buffer, allocation) = Self::create_buffer(share.get_device(), required_size)?;
+    /// Creates a mesh which is never relocated. Used for meshes whose draw parameters are copied
+    /// into other meshes like the shared quad index buffers.
+    pub(super) fn new_fixed(share: Arc<Share>, data: &MeshData) -> Result<Arc<Self>, GlobalObjectCreateError> {
+        Self::new_with_sort_state(share, data, None, false)
+    }
 
-        let (staging, staging_allocation) = share.get_staging_pool().lock().unwrap_or_else(|_| {
-            log::error!("Poisoned staging memory mutex in GlobalMesh::new");
-            panic!()
-        }).allocate(required_size, 1);
+    /// Creates a mesh whose quads can be sorted using [`GlobalMesh::sort_quads`]. The mesh must use
+    /// the [`PrimitiveTopology::Quads`] topology. The position entry defines where the vertex
+    /// positions are stored in the vertex data.
+    pub(super) fn new_sortable(share: Arc<Share>, data: &MeshData, position: &VertexFormatEntry) -> Result<Arc<Self>, GlobalObjectCreateError> {
+        Self::new_with_sort_state(share, data, Some(QuadSortState::new(data, position)), true)
+    }
 
-        unsafe {
-            let dst = std::slice::from_raw_parts_mut(staging.mapped.as_ptr(), required_size as usize);
+    fn new_with_sort_state(share: Arc<Share>, data: &MeshData, sort_state: Option<QuadSortState>, relocatable: bool) -> Result<Arc<Self>, GlobalObjectCreateError> {
+        let indices = Self::emulate_indices(data, sort_state.as_ref());
+        let layout = MeshLayout::new(data, &indices);
 
-            dst[0..data.vertex_data.len()].copy_from_slice(data.vertex_data);
-            dst[(index_offset as usize)..].copy_from_slice(data.index_data);
-        }
+        let allocation = share.get_mesh_arena().lock().unwrap_or_else(|_| {
+            log::error!("Poisoned mesh arena mutex in GlobalMesh::new");
+            panic!()
+        }).allocate(layout.required_size, layout.alignment).ok_or(GlobalObjectCreateError::Allocation)?;
 
-        let draw_info = GlobalMeshDrawInfo {
-            buffer,
-            first_index: (index_offset / (data.get_index_size() as vk::DeviceSize)) as u32,
-            index_type: data.index_type,
-            index_count: data.index_count,
-            primitive_topology: data.primitive_topology
-        };
+        let (draw_info, quad_indices) = Self::make_draw_info(&share, data, &indices, &allocation, &layout);
 
-        let mesh = Arc::new(GlobalMesh {
+        let mesh = Arc::new_cyclic(|weak| GlobalMesh {
+            weak: weak.clone(),
             share,
             id: GlobalMeshId::new(),
 
             last_used_pass: AtomicU64::new(0),
 
-            buffer,
-            allocation,
-            buffer_size: required_size,
+            placement: Mutex::new(MeshPlacement {
+                allocation,
+                draw_info,
+                previous: None,
+            }),
+            vertex_data_size: data.vertex_data.len() as vk::DeviceSize,
+            index_data_range: layout.index_data_range,
+            index_offset: layout.index_offset,
+            vertex_stride: layout.vertex_stride,
+            index_size: layout.index_size,
+
+            bounds: Mutex::new(None),
+            sort_state: sort_state.map(Mutex::new),
+            quad_indices,
+        });
+
+        mesh.write_full(data, &layout, &allocation, PassId::from_raw(0), true);
+
+        if relocatable {
+            mesh.share.get_mesh_arena().lock().unwrap().set_owner(&allocation, mesh.weak.clone(), layout.alignment);
+        }
+
+        Ok(mesh)
+    }
+
+    /// Writes new data into parts of the vertex and index data of the mesh. The offsets are in
+    /// bytes and relative to the start of the vertex or index data respectively.
+    ///
+    /// The write is executed after all previously recorded passes using this mesh. The index data
+    /// can only be updated if the mesh topology is not emulated since emulated meshes do not store
+    /// the original index data.
+    pub fn update(&self, vertex_range: Option<MeshDataRange>, index_range: Option<MeshDataRange>) {
+        let vertex_range = vertex_range.filter(|r| !r.data.is_empty());
+        let index_range = index_range.filter(|r| !r.data.is_empty());
+
+        if let Some(range) = &vertex_range {
+            if range.offset + (range.data.len() as vk::DeviceSize) > self.vertex_data_size {
+                log::error!("Attempted to update vertex range {:?} of mesh with {:?} bytes of vertex data", (range.offset, range.data.len()), self.vertex_data_size);
+                panic!()
+            }
+        }
+        if let Some(range) = &index_range {
+            let (_, index_data_size) = self.index_data_range.unwrap_or_else(|| {
+                log::error!("Attempted to update the index data of a mesh without updatable index data");
+                panic!()
+            });
+            if range.offset + (range.data.len() as vk::DeviceSize) > index_data_size {
+                log::error!("Attempted to update index range {:?} of mesh with {:?} bytes of index data", (range.offset, range.data.len()), index_data_size);
+                panic!()
+            }
+        }
+
+        let vertex_size = vertex_range.as_ref().map(|r| r.data.len()).unwrap_or(0) as vk::DeviceSize;
+        let index_size = index_range.as_ref().map(|r| r.data.len()).unwrap_or(0) as vk::DeviceSize;
+        if vertex_size + index_size == 0 {
+            return;
+        }
+
+        if let (Some(sort_state), Some(range)) = (&self.sort_state, &vertex_range) {
+            sort_state.lock().unwrap().update_vertices(range.offset, range.data);
+        }
 
-            draw_info
+        // The placement must not change until the write has been queued
+        let placement = self.placement.lock().unwrap();
+        let allocation = placement.allocation;
+
+        let (staging, staging_allocation) = self.share.get_staging_pool().lock().unwrap_or_else(|_| {
+            log::error!("Poisoned staging memory mutex in GlobalMesh::update");
+            panic!()
+        }).allocate(vertex_size + index_size, 1);
+
+        let mut regions = Vec::with_capacity(2);
+        unsafe {
+            let dst = std::slice::from_raw_parts_mut(staging.mapped.as_ptr(), (vertex_size + index_size) as usize);
+
+            if let Some(range) = &vertex_range {
+                dst[0..(vertex_size as usize)].copy_from_slice(range.data);
+                regions.push(vk::BufferCopy {
+                    src_offset: staging.offset,
+                    dst_offset: allocation.offset + range.offset,
+                    size: vertex_size
+                });
+            }
+            if let Some(range) = &index_range {
+                dst[(vertex_size as usize)..].copy_from_slice(range.data);
+                regions.push(vk::BufferCopy {
+                    src_offset: staging.offset + vertex_size,
+                    dst_offset: allocation.offset + self.index_data_range.unwrap().0 + range.offset,
+                    size: index_size
+                });
+            }
+        }
+
+        self.share.push_task(WorkerTask::WriteGlobalMesh(GlobalMeshWrite {
+            after_pass: PassId::from_raw(self.last_used_pass.load(std::sync::atomic::Ordering::Acquire)),
+            staging_allocation,
+            staging_range: (staging.offset, vertex_size + index_size),
+            staging_buffer: staging.buffer,
+            dst_mesh: self.weak.upgrade().unwrap(),
+            dst_range: allocation,
+            regions: regions.into_boxed_slice()
+        }, false));
+    }
+
+    /// Replaces the full content of the mesh.
+    ///
+    /// If the new data results in the same buffer layout and draw parameters as the current data
+    /// the mesh is updated in place after all previously recorded passes using it and a new
+    /// reference to this mesh is returned. Otherwise a new mesh is created. This mesh is not modified
+    /// in that case and is destroyed as usual once all references to it have been dropped.
+    ///
+    /// Sortable meshes stay sortable. The quads of the new data are in submission order until the
+    /// next call to [`GlobalMesh::sort_quads`].
+    pub fn update_resized(&self, data: &MeshData) -> Arc<GlobalMesh> {
+        let sort_state = self.sort_state.as_ref().map(|state| state.lock().unwrap().recreate(data));
+        let indices = Self::emulate_indices(data, sort_state.as_ref());
+        let layout = MeshLayout::new(data, &indices);
+
+        let placement = self.placement.lock().unwrap();
+        if std::cmp::max(layout.required_size, 1) == placement.allocation.size &&
+            placement.allocation.offset % layout.alignment == 0 &&
+            layout.index_data_range == self.index_data_range &&
+            (data.vertex_data.len() as vk::DeviceSize) == self.vertex_data_size {
+
+            let (draw_info, _) = Self::make_draw_info(&self.share, data, &indices, &placement.allocation, &layout);
+            if draw_info == placement.draw_info {
+                let last_used_pass = PassId::from_raw(self.last_used_pass.load(std::sync::atomic::Ordering::Acquire));
+                self.write_full(data, &layout, &placement.allocation, last_used_pass, false);
+                if let (Some(dst), Some(sort_state)) = (&self.sort_state, sort_state) {
+                    *dst.lock().unwrap() = sort_state;
+                }
+                return self.weak.upgrade().unwrap();
+            }
+        }
+        drop(placement);
+
+        let mesh = GlobalMesh::new_with_sort_state(self.share.clone(), data, sort_state, true).unwrap();
+        mesh.set_bounds(self.get_bounds());
+        mesh
+    }
+
+    /// Sets the bounding box of the mesh used for frustum culling. If [`None`] the mesh is never
+    /// culled.
+    ///
+    /// The bounds are not modified by updates to the mesh data and must be updated by the caller if
+    /// necessary.
+    pub fn set_bounds(&self, bounds: Option<MeshBounds>) {
+        *self.bounds.lock().unwrap() = bounds;
+    }
+
+    pub fn get_bounds(&self) -> Option<MeshBounds> {
+        *self.bounds.lock().unwrap()
+    }
+
+    /// Returns the mesh arena range storing the data of this mesh.
+    #[cfg(any(test, feature = "__internal_doc_test"))]
+    pub(super) fn get_arena_allocation(&self) -> MeshArenaAllocation {
+        self.placement.lock().unwrap().allocation
+    }
+
+    /// Returns true if the mesh was created as a sortable mesh.
+    pub fn is_sortable(&self) -> bool {
+        self.sort_state.is_some()
+    }
+
+    /// Sorts the quads of a sortable mesh back to front as seen from the camera position. The
+    /// camera position must be in the model space of the mesh (i.e. relative to the chunk offset
+    /// the mesh is drawn with).
+    ///
+    /// The new index data is written after all previously recorded passes using this mesh.
+    pub fn sort_quads(&self, camera_position: Vec3f32) {
+        let sort_state = self.sort_state.as_ref().unwrap_or_else(|| {
+            log::error!("Attempted to sort the quads of a mesh which is not sortable");
+            panic!()
         });
+        let index_data = sort_state.lock().unwrap().sort(camera_position);
+        if index_data.is_empty() {
+            return;
+        }
+        let index_size = index_data.len() as vk::DeviceSize;
 
-        mesh.share.push_task(WorkerTask::WriteGlobalMesh(GlobalMeshWrite {
-            after_pass: PassId::from_raw(0),
+        // The placement must not change until the write has been queued
+        let placement = self.placement.lock().unwrap();
+        let allocation = placement.allocation;
+
+        let (staging, staging_allocation) = self.share.get_staging_pool().lock().unwrap_or_else(|_| {
+            log::error!("Poisoned staging memory mutex in GlobalMesh::sort_quads");
+            panic!()
+        }).allocate(index_size, 1);
+
+        unsafe {
+            std::slice::from_raw_parts_mut(staging.mapped.as_ptr(), index_data.len()).copy_from_slice(&index_data);
+        }
+
+        self.share.push_task(WorkerTask::WriteGlobalMesh(GlobalMeshWrite {
+            after_pass: PassId::from_raw(self.last_used_pass.load(std::sync::atomic::Ordering::Acquire)),
             staging_allocation,
-            staging_range: (staging.offset, required_size),
+            staging_range: (staging.offset, index_size),
             staging_buffer: staging.buffer,
-            dst_mesh: mesh.clone(),
+            dst_mesh: self.weak.upgrade().unwrap(),
+            dst_range: allocation,
             regions: Box::new([vk::BufferCopy {
                 src_offset: staging.offset,
-                dst_offset: 0,
-                size: required_size
+                dst_offset: allocation.offset + self.index_offset,
+                size: index_size
             }])
-        }, true));
+        }, false));
+    }
 
-        Ok(mesh)
+    /// Applies topology emulation to the index data of a mesh. Sortable meshes always use their own
+    /// index data.
+    fn emulate_indices<'a>(data: &MeshData<'a>, sort_state: Option<&QuadSortState>) -> EmulatedIndices<'a> {
+        match sort_state {
+            Some(sort_state) => sort_state.make_indices(),
+            None => EmulatedIndices::new(data),
+        }
+    }
+
+    /// Writes the full mesh data into the allocation. If the mesh has already been created the
+    /// caller must hold the placement lock until this function returns.
+    fn write_full(&self, data: &MeshData, layout: &MeshLayout, allocation: &MeshArenaAllocation, after_pass: PassId, is_uninit: bool) {
+        let (staging, staging_allocation) = self.share.get_staging_pool().lock().unwrap_or_else(|_| {
+            log::error!("Poisoned staging memory mutex in GlobalMesh::write_full");
+            panic!()
+        }).allocate(layout.required_size, 1);
+
+        unsafe {
+            let dst = std::slice::from_raw_parts_mut(staging.mapped.as_ptr(), layout.required_size as usize);
+
+            dst[0..data.vertex_data.len()].copy_from_slice(data.vertex_data);
+            dst[(layout.index_offset as usize)..].copy_from_slice(layout.index_data);
+        }
+
+        self.share.push_task(WorkerTask::WriteGlobalMesh(GlobalMeshWrite {
+            after_pass,
+            staging_allocation,
+            staging_range: (staging.offset, layout.required_size),
+            staging_buffer: staging.buffer,
+            dst_mesh: self.weak.upgrade().unwrap(),
+            dst_range: *allocation,
+            regions: Box::new([vk::BufferCopy {
+                src_offset: staging.offset,
+                dst_offset: allocation.offset,
+                size: layout.required_size
+            }])
+        }, is_uninit));
+    }
+
+    /// Moves the mesh data into a new arena range. The data is copied after all passes which may
+    /// still use the old range. Those passes keep drawing from the old range while all later
+    /// passes and writes use the new range. The old range is freed once the copy has completed.
+    pub(super) fn relocate(&self, dst: MeshArenaAllocation) {
+        let mut placement = self.placement.lock().unwrap();
+
+        // The active pass may already have recorded draws using the old range
+        let last_used_pass = PassId::from_raw(self.last_used_pass.load(std::sync::atomic::Ordering::Acquire));
+        let after_pass = std::cmp::max(last_used_pass, self.share.get_last_pass_id());
+        // Writes must be ordered after the copy
+        self.update_used_in(after_pass);
+
+        // If the mesh is relocated again before the passes using the original range have been
+        // recorded those passes must keep using the original range
+        match placement.previous {
+            Some((pass, _)) if pass >= after_pass => {},
+            _ => placement.previous = Some((after_pass, placement.draw_info)),
+        }
+
+        let src = placement.allocation;
+        let mut draw_info = placement.draw_info;
+        draw_info.vertex_buffer = dst.buffer;
+        draw_info.vertex_offset = (dst.offset / self.vertex_stride) as i32;
+        // Sequential quad meshes use the index data of the shared quad index mesh
+        if draw_info.index_buffer.is_some() && self.quad_indices.is_none() {
+            draw_info.index_buffer = Some(dst.buffer);
+            draw_info.first_index = ((dst.offset + self.index_offset) / self.index_size) as u32;
+        }
+
+        placement.allocation = dst;
+        placement.draw_info = draw_info;
+
+        self.share.push_task(WorkerTask::RelocateGlobalMesh(GlobalMeshRelocation {
+            after_pass,
+            mesh: self.weak.upgrade().unwrap(),
+            src,
+            dst
+        }));
+    }
+
+    fn make_draw_info(share: &Arc<Share>, data: &MeshData, indices: &EmulatedIndices, allocation: &MeshArenaAllocation, layout: &MeshLayout) -> (GlobalMeshDrawInfo, Option<Arc<GlobalMesh>>) {
+        let buffer = allocation.buffer;
+        let vertex_offset = (allocation.offset / layout.vertex_stride) as i32;
+
+        match indices {
+            EmulatedIndices::Indexed { index_type, index_count, .. } => {
+                (GlobalMeshDrawInfo {
+                    vertex_buffer: buffer,
+                    index_buffer: Some(buffer),
+                    vertex_offset,
+                    first_index: ((allocation.offset + layout.index_offset) / layout.index_size) as u32,
+                    index_type: *index_type,
+                    index_count: *index_count,
+                    primitive_topology: data.primitive_topology
+                }, None)
+            }
+            EmulatedIndices::SequentialQuads { pattern, quad_count } => {
+                let quad_mesh = QuadIndexCache::get_quad_indices(share, *pattern, *quad_count);
+                // Quad index meshes are never relocated so the current placement is valid for all passes
+                let quad_info = quad_mesh.placement.lock().unwrap().draw_info;
+                let draw_info = GlobalMeshDrawInfo {
+                    vertex_buffer: buffer,
+                    index_buffer: quad_info.index_buffer,
+                    vertex_offset,
+                    first_index: quad_info.first_index,
+                    index_type: quad_info.index_type,
+                    index_count: *quad_count * 6,
+                    primitive_topology: data.primitive_topology
+                };
+                (draw_info, Some(quad_mesh))
+            }
+            EmulatedIndices::NonIndexed { vertex_count } => {
+                (GlobalMeshDrawInfo {
+                    vertex_buffer: buffer,
+                    index_buffer: None,
+                    vertex_offset,
+                    first_index: 0,
+                    index_type: vk::IndexType::UINT32,
+                    index_count: *vertex_count,
+                    primitive_topology: data.primitive_topology
+                }, None)
+            }
+        }
     }
 
     pub(super) fn update_used_in(&self, pass: PassId) {
@@ -112,23 +453,18 @@ impl GlobalMesh {
         }
     }
 
-    pub(super) fn get_buffer_handle(&self) -> vk::Buffer {
-        self.buffer
-    }
-
-    pub(super) fn get_draw_info(&self) -> &GlobalMeshDrawInfo {
-        &self.draw_info
-    }
-
-    fn create_buffer(device: &DeviceContext, size: vk::DeviceSize) -> Result<(vk::Buffer, Allocation), GlobalObjectCreateError> {
-        let info = vk::BufferCreateInfo::builder()
-            .size(size)
-            .usage(vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::INDEX_BUFFER)
-            .sharing_mode(vk::SharingMode::EXCLUSIVE);
-
-        unsafe {
-            device.get_allocator().create_gpu_buffer(&info, &format_args!("GlobalBuffer"))
-        }.ok_or(GlobalObjectCreateError::Allocation)
+    /// Returns the draw parameters used to draw the mesh in a pass. Passes recorded before the mesh
+    /// has been relocated keep using the old range.
+    pub(super) fn get_draw_info(&self, pass: PassId) -> GlobalMeshDrawInfo {
+        let mut placement = self.placement.lock().unwrap();
+        if let Some((last_pass, draw_info)) = placement.previous {
+            if pass <= last_pass {
+                return draw_info;
+            }
+            // Passes are recorded in order so the old range is no longer needed
+            placement.previous = None;
+        }
+        placement.draw_info
     }
 }
 
@@ -161,18 +497,116 @@ impl Hash for GlobalMesh {
 
 impl Drop for GlobalMesh {
     fn drop(&mut self) {
-        unsafe {
-            self.share.get_device().get_allocator().destroy_buffer(self.buffer, self.allocation)
-        }
+        // The old range of a relocated mesh is freed by the worker once the copy has completed
+        let last_used_pass = PassId::from_raw(self.last_used_pass.load(std::sync::atomic::Ordering::Acquire));
+        let allocation = self.placement.get_mut().unwrap().allocation;
+        self.share.destroy_after_pass(last_used_pass, DeferredObject::MeshAllocation(allocation));
     }
 }
 
+struct MeshPlacement {
+    allocation: MeshArenaAllocation,
+    draw_info: GlobalMeshDrawInfo,
+
+    /// The draw parameters of the range used before the last relocation and the last pass which
+    /// may use them.
+    previous: Option<(PassId, GlobalMeshDrawInfo)>,
+}
+
+#[derive(Copy, Clone, PartialEq)]
 pub(super) struct GlobalMeshDrawInfo {
-    pub(super) buffer: vk::Buffer,
+    pub(super) vertex_buffer: vk::Buffer,
+
+    /// The offset added to all indices in vertices. If the mesh has no indices this is the first
+    /// vertex.
+    pub(super) vertex_offset: i32,
+
+    /// The index buffer. If [`None`] the mesh is drawn without indices and `index_count` is the
+    /// number of vertices.
+    pub(super) index_buffer: Option<vk::Buffer>,
     pub(super) first_index: u32,
     pub(super) index_count: u32,
     pub(super) index_type: vk::IndexType,
-    pub(super) primitive_topology: vk::PrimitiveTopology,
+    pub(super) primitive_topology: PrimitiveTopology,
+}
+
+/// The buffer layout of the data of a [`GlobalMesh`]. Index data owned by the mesh is stored after
+/// the vertex data.
+struct MeshLayout<'a> {
+    index_data: &'a [u8],
+    vertex_stride: vk::DeviceSize,
+
+    /// The required alignment of the start of the mesh in the buffer. The vertex offset must be a
+    /// multiple of the vertex stride and the index offset a multiple of the index size.
+    alignment: vk::DeviceSize,
+    index_size: vk::DeviceSize,
+    index_offset: vk::DeviceSize,
+    required_size: vk::DeviceSize,
+
+    /// The offset and size of the index data if it is stored unmodified and can therefore be updated.
+    index_data_range: Option<(vk::DeviceSize, vk::DeviceSize)>,
+}
+
+impl<'a> MeshLayout<'a> {
+    fn new(data: &MeshData, indices: &'a EmulatedIndices) -> Self {
+        let (index_data, index_type) = match indices {
+            EmulatedIndices::Indexed { data: index_data, index_type, .. } => (index_data.as_ref(), *index_type),
+            _ => (&[] as &[u8], vk::IndexType::UINT32),
+        };
+        let index_size = get_index_type_size(index_type) as vk::DeviceSize;
+
+        let vertex_stride = std::cmp::max(data.vertex_stride as vk::DeviceSize, 1);
+        // Line expansion uses the parity of the vertex index so the first vertex must be even
+        let vertex_alignment = if data.primitive_topology.is_wide_line() {
+            vertex_stride * 2
+        } else {
+            vertex_stride
+        };
+        let alignment = least_common_multiple(vertex_alignment, index_size);
+
+        let index_offset = next_aligned(data.vertex_data.len() as vk::DeviceSize, index_size);
+        let required_size = index_offset + (index_data.len() as vk::DeviceSize);
+
+        let index_data_range = if data.index_data.is_some() && !data.primitive_topology.is_emulated() {
+            Some((index_offset, index_data.len() as vk::DeviceSize))
+        } else {
+            None
+        };
+
+        Self {
+            index_data,
+            vertex_stride,
+            alignment,
+            index_size,
+            index_offset,
+            required_size,
+            index_data_range,
+        }
+    }
+}
+
+fn least_common_multiple(a: vk::DeviceSize, b: vk::DeviceSize) -> vk::DeviceSize {
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        (x, y) = (y, x % y);
+    }
+    a / x * b
+}
+
+/// A range of vertex or index data used to update a [`GlobalMesh`].
+pub struct MeshDataRange<'a> {
+    /// The offset in bytes relative to the start of the vertex or index data.
+    pub offset: vk::DeviceSize,
+    pub data: &'a [u8],
+}
+
+impl<'a> MeshDataRange<'a> {
+    pub fn new(offset: vk::DeviceSize, data: &'a [u8]) -> Self {
+        Self {
+            offset,
+            data,
+        }
+    }
 }
 
 pub struct ImageData<'a> {
@@ -187,6 +621,13 @@ pub struct ImageData<'a> {
 
     /// The size of the upload region in the image.
     pub extent: Vec2u32,
+
+    /// The mip level to upload to. The offset and extent are relative to the size of this mip level.
+    pub mip_level: u32,
+
+    /// The array layer to upload to. For 3D images this selects the depth slice of the mip level
+    /// instead.
+    pub array_layer: u32,
 }
 
 impl<'a> ImageData<'a> {
@@ -196,6 +637,8 @@ impl<'a> ImageData<'a> {
             row_stride: 0,
             offset: Vec2u32::new(0, 0),
             extent: size,
+            mip_level: 0,
+            array_layer: 0,
         }
     }
 
@@ -205,6 +648,8 @@ impl<'a> ImageData<'a> {
             row_stride,
             offset: Vec2u32::new(0, 0),
             extent: size,
+            mip_level: 0,
+            array_layer: 0,
         }
     }
 
@@ -213,7 +658,9 @@ impl<'a> ImageData<'a> {
             data,
             row_stride: 0,
             offset,
-            extent
+            extent,
+            mip_level: 0,
+            array_layer: 0,
         }
     }
 
@@ -222,9 +669,18 @@ impl<'a> ImageData<'a> {
             data,
             row_stride,
             offset,
-            extent
+            extent,
+            mip_level: 0,
+            array_layer: 0,
         }
     }
+
+    /// Changes the mip level and array layer the data is uploaded to.
+    pub fn with_subresource(mut self, mip_level: u32, array_layer: u32) -> Self {
+        self.mip_level = mip_level;
+        self.array_layer = array_layer;
+        self
+    }
 }
 
 define_uuid_type!(pub, GlobalImageId);
@@ -239,15 +695,48 @@ pub struct GlobalImage {
     image: vk::Image,
     sampler_view: vk::ImageView,
     allocation: Allocation,
-    size: Vec2u32,
-    mip_levels: u32,
+    size: ImageSize,
+    view_type: vk::ImageViewType,
+    format: &'static Format,
 
     sampler_database: Mutex<HashMap<SamplerInfo, vk::Sampler>>,
 }
 
 impl GlobalImage {
     pub(super) fn new(share: Arc<Share>, size: Vec2u32, mip_levels: u32, format: &'static Format) -> Result<Arc<Self>, GlobalObjectCreateError> {
-        let (image, allocation, sampler_view) = Self::create_image(share.get_device(), format.into(), size, mip_levels)?;
+        let size = ImageSize::make_2d_mip(size[0], size[1], mip_levels);
+        Self::new_with_usage(share, size, vk::ImageViewType::TYPE_2D, format, vk::ImageUsageFlags::empty(), vk::ImageCreateFlags::empty())
+    }
+
+    /// Creates a new 2D array image. The sampler view has the type [`vk::ImageViewType::TYPE_2D_ARRAY`].
+    pub(super) fn new_array(share: Arc<Share>, size: Vec2u32, array_layers: u32, mip_levels: u32, format: &'static Format) -> Result<Arc<Self>, GlobalObjectCreateError> {
+        let size = ImageSize::make_2d_array_mip(size[0], size[1], array_layers, mip_levels);
+        Self::new_with_usage(share, size, vk::ImageViewType::TYPE_2D_ARRAY, format, vk::ImageUsageFlags::empty(), vk::ImageCreateFlags::empty())
+    }
+
+    /// Creates a new 3D image. The sampler view has the type [`vk::ImageViewType::TYPE_3D`].
+    pub(super) fn new_3d(share: Arc<Share>, size: Vec3u32, mip_levels: u32, format: &'static Format) -> Result<Arc<Self>, GlobalObjectCreateError> {
+        let size = ImageSize::make_3d_mip(size[0], size[1], size[2], mip_levels);
+        Self::new_with_usage(share, size, vk::ImageViewType::TYPE_3D, format, vk::ImageUsageFlags::empty(), vk::ImageCreateFlags::empty())
+    }
+
+    /// Creates a new cube map with square faces of the specified size. The faces are stored in the
+    /// array layers 0 to 5 in the order +X, -X, +Y, -Y, +Z, -Z. The sampler view has the type
+    /// [`vk::ImageViewType::CUBE`].
+    pub(super) fn new_cube(share: Arc<Share>, size: u32, mip_levels: u32, format: &'static Format) -> Result<Arc<Self>, GlobalObjectCreateError> {
+        let size = ImageSize::make_2d_array_mip(size, size, 6, mip_levels);
+        Self::new_with_usage(share, size, vk::ImageViewType::CUBE, format, vk::ImageUsageFlags::empty(), vk::ImageCreateFlags::CUBE_COMPATIBLE)
+    }
+
+    /// Creates a new image which can additionally be used as a color attachment. Used as the color
+    /// image of [`RenderTarget`](crate::renderer::emulator::RenderTarget)s.
+    pub(super) fn new_attachment(share: Arc<Share>, size: Vec2u32, format: &'static Format) -> Result<Arc<Self>, GlobalObjectCreateError> {
+        let size = ImageSize::make_2d(size[0], size[1]);
+        Self::new_with_usage(share, size, vk::ImageViewType::TYPE_2D, format, vk::ImageUsageFlags::COLOR_ATTACHMENT, vk::ImageCreateFlags::empty())
+    }
+
+    fn new_with_usage(share: Arc<Share>, size: ImageSize, view_type: vk::ImageViewType, format: &'static Format, usage: vk::ImageUsageFlags, flags: vk::ImageCreateFlags) -> Result<Arc<Self>, GlobalObjectCreateError> {
+        let (image, allocation, sampler_view) = Self::create_image(share.get_device(), format.into(), &size, view_type, usage, flags)?;
 
         let image = Arc::new_cyclic(|weak| GlobalImage {
             weak: weak.clone(),
@@ -260,7 +749,8 @@ impl GlobalImage {
             sampler_view,
             allocation,
             size,
-            mip_levels,
+            view_type,
+            format,
 
             sampler_database: Mutex::new(HashMap::new())
         });
@@ -291,10 +781,23 @@ impl GlobalImage {
         self.id
     }
 
+    /// Returns the width and height of the first mip level.
     pub fn get_size(&self) -> Vec2u32 {
+        Vec2u32::new(self.size.get_width(), self.size.get_height())
+    }
+
+    pub fn get_image_size(&self) -> ImageSize {
         self.size
     }
 
+    pub fn get_view_type(&self) -> vk::ImageViewType {
+        self.view_type
+    }
+
+    pub fn get_format(&self) -> &'static Format {
+        self.format
+    }
+
     pub fn update_regions(&self, regions: &[ImageData]) {
         if regions.is_empty() {
             return;
@@ -307,17 +810,24 @@ impl GlobalImage {
         let mut copies = Vec::with_capacity(regions.len());
         let mut current_offset = 0;
         for region in regions {
+            let mip_levels = self.size.get_mip_levels();
+            if region.mip_level >= mip_levels {
+                log::error!("Attempted to upload to mip level {} of a image with {} mip levels", region.mip_level, mip_levels);
+                panic!();
+            }
+            let (base_array_layer, depth_slice) = self.resolve_array_layer(region.mip_level, region.array_layer);
+
             copies.push(vk::BufferImageCopy {
                 buffer_offset: staging.offset + current_offset,
                 buffer_row_length: region.row_stride,
                 buffer_image_height: 0,
                 image_subresource: vk::ImageSubresourceLayers {
                     aspect_mask: vk::ImageAspectFlags::COLOR,
-                    mip_level: 0,
-                    base_array_layer: 0,
+                    mip_level: region.mip_level,
+                    base_array_layer,
                     layer_count: 1
                 },
-                image_offset: vk::Offset3D { x: region.offset[0] as i32, y: region.offset[1] as i32, z: 0 },
+                image_offset: vk::Offset3D { x: region.offset[0] as i32, y: region.offset[1] as i32, z: depth_slice as i32 },
                 image_extent: vk::Extent3D {
                     width: region.extent[0],
                     height: region.extent[1],
@@ -343,12 +853,188 @@ impl GlobalImage {
         }));
     }
 
+    /// Regenerates all mip levels except the first one from the first mip level. Does nothing if the
+    /// image only has a single mip level.
+    pub fn generate_mipmaps(&self) {
+        if self.size.get_mip_levels() <= 1 {
+            return;
+        }
+
+        self.share.push_task(WorkerTask::GenerateGlobalImageMipmaps(
+            self.weak.upgrade().unwrap(),
+            PassId::from_raw(self.last_used_pass.load(std::sync::atomic::Ordering::Acquire))
+        ));
+    }
+
+    /// Copies a region of a mip level into host memory. The copy is executed asynchronously before
+    /// the next pass and the returned handle can be used to access the data once it completes.
+    ///
+    /// The data is tightly packed. The offset and extent are relative to the size of the mip level.
+    /// For 3D images the array layer selects the depth slice of the mip level.
+    pub fn read_back(&self, offset: Vec2u32, extent: Vec2u32, mip_level: u32, array_layer: u32) -> ReadbackHandle {
+        let (base_array_layer, depth_slice) = self.validate_region(&ImageRegion::new(offset, extent, mip_level, array_layer));
+        let texel_size = self.format.get_texel_size().unwrap_or_else(|| {
+            log::error!("Attempted to read back image with format {:?} which has no texel size", self.format);
+            panic!();
+        });
+
+        let size = (extent[0] as u64) * (extent[1] as u64) * (texel_size as u64);
+
+        // Copies require the buffer offset to be a multiple of the texel size
+        let (staging, allocation) = self.share.get_staging_pool().lock().unwrap().allocate(size, (texel_size * 4) as u64);
+
+        let readback = Arc::new(ReadbackState {
+            share: self.share.clone(),
+            staging_allocation: Some(allocation),
+            staging_offset: staging.offset,
+            mapped: staging.mapped,
+            size: size as usize,
+            ready: Mutex::new(false),
+            condvar: Condvar::new(),
+        });
+
+        let region = vk::BufferImageCopy {
+            buffer_offset: staging.offset,
+            buffer_row_length: 0,
+            buffer_image_height: 0,
+            image_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level,
+                base_array_layer,
+                layer_count: 1
+            },
+            image_offset: vk::Offset3D { x: offset[0] as i32, y: offset[1] as i32, z: depth_slice as i32 },
+            image_extent: vk::Extent3D {
+                width: extent[0],
+                height: extent[1],
+                depth: 1
+            }
+        };
+
+        self.share.push_task(WorkerTask::ReadGlobalImage(GlobalImageRead {
+            after_pass: PassId::from_raw(self.last_used_pass.load(std::sync::atomic::Ordering::Acquire)),
+            staging_range: (staging.offset, size),
+            staging_buffer: staging.buffer,
+            src_image: self.weak.upgrade().unwrap(),
+            region,
+            readback: readback.clone()
+        }));
+
+        ReadbackHandle {
+            state: readback
+        }
+    }
+
+    /// Copies a region of another image into this image. The formats of both images must be
+    /// compatible. The copy is executed after all previously submitted passes using either image.
+    pub fn copy_from(&self, src: &Arc<GlobalImage>, src_region: &ImageRegion, dst_offset: Vec2u32, dst_mip_level: u32, dst_array_layer: u32) {
+        if !self.format.is_compatible_with(src.format) {
+            log::error!("Attempted to copy between incompatible formats {:?} and {:?}", src.format, self.format);
+            panic!();
+        }
+        let dst_region = ImageRegion::new(dst_offset, src_region.extent, dst_mip_level, dst_array_layer);
+        let ((src_layer, src_slice), (dst_layer, dst_slice)) = self.validate_copy(src, src_region, &dst_region);
+
+        let copy = vk::ImageCopy {
+            src_subresource: src_region.make_subresource_layers(src_layer),
+            src_offset: src_region.make_offset(src_slice),
+            dst_subresource: dst_region.make_subresource_layers(dst_layer),
+            dst_offset: dst_region.make_offset(dst_slice),
+            extent: vk::Extent3D {
+                width: src_region.extent[0],
+                height: src_region.extent[1],
+                depth: 1
+            }
+        };
+
+        self.push_copy(src, GlobalImageCopyRegion::Copy(copy));
+    }
+
+    /// Copies a region of another image into a region of this image scaling it if the extents do
+    /// not match. The copy is executed after all previously submitted passes using either image.
+    pub fn blit_from(&self, src: &Arc<GlobalImage>, src_region: &ImageRegion, dst_region: &ImageRegion, filter: vk::Filter) {
+        let ((src_layer, src_slice), (dst_layer, dst_slice)) = self.validate_copy(src, src_region, dst_region);
+
+        let blit = vk::ImageBlit {
+            src_subresource: src_region.make_subresource_layers(src_layer),
+            src_offsets: src_region.make_offsets(src_slice),
+            dst_subresource: dst_region.make_subresource_layers(dst_layer),
+            dst_offsets: dst_region.make_offsets(dst_slice)
+        };
+
+        self.push_copy(src, GlobalImageCopyRegion::Blit(blit, filter));
+    }
+
+    /// Validates the regions of a copy from `src` into this image. Returns the base array layer
+    /// and depth slice of the source and destination region.
+    fn validate_copy(&self, src: &Arc<GlobalImage>, src_region: &ImageRegion, dst_region: &ImageRegion) -> ((u32, u32), (u32, u32)) {
+        if src.id == self.id {
+            log::error!("Attempted to copy a image into itself");
+            panic!();
+        }
+        (src.validate_region(src_region), self.validate_region(dst_region))
+    }
+
+    fn push_copy(&self, src: &Arc<GlobalImage>, region: GlobalImageCopyRegion) {
+        let after_pass = std::cmp::max(
+            src.last_used_pass.load(std::sync::atomic::Ordering::Acquire),
+            self.last_used_pass.load(std::sync::atomic::Ordering::Acquire)
+        );
+
+        self.share.push_task(WorkerTask::CopyGlobalImage(GlobalImageCopy {
+            after_pass: PassId::from_raw(after_pass),
+            src_image: src.clone(),
+            dst_image: self.weak.upgrade().unwrap(),
+            region
+        }));
+    }
+
+    /// Validates that a region lies inside of this image. Returns the base array layer and depth
+    /// slice of the region.
+    fn validate_region(&self, region: &ImageRegion) -> (u32, u32) {
+        let mip_levels = self.size.get_mip_levels();
+        if region.mip_level >= mip_levels {
+            log::error!("Attempted to access mip level {} of a image with {} mip levels", region.mip_level, mip_levels);
+            panic!();
+        }
+        let mip_size = self.get_size().map(|v| std::cmp::max(v >> region.mip_level, 1));
+        if region.offset[0] + region.extent[0] > mip_size[0] || region.offset[1] + region.extent[1] > mip_size[1] {
+            log::error!("Attempted to access region {:?} outside of mip level {} with size {:?}", region, region.mip_level, mip_size);
+            panic!();
+        }
+        self.resolve_array_layer(region.mip_level, region.array_layer)
+    }
+
+    /// Validates the array layer of a region and returns the base array layer and depth slice it
+    /// refers to. 3D images have no array layers so the array layer selects the depth slice instead.
+    fn resolve_array_layer(&self, mip_level: u32, array_layer: u32) -> (u32, u32) {
+        if let ImageSize::Type3D { depth, .. } = self.size {
+            let mip_depth = std::cmp::max(depth >> mip_level, 1);
+            if array_layer >= mip_depth {
+                log::error!("Attempted to access depth slice {} of a mip level with depth {}", array_layer, mip_depth);
+                panic!();
+            }
+            (0, array_layer)
+        } else {
+            let array_layers = self.size.get_array_layers();
+            if array_layer >= array_layers {
+                log::error!("Attempted to access array layer {} of a image with {} array layers", array_layer, array_layers);
+                panic!();
+            }
+            (array_layer, 0)
+        }
+    }
+
     pub(super) fn get_image_handle(&self) -> vk::Image {
         self.image
     }
 
     pub(super) fn get_mip_levels(&self) -> u32 {
-        self.mip_levels
+        self.size.get_mip_levels()
+    }
+
+    pub(super) fn get_array_layers(&self) -> u32 {
+        self.size.get_array_layers()
     }
 
     pub(super) fn get_sampler_view(&self) -> vk::ImageView {
@@ -360,19 +1046,30 @@ impl GlobalImage {
         if let Some(sampler) = guard.get(sampler_info) {
             *sampler
         } else {
+            let device = self.share.get_device();
+
+            let anisotropy_enable = sampler_info.anisotropy_enable && device.get_enabled_features().sampler_anisotropy == vk::TRUE;
+            let max_anisotropy = if anisotropy_enable {
+                sampler_info.max_anisotropy.clamp(1f32, device.get_limits().max_sampler_anisotropy)
+            } else {
+                1f32
+            };
+
             let info = vk::SamplerCreateInfo::builder()
                 .mag_filter(sampler_info.mag_filter)
                 .min_filter(sampler_info.min_filter)
                 .mipmap_mode(sampler_info.mipmap_mode)
                 .address_mode_u(sampler_info.address_mode_u)
                 .address_mode_v(sampler_info.address_mode_v)
-                .address_mode_w(vk::SamplerAddressMode::REPEAT)
-                .mip_lod_bias(0f32)
-                .anisotropy_enable(sampler_info.anisotropy_enable)
-                .max_anisotropy(0f32)
-                .compare_enable(false)
-                .min_lod(0f32)
-                .max_lod(vk::LOD_CLAMP_NONE)
+                .address_mode_w(sampler_info.address_mode_w)
+                .mip_lod_bias(sampler_info.mip_lod_bias)
+                .anisotropy_enable(anisotropy_enable)
+                .max_anisotropy(max_anisotropy)
+                .compare_enable(sampler_info.compare_op.is_some())
+                .compare_op(sampler_info.compare_op.unwrap_or(vk::CompareOp::ALWAYS))
+                .min_lod(sampler_info.min_lod)
+                .max_lod(sampler_info.max_lod)
+                .border_color(sampler_info.border_color)
                 .unnormalized_coordinates(false);
 
             let sampler = unsafe {
@@ -387,20 +1084,17 @@ impl GlobalImage {
         }
     }
 
-    fn create_image(device: &DeviceContext, format: vk::Format, size: Vec2u32, mip_levels: u32) -> Result<(vk::Image, Allocation, vk::ImageView), GlobalObjectCreateError> {
+    fn create_image(device: &DeviceContext, format: vk::Format, size: &ImageSize, view_type: vk::ImageViewType, usage: vk::ImageUsageFlags, flags: vk::ImageCreateFlags) -> Result<(vk::Image, Allocation, vk::ImageView), GlobalObjectCreateError> {
         let info = vk::ImageCreateInfo::builder()
-            .image_type(vk::ImageType::TYPE_2D)
+            .flags(flags)
+            .image_type(size.get_vulkan_type())
             .format(format)
-            .extent(vk::Extent3D {
-                width: size[0],
-                height: size[1],
-                depth: 1
-            })
-            .mip_levels(mip_levels)
-            .array_layers(1)
+            .extent(size.as_extent_3d())
+            .mip_levels(size.get_mip_levels())
+            .array_layers(size.get_array_layers())
             .samples(vk::SampleCountFlags::TYPE_1)
             .tiling(vk::ImageTiling::OPTIMAL)
-            .usage(vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED)
+            .usage(vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED | usage)
             .sharing_mode(vk::SharingMode::EXCLUSIVE)
             .initial_layout(vk::ImageLayout::UNDEFINED);
 
@@ -410,7 +1104,7 @@ impl GlobalImage {
 
         let info = vk::ImageViewCreateInfo::builder()
             .image(image)
-            .view_type(vk::ImageViewType::TYPE_2D)
+            .view_type(view_type)
             .format(format)
             .components(vk::ComponentMapping {
                 r: vk::ComponentSwizzle::IDENTITY,
@@ -421,9 +1115,9 @@ impl GlobalImage {
             .subresource_range(vk::ImageSubresourceRange {
                 aspect_mask: vk::ImageAspectFlags::COLOR,
                 base_mip_level: 0,
-                level_count: mip_levels,
+                level_count: size.get_mip_levels(),
                 base_array_layer: 0,
-                layer_count: 1
+                layer_count: size.get_array_layers()
             });
 
         let sampler_view = match unsafe {
@@ -470,20 +1164,234 @@ impl Hash for GlobalImage {
 
 impl Drop for GlobalImage {
     fn drop(&mut self) {
-        let device = self.share.get_device();
+        let last_used_pass = PassId::from_raw(self.last_used_pass.load(std::sync::atomic::Ordering::Acquire));
+        self.share.destroy_after_pass(last_used_pass, DeferredObject::Image(self.image, self.sampler_view, self.allocation));
+    }
+}
+
+/// A 2D region of a single mip level and array layer of a image.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ImageRegion {
+    /// The offset of the region in the mip level.
+    pub offset: Vec2u32,
+
+    /// The size of the region.
+    pub extent: Vec2u32,
+
+    pub mip_level: u32,
+
+    /// The array layer of the region. For 3D images this selects the depth slice of the mip level
+    /// instead.
+    pub array_layer: u32,
+}
+
+impl ImageRegion {
+    pub fn new(offset: Vec2u32, extent: Vec2u32, mip_level: u32, array_layer: u32) -> Self {
+        Self {
+            offset,
+            extent,
+            mip_level,
+            array_layer
+        }
+    }
+
+    fn make_subresource_layers(&self, base_array_layer: u32) -> vk::ImageSubresourceLayers {
+        vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: self.mip_level,
+            base_array_layer,
+            layer_count: 1
+        }
+    }
+
+    fn make_offset(&self, depth_slice: u32) -> vk::Offset3D {
+        vk::Offset3D { x: self.offset[0] as i32, y: self.offset[1] as i32, z: depth_slice as i32 }
+    }
+
+    fn make_offsets(&self, depth_slice: u32) -> [vk::Offset3D; 2] {
+        let end = self.offset + self.extent;
+        [self.make_offset(depth_slice), vk::Offset3D { x: end[0] as i32, y: end[1] as i32, z: (depth_slice + 1) as i32 }]
+    }
+}
+
+/// Handle to the result of a [`GlobalImage::read_back`] call.
+///
+/// The copy is only submitted together with the next pass. Waiting for the data without starting
+/// and ending a pass will never return.
+pub struct ReadbackHandle {
+    state: Arc<ReadbackState>,
+}
+
+impl ReadbackHandle {
+    /// Returns the size of the data in bytes.
+    pub fn get_size(&self) -> usize {
+        self.state.size
+    }
+
+    /// Returns true if the data is available.
+    pub fn is_ready(&self) -> bool {
+        *self.state.ready.lock().unwrap()
+    }
+
+    /// Returns the data if it is available.
+    pub fn try_get(&self) -> Option<&[u8]> {
+        if self.is_ready() {
+            Some(self.state.get_data())
+        } else {
+            None
+        }
+    }
+
+    /// Blocks until the data is available and returns it.
+    pub fn wait(&self) -> &[u8] {
+        let mut guard = self.state.ready.lock().unwrap();
+        while !*guard {
+            guard = self.state.condvar.wait(guard).unwrap();
+        }
+        drop(guard);
+
+        self.state.get_data()
+    }
+}
+
+/// State shared between a [`ReadbackHandle`] and the worker. The staging memory is freed once
+/// both the handle has been dropped and the copy has completed.
+pub(super) struct ReadbackState {
+    share: Arc<Share>,
+    staging_allocation: Option<StagingAllocationId>,
+    staging_offset: vk::DeviceSize,
+    mapped: NonNull<u8>,
+    size: usize,
+
+    ready: Mutex<bool>,
+    condvar: Condvar,
+}
+
+// Needed because of NonNull<u8>
+unsafe impl Send for ReadbackState {
+}
+unsafe impl Sync for ReadbackState {
+}
+
+impl ReadbackState {
+    /// Called by the worker once the copy has completed execution and before
+    /// [`ReadbackState::mark_ready`]. The staging memory may not be host coherent so the range must
+    /// be invalidated before the host reads it.
+    pub(super) fn invalidate(&self, staging_pool: &StagingMemoryPool) {
+        if let Some(allocation) = &self.staging_allocation {
+            staging_pool.invalidate(allocation, self.staging_offset, self.size as vk::DeviceSize);
+        }
+    }
+
+    /// Called by the worker once the copy has completed execution.
+    pub(super) fn mark_ready(&self) {
+        *self.ready.lock().unwrap() = true;
+        self.condvar.notify_all();
+    }
+
+    fn get_data(&self) -> &[u8] {
         unsafe {
-            device.vk().destroy_image_view(self.sampler_view, None);
-            device.get_allocator().destroy_image(self.image, self.allocation);
+            std::slice::from_raw_parts(self.mapped.as_ptr(), self.size)
         }
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+impl Drop for ReadbackState {
+    fn drop(&mut self) {
+        if let Some(allocation) = self.staging_allocation.take() {
+            self.share.get_staging_pool().lock().unwrap().free(allocation);
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
 pub struct SamplerInfo {
     pub mag_filter: vk::Filter,
     pub min_filter: vk::Filter,
     pub mipmap_mode: vk::SamplerMipmapMode,
     pub address_mode_u: vk::SamplerAddressMode,
     pub address_mode_v: vk::SamplerAddressMode,
+    pub address_mode_w: vk::SamplerAddressMode,
+
+    /// If anisotropic filtering should be used. Ignored if the device does not support anisotropic
+    /// filtering.
     pub anisotropy_enable: bool,
+
+    /// The maximum anisotropy level. Clamped to the range supported by the device.
+    pub max_anisotropy: f32,
+
+    pub mip_lod_bias: f32,
+    pub min_lod: f32,
+
+    /// The maximum lod. Use [`vk::LOD_CLAMP_NONE`] to not clamp the lod.
+    pub max_lod: f32,
+
+    /// The border color used by the clamp to border address mode.
+    pub border_color: vk::BorderColor,
+
+    /// If set the sampler performs a depth comparison using the provided operation.
+    pub compare_op: Option<vk::CompareOp>,
+}
+
+impl SamplerInfo {
+    /// Creates a sampler info with the specified filters and address modes. Anisotropic filtering
+    /// and depth comparison are disabled and the lod is not clamped.
+    pub fn new(mag_filter: vk::Filter, min_filter: vk::Filter, mipmap_mode: vk::SamplerMipmapMode, address_mode_u: vk::SamplerAddressMode, address_mode_v: vk::SamplerAddressMode) -> Self {
+        Self {
+            mag_filter,
+            min_filter,
+            mipmap_mode,
+            address_mode_u,
+            address_mode_v,
+            address_mode_w: vk::SamplerAddressMode::REPEAT,
+            anisotropy_enable: false,
+            max_anisotropy: 1f32,
+            mip_lod_bias: 0f32,
+            min_lod: 0f32,
+            max_lod: vk::LOD_CLAMP_NONE,
+            border_color: vk::BorderColor::FLOAT_TRANSPARENT_BLACK,
+            compare_op: None
+        }
+    }
+}
+
+// The float fields are compared by their bit pattern so that the sampler info can be used as a
+// hash map key.
+impl PartialEq for SamplerInfo {
+    fn eq(&self, other: &Self) -> bool {
+        self.mag_filter == other.mag_filter &&
+            self.min_filter == other.min_filter &&
+            self.mipmap_mode == other.mipmap_mode &&
+            self.address_mode_u == other.address_mode_u &&
+            self.address_mode_v == other.address_mode_v &&
+            self.address_mode_w == other.address_mode_w &&
+            self.anisotropy_enable == other.anisotropy_enable &&
+            self.max_anisotropy.to_bits() == other.max_anisotropy.to_bits() &&
+            self.mip_lod_bias.to_bits() == other.mip_lod_bias.to_bits() &&
+            self.min_lod.to_bits() == other.min_lod.to_bits() &&
+            self.max_lod.to_bits() == other.max_lod.to_bits() &&
+            self.border_color == other.border_color &&
+            self.compare_op == other.compare_op
+    }
+}
+
+impl Eq for SamplerInfo {
+}
+
+impl Hash for SamplerInfo {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.mag_filter.hash(state);
+        self.min_filter.hash(state);
+        self.mipmap_mode.hash(state);
+        self.address_mode_u.hash(state);
+        self.address_mode_v.hash(state);
+        self.address_mode_w.hash(state);
+        self.anisotropy_enable.hash(state);
+        self.max_anisotropy.to_bits().hash(state);
+        self.mip_lod_bias.to_bits().hash(state);
+        self.min_lod.to_bits().hash(state);
+        self.max_lod.to_bits().hash(state);
+        self.border_color.hash(state);
+        self.compare_op.hash(state);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,844 @@
+//! Provides the main [`EmulatorPipeline`] implementation which renders objects the same way
+//! minecraft does.
+
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Instant;
+use ash::vk;
+use bumpalo::Bump;
+use bytemuck::cast_slice;
+use include_bytes_aligned::include_bytes_aligned;
+use crate::allocator::Allocation;
+use crate::device::device::Queue;
+
+use crate::prelude::*;
+use crate::renderer::emulator::{EmulatorRenderer, RenderTarget};
+use crate::renderer::emulator::mc_shaders::{McUniform, ShaderDropListener, ShaderId, VertexFormat, VertexFormatEntry};
+use crate::renderer::emulator::pipeline::{DrawBatchTask, DrawTask, EmulatorPipeline, InstancedDrawTask, EmulatorPipelinePass, ObjectCreateError, PipelineTask, PooledObjectProvider, SubmitRecorder};
+use crate::renderer::emulator::pipeline_common::{DRAW_DYNAMIC_STATES, DrawPipeline, DrawState, InstanceInput, PipelineCache, PipelineConfig, SHADER_ENTRY, try_create_shader_module};
+use crate::util::vk::make_full_rect;
+
+/// A [`EmulatorPipeline`] emulating minecrafts core shaders.
+///
+/// Instead of compiling the minecraft shaders a single set of shaders is used which is configured
+/// for each shader based on its vertex format and used uniforms. It supports textures, the
+/// lightmap, entity overlays, lighting, fog and the color modulator.
+///
+/// Minecraft blends in gamma space. To emulate this rendering is done into a UNORM image which is
+/// then exposed through a SRGB view for sampling.
+pub struct McPipeline {
+    emulator: Arc<EmulatorRenderer>,
+    weak: Weak<Self>,
+
+    framebuffer_size: Vec2u32,
+
+    vertex_module: vk::ShaderModule,
+    fragment_module: vk::ShaderModule,
+    render_pass: vk::RenderPass,
+    draw_pipeline: DrawPipeline,
+
+    pipelines: PipelineCache,
+    next_index: AtomicUsize,
+    pass_objects: Box<[PassObjects]>,
+    output_views: Box<[vk::ImageView]>,
+}
+assert_impl_all!(McPipeline: Send, Sync);
+
+impl McPipeline {
+    pub fn new(emulator: Arc<EmulatorRenderer>, framebuffer_size: Vec2u32) -> Result<Arc<Self>, ObjectCreateError> {
+        let concurrent_passes = 2usize;
+        // Must match the render target formats so passes can render into them
+        let depth_format = RenderTarget::DEPTH_FORMAT;
+        let color_format = RenderTarget::COLOR_FORMAT;
+        let output_format = vk::Format::R8G8B8A8_SRGB;
+
+        let device = emulator.get_device();
+
+        let vertex_module = try_create_shader_module(device, MAIN_VERTEX_BIN, "main_vertex")?;
+        let fragment_module = try_create_shader_module(device, MAIN_FRAGMENT_BIN, "main_fragment").map_err(|err| {
+            unsafe { device.vk().destroy_shader_module(vertex_module, None) };
+            err
+        })?;
+
+        let render_pass = match Self::create_render_pass(device, depth_format, color_format) {
+            Ok(render_pass) => render_pass,
+            Err(err) => {
+                unsafe {
+                    device.vk().destroy_shader_module(fragment_module, None);
+                    device.vk().destroy_shader_module(vertex_module, None);
+                }
+                return Err(err);
+            }
+        };
+
+        let mut draw_pipeline = match DrawPipeline::new(device) {
+            Ok(pipeline) => pipeline,
+            Err(err) => {
+                unsafe {
+                    device.vk().destroy_render_pass(render_pass, None);
+                    device.vk().destroy_shader_module(fragment_module, None);
+                    device.vk().destroy_shader_module(vertex_module, None);
+                }
+                return Err(err);
+            }
+        };
+
+        let mut pass_objects: Vec<PassObjects> = Vec::with_capacity(concurrent_passes);
+        for _ in 0..concurrent_passes {
+            let objects = match PassObjects::new(device, framebuffer_size, depth_format, color_format, output_format, render_pass) {
+                Ok(objects) => objects,
+                Err(err) => {
+                    for mut pass_object in pass_objects {
+                        pass_object.destroy(device);
+                    }
+                    draw_pipeline.destroy(device);
+                    unsafe {
+                        device.vk().destroy_render_pass(render_pass, None);
+                        device.vk().destroy_shader_module(fragment_module, None);
+                        device.vk().destroy_shader_module(vertex_module, None);
+                    }
+                    return Err(err);
+                }
+            };
+            pass_objects.push(objects);
+        }
+        let pass_objects = pass_objects.into_boxed_slice();
+
+        let output_views = pass_objects.iter().map(|obj| obj.output_view).collect();
+
+        Ok(Arc::new_cyclic(|weak| {
+            Self {
+                emulator,
+                weak: weak.clone(),
+
+                framebuffer_size,
+
+                vertex_module,
+                fragment_module,
+                render_pass,
+                draw_pipeline,
+
+                pipelines: PipelineCache::new(),
+                next_index: AtomicUsize::new(0),
+                pass_objects,
+                output_views
+            }
+        }))
+    }
+
+    /// Returns the next index to be used for a pass and increments the internal counter.
+    fn next_index(&self) -> usize {
+        loop {
+            let current = self.next_index.load(Ordering::SeqCst);
+            let next = (current + 1) % self.pass_objects.len();
+            if self.next_index.compare_exchange(current, next, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                return current;
+            }
+        }
+    }
+
+    fn create_pipeline(&self, config: &PipelineConfig, vertex_format: &VertexFormat, used_uniforms: McUniform, alpha_cutoff: f32) -> vk::Pipeline {
+        let alloc = Bump::new();
+        let (shader_stages, input_state) = self.configure_shaders(config, vertex_format, used_uniforms, alpha_cutoff, &alloc);
+
+        // Viewport and scissor are dynamic state set by the DrawState
+        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let rasterization_state = config.get_rasterization_state(self.emulator.get_device());
+
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1)
+            .sample_shading_enable(false);
+
+        let attachment_blend_state = [
+            config.get_attachment_blend_state()
+        ];
+
+        let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+            .logic_op_enable(false)
+            .attachments(&attachment_blend_state);
+
+        let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
+            .dynamic_states(&DRAW_DYNAMIC_STATES);
+
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(config.primitive_topology.get_native())
+            .primitive_restart_enable(false);
+
+        let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(config.depth_test_enable)
+            .depth_write_enable(config.depth_write_enable)
+            .depth_compare_op(config.depth_compare_op);
+
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(shader_stages)
+            .vertex_input_state(input_state)
+            .input_assembly_state(&input_assembly_state)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            .depth_stencil_state(&depth_stencil_state)
+            .color_blend_state(&color_blend_state)
+            .dynamic_state(&dynamic_state)
+            .layout(self.draw_pipeline.pipeline_layout)
+            .render_pass(self.render_pass)
+            .subpass(0);
+
+        let pipeline = *unsafe {
+            self.emulator.get_device().vk().create_graphics_pipelines(vk::PipelineCache::null(), std::slice::from_ref(&info), None)
+        }.unwrap_or_else(|(_, err)| {
+            log::error!("Failed to create graphics pipeline {:?}", err);
+            panic!();
+        }).get(0).unwrap();
+
+        pipeline
+    }
+
+    /// Generates the shader stages and vertex input state for a vertex format.
+    ///
+    /// The main shaders always consume all attributes. Attributes not present in the vertex format
+    /// are sourced from the position attribute and disabled using specialization constants.
+    fn configure_shaders<'a>(&self, config: &PipelineConfig, vertex_format: &VertexFormat, used_uniforms: McUniform, alpha_cutoff: f32, alloc: &'a Bump) -> (&'a [vk::PipelineShaderStageCreateInfo], &'a vk::PipelineVertexInputStateCreateInfo) {
+        let (instance_binding, instance_attributes) = config.get_instance_inputs(vertex_format, 6);
+
+        let vertex_binding = vk::VertexInputBindingDescription {
+            binding: 0,
+            stride: vertex_format.stride,
+            input_rate: vk::VertexInputRate::VERTEX
+        };
+        let input_bindings: &[_] = match instance_binding {
+            Some(instance_binding) => alloc.alloc([vertex_binding, instance_binding]),
+            None => alloc.alloc([vertex_binding]),
+        };
+
+        let attribute = |location: u32, entry: Option<&VertexFormatEntry>| {
+            let entry = entry.unwrap_or(&vertex_format.position);
+            vk::VertexInputAttributeDescription {
+                location,
+                binding: 0,
+                format: entry.format,
+                offset: entry.offset
+            }
+        };
+
+        let [chunk_offset, model_view_0, model_view_1, model_view_2, model_view_3, instance_color] = instance_attributes;
+        let input_attributes: &[_] = alloc.alloc([
+            attribute(0, Some(&vertex_format.position)),
+            attribute(1, vertex_format.color.as_ref()),
+            attribute(2, vertex_format.uv0.as_ref()),
+            attribute(3, vertex_format.uv1.as_ref()),
+            attribute(4, vertex_format.uv2.as_ref()),
+            attribute(5, vertex_format.normal.as_ref()),
+            chunk_offset,
+            model_view_0,
+            model_view_1,
+            model_view_2,
+            model_view_3,
+            instance_color,
+        ]);
+
+        let mut features = 0u32;
+        if vertex_format.color.is_some() {
+            features |= FEATURE_COLOR;
+        }
+        if vertex_format.uv0.is_some() {
+            features |= FEATURE_UV0;
+        }
+        if vertex_format.uv1.is_some() {
+            features |= FEATURE_UV1;
+        }
+        if vertex_format.uv2.is_some() {
+            features |= FEATURE_UV2;
+        }
+        if config.primitive_topology.is_wide_line() {
+            // The normal attribute contains the line direction
+            if vertex_format.normal.is_some() {
+                features |= FEATURE_WIDE_LINES;
+            } else {
+                log::warn!("Vertex format used with {:?} has no normal attribute. Lines will not be visible", config.primitive_topology);
+            }
+        } else if vertex_format.normal.is_some() && used_uniforms.contains(&(McUniform::LIGHT0_DIRECTION | McUniform::LIGHT1_DIRECTION)) {
+            features |= FEATURE_LIGHTING;
+        }
+        if used_uniforms.contains(&(McUniform::FOG_START | McUniform::FOG_END)) {
+            features |= FEATURE_FOG;
+        }
+        match config.instance_input {
+            InstanceInput::None => {}
+            InstanceInput::ChunkOffset => features |= FEATURE_BATCHED,
+            InstanceInput::Transform => features |= FEATURE_INSTANCED,
+        }
+
+        let uv1_scale = vertex_format.uv1.as_ref().map(|entry| get_normalized_scale(entry.format)).unwrap_or(1f32);
+        let uv2_scale = vertex_format.uv2.as_ref().map(|entry| get_normalized_scale(entry.format)).unwrap_or(1f32);
+
+        let specialization_data: &[u32] = alloc.alloc([features, uv1_scale.to_bits(), uv2_scale.to_bits(), alpha_cutoff.to_bits()]);
+        let specialization_entries: &[_] = alloc.alloc([
+            vk::SpecializationMapEntry {
+                constant_id: 0,
+                offset: 0,
+                size: 4
+            },
+            vk::SpecializationMapEntry {
+                constant_id: 1,
+                offset: 4,
+                size: 4
+            },
+            vk::SpecializationMapEntry {
+                constant_id: 2,
+                offset: 8,
+                size: 4
+            },
+            vk::SpecializationMapEntry {
+                constant_id: 3,
+                offset: 12,
+                size: 4
+            },
+        ]);
+        let specialization_info = alloc.alloc(vk::SpecializationInfo::builder()
+            .map_entries(specialization_entries)
+            .data(cast_slice(specialization_data))
+            .build()
+        );
+
+        let shader_stages: &[_] = alloc.alloc([
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(self.vertex_module)
+                .name(SHADER_ENTRY)
+                .specialization_info(specialization_info)
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(self.fragment_module)
+                .name(SHADER_ENTRY)
+                .specialization_info(specialization_info)
+                .build(),
+        ]);
+
+        let input_state: &_ = alloc.alloc(vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(input_bindings)
+            .vertex_attribute_descriptions(input_attributes)
+            .build()
+        );
+
+        (shader_stages, input_state)
+    }
+
+    fn create_render_pass(device: &DeviceContext, depth_format: vk::Format, color_format: vk::Format) -> Result<vk::RenderPass, ObjectCreateError> {
+        let attachments = [
+            vk::AttachmentDescription::builder()
+                .format(depth_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(color_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .build(),
+        ];
+
+        let depth = vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+        };
+
+        let color = [
+            vk::AttachmentReference {
+                attachment: 1,
+                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+            },
+        ];
+
+        let subpasses = [
+            vk::SubpassDescription::builder()
+                .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+                .color_attachments(&color)
+                .depth_stencil_attachment(&depth)
+                .build(),
+        ];
+
+        let info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses);
+
+        let render_pass = unsafe {
+            device.vk().create_render_pass(&info, None)
+        }.map_err(|err| {
+            log::error!("vkCreateRenderPass returned {:?} in McPipeline::create_render_pass", err);
+            err
+        })?;
+
+        Ok(render_pass)
+    }
+}
+
+impl EmulatorPipeline for McPipeline {
+    fn start_pass(&self) -> Box<dyn EmulatorPipelinePass + Send> {
+        let index = self.next_index();
+        self.pass_objects[index].wait_and_take();
+
+        Box::new(McPipelinePass::new(self.weak.upgrade().unwrap(), index))
+    }
+
+    fn get_output(&self) -> (Vec2u32, &[vk::ImageView]) {
+        (self.framebuffer_size, &self.output_views)
+    }
+
+    fn inc_shader_used(&self, shader: ShaderId) {
+        self.pipelines.inc_shader_used(&self.emulator, shader, self.weak.upgrade().unwrap());
+    }
+
+    fn dec_shader_used(&self, shader: ShaderId) {
+        self.pipelines.dec_shader_used(shader);
+    }
+}
+
+impl ShaderDropListener for McPipeline {
+    fn on_shader_drop(&self, id: ShaderId) {
+        self.pipelines.on_shader_drop(id);
+    }
+}
+
+impl Drop for McPipeline {
+    fn drop(&mut self) {
+        let device = self.emulator.get_device();
+        for objects in self.pass_objects.iter_mut() {
+            objects.destroy(device);
+        }
+        self.pipelines.clear();
+        self.draw_pipeline.destroy(device);
+        unsafe {
+            device.vk().destroy_render_pass(self.render_pass, None);
+            device.vk().destroy_shader_module(self.fragment_module, None);
+            device.vk().destroy_shader_module(self.vertex_module, None);
+        }
+    }
+}
+
+struct PassObjects {
+    ready: AtomicBool,
+
+    depth_image: vk::Image,
+    depth_view: vk::ImageView,
+
+    color_image: vk::Image,
+    color_view: vk::ImageView,
+    output_view: vk::ImageView,
+
+    framebuffer: vk::Framebuffer,
+
+    allocations: Vec<Allocation>,
+}
+
+impl PassObjects {
+    fn new(device: &DeviceContext, framebuffer_size: Vec2u32, depth_format: vk::Format, color_format: vk::Format, output_format: vk::Format, render_pass: vk::RenderPass) -> Result<Self, ObjectCreateError> {
+        let mut result = PassObjects {
+            ready: AtomicBool::new(true),
+
+            depth_image: vk::Image::null(),
+            depth_view: vk::ImageView::null(),
+
+            color_image: vk::Image::null(),
+            color_view: vk::ImageView::null(),
+            output_view: vk::ImageView::null(),
+
+            framebuffer: vk::Framebuffer::null(),
+
+            allocations: Vec::with_capacity(2)
+        };
+
+        let (depth_image, allocation) = Self::create_image(device, framebuffer_size, depth_format, vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT, vk::ImageCreateFlags::empty())?;
+        result.depth_image = depth_image;
+        result.allocations.push(allocation);
+
+        let depth_view = Self::create_image_view(device, depth_image, depth_format, vk::ImageAspectFlags::DEPTH).map_err(|err| {
+            result.destroy(device);
+            err
+        })?;
+        result.depth_view = depth_view;
+
+        let (color_image, allocation) = Self::create_image(device, framebuffer_size, color_format, vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED, vk::ImageCreateFlags::MUTABLE_FORMAT).map_err(|err| {
+            result.destroy(device);
+            err
+        })?;
+        result.color_image = color_image;
+        result.allocations.push(allocation);
+
+        let color_view = Self::create_image_view(device, color_image, color_format, vk::ImageAspectFlags::COLOR).map_err(|err| {
+            result.destroy(device);
+            err
+        })?;
+        result.color_view = color_view;
+
+        let output_view = Self::create_image_view(device, color_image, output_format, vk::ImageAspectFlags::COLOR).map_err(|err| {
+            result.destroy(device);
+            err
+        })?;
+        result.output_view = output_view;
+
+        let framebuffer = Self::create_framebuffer(device, framebuffer_size, depth_view, color_view, render_pass).map_err(|err| {
+            result.destroy(device);
+            err
+        })?;
+        result.framebuffer = framebuffer;
+
+        Ok(result)
+    }
+
+    fn wait_and_take(&self) {
+        let mut start = Instant::now();
+        loop {
+            if self.ready.compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                return;
+            }
+            std::thread::yield_now();
+            if start.elapsed().as_millis() > 1000 {
+                log::warn!("Hit 1s timeout waiting for next mc pipeline object");
+                start = Instant::now();
+            }
+        }
+    }
+
+    fn destroy(&mut self, device: &DeviceContext) {
+        unsafe {
+            if self.framebuffer != vk::Framebuffer::null() {
+                device.vk().destroy_framebuffer(self.framebuffer, None);
+            }
+            if self.output_view != vk::ImageView::null() {
+                device.vk().destroy_image_view(self.output_view, None);
+            }
+            if self.color_view != vk::ImageView::null() {
+                device.vk().destroy_image_view(self.color_view, None);
+            }
+            if self.color_image != vk::Image::null() {
+                device.vk().destroy_image(self.color_image, None);
+            }
+            if self.depth_view != vk::ImageView::null() {
+                device.vk().destroy_image_view(self.depth_view, None);
+            }
+            if self.depth_image != vk::Image::null() {
+                device.vk().destroy_image(self.depth_image, None);
+            }
+            device.get_allocator().free_memory_pages(&self.allocations);
+        }
+    }
+
+    fn create_image(device: &DeviceContext, size: Vec2u32, format: vk::Format, usage: vk::ImageUsageFlags, flags: vk::ImageCreateFlags) -> Result<(vk::Image, Allocation), ObjectCreateError> {
+        let info = vk::ImageCreateInfo::builder()
+            .flags(flags)
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width: size[0],
+                height: size[1],
+                depth: 1
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+
+        unsafe {
+            device.get_allocator().create_gpu_image(&info, &format_args!("McPipelineImage"))
+        }.ok_or(ObjectCreateError::Allocation)
+    }
+
+    fn create_image_view(device: &DeviceContext, image: vk::Image, format: vk::Format, aspect_mask: vk::ImageAspectFlags) -> Result<vk::ImageView, ObjectCreateError> {
+        let info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .components(vk::ComponentMapping {
+                r: vk::ComponentSwizzle::IDENTITY,
+                g: vk::ComponentSwizzle::IDENTITY,
+                b: vk::ComponentSwizzle::IDENTITY,
+                a: vk::ComponentSwizzle::IDENTITY
+            })
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1
+            });
+
+        let image_view = unsafe {
+            device.vk().create_image_view(&info, None)
+        }.map_err(|err| {
+            log::error!("vkCreateImageView returned {:?} in PassObjects::create_image_view", err);
+            err
+        })?;
+
+        Ok(image_view)
+    }
+
+    fn create_framebuffer(device: &DeviceContext, size: Vec2u32, depth_view: vk::ImageView, color_view: vk::ImageView, render_pass: vk::RenderPass) -> Result<vk::Framebuffer, ObjectCreateError> {
+        let attachments = [
+            depth_view, color_view
+        ];
+
+        let info = vk::FramebufferCreateInfo::builder()
+            .render_pass(render_pass)
+            .attachments(&attachments)
+            .width(size[0])
+            .height(size[1])
+            .layers(1);
+
+        let framebuffer = unsafe {
+            device.vk().create_framebuffer(&info, None)
+        }.map_err(|err| {
+            log::error!("vkCreateFramebuffer returned {:?} in PassObjects::create_framebuffer", err);
+            err
+        })?;
+
+        Ok(framebuffer)
+    }
+}
+
+struct McPipelinePass {
+    parent: Arc<McPipeline>,
+    index: usize,
+
+    command_buffer: Option<vk::CommandBuffer>,
+    draw_state: DrawState,
+    render_target: Option<Arc<RenderTarget>>,
+}
+
+impl McPipelinePass {
+    fn new(parent: Arc<McPipeline>, index: usize) -> Self {
+        let draw_state = DrawState::new(parent.framebuffer_size);
+
+        Self {
+            parent,
+            index,
+
+            command_buffer: None,
+            draw_state,
+            render_target: None,
+        }
+    }
+
+    fn flush_draws(&mut self) {
+        let parent = &self.parent;
+        self.draw_state.flush(parent.emulator.get_device(), *self.command_buffer.as_ref().unwrap(), parent.draw_pipeline.pipeline_layout);
+    }
+
+    fn draw(&mut self, task: &DrawTask, obj: &mut PooledObjectProvider) {
+        let parent = &self.parent;
+        self.draw_state.draw(
+            parent.emulator.get_device(),
+            *self.command_buffer.as_ref().unwrap(),
+            parent.draw_pipeline.pipeline_layout,
+            &parent.pipelines,
+            task,
+            obj,
+            |config, format, used_uniforms, alpha_cutoff| parent.create_pipeline(config, format, used_uniforms, alpha_cutoff)
+        );
+    }
+
+    fn draw_batch(&mut self, task: &DrawBatchTask, obj: &mut PooledObjectProvider) {
+        let parent = &self.parent;
+        self.draw_state.draw_batch(
+            parent.emulator.get_device(),
+            *self.command_buffer.as_ref().unwrap(),
+            parent.draw_pipeline.pipeline_layout,
+            &parent.pipelines,
+            task,
+            obj,
+            |config, format, used_uniforms, alpha_cutoff| parent.create_pipeline(config, format, used_uniforms, alpha_cutoff)
+        );
+    }
+
+    fn draw_instanced(&mut self, task: &InstancedDrawTask, obj: &mut PooledObjectProvider) {
+        let parent = &self.parent;
+        self.draw_state.draw_instanced(
+            parent.emulator.get_device(),
+            *self.command_buffer.as_ref().unwrap(),
+            parent.draw_pipeline.pipeline_layout,
+            &parent.pipelines,
+            task,
+            obj,
+            |config, format, used_uniforms, alpha_cutoff| parent.create_pipeline(config, format, used_uniforms, alpha_cutoff)
+        );
+    }
+}
+
+impl EmulatorPipelinePass for McPipelinePass {
+    fn init(&mut self, _: &Queue, obj: &mut PooledObjectProvider, render_target: Option<Arc<RenderTarget>>, placeholder_texture: vk::ImageView, placeholder_sampler: vk::Sampler) {
+        self.draw_state.set_placeholder(placeholder_texture, placeholder_sampler);
+
+        let cmd = obj.get_begin_command_buffer().unwrap();
+        self.command_buffer = Some(cmd);
+
+        if let Some(render_target) = render_target {
+            self.draw_state.set_framebuffer_size(render_target.get_size());
+            render_target.begin_render_pass(cmd);
+            self.render_target = Some(render_target);
+            return;
+        }
+
+        let device = self.parent.emulator.get_device();
+
+        let clear_values = [
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0
+                }
+            },
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0f32, 0f32, 0f32, 1f32],
+                }
+            },
+        ];
+        let info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.parent.render_pass)
+            .framebuffer(self.parent.pass_objects[self.index].framebuffer)
+            .render_area(make_full_rect(self.parent.framebuffer_size))
+            .clear_values(&clear_values);
+
+        unsafe {
+            device.vk().cmd_begin_render_pass(cmd, &info, vk::SubpassContents::INLINE);
+        }
+    }
+
+    fn process_task(&mut self, task: &PipelineTask, obj: &mut PooledObjectProvider) {
+        match task {
+            PipelineTask::UpdateUniform(shader, data) => {
+                self.draw_state.update_uniform(&self.parent.pipelines, *shader, data);
+            }
+            PipelineTask::UpdateTexture(shader, index, view, sampler) => {
+                self.draw_state.update_texture(&self.parent.pipelines, *shader, *index, *view, *sampler);
+            }
+            PipelineTask::SetViewport(viewport) => {
+                self.flush_draws();
+                self.draw_state.set_viewport(*viewport);
+            }
+            PipelineTask::SetScissor(scissor) => {
+                self.flush_draws();
+                self.draw_state.set_scissor(*scissor);
+            }
+            PipelineTask::Clear(color, depth, _) => {
+                self.flush_draws();
+                // There is no stencil attachment so the stencil value is ignored
+                self.draw_state.clear(self.parent.emulator.get_device(), *self.command_buffer.as_ref().unwrap(), *color, *depth);
+            }
+            PipelineTask::Draw(task) => {
+                self.draw(task, obj);
+            }
+            PipelineTask::DrawBatch(task) => {
+                self.draw_batch(task, obj);
+            }
+            PipelineTask::DrawInstanced(task) => {
+                self.draw_instanced(task, obj);
+            }
+        }
+    }
+
+    fn record<'a>(&mut self, _: &mut PooledObjectProvider, submits: &mut SubmitRecorder<'a>, alloc: &'a Bump) {
+        self.flush_draws();
+
+        let device = self.parent.emulator.get_device();
+        let cmd = self.command_buffer.take().unwrap();
+
+        if let Some(render_target) = &self.render_target {
+            render_target.end_render_pass(cmd);
+        } else {
+            let image_barrier = [
+                vk::ImageMemoryBarrier2::builder()
+                    .src_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
+                    .src_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE)
+                    .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                    .dst_access_mask(vk::AccessFlags2::MEMORY_READ)
+                    .old_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                    .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                    .src_queue_family_index(0)
+                    .dst_queue_family_index(0)
+                    .image(self.parent.pass_objects[self.index].color_image)
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        base_mip_level: 0,
+                        level_count: 1,
+                        base_array_layer: 0,
+                        layer_count: 1
+                    })
+                    .build(),
+            ];
+
+            let info = vk::DependencyInfo::builder()
+                .image_memory_barriers(&image_barrier);
+
+            unsafe {
+                device.vk().cmd_end_render_pass(cmd);
+
+                device.synchronization_2_khr().cmd_pipeline_barrier2(cmd, &info);
+            }
+        }
+
+        unsafe {
+            device.vk().end_command_buffer(cmd).unwrap();
+        }
+
+        let command_buffer_info = alloc.alloc(vk::CommandBufferSubmitInfo::builder()
+            .command_buffer(cmd)
+        );
+
+        submits.push(vk::SubmitInfo2::builder()
+            .command_buffer_infos(std::slice::from_ref(command_buffer_info))
+        );
+    }
+
+    fn get_output_index(&self) -> usize {
+        self.index
+    }
+
+    fn get_internal_fences(&self, _: &mut Vec<vk::Fence>) {
+        // All submissions are made through record so there is nothing to wait on
+    }
+}
+
+impl Drop for McPipelinePass {
+    fn drop(&mut self) {
+        self.parent.pass_objects[self.index].ready.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Returns the factor needed to convert a normalized attribute back into its integer value.
+/// Minecraft uses integer attributes for the overlay and lightmap coordinates which we receive as
+/// normalized values.
+fn get_normalized_scale(format: vk::Format) -> f32 {
+    match format {
+        vk::Format::R8G8_UNORM => u8::MAX as f32,
+        vk::Format::R8G8_SNORM => i8::MAX as f32,
+        vk::Format::R16G16_UNORM => u16::MAX as f32,
+        vk::Format::R16G16_SNORM => i16::MAX as f32,
+        _ => 1f32,
+    }
+}
+
+const FEATURE_COLOR: u32 = 1;
+const FEATURE_UV0: u32 = 2;
+const FEATURE_UV1: u32 = 4;
+const FEATURE_UV2: u32 = 8;
+const FEATURE_LIGHTING: u32 = 16;
+const FEATURE_FOG: u32 = 32;
+const FEATURE_WIDE_LINES: u32 = 64;
+const FEATURE_BATCHED: u32 = 128;
+const FEATURE_INSTANCED: u32 = 256;
+
+static MAIN_VERTEX_BIN: &'static [u8] = include_bytes_aligned!(4, concat!(env!("B4D_RESOURCE_DIR"), "emulator/main/main_vert.spv"));
+static MAIN_FRAGMENT_BIN: &'static [u8] = include_bytes_aligned!(4, concat!(env!("B4D_RESOURCE_DIR"), "emulator/main/main_frag.spv"));
@@ -19,17 +19,19 @@ pub struct Shader {
     id: ShaderId,
     vertex_format: VertexFormat,
     used_uniforms: McUniform,
+    alpha_cutoff: f32,
     weak: Weak<Self>,
     listeners: Mutex<HashMap<UUID, Weak<dyn ShaderDropListener + Send + Sync>>>,
 }
 
 impl Shader {
-    pub fn new(vertex_format: VertexFormat, used_uniforms: McUniform) -> Arc<Self> {
+    pub fn new(vertex_format: VertexFormat, used_uniforms: McUniform, alpha_cutoff: f32) -> Arc<Self> {
         Arc::new_cyclic(|weak| {
             Self {
                 id: ShaderId::new(),
                 vertex_format,
                 used_uniforms,
+                alpha_cutoff,
                 weak: weak.clone(),
                 listeners: Mutex::new(HashMap::new()),
             }
@@ -48,6 +50,11 @@ impl Shader {
         self.used_uniforms
     }
 
+    /// Returns the alpha value below which fragments are discarded. A cutoff of 0 never discards.
+    pub fn get_alpha_cutoff(&self) -> f32 {
+        self.alpha_cutoff
+    }
+
     /// Registers a drop listener to this shader. If this shader is dropped the listener will be called.
     ///
     /// The returned [`ShaderListener`] is used keep track of the liveliness of the listener. If it is
@@ -0,0 +1,353 @@
+//! Sub-allocation of global meshes from large shared buffers.
+//!
+//! Creating a dedicated buffer for every [`GlobalMesh`](crate::renderer::emulator::GlobalMesh)
+//! results in a huge number of allocations and forces a buffer rebind for every draw. Instead all
+//! global meshes are packed into a small number of large buffers managed by the [`MeshArena`].
+//!
+//! Adjacent free ranges are merged when a range is freed. Additionally the meshes of sparsely used
+//! buffers are relocated into other buffers once a pass has completed (see
+//! [`MeshArena::find_sparse_block`]) so that the sparse buffers can be destroyed. A relocated mesh
+//! copies its data on the gpu after all passes which may still use the old range and the old range
+//! is freed once that copy has completed.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+
+use ash::vk;
+
+use crate::allocator::Allocation;
+use crate::renderer::emulator::GlobalMesh;
+use crate::util::alloc::next_aligned;
+
+use crate::prelude::*;
+
+/// A range of a arena buffer owned by a single mesh.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub(super) struct MeshArenaAllocation {
+    pub(super) buffer: vk::Buffer,
+    pub(super) offset: vk::DeviceSize,
+    pub(super) size: vk::DeviceSize,
+}
+
+pub(super) struct MeshArena {
+    device: Arc<DeviceContext>,
+    blocks: Vec<ArenaBlock>,
+}
+
+impl MeshArena {
+    /// The size of newly created arena buffers. Larger meshes get a buffer of their own size.
+    const BLOCK_SIZE: vk::DeviceSize = 1u64 << 26;
+
+    pub(super) fn new(device: Arc<DeviceContext>) -> Self {
+        Self {
+            device,
+            blocks: Vec::new(),
+        }
+    }
+
+    /// Allocates a range of the specified size. The offset of the range is a multiple of the
+    /// alignment which does not need to be a power of 2.
+    ///
+    /// Returns [`None`] if a new arena buffer is needed and its creation failed.
+    pub(super) fn allocate(&mut self, size: vk::DeviceSize, alignment: vk::DeviceSize) -> Option<MeshArenaAllocation> {
+        // Vulkan does not allow empty buffers or copies so we always allocate at least 1 byte
+        let size = std::cmp::max(size, 1);
+
+        for block in &mut self.blocks {
+            if let Some(offset) = block.free_list.allocate(size, alignment) {
+                return Some(MeshArenaAllocation {
+                    buffer: block.buffer,
+                    offset,
+                    size
+                });
+            }
+        }
+
+        let block_size = std::cmp::max(Self::BLOCK_SIZE, size);
+        let mut block = ArenaBlock::new(&self.device, block_size)?;
+        let offset = block.free_list.allocate(size, alignment).unwrap();
+        let buffer = block.buffer;
+        self.blocks.push(block);
+
+        Some(MeshArenaAllocation {
+            buffer,
+            offset,
+            size
+        })
+    }
+
+    /// Registers the mesh stored in a range. Only ranges with a registered owner are relocated by
+    /// the arena compaction. The alignment is the alignment the range was allocated with.
+    pub(super) fn set_owner(&mut self, allocation: &MeshArenaAllocation, mesh: Weak<GlobalMesh>, alignment: vk::DeviceSize) {
+        let block = self.blocks.iter_mut().find(|block| block.buffer == allocation.buffer).unwrap_or_else(|| {
+            log::error!("Attempted to set the owner of mesh arena allocation {:?} which is not part of the arena", allocation);
+            panic!()
+        });
+
+        block.owners.insert(allocation.offset, RangeOwner {
+            mesh,
+            size: allocation.size,
+            alignment
+        });
+    }
+
+    /// Searches for a buffer which is at most a quarter used and whose ranges are all owned by
+    /// relocatable meshes. Returns the ranges of the buffer together with their alignment and
+    /// owner.
+    ///
+    /// The first buffer is never selected since it is kept alive anyway. The owners must be
+    /// upgraded without holding the arena lock since dropping the last reference to a mesh frees
+    /// its range.
+    pub(super) fn find_sparse_block(&self) -> Option<Vec<(MeshArenaAllocation, vk::DeviceSize, Weak<GlobalMesh>)>> {
+        let block = self.blocks.iter().skip(1).filter(|block| {
+            let used = block.free_list.get_used_size();
+            let owned: vk::DeviceSize = block.owners.values().map(|owner| owner.size).sum();
+            used != 0 && used <= block.free_list.size / 4 && used == owned
+        }).min_by_key(|block| block.free_list.get_used_size())?;
+
+        Some(block.owners.iter().map(|(offset, owner)| {
+            let allocation = MeshArenaAllocation {
+                buffer: block.buffer,
+                offset: *offset,
+                size: owner.size
+            };
+            (allocation, owner.alignment, owner.mesh.clone())
+        }).collect())
+    }
+
+    /// Allocates a new range for a mesh which is moved out of the buffer of the source range. No new
+    /// buffers are created. The ownership is transferred to the new range, the source range stays
+    /// allocated until it is freed.
+    ///
+    /// Returns [`None`] if the source range is no longer owned by the mesh or no other buffer has
+    /// enough free space.
+    pub(super) fn allocate_relocation(&mut self, src: &MeshArenaAllocation, alignment: vk::DeviceSize, mesh: &Weak<GlobalMesh>) -> Option<MeshArenaAllocation> {
+        let src_block = self.blocks.iter().position(|block| block.buffer == src.buffer)?;
+        if !self.blocks[src_block].owners.get(&src.offset).is_some_and(|owner| owner.mesh.ptr_eq(mesh)) {
+            return None;
+        }
+
+        let (block, offset) = self.blocks.iter_mut().enumerate()
+            .filter(|(index, _)| *index != src_block)
+            .find_map(|(_, block)| block.free_list.allocate(src.size, alignment).map(|offset| (block, offset)))?;
+
+        let dst = MeshArenaAllocation {
+            buffer: block.buffer,
+            offset,
+            size: src.size
+        };
+        block.owners.insert(offset, RangeOwner {
+            mesh: mesh.clone(),
+            size: src.size,
+            alignment
+        });
+        self.blocks[src_block].owners.remove(&src.offset);
+
+        Some(dst)
+    }
+
+    /// Returns a range to the arena. The range must no longer be in use by the device.
+    ///
+    /// Arena buffers which become completely unused are destroyed except for the first one.
+    pub(super) fn free(&mut self, allocation: MeshArenaAllocation) {
+        let index = self.blocks.iter().position(|block| block.buffer == allocation.buffer).unwrap_or_else(|| {
+            log::error!("Attempted to free mesh arena allocation {:?} which is not part of the arena", allocation);
+            panic!()
+        });
+
+        let block = &mut self.blocks[index];
+        block.free_list.free(allocation.offset, allocation.size);
+        block.owners.remove(&allocation.offset);
+
+        // Keep the first block alive so that meshes which are repeatedly created and dropped do not
+        // recreate it every time
+        if index != 0 && block.free_list.is_unused() {
+            let block = self.blocks.swap_remove(index);
+            block.destroy(&self.device);
+        }
+    }
+
+    /// Returns true if the range has been allocated and not yet freed.
+    #[cfg(any(test, feature = "__internal_doc_test"))]
+    pub(super) fn is_allocated(&self, allocation: &MeshArenaAllocation) -> bool {
+        self.blocks.iter().find(|block| block.buffer == allocation.buffer).map(|block| {
+            !block.free_list.is_free(allocation.offset, allocation.size)
+        }).unwrap_or(false)
+    }
+}
+
+impl Drop for MeshArena {
+    fn drop(&mut self) {
+        for block in std::mem::take(&mut self.blocks) {
+            block.destroy(&self.device);
+        }
+    }
+}
+
+struct ArenaBlock {
+    buffer: vk::Buffer,
+    allocation: Allocation,
+    free_list: FreeList,
+
+    /// The owners of relocatable ranges keyed by the range offset.
+    owners: HashMap<vk::DeviceSize, RangeOwner>,
+}
+
+impl ArenaBlock {
+    fn new(device: &DeviceContext, size: vk::DeviceSize) -> Option<Self> {
+        let info = vk::BufferCreateInfo::builder()
+            .size(size)
+            .usage(vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::INDEX_BUFFER)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+        let (buffer, allocation) = unsafe {
+            device.get_allocator().create_gpu_buffer(&info, &format_args!("GlobalMeshArena"))
+        }?;
+
+        Some(Self {
+            buffer,
+            allocation,
+            free_list: FreeList::new(size),
+            owners: HashMap::new(),
+        })
+    }
+
+    fn destroy(self, device: &DeviceContext) {
+        unsafe {
+            device.get_allocator().destroy_buffer(self.buffer, self.allocation)
+        }
+    }
+}
+
+struct RangeOwner {
+    mesh: Weak<GlobalMesh>,
+    size: vk::DeviceSize,
+    alignment: vk::DeviceSize,
+}
+
+/// First fit free list. Adjacent free ranges are merged when a range is freed.
+struct FreeList {
+    size: vk::DeviceSize,
+
+    /// The free ranges as (offset, size) sorted by offset.
+    ranges: Vec<(vk::DeviceSize, vk::DeviceSize)>,
+}
+
+impl FreeList {
+    fn new(size: vk::DeviceSize) -> Self {
+        Self {
+            size,
+            ranges: vec![(0, size)],
+        }
+    }
+
+    fn allocate(&mut self, size: vk::DeviceSize, alignment: vk::DeviceSize) -> Option<vk::DeviceSize> {
+        for index in 0..self.ranges.len() {
+            let (start, len) = self.ranges[index];
+            let end = start + len;
+
+            let aligned = next_aligned(start, alignment);
+            if aligned + size > end {
+                continue;
+            }
+
+            // Replace the range with the remaining space before and after the allocation
+            self.ranges.remove(index);
+            let mut insert = index;
+            if aligned > start {
+                self.ranges.insert(insert, (start, aligned - start));
+                insert += 1;
+            }
+            if aligned + size < end {
+                self.ranges.insert(insert, (aligned + size, end - (aligned + size)));
+            }
+
+            return Some(aligned);
+        }
+
+        None
+    }
+
+    fn free(&mut self, offset: vk::DeviceSize, size: vk::DeviceSize) {
+        let index = self.ranges.partition_point(|(start, _)| *start < offset);
+
+        let merge_prev = index > 0 && {
+            let (start, len) = self.ranges[index - 1];
+            start + len == offset
+        };
+        let merge_next = index < self.ranges.len() && self.ranges[index].0 == offset + size;
+
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                let (_, next_len) = self.ranges.remove(index);
+                self.ranges[index - 1].1 += size + next_len;
+            }
+            (true, false) => {
+                self.ranges[index - 1].1 += size;
+            }
+            (false, true) => {
+                let next = &mut self.ranges[index];
+                next.0 = offset;
+                next.1 += size;
+            }
+            (false, false) => {
+                self.ranges.insert(index, (offset, size));
+            }
+        }
+    }
+
+    /// Returns true if the range is fully contained in a free range.
+    #[cfg(any(test, feature = "__internal_doc_test"))]
+    fn is_free(&self, offset: vk::DeviceSize, size: vk::DeviceSize) -> bool {
+        self.ranges.iter().any(|(start, len)| *start <= offset && offset + size <= start + len)
+    }
+
+    /// Returns true if no ranges are allocated.
+    fn is_unused(&self) -> bool {
+        self.ranges.len() == 1 && self.ranges[0] == (0, self.size)
+    }
+
+    /// Returns the total size of all allocated ranges.
+    fn get_used_size(&self) -> vk::DeviceSize {
+        self.size - self.ranges.iter().map(|(_, len)| *len).sum::<vk::DeviceSize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_free_list_alignment() {
+        let mut list = FreeList::new(1024);
+
+        assert_eq!(list.allocate(10, 1), Some(0));
+        assert_eq!(list.allocate(24, 28), Some(28));
+        assert_eq!(list.allocate(8, 1), Some(10));
+        assert_eq!(list.allocate(1024, 1), None);
+
+        list.free(28, 24);
+        list.free(0, 10);
+        list.free(10, 8);
+        assert!(list.is_unused());
+    }
+
+    #[test]
+    fn test_free_list_merge() {
+        let mut list = FreeList::new(300);
+
+        assert_eq!(list.allocate(100, 1), Some(0));
+        assert_eq!(list.allocate(100, 1), Some(100));
+        assert_eq!(list.allocate(100, 1), Some(200));
+        assert_eq!(list.allocate(1, 1), None);
+
+        list.free(0, 100);
+        list.free(200, 100);
+        assert_eq!(list.allocate(150, 1), None);
+
+        list.free(100, 100);
+        assert!(list.is_unused());
+        assert_eq!(list.allocate(300, 1), Some(0));
+        assert_eq!(list.get_used_size(), 300);
+    }
+}
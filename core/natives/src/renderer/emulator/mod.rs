@@ -16,12 +16,20 @@ mod immediate;
 mod worker;
 mod global_objects;
 mod pass;
+mod topology;
+mod render_target;
 
 pub mod pipeline;
 pub mod debug_pipeline;
+pub mod mc_pipeline;
 pub mod mc_shaders;
+mod pipeline_common;
 mod descriptors;
 mod share;
+mod deferred;
+mod mesh_arena;
+mod cull;
+mod quad_sort;
 mod staging;
 
 use std::fmt::{Debug, Formatter};
@@ -35,14 +43,20 @@ use crate::renderer::emulator::pipeline::EmulatorPipeline;
 
 use crate::prelude::*;
 
-pub use global_objects::{GlobalMesh, GlobalImage, ImageData, SamplerInfo};
+pub use global_objects::{GlobalMesh, GlobalImage, ImageData, MeshDataRange, ImageRegion, ReadbackHandle, SamplerInfo};
+pub use cull::MeshBounds;
 
 pub use pass::PassId;
 pub use pass::PassRecorder;
 pub use pass::ImmediateMeshId;
+pub use pass::MeshInstance;
+
+pub use topology::PrimitiveTopology;
+
+pub use render_target::RenderTarget;
 
 use share::Share;
-use crate::renderer::emulator::mc_shaders::{McUniform, Shader, ShaderId, VertexFormat};
+use crate::renderer::emulator::mc_shaders::{McUniform, Shader, ShaderId, VertexFormat, VertexFormatEntry};
 use crate::util::format::Format;
 
 pub struct EmulatorRenderer {
@@ -67,14 +81,13 @@ impl EmulatorRenderer {
         });
 
         let placeholder_image = Self::create_placeholder_image(share.clone());
-        let placeholder_sampler = SamplerInfo {
-            mag_filter: vk::Filter::LINEAR,
-            min_filter: vk::Filter::LINEAR,
-            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
-            address_mode_u: vk::SamplerAddressMode::REPEAT,
-            address_mode_v: vk::SamplerAddressMode::REPEAT,
-            anisotropy_enable: false
-        };
+        let placeholder_sampler = SamplerInfo::new(
+            vk::Filter::LINEAR,
+            vk::Filter::LINEAR,
+            vk::SamplerMipmapMode::LINEAR,
+            vk::SamplerAddressMode::REPEAT,
+            vk::SamplerAddressMode::REPEAT
+        );
 
         Self {
             share,
@@ -84,14 +97,50 @@ impl EmulatorRenderer {
         }
     }
 
+    /// Creates a emulator renderer without a [`Blaze4D`](crate::b4d::Blaze4D) instance. Only used
+    /// by tests.
+    #[cfg(any(test, feature = "__internal_doc_test"))]
+    pub fn new_for_test(device: Arc<DeviceContext>) -> Self {
+        Self::new(device)
+    }
+
     pub fn get_device(&self) -> &Arc<DeviceContext> {
         self.share.get_device()
     }
 
+    /// Returns the buffer, offset and size of the mesh arena range currently storing the data of a
+    /// mesh. Only used by tests to validate when the range is freed.
+    #[cfg(any(test, feature = "__internal_doc_test"))]
+    pub fn get_mesh_arena_range(&self, mesh: &GlobalMesh) -> (vk::Buffer, vk::DeviceSize, vk::DeviceSize) {
+        let allocation = mesh.get_arena_allocation();
+        (allocation.buffer, allocation.offset, allocation.size)
+    }
+
+    /// Returns true if the mesh arena range has not been freed yet. Only used by tests to validate
+    /// that meshes are not destroyed while still in use.
+    #[cfg(any(test, feature = "__internal_doc_test"))]
+    pub fn is_mesh_arena_range_allocated(&self, range: (vk::Buffer, vk::DeviceSize, vk::DeviceSize)) -> bool {
+        let (buffer, offset, size) = range;
+        self.share.get_mesh_arena().lock().unwrap().is_allocated(&mesh_arena::MeshArenaAllocation { buffer, offset, size })
+    }
+
+    /// Returns true if any destroyed global objects are still waiting for a pass to complete. Only
+    /// used by tests to validate that objects are not destroyed while still in use.
+    #[cfg(any(test, feature = "__internal_doc_test"))]
+    pub fn has_pending_deferred_objects(&self) -> bool {
+        self.share.has_pending_deferred_objects()
+    }
+
     pub fn create_global_mesh(&self, data: &MeshData) -> Arc<GlobalMesh> {
         GlobalMesh::new(self.share.clone(), data).unwrap()
     }
 
+    /// Creates a quads mesh which can be depth sorted using [`GlobalMesh::sort_quads`]. The
+    /// position entry defines where the vertex positions are stored in the vertex data.
+    pub fn create_sortable_global_mesh(&self, data: &MeshData, position: &VertexFormatEntry) -> Arc<GlobalMesh> {
+        GlobalMesh::new_sortable(self.share.clone(), data, position).unwrap()
+    }
+
     pub fn create_global_image(&self, size: Vec2u32, format: &'static Format) -> Arc<GlobalImage> {
         GlobalImage::new(self.share.clone(), size, 1, format).unwrap()
     }
@@ -100,8 +149,34 @@ impl EmulatorRenderer {
         GlobalImage::new(self.share.clone(), size, mip_levels, format).unwrap()
     }
 
-    pub fn create_shader(&self, vertex_format: &VertexFormat, used_uniforms: McUniform) -> ShaderId {
-        self.share.create_shader(vertex_format, used_uniforms)
+    /// Creates a new 2D array image. Array images can currently not be bound
+    /// with [`PassRecorder::update_texture`] since all shaders only declare `sampler2D` textures.
+    pub fn create_global_image_array(&self, size: Vec2u32, array_layers: u32, mip_levels: u32, format: &'static Format) -> Arc<GlobalImage> {
+        GlobalImage::new_array(self.share.clone(), size, array_layers, mip_levels, format).unwrap()
+    }
+
+    /// Creates a new 3D image. 3D images can currently not be bound with
+    /// [`PassRecorder::update_texture`] since all shaders only declare `sampler2D` textures.
+    pub fn create_global_image_3d(&self, size: Vec3u32, mip_levels: u32, format: &'static Format) -> Arc<GlobalImage> {
+        GlobalImage::new_3d(self.share.clone(), size, mip_levels, format).unwrap()
+    }
+
+    /// Creates a new cube map with square faces. Cube maps can currently not be
+    /// bound with [`PassRecorder::update_texture`] since all shaders only declare `sampler2D` textures.
+    pub fn create_global_image_cube(&self, size: u32, mip_levels: u32, format: &'static Format) -> Arc<GlobalImage> {
+        GlobalImage::new_cube(self.share.clone(), size, mip_levels, format).unwrap()
+    }
+
+    /// Creates a new offscreen render target of the specified size.
+    pub fn create_render_target(&self, size: Vec2u32) -> Arc<RenderTarget> {
+        RenderTarget::new(self.share.clone(), size).unwrap()
+    }
+
+    /// Creates a new shader. Fragments with an alpha value below `alpha_cutoff` are discarded. A
+    /// cutoff of 0 disables discarding. To only discard fully transparent fragments (like the
+    /// vanilla position_tex shader) use [`f32::MIN_POSITIVE`].
+    pub fn create_shader(&self, vertex_format: &VertexFormat, used_uniforms: McUniform, alpha_cutoff: f32) -> ShaderId {
+        self.share.create_shader(vertex_format, used_uniforms, alpha_cutoff)
     }
 
     pub fn drop_shader(&self, id: ShaderId) {
@@ -113,7 +188,13 @@ impl EmulatorRenderer {
     }
 
     pub fn start_pass(&self, pipeline: Arc<dyn EmulatorPipeline>) -> PassRecorder {
-        PassRecorder::new(self.share.clone(), pipeline, self.placeholder_image.clone(), &self.placeholder_sampler)
+        PassRecorder::new(self.share.clone(), pipeline, None, self.placeholder_image.clone(), &self.placeholder_sampler)
+    }
+
+    /// Starts a pass which renders into the provided render target instead of the internal images
+    /// of the pipeline. The pipeline must support render targets.
+    pub fn start_target_pass(&self, pipeline: Arc<dyn EmulatorPipeline>, render_target: Arc<RenderTarget>) -> PassRecorder {
+        PassRecorder::new(self.share.clone(), pipeline, Some(render_target), self.placeholder_image.clone(), &self.placeholder_sampler)
     }
 
     fn create_placeholder_image(share: Arc<Share>) -> Arc<GlobalImage> {
@@ -130,12 +211,7 @@ impl EmulatorRenderer {
 
         let bytes = cast_slice(data.as_ref());
 
-        let info = ImageData {
-            data: bytes,
-            row_stride: 0,
-            offset: Vec2u32::new(0, 0),
-            extent: size
-        };
+        let info = ImageData::new_full(bytes, size);
 
         let image = GlobalImage::new(share, size, 1, &Format::R8G8B8A8_SRGB).unwrap();
         image.update_regions(std::slice::from_ref(&info));
@@ -157,24 +233,24 @@ impl RefUnwindSafe for EmulatorRenderer { // Join handle is making issues
 
 pub struct MeshData<'a> {
     pub vertex_data: &'a [u8],
-    pub index_data: &'a [u8],
+
+    /// The index data of the mesh. If [`None`] the vertices are drawn in sequential order.
+    pub index_data: Option<&'a [u8]>,
     pub vertex_stride: u32,
+
+    /// The number of indices. If the mesh has no index data this is the number of vertices.
     pub index_count: u32,
+
+    /// The type of the index data. Ignored if the mesh has no index data.
     pub index_type: vk::IndexType,
-    pub primitive_topology: vk::PrimitiveTopology,
+
+    /// The topology of the mesh. Emulated topologies are converted when the mesh is uploaded.
+    pub primitive_topology: PrimitiveTopology,
 }
 
 impl<'a> MeshData<'a> {
     pub fn get_index_size(&self) -> u32 {
-        match self.index_type {
-            vk::IndexType::UINT8_EXT => 1u32,
-            vk::IndexType::UINT16 => 2u32,
-            vk::IndexType::UINT32 => 4u32,
-            _ => {
-                log::error!("Invalid index type");
-                panic!()
-            }
-        }
+        topology::get_index_type_size(self.index_type)
     }
 }
 
@@ -182,7 +258,7 @@ impl<'a> Debug for MeshData<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("MeshData")
             .field("vertex_data.len()", &self.vertex_data.len())
-            .field("index_data.len()", &self.index_data.len())
+            .field("index_data.len()", &self.index_data.map(|data| data.len()))
             .field("vertex_stride", &self.vertex_stride)
             .field("index_count", &self.index_count)
             .field("index_type", &self.index_type)
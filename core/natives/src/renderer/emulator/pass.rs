@@ -1,16 +1,20 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 use ash::vk;
+use bytemuck::cast_slice;
 
+use crate::renderer::emulator::cull::{CullTask, extract_frustum_planes};
 use crate::renderer::emulator::immediate::ImmediateBuffer;
-use crate::renderer::emulator::{GlobalImage, GlobalMesh, MeshData};
+use crate::renderer::emulator::{GlobalImage, GlobalMesh, MeshBounds, MeshData, PrimitiveTopology, RenderTarget};
 use crate::renderer::emulator::global_objects::{GlobalImageId, SamplerInfo};
 use crate::renderer::emulator::worker::WorkerTask;
 
 use crate::renderer::emulator::mc_shaders::{McUniformData, ShaderId};
-use crate::renderer::emulator::pipeline::{DrawTask, EmulatorOutput, EmulatorPipeline, PipelineTask};
+use crate::renderer::emulator::pipeline::{DrawBatchTask, DrawConfig, DrawTask, EmulatorOutput, EmulatorPipeline, InstanceData, InstancedDrawTask, PipelineTask};
 use crate::renderer::emulator::share::Share;
+use crate::renderer::emulator::topology::{EmulatedIndices, get_index_type_size, QuadIndexCache};
+use crate::prelude::*;
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
 pub struct PassId(u64);
@@ -44,16 +48,22 @@ pub struct PassRecorder {
 
     used_shaders: HashSet<ShaderId>,
     used_global_image: HashSet<GlobalImageId>,
+
+    /// The current projection and model view matrix of each shader if they have been set.
+    shader_matrices: HashMap<ShaderId, (Option<Mat4f32>, Option<Mat4f32>)>,
     immediate_meshes: Vec<ImmediateMeshInfo>,
 
     immediate_buffer: Option<Box<ImmediateBuffer>>,
 
+    /// The render target this pass renders into. Its color image must not be sampled by the pass.
+    render_target: Option<Arc<RenderTarget>>,
+
     #[allow(unused)] // We just need to keep the pipeline alive
     pipeline: Arc<dyn EmulatorPipeline>,
 }
 
 impl PassRecorder {
-    pub(super) fn new(share: Arc<Share>, pipeline: Arc<dyn EmulatorPipeline>, placeholder_image: Arc<GlobalImage>, placeholder_sampler: &SamplerInfo) -> Self {
+    pub(super) fn new(share: Arc<Share>, pipeline: Arc<dyn EmulatorPipeline>, render_target: Option<Arc<RenderTarget>>, placeholder_image: Arc<GlobalImage>, placeholder_sampler: &SamplerInfo) -> Self {
         let id = share.try_start_pass_id().unwrap_or_else(|| {
             log::error!("Attempted to start pass with an already running pass!");
             panic!();
@@ -63,7 +73,11 @@ impl PassRecorder {
         let immediate_buffer = Some(share.get_next_immediate_buffer());
 
         let placeholder_sampler = placeholder_image.get_sampler(placeholder_sampler);
-        share.push_task(WorkerTask::StartPass(id, pipeline.clone(), pipeline.start_pass(), placeholder_image, placeholder_sampler));
+        if let Some(render_target) = &render_target {
+            render_target.update_used_in(id);
+        }
+
+        share.push_task(WorkerTask::StartPass(id, pipeline.clone(), pipeline.start_pass(), render_target.clone(), placeholder_image, placeholder_sampler));
 
         Self {
             id,
@@ -71,10 +85,13 @@ impl PassRecorder {
 
             used_shaders: HashSet::new(),
             used_global_image: HashSet::new(),
+            shader_matrices: HashMap::new(),
             immediate_meshes: Vec::with_capacity(128),
 
             immediate_buffer,
 
+            render_target,
+
             pipeline,
         }
     }
@@ -85,10 +102,39 @@ impl PassRecorder {
 
     pub fn update_uniform(&mut self, data: &McUniformData, shader: ShaderId) {
         self.use_shader(shader);
+
+        // Needed to calculate the frustum of culled draws
+        match data {
+            McUniformData::ProjectionMatrix(mat) => {
+                self.shader_matrices.entry(shader).or_default().0 = Some(*mat);
+            }
+            McUniformData::ModelViewMatrix(mat) => {
+                self.shader_matrices.entry(shader).or_default().1 = Some(*mat);
+            }
+            _ => {}
+        }
+
         self.share.push_task(WorkerTask::PipelineTask(PipelineTask::UpdateUniform(shader, *data)))
     }
 
+    /// Binds an image to a texture slot of a shader. All shaders declare their textures as
+    /// `sampler2D` so only images with a [`vk::ImageViewType::TYPE_2D`] view can be bound. The
+    /// color image of the render target the pass renders into can not be bound.
     pub fn update_texture(&mut self, index: u32, image: &Arc<GlobalImage>, sampler_info: &SamplerInfo, shader: ShaderId) {
+        let view_type = image.get_view_type();
+        if view_type != vk::ImageViewType::TYPE_2D {
+            log::error!("Called update_texture with image view type {:?}. Only TYPE_2D images can be bound to texture slots", view_type);
+            panic!()
+        }
+        if let Some(render_target) = &self.render_target {
+            if render_target.get_color_image().get_id() == image.get_id() {
+                log::error!("Called update_texture with the color image of the render target the pass is rendering into");
+                panic!()
+            }
+        }
+
+        image.update_used_in(self.id);
+
         self.use_shader(shader);
         let view = image.get_sampler_view();
         let sampler = image.get_sampler(sampler_info);
@@ -100,28 +146,70 @@ impl PassRecorder {
         self.share.push_task(WorkerTask::PipelineTask(PipelineTask::UpdateTexture(shader, index, view, sampler)));
     }
 
+    /// Sets the viewport used by all following draws. Coordinates are in framebuffer pixels with
+    /// the origin in the top left corner. If [`None`] the full framebuffer is used. Viewports with
+    /// a width or height of 0 are ignored and the previous viewport stays in use.
+    pub fn set_viewport(&mut self, viewport: Option<vk::Viewport>) {
+        self.share.push_task(WorkerTask::PipelineTask(PipelineTask::SetViewport(viewport)));
+    }
+
+    /// Sets the scissor rect used by all following draws. Coordinates are in framebuffer pixels
+    /// with the origin in the top left corner. If [`None`] scissoring is disabled.
+    pub fn set_scissor(&mut self, scissor: Option<vk::Rect2D>) {
+        self.share.push_task(WorkerTask::PipelineTask(PipelineTask::SetScissor(scissor)));
+    }
+
+    /// Clears the color, depth and stencil attachments of the pass. Only the area inside the
+    /// current scissor rect is cleared. Any [`None`] value is not cleared.
+    pub fn clear(&mut self, color: Option<Vec4f32>, depth: Option<f32>, stencil: Option<u32>) {
+        self.share.push_task(WorkerTask::PipelineTask(PipelineTask::Clear(color, depth, stencil)));
+    }
+
     pub fn upload_immediate(&mut self, data: &MeshData) -> ImmediateMeshId {
-        let index_size = data.get_index_size();
+        // Line expansion uses the parity of the vertex index so the first vertex must be even
+        let vertex_alignment = if data.primitive_topology.is_wide_line() {
+            (data.vertex_stride as vk::DeviceSize) * 2
+        } else {
+            data.vertex_stride as vk::DeviceSize
+        };
 
         let immediate = self.immediate_buffer.as_mut().unwrap();
-        let (vertex_buffer, vertex_offset) = immediate.allocate(data.vertex_data, data.vertex_stride as vk::DeviceSize);
-        let (index_buffer, index_offset) = immediate.allocate(data.index_data, index_size as vk::DeviceSize);
+        let (vertex_buffer, vertex_offset) = immediate.allocate(data.vertex_data, vertex_alignment);
+
+        let (index_buffer, first_index, index_type, index_count) = match EmulatedIndices::new(data) {
+            EmulatedIndices::Indexed { data: index_data, index_type, index_count } => {
+                let index_size = get_index_type_size(index_type);
+                let (index_buffer, index_offset) = immediate.allocate(&index_data, index_size as vk::DeviceSize);
+                (Some(index_buffer), (index_offset / (index_size as vk::DeviceSize)) as u32, index_type, index_count)
+            }
+            EmulatedIndices::SequentialQuads { pattern, quad_count } => {
+                let quad_indices = QuadIndexCache::get_quad_indices(&self.share, pattern, quad_count);
+                let draw_info = quad_indices.get_draw_info(self.id);
+                let info = (draw_info.index_buffer, draw_info.first_index, draw_info.index_type, quad_count * 6);
+
+                self.share.push_task(WorkerTask::UseGlobalMesh(quad_indices));
+                info
+            }
+            EmulatedIndices::NonIndexed { vertex_count } => {
+                (None, 0, vk::IndexType::UINT32, vertex_count)
+            }
+        };
 
         let id = self.immediate_meshes.len() as u32;
         self.immediate_meshes.push(ImmediateMeshInfo {
             vertex_buffer,
             index_buffer,
             vertex_offset: (vertex_offset / (data.vertex_stride as vk::DeviceSize)) as i32,
-            first_index: (index_offset / (index_size as vk::DeviceSize)) as u32,
-            index_type: data.index_type,
-            index_count: data.index_count,
+            first_index,
+            index_type,
+            index_count,
             primitive_topology: data.primitive_topology
         });
 
         ImmediateMeshId::form_raw(id)
     }
 
-    pub fn draw_immediate(&mut self, id: ImmediateMeshId, shader: ShaderId, depth_write_enable: bool) {
+    pub fn draw_immediate(&mut self, id: ImmediateMeshId, shader: ShaderId, config: &DrawConfig) {
         self.use_shader(shader);
 
         let mesh_data = self.immediate_meshes.get(id.get_raw() as usize).unwrap();
@@ -135,34 +223,198 @@ impl PassRecorder {
             index_count: mesh_data.index_count,
             shader,
             primitive_topology: mesh_data.primitive_topology,
-            depth_write_enable,
+            config: *config,
         };
         self.share.push_task(WorkerTask::PipelineTask(PipelineTask::Draw(draw_task)));
     }
 
-    pub fn draw_global(&mut self, mesh: Arc<GlobalMesh>, shader: ShaderId, depth_write_enable: bool) {
+    pub fn draw_global(&mut self, mesh: Arc<GlobalMesh>, shader: ShaderId, config: &DrawConfig) {
         mesh.update_used_in(self.id);
 
         self.use_shader(shader);
 
-        let draw_info = mesh.get_draw_info();
+        let draw_info = mesh.get_draw_info(self.id);
 
         let draw_task = DrawTask {
-            vertex_buffer: draw_info.buffer,
-            index_buffer: draw_info.buffer,
-            vertex_offset: 0,
+            vertex_buffer: draw_info.vertex_buffer,
+            index_buffer: draw_info.index_buffer,
+            vertex_offset: draw_info.vertex_offset,
             first_index: draw_info.first_index,
             index_type: draw_info.index_type,
             index_count: draw_info.index_count,
             shader,
             primitive_topology: draw_info.primitive_topology,
-            depth_write_enable,
+            config: *config,
         };
 
         self.share.push_task(WorkerTask::UseGlobalMesh(mesh));
         self.share.push_task(WorkerTask::PipelineTask(PipelineTask::Draw(draw_task)));
     }
 
+    /// Draws a global mesh once for each instance. Each instance uses its own model view matrix
+    /// which replaces the [`McUniformData::ModelViewMatrix`] uniform and a color which is
+    /// multiplied with the vertex color.
+    pub fn draw_global_instanced(&mut self, mesh: Arc<GlobalMesh>, shader: ShaderId, instances: &[MeshInstance], config: &DrawConfig) {
+        if instances.is_empty() {
+            return;
+        }
+
+        mesh.update_used_in(self.id);
+
+        self.use_shader(shader);
+
+        let instance_data: Vec<InstanceData> = instances.iter().map(|instance| InstanceData {
+            model_view_matrix: instance.model_view_matrix,
+            color: instance.color.unwrap_or_else(|| Vec4f32::new(1f32, 1f32, 1f32, 1f32)),
+        }).collect();
+        let (instance_buffer, instance_offset) = self.immediate_buffer.as_mut().unwrap().allocate(cast_slice(&instance_data), 16);
+
+        let draw_info = mesh.get_draw_info(self.id);
+
+        let draw_task = InstancedDrawTask {
+            draw: DrawTask {
+                vertex_buffer: draw_info.vertex_buffer,
+                index_buffer: draw_info.index_buffer,
+                vertex_offset: draw_info.vertex_offset,
+                first_index: draw_info.first_index,
+                index_type: draw_info.index_type,
+                index_count: draw_info.index_count,
+                shader,
+                primitive_topology: draw_info.primitive_topology,
+                config: *config,
+            },
+            instance_buffer,
+            instance_offset,
+            instance_count: instances.len() as u32,
+        };
+
+        self.share.push_task(WorkerTask::UseGlobalMesh(mesh));
+        self.share.push_task(WorkerTask::PipelineTask(PipelineTask::DrawInstanced(draw_task)));
+    }
+
+    /// Draws multiple global meshes using the same shader and configuration. Each mesh is drawn
+    /// with its own chunk offset which replaces the [`McUniformData::ChunkOffset`] uniform.
+    ///
+    /// Consecutive meshes sharing the same buffers and primitive topology are recorded as a single
+    /// indirect draw. The meshes are always drawn in the order they are passed in.
+    pub fn draw_global_batch(&mut self, draws: &[(Arc<GlobalMesh>, Vec3f32)], shader: ShaderId, config: &DrawConfig) {
+        self.record_global_batch(draws, shader, config, false);
+    }
+
+    /// Same as [`PassRecorder::draw_global_batch`] but meshes outside of the view frustum are
+    /// culled on the gpu before the pass executes.
+    ///
+    /// The frustum is calculated from the current projection and model view matrix uniforms of
+    /// the shader. Meshes without bounds (see [`GlobalMesh::set_bounds`]) are never culled. If
+    /// either matrix has not been set in this pass no meshes are culled.
+    pub fn draw_global_batch_culled(&mut self, draws: &[(Arc<GlobalMesh>, Vec3f32)], shader: ShaderId, config: &DrawConfig) {
+        self.record_global_batch(draws, shader, config, true);
+    }
+
+    fn record_global_batch(&mut self, draws: &[(Arc<GlobalMesh>, Vec3f32)], shader: ShaderId, config: &DrawConfig, cull: bool) {
+        if draws.is_empty() {
+            return;
+        }
+
+        self.use_shader(shader);
+
+        let device = self.share.get_device();
+        let first_instance_indexing = device.get_enabled_features().draw_indirect_first_instance == vk::TRUE;
+
+        let frustum_planes = if cull {
+            match self.shader_matrices.get(&shader) {
+                Some((Some(projection), Some(model_view))) => Some(extract_frustum_planes(&(projection * model_view))),
+                _ => {
+                    log::warn!("Culled batch draw with shader {:?} which has no projection or model view matrix. Drawing without culling", shader);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // Culling accesses the batch data through storage buffers
+        let alignment = if frustum_planes.is_some() {
+            std::cmp::max(device.get_limits().min_storage_buffer_offset_alignment, 4)
+        } else {
+            4
+        };
+
+        // The draw parameters may change if a mesh is relocated so they must only be queried once.
+        // The mesh must be marked as used before querying them.
+        let draw_infos: Vec<_> = draws.iter().map(|(mesh, _)| {
+            mesh.update_used_in(self.id);
+            mesh.get_draw_info(self.id)
+        }).collect();
+
+        let mut batch_start = 0;
+        while batch_start < draws.len() {
+            let first_info = &draw_infos[batch_start];
+            let batch_len = draw_infos[batch_start..].iter().take_while(|info| {
+                info.vertex_buffer == first_info.vertex_buffer &&
+                    info.index_buffer == first_info.index_buffer &&
+                    (info.index_buffer.is_none() || info.index_type == first_info.index_type) &&
+                    info.primitive_topology == first_info.primitive_topology
+            }).count();
+            let batch = &draws[batch_start..(batch_start + batch_len)];
+            let batch_infos = &draw_infos[batch_start..(batch_start + batch_len)];
+            batch_start += batch_len;
+
+            let mut chunk_offsets: Vec<f32> = Vec::with_capacity(batch.len() * 3);
+            let mut commands: Vec<u32> = Vec::with_capacity(batch.len() * 5);
+            for (index, ((mesh, chunk_offset), info)) in batch.iter().zip(batch_infos).enumerate() {
+                self.share.push_task(WorkerTask::UseGlobalMesh(mesh.clone()));
+
+                let first_instance = if first_instance_indexing { index as u32 } else { 0 };
+                if info.index_buffer.is_some() {
+                    // Matches vk::DrawIndexedIndirectCommand
+                    commands.extend_from_slice(&[info.index_count, 1, info.first_index, info.vertex_offset as u32, first_instance]);
+                } else {
+                    // Matches vk::DrawIndirectCommand
+                    commands.extend_from_slice(&[info.index_count, 1, info.vertex_offset as u32, first_instance]);
+                }
+                chunk_offsets.extend_from_slice(chunk_offset.as_slice());
+            }
+
+            let immediate = self.immediate_buffer.as_mut().unwrap();
+            let (instance_buffer, instance_offset) = immediate.allocate(cast_slice(&chunk_offsets), alignment);
+            let (indirect_buffer, indirect_offset) = immediate.allocate(cast_slice(&commands), alignment);
+
+            if let Some(planes) = frustum_planes {
+                let bounds: Vec<f32> = batch.iter().flat_map(|(mesh, _)| MeshBounds::to_shader_data(mesh.get_bounds().as_ref())).collect();
+                let (bounds_buffer, bounds_offset) = immediate.allocate(cast_slice(&bounds), alignment);
+
+                self.share.push_task(WorkerTask::CullBatch(CullTask {
+                    indirect_buffer,
+                    indirect_offset,
+                    indexed: first_info.index_buffer.is_some(),
+                    instance_buffer,
+                    instance_offset,
+                    bounds_buffer,
+                    bounds_offset,
+                    draw_count: batch.len() as u32,
+                    planes,
+                }));
+            }
+
+            let draw_task = DrawBatchTask {
+                vertex_buffer: first_info.vertex_buffer,
+                index_buffer: first_info.index_buffer,
+                index_type: first_info.index_type,
+                instance_buffer,
+                instance_offset,
+                indirect_buffer,
+                indirect_offset,
+                draw_count: batch.len() as u32,
+                first_instance_indexing,
+                shader,
+                primitive_topology: first_info.primitive_topology,
+                config: *config,
+            };
+            self.share.push_task(WorkerTask::PipelineTask(PipelineTask::DrawBatch(draw_task)));
+        }
+    }
+
     fn use_shader(&mut self, shader: ShaderId) {
         if self.used_shaders.insert(shader) {
             self.pipeline.inc_shader_used(shader);
@@ -178,12 +430,30 @@ impl Drop for PassRecorder {
     }
 }
 
+/// A single instance of a instanced draw.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct MeshInstance {
+    pub model_view_matrix: Mat4f32,
+
+    /// The color multiplied with the vertex color. If [`None`] the vertex color is used unmodified.
+    pub color: Option<Vec4f32>,
+}
+
+impl MeshInstance {
+    pub fn new(model_view_matrix: Mat4f32, color: Option<Vec4f32>) -> Self {
+        Self {
+            model_view_matrix,
+            color
+        }
+    }
+}
+
 struct ImmediateMeshInfo {
     vertex_buffer: vk::Buffer,
-    index_buffer: vk::Buffer,
+    index_buffer: Option<vk::Buffer>,
     vertex_offset: i32,
     first_index: u32,
     index_type: vk::IndexType,
     index_count: u32,
-    primitive_topology: vk::PrimitiveTopology,
+    primitive_topology: PrimitiveTopology,
 }
\ No newline at end of file
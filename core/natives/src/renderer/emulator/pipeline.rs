@@ -5,16 +5,30 @@ use ash::prelude::VkResult;
 
 use ash::vk;
 use bumpalo::Bump;
+use bytemuck::{Pod, Zeroable};
 use crate::device::device::Queue;
 use crate::device::device_utils::BlitPass;
 use crate::device::surface::{AcquiredImageInfo, SurfaceSwapchain};
 
 use crate::prelude::*;
 use crate::renderer::emulator::mc_shaders::{McUniformData, ShaderId};
+use crate::renderer::emulator::{PrimitiveTopology, RenderTarget};
 
 pub use super::worker::SubmitRecorder;
 pub use super::worker::PooledObjectProvider;
 
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum ObjectCreateError {
+    Vulkan(vk::Result),
+    Allocation,
+}
+
+impl From<vk::Result> for ObjectCreateError {
+    fn from(result: vk::Result) -> Self {
+        Self::Vulkan(result)
+    }
+}
+
 /// A [`EmulatorPipeline`] performs the actual rendering inside a pass.
 ///
 /// To define how objects should be rendered a pipeline can define multiple types. The meaning of
@@ -72,9 +86,13 @@ pub trait EmulatorPipelinePass {
     /// The queue which will be used to submit command buffers is provided. All resources (i.e.
     /// buffers, images etc.) passed to this pass will be owned by this queue family.
     ///
+    /// If a render target is provided the pass must render into it instead of its internal images.
+    /// The pass must keep the render target alive until it is dropped. Pipelines which do not
+    /// support render targets should log a warning and ignore it.
+    ///
     /// A placeholder image is provided which can be used for sampled images. This image must only
     /// be used in submits made by [`EmulatorPipelinePass::record`].
-    fn init(&mut self, queue: &Queue, obj: &mut PooledObjectProvider, placeholder_image: vk::ImageView, placeholder_sampler: vk::Sampler);
+    fn init(&mut self, queue: &Queue, obj: &mut PooledObjectProvider, render_target: Option<Arc<RenderTarget>>, placeholder_image: vk::ImageView, placeholder_sampler: vk::Sampler);
 
     /// Called to process a task.
     ///
@@ -110,20 +128,168 @@ pub trait EmulatorPipelinePass {
 pub enum PipelineTask {
     UpdateUniform(ShaderId, McUniformData),
     UpdateTexture(ShaderId, u32, vk::ImageView, vk::Sampler),
+    /// Sets the viewport of future draws. If [`None`] the full framebuffer is used.
+    SetViewport(Option<vk::Viewport>),
+    /// Sets the scissor rect of future draws. If [`None`] scissoring is disabled.
+    SetScissor(Option<vk::Rect2D>),
+    /// Clears the color, depth and stencil attachments inside the current scissor rect. Any
+    /// [`None`] value is not cleared.
+    Clear(Option<Vec4f32>, Option<f32>, Option<u32>),
     Draw(DrawTask),
+    DrawBatch(DrawBatchTask),
+    DrawInstanced(InstancedDrawTask),
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
 pub struct DrawTask {
     pub vertex_buffer: vk::Buffer,
-    pub index_buffer: vk::Buffer,
+
+    /// The index buffer of the draw. If [`None`] the draw is not indexed, `first_index` and
+    /// `index_type` are ignored and `index_count` vertices are drawn starting at `vertex_offset`.
+    pub index_buffer: Option<vk::Buffer>,
     pub vertex_offset: i32,
     pub first_index: u32,
     pub index_type: vk::IndexType,
     pub index_count: u32,
     pub shader: ShaderId,
-    pub primitive_topology: vk::PrimitiveTopology,
+    pub primitive_topology: PrimitiveTopology,
+    pub config: DrawConfig,
+}
+
+/// A batch of draws sharing the same buffers, shader and configuration.
+///
+/// The draw parameters are stored in `indirect_buffer` as `draw_count` tightly packed
+/// [`vk::DrawIndexedIndirectCommand`] or [`vk::DrawIndirectCommand`] if the draws are not indexed.
+/// Every draw uses its own chunk offset instead of the `CHUNK_OFFSET` uniform. The chunk offsets
+/// are stored in `instance_buffer` as tightly packed `vec3` of `f32`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub struct DrawBatchTask {
+    pub vertex_buffer: vk::Buffer,
+
+    /// The index buffer of the batch. If [`None`] the draws are not indexed and `index_type` is
+    /// ignored.
+    pub index_buffer: Option<vk::Buffer>,
+    pub index_type: vk::IndexType,
+    pub instance_buffer: vk::Buffer,
+    pub instance_offset: vk::DeviceSize,
+    pub indirect_buffer: vk::Buffer,
+    pub indirect_offset: vk::DeviceSize,
+    pub draw_count: u32,
+
+    /// If true the `first_instance` of each draw command selects its chunk offset. Otherwise
+    /// `first_instance` is 0 and the instance buffer must be rebound for every draw. Only set if
+    /// the `drawIndirectFirstInstance` device feature is enabled.
+    pub first_instance_indexing: bool,
+    pub shader: ShaderId,
+    pub primitive_topology: PrimitiveTopology,
+    pub config: DrawConfig,
+}
+
+/// A draw task drawn multiple times with a different model view matrix and color for each
+/// instance. The instances are stored in `instance_buffer` as tightly packed [`InstanceData`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub struct InstancedDrawTask {
+    pub draw: DrawTask,
+    pub instance_buffer: vk::Buffer,
+    pub instance_offset: vk::DeviceSize,
+    pub instance_count: u32,
+}
+
+/// The per instance data of a [`InstancedDrawTask`] as stored in the instance buffer.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct InstanceData {
+    /// Replaces the model view matrix uniform.
+    pub model_view_matrix: Mat4f32,
+
+    /// Multiplied with the vertex color.
+    pub color: Vec4f32,
+}
+const_assert_eq!(std::mem::size_of::<InstanceData>(), 80);
+
+unsafe impl Zeroable for InstanceData {}
+unsafe impl Pod for InstanceData {}
+
+/// Fixed function state used for a draw. Pipelines must honor all of these.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub struct DrawConfig {
+    pub depth_test_enable: bool,
+    pub depth_compare_op: vk::CompareOp,
     pub depth_write_enable: bool,
+
+    /// The blend state of the draw. If [`None`] blending is disabled.
+    pub blend_state: Option<BlendState>,
+
+    pub cull_mode: vk::CullModeFlags,
+    pub front_face: vk::FrontFace,
+
+    /// The polygon mode of the draw. Modes other than [`vk::PolygonMode::FILL`] require the
+    /// `fillModeNonSolid` device feature. If it is not supported the draw falls back to
+    /// [`vk::PolygonMode::FILL`].
+    pub polygon_mode: vk::PolygonMode,
+
+    /// If true the draw is never reordered relative to other draws of the pass. See
+    /// [`DrawConfig::can_reorder`].
+    pub order_dependent: bool,
+}
+
+impl DrawConfig {
+    /// Returns true if pipelines may reorder the draw relative to other reorderable draws to
+    /// reduce state changes.
+    ///
+    /// Only opaque draws which test and write depth with an ordering compare op produce the same
+    /// result independent of the draw order. All other draws (for example translucent geometry or
+    /// draws using [`vk::CompareOp::EQUAL`]) are treated as order dependent even if
+    /// `order_dependent` is false.
+    ///
+    /// Coplanar fragments are an exception since with [`vk::CompareOp::LESS_OR_EQUAL`] or
+    /// [`vk::CompareOp::GREATER_OR_EQUAL`] the last draw wins while with the strict ops the first
+    /// draw wins. Reordered draws keep their submission order relative to draws with the same
+    /// state.
+    pub fn can_reorder(&self) -> bool {
+        let ordered_compare_op = matches!(self.depth_compare_op,
+            vk::CompareOp::LESS | vk::CompareOp::LESS_OR_EQUAL | vk::CompareOp::GREATER | vk::CompareOp::GREATER_OR_EQUAL);
+
+        !self.order_dependent && self.blend_state.is_none() && self.depth_test_enable && self.depth_write_enable && ordered_compare_op
+    }
+}
+
+impl Default for DrawConfig {
+    /// Matches the default opengl state used by minecraft.
+    fn default() -> Self {
+        Self {
+            depth_test_enable: true,
+            depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
+            depth_write_enable: true,
+            blend_state: None,
+            cull_mode: vk::CullModeFlags::BACK,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            polygon_mode: vk::PolygonMode::FILL,
+            order_dependent: false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub struct BlendState {
+    pub color_op: vk::BlendOp,
+    pub color_src_factor: vk::BlendFactor,
+    pub color_dst_factor: vk::BlendFactor,
+    pub alpha_op: vk::BlendOp,
+    pub alpha_src_factor: vk::BlendFactor,
+    pub alpha_dst_factor: vk::BlendFactor,
+}
+
+impl BlendState {
+    /// Regular alpha blending. Equivalent to minecrafts translucent transparency.
+    pub const ALPHA: BlendState = BlendState {
+        color_op: vk::BlendOp::ADD,
+        color_src_factor: vk::BlendFactor::SRC_ALPHA,
+        color_dst_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+        alpha_op: vk::BlendOp::ADD,
+        alpha_src_factor: vk::BlendFactor::ONE,
+        alpha_dst_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+    };
 }
 
 /// Used to process the output of a [`EmulatorPipelinePass`].
@@ -0,0 +1,1156 @@
+//! Building blocks shared by the [`EmulatorPipeline`] implementations.
+//!
+//! All pipelines use the same descriptor and push constant layout (defined by `mc_uniforms.glsl`)
+//! and process draw tasks in the same way. Only the shaders and render passes differ.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::sync::{Arc, Mutex};
+use ash::vk;
+use ash::vk::Handle;
+use bytemuck::{bytes_of, Pod, Zeroable};
+use crate::device::device_utils::create_shader_from_bytes;
+
+use crate::prelude::*;
+use crate::renderer::emulator::{EmulatorRenderer, PrimitiveTopology};
+use crate::renderer::emulator::mc_shaders::{McUniform, McUniformData, ShaderDropListener, ShaderId, ShaderListener, VertexFormat};
+use crate::util::vk::{make_full_rect, make_full_viewport};
+use crate::renderer::emulator::pipeline::{BlendState, DrawBatchTask, DrawConfig, DrawTask, InstancedDrawTask, ObjectCreateError, PooledObjectProvider};
+
+/// The descriptor set layout and pipeline layout used by all draw pipelines.
+pub(super) struct DrawPipeline {
+    pub(super) set0_layout: vk::DescriptorSetLayout,
+    pub(super) pipeline_layout: vk::PipelineLayout,
+}
+
+impl DrawPipeline {
+    pub(super) fn new(device: &DeviceContext) -> Result<Self, ObjectCreateError> {
+        let bindings = [
+            vk::DescriptorSetLayoutBinding {
+                binding: 0,
+                descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
+                descriptor_count: 1,
+                stage_flags: vk::ShaderStageFlags::ALL,
+                p_immutable_samplers: std::ptr::null(),
+            },
+            vk::DescriptorSetLayoutBinding {
+                binding: 1,
+                descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                descriptor_count: 3,
+                stage_flags: vk::ShaderStageFlags::ALL_GRAPHICS,
+                p_immutable_samplers: std::ptr::null(),
+            },
+        ];
+
+        let info = vk::DescriptorSetLayoutCreateInfo::builder()
+            .flags(vk::DescriptorSetLayoutCreateFlags::PUSH_DESCRIPTOR_KHR)
+            .bindings(&bindings);
+
+        let set0_layout = unsafe {
+            device.vk().create_descriptor_set_layout(&info, None)
+        }.map_err(|err| {
+            log::error!("vkCreateDescriptorSetLayout returned {:?} in DrawPipeline::new when creating set 0 layout", err);
+            err
+        })?;
+
+        let push_constant_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::ALL_GRAPHICS,
+            offset: 0,
+            size: std::mem::size_of::<PushConstants>() as u32,
+        };
+
+        let layouts = [
+            set0_layout
+        ];
+
+        let info = vk::PipelineLayoutCreateInfo::builder()
+            .push_constant_ranges(std::slice::from_ref(&push_constant_range))
+            .set_layouts(&layouts);
+
+        let pipeline_layout = unsafe {
+            device.vk().create_pipeline_layout(&info, None)
+        }.map_err(|err| {
+            log::error!("vkCreatePipelineLayout returned {:?} in DrawPipeline::new", err);
+            unsafe { device.vk().destroy_descriptor_set_layout(set0_layout, None) };
+            err
+        })?;
+
+        Ok(Self {
+            set0_layout,
+            pipeline_layout
+        })
+    }
+
+    pub(super) fn destroy(&mut self, device: &DeviceContext) {
+        unsafe {
+            device.vk().destroy_pipeline_layout(self.pipeline_layout, None);
+            device.vk().destroy_descriptor_set_layout(self.set0_layout, None);
+        }
+    }
+}
+
+/// The dynamic state used by all draw pipelines. Must be set by the [`DrawState`] before drawing.
+pub(super) const DRAW_DYNAMIC_STATES: [vk::DynamicState; 2] = [
+    vk::DynamicState::VIEWPORT,
+    vk::DynamicState::SCISSOR,
+];
+
+/// The vertex binding used to source per instance data.
+pub(super) const INSTANCE_BINDING: u32 = 1;
+
+/// The size of one chunk offset in the instance buffer of a batched draw.
+pub(super) const BATCH_CHUNK_OFFSET_STRIDE: vk::DeviceSize = 12;
+
+/// The size of one instance in the instance buffer of a instanced draw. Must match
+/// [`InstanceData`](crate::renderer::emulator::pipeline::InstanceData).
+pub(super) const INSTANCE_TRANSFORM_STRIDE: vk::DeviceSize = 80;
+
+/// The per instance data consumed by a pipeline.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub(super) enum InstanceInput {
+    None,
+
+    /// A chunk offset per draw of a [`DrawBatchTask`] replacing the chunk offset uniform.
+    ChunkOffset,
+
+    /// A model view matrix and color per instance of a [`InstancedDrawTask`]. The model view
+    /// matrix replaces the model view matrix uniform and the color is multiplied with the vertex
+    /// color.
+    Transform,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub(super) struct PipelineConfig {
+    pub(super) primitive_topology: PrimitiveTopology,
+    pub(super) depth_test_enable: bool,
+    pub(super) depth_compare_op: vk::CompareOp,
+    pub(super) depth_write_enable: bool,
+    pub(super) blend_state: Option<BlendState>,
+    pub(super) cull_mode: vk::CullModeFlags,
+    pub(super) front_face: vk::FrontFace,
+    pub(super) polygon_mode: vk::PolygonMode,
+    pub(super) instance_input: InstanceInput,
+}
+
+impl PipelineConfig {
+    pub(super) fn from_draw_task(task: &DrawTask) -> Self {
+        Self::new(task.primitive_topology, &task.config, InstanceInput::None)
+    }
+
+    pub(super) fn from_draw_batch_task(task: &DrawBatchTask) -> Self {
+        Self::new(task.primitive_topology, &task.config, InstanceInput::ChunkOffset)
+    }
+
+    pub(super) fn from_instanced_draw_task(task: &InstancedDrawTask) -> Self {
+        Self::new(task.draw.primitive_topology, &task.draw.config, InstanceInput::Transform)
+    }
+
+    fn new(primitive_topology: PrimitiveTopology, config: &DrawConfig, instance_input: InstanceInput) -> Self {
+        Self {
+            primitive_topology,
+            depth_test_enable: config.depth_test_enable,
+            depth_compare_op: config.depth_compare_op,
+            depth_write_enable: config.depth_write_enable,
+            blend_state: config.blend_state,
+            // Line quads may face either direction depending on the line
+            cull_mode: if primitive_topology.is_wide_line() { vk::CullModeFlags::NONE } else { config.cull_mode },
+            front_face: config.front_face,
+            polygon_mode: config.polygon_mode,
+            instance_input,
+        }
+    }
+
+    /// Returns the vertex binding and attributes used to source the per instance inputs of the
+    /// shaders starting at the specified location. The attributes are in order the chunk offset,
+    /// the 4 columns of the model view matrix and the color.
+    ///
+    /// Inputs not used by the instance input mode are sourced from the position attribute and
+    /// must be ignored by the shaders.
+    pub(super) fn get_instance_inputs(&self, vertex_format: &VertexFormat, location: u32) -> (Option<vk::VertexInputBindingDescription>, [vk::VertexInputAttributeDescription; 6]) {
+        let mut attributes: [_; 6] = std::array::from_fn(|index| {
+            vk::VertexInputAttributeDescription {
+                location: location + (index as u32),
+                binding: 0,
+                format: vertex_format.position.format,
+                offset: vertex_format.position.offset
+            }
+        });
+
+        let stride = match self.instance_input {
+            InstanceInput::None => return (None, attributes),
+            InstanceInput::ChunkOffset => {
+                attributes[0].binding = INSTANCE_BINDING;
+                attributes[0].format = vk::Format::R32G32B32_SFLOAT;
+                attributes[0].offset = 0;
+                BATCH_CHUNK_OFFSET_STRIDE
+            }
+            InstanceInput::Transform => {
+                for (index, attribute) in attributes[1..].iter_mut().enumerate() {
+                    attribute.binding = INSTANCE_BINDING;
+                    attribute.format = vk::Format::R32G32B32A32_SFLOAT;
+                    attribute.offset = (index as u32) * 16;
+                }
+                INSTANCE_TRANSFORM_STRIDE
+            }
+        };
+
+        let binding = vk::VertexInputBindingDescription {
+            binding: INSTANCE_BINDING,
+            stride: stride as u32,
+            input_rate: vk::VertexInputRate::INSTANCE
+        };
+        (Some(binding), attributes)
+    }
+
+    pub(super) fn get_rasterization_state(&self, device: &DeviceContext) -> vk::PipelineRasterizationStateCreateInfo {
+        let polygon_mode = if self.polygon_mode != vk::PolygonMode::FILL && device.get_enabled_features().fill_mode_non_solid != vk::TRUE {
+            log::warn!("Polygon mode {:?} requested but fillModeNonSolid is not supported. Falling back to fill", self.polygon_mode);
+            vk::PolygonMode::FILL
+        } else {
+            self.polygon_mode
+        };
+
+        vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(polygon_mode)
+            .cull_mode(self.cull_mode)
+            .front_face(self.front_face)
+            .line_width(1f32)
+            .build()
+    }
+
+    pub(super) fn get_attachment_blend_state(&self) -> vk::PipelineColorBlendAttachmentState {
+        if let Some(blend_state) = &self.blend_state {
+            vk::PipelineColorBlendAttachmentState::builder()
+                .blend_enable(true)
+                .src_color_blend_factor(blend_state.color_src_factor)
+                .dst_color_blend_factor(blend_state.color_dst_factor)
+                .color_blend_op(blend_state.color_op)
+                .src_alpha_blend_factor(blend_state.alpha_src_factor)
+                .dst_alpha_blend_factor(blend_state.alpha_dst_factor)
+                .alpha_blend_op(blend_state.alpha_op)
+                .color_write_mask(vk::ColorComponentFlags::RGBA)
+                .build()
+        } else {
+            vk::PipelineColorBlendAttachmentState::builder()
+                .blend_enable(false)
+                .color_write_mask(vk::ColorComponentFlags::RGBA)
+                .build()
+        }
+    }
+}
+
+/// Keeps track of all vulkan pipelines created for shaders used by a [`EmulatorPipeline`].
+pub(super) struct PipelineCache {
+    pipelines: Mutex<HashMap<ShaderId, ShaderPipelines>>,
+}
+
+impl PipelineCache {
+    pub(super) fn new() -> Self {
+        Self {
+            pipelines: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the pipeline to be used for a specific configuration. If the pipeline doesnt exits
+    /// yet a new one is created by calling `create_fn`.
+    pub(super) fn get_pipeline<T: FnOnce(&VertexFormat, McUniform, f32) -> vk::Pipeline>(&self, shader: ShaderId, config: &PipelineConfig, create_fn: T) -> vk::Pipeline {
+        let mut guard = self.pipelines.lock().unwrap();
+        let pipelines = guard.get_mut(&shader).unwrap_or_else(|| {
+            log::error!("Called get_pipeline for unregistered shader {:?}", shader);
+            panic!()
+        });
+
+        pipelines.get_or_create_pipeline(config, create_fn)
+    }
+
+    pub(super) fn get_used_uniforms(&self, shader: ShaderId) -> McUniform {
+        self.pipelines.lock().unwrap().get(&shader).unwrap().used_uniforms
+    }
+
+    pub(super) fn inc_shader_used(&self, emulator: &EmulatorRenderer, shader: ShaderId, listener: Arc<dyn ShaderDropListener + Send + Sync>) {
+        let mut guard = self.pipelines.lock().unwrap();
+        if let Some(pipelines) = guard.get_mut(&shader) {
+            pipelines.inc_used();
+        } else {
+            let shader_obj = emulator.get_shader(shader).unwrap_or_else(|| {
+                log::error!("Called inc_shader_used for nonexistent shader {:?}", shader);
+                panic!()
+            });
+            let listener = shader_obj.register_drop_listener(&listener);
+
+            let vertex_format = shader_obj.get_vertex_format().clone();
+            let used_uniforms = shader_obj.get_used_uniforms();
+            let alpha_cutoff = shader_obj.get_alpha_cutoff();
+
+            let mut pipelines = ShaderPipelines::new(emulator.get_device().clone(), vertex_format, used_uniforms, alpha_cutoff, listener);
+            pipelines.inc_used();
+
+            guard.insert(shader, pipelines);
+        }
+    }
+
+    pub(super) fn dec_shader_used(&self, shader: ShaderId) {
+        let mut guard = self.pipelines.lock().unwrap();
+        let pipelines = guard.get_mut(&shader).unwrap_or_else(|| {
+            log::error!("Called dec_shader_used for shader which is not registered {:?}", shader);
+            panic!();
+        });
+        pipelines.dec_used();
+        let drop = pipelines.can_drop();
+        if drop {
+            guard.remove(&shader);
+        }
+    }
+
+    pub(super) fn on_shader_drop(&self, shader: ShaderId) {
+        let mut drop = false;
+        let mut guard = self.pipelines.lock().unwrap();
+        if let Some(pipeline) = guard.get_mut(&shader) {
+            pipeline.mark();
+            drop = pipeline.can_drop();
+        }
+        if drop {
+            guard.remove(&shader);
+        }
+    }
+
+    /// Destroys all pipelines. Must only be called once no more pass is using this cache.
+    pub(super) fn clear(&mut self) {
+        self.pipelines.get_mut().unwrap().clear();
+    }
+}
+
+struct ShaderPipelines {
+    device: Arc<DeviceContext>,
+    vertex_format: VertexFormat,
+    used_uniforms: McUniform,
+    alpha_cutoff: f32,
+    pipelines: HashMap<PipelineConfig, vk::Pipeline>,
+    #[allow(unused)]
+    listener: ShaderListener,
+    used_counter: u32,
+    marked: bool,
+}
+
+impl ShaderPipelines {
+    fn new(device: Arc<DeviceContext>, vertex_format: VertexFormat, used_uniforms: McUniform, alpha_cutoff: f32, listener: ShaderListener) -> Self {
+        Self {
+            device,
+            vertex_format,
+            used_uniforms,
+            alpha_cutoff,
+            pipelines: HashMap::new(),
+            listener,
+            used_counter: 0,
+            marked: false,
+        }
+    }
+
+    fn get_or_create_pipeline<T: FnOnce(&VertexFormat, McUniform, f32) -> vk::Pipeline>(&mut self, config: &PipelineConfig, create_fn: T) -> vk::Pipeline {
+        if let Some(pipeline) = self.pipelines.get(config) {
+            *pipeline
+        } else {
+            let pipeline = create_fn(&self.vertex_format, self.used_uniforms, self.alpha_cutoff);
+            self.pipelines.insert(*config, pipeline);
+            pipeline
+        }
+    }
+
+    fn inc_used(&mut self) {
+        self.used_counter += 1;
+    }
+
+    fn dec_used(&mut self) {
+        self.used_counter -= 1;
+    }
+
+    fn mark(&mut self) {
+        self.marked = true;
+    }
+
+    fn can_drop(&self) -> bool {
+        self.marked && self.used_counter == 0
+    }
+}
+
+impl Drop for ShaderPipelines {
+    fn drop(&mut self) {
+        for pipeline in self.pipelines.values() {
+            unsafe {
+                self.device.vk().destroy_pipeline(*pipeline, None);
+            }
+        }
+    }
+}
+
+/// Tracks the state of a command buffer while draw tasks are recorded into it and avoids
+/// redundant binds and uniform updates.
+///
+/// Draws which can be reordered (see [`DrawConfig::can_reorder`]) are queued together with a
+/// snapshot of their uniforms and textures and only recorded once [`DrawState::flush`] is called.
+/// When flushed they are sorted by pipeline, descriptors and buffers to minimize the number of
+/// binds. Uniform and texture updates therefore do not require a flush.
+///
+/// The sort is stable so draws using the same pipeline, descriptors and buffers are recorded in
+/// submission order. Draws with different state may however be swapped which changes the result
+/// of z-fighting between coplanar geometry.
+pub(super) struct DrawState {
+    placeholder_texture: vk::ImageView,
+    placeholder_sampler: vk::Sampler,
+    shader_uniforms: HashMap<ShaderId, UniformStateTracker>,
+
+    framebuffer_size: Vec2u32,
+    viewport: vk::Viewport,
+    scissor: vk::Rect2D,
+    dynamic_state_dirty: bool,
+
+    queued_draws: Vec<QueuedDraw>,
+
+    current_pipeline: Option<vk::Pipeline>,
+    current_push_constants: Option<PushConstants>,
+    current_static_uniforms: Option<(vk::Buffer, vk::DeviceSize)>,
+    current_textures: Option<[(vk::ImageView, vk::Sampler); 3]>,
+    current_vertex_buffer: Option<vk::Buffer>,
+    current_index_buffer: Option<(vk::Buffer, vk::IndexType)>,
+}
+
+impl DrawState {
+    pub(super) fn new(framebuffer_size: Vec2u32) -> Self {
+        Self {
+            placeholder_texture: vk::ImageView::null(),
+            placeholder_sampler: vk::Sampler::null(),
+            shader_uniforms: HashMap::new(),
+
+            framebuffer_size,
+            viewport: make_full_viewport(framebuffer_size),
+            scissor: make_full_rect(framebuffer_size),
+            dynamic_state_dirty: true,
+
+            queued_draws: Vec::new(),
+
+            current_pipeline: None,
+            current_push_constants: None,
+            current_static_uniforms: None,
+            current_textures: None,
+            current_vertex_buffer: None,
+            current_index_buffer: None,
+        }
+    }
+
+    pub(super) fn set_placeholder(&mut self, placeholder_texture: vk::ImageView, placeholder_sampler: vk::Sampler) {
+        self.placeholder_texture = placeholder_texture;
+        self.placeholder_sampler = placeholder_sampler;
+    }
+
+    pub(super) fn update_uniform(&mut self, cache: &PipelineCache, shader: ShaderId, data: &McUniformData) {
+        self.get_tracker(cache, shader).update_uniform(data);
+    }
+
+    pub(super) fn update_texture(&mut self, cache: &PipelineCache, shader: ShaderId, index: u32, view: vk::ImageView, sampler: vk::Sampler) {
+        self.get_tracker(cache, shader).update_texture(index, view, sampler);
+    }
+
+    /// Changes the size of the framebuffer drawn into. Resets the viewport and scissor to cover the
+    /// full framebuffer.
+    pub(super) fn set_framebuffer_size(&mut self, framebuffer_size: Vec2u32) {
+        self.framebuffer_size = framebuffer_size;
+        self.viewport = make_full_viewport(framebuffer_size);
+        self.scissor = make_full_rect(framebuffer_size);
+        self.dynamic_state_dirty = true;
+    }
+
+    /// Sets the viewport used by future draws. If [`None`] the full framebuffer is used.
+    pub(super) fn set_viewport(&mut self, viewport: Option<vk::Viewport>) {
+        if let Some(viewport) = &viewport {
+            // Vulkan requires a positive width and a non zero height
+            if viewport.width.is_nan() || viewport.width <= 0f32 || viewport.height.is_nan() || viewport.height == 0f32 {
+                log::warn!("Ignoring invalid viewport {:?}", viewport);
+                return;
+            }
+        }
+
+        self.viewport = viewport.unwrap_or_else(|| make_full_viewport(self.framebuffer_size));
+        self.dynamic_state_dirty = true;
+    }
+
+    /// Sets the scissor rect used by future draws. If [`None`] the full framebuffer is used.
+    pub(super) fn set_scissor(&mut self, scissor: Option<vk::Rect2D>) {
+        self.scissor = scissor.map(|scissor| self.clamp_rect(scissor)).unwrap_or_else(|| make_full_rect(self.framebuffer_size));
+        self.dynamic_state_dirty = true;
+    }
+
+    /// Clears the color and depth attachments inside the current scissor rect. Must be called
+    /// inside a subpass using a single color attachment and a depth attachment.
+    pub(super) fn clear(&self, device: &DeviceContext, cmd: vk::CommandBuffer, color: Option<Vec4f32>, depth: Option<f32>) {
+        if self.scissor.extent.width == 0 || self.scissor.extent.height == 0 {
+            return;
+        }
+
+        let mut attachments = Vec::with_capacity(2);
+        if let Some(color) = color {
+            attachments.push(vk::ClearAttachment {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                color_attachment: 0,
+                clear_value: vk::ClearValue {
+                    color: vk::ClearColorValue {
+                        float32: [color[0], color[1], color[2], color[3]]
+                    }
+                }
+            });
+        }
+        if let Some(depth) = depth {
+            attachments.push(vk::ClearAttachment {
+                aspect_mask: vk::ImageAspectFlags::DEPTH,
+                color_attachment: vk::ATTACHMENT_UNUSED,
+                clear_value: vk::ClearValue {
+                    depth_stencil: vk::ClearDepthStencilValue {
+                        depth,
+                        stencil: 0
+                    }
+                }
+            });
+        }
+
+        if attachments.is_empty() {
+            return;
+        }
+
+        let rect = vk::ClearRect {
+            rect: self.scissor,
+            base_array_layer: 0,
+            layer_count: 1
+        };
+
+        unsafe {
+            device.vk().cmd_clear_attachments(cmd, &attachments, std::slice::from_ref(&rect));
+        }
+    }
+
+    /// Clamps a rect to the framebuffer. Vulkan does not allow negative scissor offsets.
+    fn clamp_rect(&self, rect: vk::Rect2D) -> vk::Rect2D {
+        let min_x = (rect.offset.x as i64).clamp(0, self.framebuffer_size[0] as i64);
+        let min_y = (rect.offset.y as i64).clamp(0, self.framebuffer_size[1] as i64);
+        let max_x = (rect.offset.x as i64 + rect.extent.width as i64).clamp(min_x, self.framebuffer_size[0] as i64);
+        let max_y = (rect.offset.y as i64 + rect.extent.height as i64).clamp(min_y, self.framebuffer_size[1] as i64);
+
+        vk::Rect2D {
+            offset: vk::Offset2D { x: min_x as i32, y: min_y as i32 },
+            extent: vk::Extent2D { width: (max_x - min_x) as u32, height: (max_y - min_y) as u32 }
+        }
+    }
+
+    /// Queues a draw task. If the shader has no pipeline for the required configuration yet
+    /// `create_fn` is called to create it.
+    pub(super) fn draw<T: FnOnce(&PipelineConfig, &VertexFormat, McUniform, f32) -> vk::Pipeline>(&mut self, device: &DeviceContext, cmd: vk::CommandBuffer, layout: vk::PipelineLayout, cache: &PipelineCache, task: &DrawTask, obj: &mut PooledObjectProvider, create_fn: T) {
+        self.queue_draw(device, cmd, layout, cache, DrawCommand::Draw(*task), obj, create_fn);
+    }
+
+    /// Queues a instanced draw task. If the shader has no instanced pipeline for the required
+    /// configuration yet `create_fn` is called to create it.
+    pub(super) fn draw_instanced<T: FnOnce(&PipelineConfig, &VertexFormat, McUniform, f32) -> vk::Pipeline>(&mut self, device: &DeviceContext, cmd: vk::CommandBuffer, layout: vk::PipelineLayout, cache: &PipelineCache, task: &InstancedDrawTask, obj: &mut PooledObjectProvider, create_fn: T) {
+        if task.instance_count == 0 {
+            return;
+        }
+        self.queue_draw(device, cmd, layout, cache, DrawCommand::Instanced(*task), obj, create_fn);
+    }
+
+    /// Queues a batch of indirect draws. If the shader has no batched pipeline for the required
+    /// configuration yet `create_fn` is called to create it.
+    pub(super) fn draw_batch<T: FnOnce(&PipelineConfig, &VertexFormat, McUniform, f32) -> vk::Pipeline>(&mut self, device: &DeviceContext, cmd: vk::CommandBuffer, layout: vk::PipelineLayout, cache: &PipelineCache, task: &DrawBatchTask, obj: &mut PooledObjectProvider, create_fn: T) {
+        if task.draw_count == 0 {
+            return;
+        }
+        self.queue_draw(device, cmd, layout, cache, DrawCommand::Batch(*task), obj, create_fn);
+    }
+
+    /// Records all queued draws sorted by pipeline, descriptors and buffers.
+    ///
+    /// Must be called before any command which depends on the queued draws or affects their
+    /// result (for example clears or viewport and scissor changes) is recorded and before the
+    /// render pass ends.
+    pub(super) fn flush(&mut self, device: &DeviceContext, cmd: vk::CommandBuffer, layout: vk::PipelineLayout) {
+        if self.queued_draws.is_empty() {
+            return;
+        }
+
+        // Taken out of self so we can record while iterating. Put back afterwards to reuse the allocation.
+        let mut queued_draws = std::mem::take(&mut self.queued_draws);
+        queued_draws.sort_by_key(QueuedDraw::get_sort_key);
+
+        for draw in queued_draws.iter() {
+            self.record_draw(device, cmd, layout, draw);
+        }
+
+        queued_draws.clear();
+        self.queued_draws = queued_draws;
+    }
+
+    /// Captures the uniform and texture state of a draw and queues it. Draws which cannot be
+    /// reordered flush the queue and are recorded immediately.
+    fn queue_draw<T: FnOnce(&PipelineConfig, &VertexFormat, McUniform, f32) -> vk::Pipeline>(&mut self, device: &DeviceContext, cmd: vk::CommandBuffer, layout: vk::PipelineLayout, cache: &PipelineCache, command: DrawCommand, obj: &mut PooledObjectProvider, create_fn: T) {
+        let shader = command.get_shader();
+        let pipeline_config = command.get_pipeline_config();
+        let pipeline = cache.get_pipeline(shader, &pipeline_config, |format, used_uniforms, alpha_cutoff| create_fn(&pipeline_config, format, used_uniforms, alpha_cutoff));
+
+        if !self.shader_uniforms.contains_key(&shader) {
+            log::warn!("Called draw without any shader uniforms. Using default values!");
+        }
+        let tracker = self.get_tracker(cache, shader);
+
+        let draw = QueuedDraw {
+            pipeline,
+            push_constants: tracker.get_push_constants(pipeline_config.instance_input == InstanceInput::ChunkOffset),
+            static_uniforms: tracker.get_static_uniforms(obj),
+            textures: tracker.textures,
+            command,
+        };
+
+        if command.get_config().can_reorder() {
+            self.queued_draws.push(draw);
+        } else {
+            self.flush(device, cmd, layout);
+            self.record_draw(device, cmd, layout, &draw);
+        }
+    }
+
+    /// Binds all state needed by a queued draw and records its draw commands.
+    fn record_draw(&mut self, device: &DeviceContext, cmd: vk::CommandBuffer, layout: vk::PipelineLayout, draw: &QueuedDraw) {
+        self.bind_state(device, cmd, layout, draw);
+
+        match &draw.command {
+            DrawCommand::Draw(task) => {
+                unsafe {
+                    if task.index_buffer.is_some() {
+                        device.vk().cmd_draw_indexed(cmd, task.index_count, 1, task.first_index, task.vertex_offset, 0);
+                    } else {
+                        device.vk().cmd_draw(cmd, task.index_count, 1, task.vertex_offset as u32, 0);
+                    }
+                }
+            }
+            DrawCommand::Instanced(task) => {
+                let draw = &task.draw;
+                unsafe {
+                    device.vk().cmd_bind_vertex_buffers(
+                        cmd,
+                        INSTANCE_BINDING,
+                        std::slice::from_ref(&task.instance_buffer),
+                        std::slice::from_ref(&task.instance_offset)
+                    );
+
+                    if draw.index_buffer.is_some() {
+                        device.vk().cmd_draw_indexed(cmd, draw.index_count, task.instance_count, draw.first_index, draw.vertex_offset, 0);
+                    } else {
+                        device.vk().cmd_draw(cmd, draw.index_count, task.instance_count, draw.vertex_offset as u32, 0);
+                    }
+                }
+            }
+            DrawCommand::Batch(task) => {
+                Self::record_draw_batch(device, cmd, task);
+            }
+        }
+    }
+
+    /// Records the indirect draws of a batch.
+    ///
+    /// If the `multiDrawIndirect` feature is not enabled or the batch does not use
+    /// `first_instance` to select chunk offsets the draws are recorded one by one.
+    fn record_draw_batch(device: &DeviceContext, cmd: vk::CommandBuffer, task: &DrawBatchTask) {
+        let stride = if task.index_buffer.is_some() {
+            std::mem::size_of::<vk::DrawIndexedIndirectCommand>()
+        } else {
+            std::mem::size_of::<vk::DrawIndirectCommand>()
+        } as vk::DeviceSize;
+
+        let record_indirect = |offset: vk::DeviceSize, draw_count: u32| {
+            unsafe {
+                if task.index_buffer.is_some() {
+                    device.vk().cmd_draw_indexed_indirect(cmd, task.indirect_buffer, offset, draw_count, stride as u32);
+                } else {
+                    device.vk().cmd_draw_indirect(cmd, task.indirect_buffer, offset, draw_count, stride as u32);
+                }
+            }
+        };
+        let bind_instance_buffer = |offset: vk::DeviceSize| {
+            unsafe {
+                device.vk().cmd_bind_vertex_buffers(
+                    cmd,
+                    INSTANCE_BINDING,
+                    std::slice::from_ref(&task.instance_buffer),
+                    std::slice::from_ref(&offset)
+                );
+            }
+        };
+
+        if task.first_instance_indexing {
+            bind_instance_buffer(task.instance_offset);
+
+            if device.get_enabled_features().multi_draw_indirect == vk::TRUE {
+                record_indirect(task.indirect_offset, task.draw_count);
+            } else {
+                for draw in 0..(task.draw_count as vk::DeviceSize) {
+                    record_indirect(task.indirect_offset + draw * stride, 1);
+                }
+            }
+        } else {
+            for draw in 0..(task.draw_count as vk::DeviceSize) {
+                bind_instance_buffer(task.instance_offset + draw * BATCH_CHUNK_OFFSET_STRIDE);
+                record_indirect(task.indirect_offset + draw * stride, 1);
+            }
+        }
+    }
+
+    /// Binds the pipeline, buffers and uniforms needed by a queued draw. Only state which differs
+    /// from the currently bound state is updated.
+    fn bind_state(&mut self, device: &DeviceContext, cmd: vk::CommandBuffer, layout: vk::PipelineLayout, draw: &QueuedDraw) {
+        if self.current_pipeline != Some(draw.pipeline) {
+            self.current_pipeline = Some(draw.pipeline);
+            unsafe {
+                device.vk().cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, draw.pipeline);
+            }
+        }
+
+        if self.dynamic_state_dirty {
+            self.dynamic_state_dirty = false;
+            unsafe {
+                device.vk().cmd_set_viewport(cmd, 0, std::slice::from_ref(&self.viewport));
+                device.vk().cmd_set_scissor(cmd, 0, std::slice::from_ref(&self.scissor));
+            }
+        }
+
+        if self.current_push_constants != Some(draw.push_constants) {
+            self.current_push_constants = Some(draw.push_constants);
+            unsafe {
+                device.vk().cmd_push_constants(
+                    cmd,
+                    layout,
+                    vk::ShaderStageFlags::ALL_GRAPHICS,
+                    0,
+                    bytes_of(&draw.push_constants)
+                );
+            }
+        }
+
+        if self.current_static_uniforms != Some(draw.static_uniforms) {
+            self.current_static_uniforms = Some(draw.static_uniforms);
+
+            let (buffer, offset) = draw.static_uniforms;
+            let buffer_info = vk::DescriptorBufferInfo {
+                buffer,
+                offset,
+                range: std::mem::size_of::<StaticUniforms>() as vk::DeviceSize
+            };
+            let write = vk::WriteDescriptorSet::builder()
+                .dst_binding(0)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(std::slice::from_ref(&buffer_info));
+
+            unsafe {
+                device.push_descriptor_khr().cmd_push_descriptor_set(
+                    cmd,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    layout,
+                    0,
+                    std::slice::from_ref(&write)
+                );
+            }
+        }
+
+        if self.current_textures != Some(draw.textures) {
+            self.current_textures = Some(draw.textures);
+
+            let image_infos = draw.textures.map(|(image_view, sampler)| vk::DescriptorImageInfo {
+                sampler,
+                image_view,
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+            });
+            let writes = [
+                vk::WriteDescriptorSet::builder()
+                    .dst_binding(1)
+                    .dst_array_element(0)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(std::slice::from_ref(&image_infos[0]))
+                    .build(),
+                vk::WriteDescriptorSet::builder()
+                    .dst_binding(1)
+                    .dst_array_element(1)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(std::slice::from_ref(&image_infos[1]))
+                    .build(),
+                vk::WriteDescriptorSet::builder()
+                    .dst_binding(1)
+                    .dst_array_element(2)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(std::slice::from_ref(&image_infos[2]))
+                    .build(),
+            ];
+
+            unsafe {
+                device.push_descriptor_khr().cmd_push_descriptor_set(
+                    cmd,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    layout,
+                    0,
+                    &writes
+                );
+            }
+        }
+
+        let vertex_buffer = draw.command.get_vertex_buffer();
+        if self.current_vertex_buffer != Some(vertex_buffer) {
+            unsafe {
+                device.vk().cmd_bind_vertex_buffers(
+                    cmd,
+                    0,
+                    std::slice::from_ref(&vertex_buffer),
+                    std::slice::from_ref(&0)
+                );
+            }
+            self.current_vertex_buffer = Some(vertex_buffer);
+        }
+
+        if let Some((index_buffer, index_type)) = draw.command.get_index_buffer() {
+            if self.current_index_buffer != Some((index_buffer, index_type)) {
+                unsafe {
+                    device.vk().cmd_bind_index_buffer(cmd, index_buffer, 0, index_type);
+                }
+                self.current_index_buffer = Some((index_buffer, index_type));
+            }
+        }
+    }
+
+    fn get_tracker(&mut self, cache: &PipelineCache, shader: ShaderId) -> &mut UniformStateTracker {
+        let placeholder_texture = self.placeholder_texture;
+        let placeholder_sampler = self.placeholder_sampler;
+        self.shader_uniforms.entry(shader).or_insert_with(|| {
+            UniformStateTracker::new(cache.get_used_uniforms(shader), placeholder_texture, placeholder_sampler)
+        })
+    }
+}
+
+/// A draw waiting to be recorded together with the state captured when it was queued.
+struct QueuedDraw {
+    pipeline: vk::Pipeline,
+    push_constants: PushConstants,
+    static_uniforms: (vk::Buffer, vk::DeviceSize),
+    textures: [(vk::ImageView, vk::Sampler); 3],
+    command: DrawCommand,
+}
+
+impl QueuedDraw {
+    /// Draws are sorted by pipeline first, followed by the descriptors and finally the vertex and
+    /// index buffers.
+    fn get_sort_key(&self) -> (u64, (u64, u64), [u64; 6], u64, Option<(u64, i32)>) {
+        let (uniform_buffer, uniform_offset) = self.static_uniforms;
+        let textures = [
+            self.textures[0].0.as_raw(), self.textures[0].1.as_raw(),
+            self.textures[1].0.as_raw(), self.textures[1].1.as_raw(),
+            self.textures[2].0.as_raw(), self.textures[2].1.as_raw(),
+        ];
+
+        (
+            self.pipeline.as_raw(),
+            (uniform_buffer.as_raw(), uniform_offset),
+            textures,
+            self.command.get_vertex_buffer().as_raw(),
+            self.command.get_index_buffer().map(|(buffer, index_type)| (buffer.as_raw(), index_type.as_raw())),
+        )
+    }
+}
+
+#[derive(Copy, Clone)]
+enum DrawCommand {
+    Draw(DrawTask),
+    Batch(DrawBatchTask),
+    Instanced(InstancedDrawTask),
+}
+
+impl DrawCommand {
+    fn get_draw_task(&self) -> Option<&DrawTask> {
+        match self {
+            DrawCommand::Draw(task) => Some(task),
+            DrawCommand::Instanced(task) => Some(&task.draw),
+            DrawCommand::Batch(_) => None,
+        }
+    }
+
+    fn get_shader(&self) -> ShaderId {
+        match self {
+            DrawCommand::Batch(task) => task.shader,
+            _ => self.get_draw_task().unwrap().shader,
+        }
+    }
+
+    fn get_config(&self) -> &DrawConfig {
+        match self {
+            DrawCommand::Batch(task) => &task.config,
+            _ => &self.get_draw_task().unwrap().config,
+        }
+    }
+
+    fn get_pipeline_config(&self) -> PipelineConfig {
+        match self {
+            DrawCommand::Draw(task) => PipelineConfig::from_draw_task(task),
+            DrawCommand::Batch(task) => PipelineConfig::from_draw_batch_task(task),
+            DrawCommand::Instanced(task) => PipelineConfig::from_instanced_draw_task(task),
+        }
+    }
+
+    fn get_vertex_buffer(&self) -> vk::Buffer {
+        match self {
+            DrawCommand::Batch(task) => task.vertex_buffer,
+            _ => self.get_draw_task().unwrap().vertex_buffer,
+        }
+    }
+
+    fn get_index_buffer(&self) -> Option<(vk::Buffer, vk::IndexType)> {
+        match self {
+            DrawCommand::Batch(task) => task.index_buffer.map(|buffer| (buffer, task.index_type)),
+            _ => {
+                let task = self.get_draw_task().unwrap();
+                task.index_buffer.map(|buffer| (buffer, task.index_type))
+            }
+        }
+    }
+}
+
+struct UniformStateTracker {
+    used_uniforms: McUniform,
+    push_constant_cache: PushConstants,
+    static_uniform_cache: StaticUniforms,
+
+    /// The location of the last upload of `static_uniform_cache`. [`None`] if the static uniforms
+    /// have been modified since.
+    static_uniform_allocation: Option<(vk::Buffer, vk::DeviceSize)>,
+    textures: [(vk::ImageView, vk::Sampler); 3],
+}
+
+impl UniformStateTracker {
+    fn new(used_uniforms: McUniform, initial_texture: vk::ImageView, initial_sampler: vk::Sampler) -> Self {
+        Self {
+            used_uniforms,
+            push_constant_cache: PushConstants {
+                model_view_matrix: Mat4f32::identity(),
+                chunk_offset: Vec3f32::zeros(),
+                _padding0: Default::default(),
+            },
+            static_uniform_cache: StaticUniforms {
+                projection_matrix: Mat4f32::identity(),
+                inverse_view_rotation_matrix: Mat4f32::identity(),
+                texture_matrix: Mat4f32::identity(),
+                fog_color: Vec4f32::zeros(),
+                color_modulator: Vec4f32::new(1f32, 1f32, 1f32, 1f32),
+                fog_range_and_game_time: Vec3f32::zeros(),
+                fog_shape: 0,
+                light_0_direction: Vec3f32::zeros(),
+                line_width: 1f32,
+                light_1_direction: Vec3f32::zeros(),
+                _padding0: Default::default(),
+                screen_size: Vec2f32::zeros(),
+                _padding1: Default::default(),
+            },
+            static_uniform_allocation: None,
+            textures: [(initial_texture, initial_sampler); 3],
+        }
+    }
+
+    fn update_uniform(&mut self, data: &McUniformData) {
+        match data {
+            McUniformData::ModelViewMatrix(mat) => {
+                if self.used_uniforms.contains(&McUniform::MODEL_VIEW_MATRIX) {
+                    self.push_constant_cache.model_view_matrix = *mat;
+                }
+            }
+            McUniformData::ProjectionMatrix(mat) => {
+                if self.used_uniforms.contains(&McUniform::PROJECTION_MATRIX) {
+                    self.static_uniform_cache.projection_matrix = *mat;
+                    self.static_uniform_allocation = None;
+                }
+            }
+            McUniformData::InverseViewRotationMatrix(mat) => {
+                if self.used_uniforms.contains(&McUniform::INVERSE_VIEW_ROTATION_MATRIX) {
+                    self.static_uniform_cache.inverse_view_rotation_matrix = *mat;
+                    self.static_uniform_allocation = None;
+                }
+            }
+            McUniformData::TextureMatrix(mat) => {
+                if self.used_uniforms.contains(&McUniform::TEXTURE_MATRIX) {
+                    self.static_uniform_cache.texture_matrix = *mat;
+                    self.static_uniform_allocation = None;
+                }
+            }
+            McUniformData::ScreenSize(size) => {
+                if self.used_uniforms.contains(&McUniform::SCREEN_SIZE) {
+                    self.static_uniform_cache.screen_size = *size;
+                    self.static_uniform_allocation = None;
+                }
+            }
+            McUniformData::ColorModulator(color) => {
+                if self.used_uniforms.contains(&McUniform::COLOR_MODULATOR) {
+                    self.static_uniform_cache.color_modulator = *color;
+                    self.static_uniform_allocation = None;
+                }
+            }
+            McUniformData::Light0Direction(dir) => {
+                if self.used_uniforms.contains(&McUniform::LIGHT0_DIRECTION) {
+                    self.static_uniform_cache.light_0_direction = *dir;
+                    self.static_uniform_allocation = None;
+                }
+            }
+            McUniformData::Light1Direction(dir) => {
+                if self.used_uniforms.contains(&McUniform::LIGHT1_DIRECTION) {
+                    self.static_uniform_cache.light_1_direction = *dir;
+                    self.static_uniform_allocation = None;
+                }
+            }
+            McUniformData::FogStart(start) => {
+                if self.used_uniforms.contains(&McUniform::FOG_START) {
+                    self.static_uniform_cache.fog_range_and_game_time[0] = *start;
+                    self.static_uniform_allocation = None;
+                }
+            }
+            McUniformData::FogEnd(end) => {
+                if self.used_uniforms.contains(&McUniform::FOG_END) {
+                    self.static_uniform_cache.fog_range_and_game_time[1] = *end;
+                    self.static_uniform_allocation = None;
+                }
+            }
+            McUniformData::FogColor(color) => {
+                if self.used_uniforms.contains(&McUniform::FOG_COLOR) {
+                    self.static_uniform_cache.fog_color = *color;
+                    self.static_uniform_allocation = None;
+                }
+            }
+            McUniformData::FogShape(shape) => {
+                if self.used_uniforms.contains(&McUniform::FOG_SHAPE) {
+                    self.static_uniform_cache.fog_shape = *shape;
+                    self.static_uniform_allocation = None;
+                }
+            }
+            McUniformData::LineWidth(width) => {
+                if self.used_uniforms.contains(&McUniform::LINE_WIDTH) {
+                    self.static_uniform_cache.line_width = *width;
+                    self.static_uniform_allocation = None;
+                }
+            }
+            McUniformData::GameTime(time) => {
+                if self.used_uniforms.contains(&McUniform::GAME_TIME) {
+                    self.static_uniform_cache.fog_range_and_game_time[2] = *time;
+                    self.static_uniform_allocation = None;
+                }
+            }
+            McUniformData::ChunkOffset(offset) => {
+                if self.used_uniforms.contains(&McUniform::CHUNK_OFFSET) {
+                    self.push_constant_cache.chunk_offset = *offset;
+                }
+            }
+        }
+    }
+
+    fn update_texture(&mut self, index: u32, view: vk::ImageView, sampler: vk::Sampler) {
+        match index {
+            0 => {
+                self.textures[0] = (view, sampler);
+            },
+            1 => {
+                self.textures[1] = (view, sampler);
+            },
+            2 => {
+                self.textures[2] = (view, sampler);
+            },
+            _ => log::warn!("Called updated texture on index {:?} which is out of bounds", index),
+        }
+    }
+
+    /// Returns the current push constants. Batched draws source the chunk offset from a vertex
+    /// attribute so it is set to 0 if `batched` is true.
+    fn get_push_constants(&self, batched: bool) -> PushConstants {
+        let mut push_constants = self.push_constant_cache;
+        if batched {
+            push_constants.chunk_offset = Vec3f32::zeros();
+        }
+        push_constants
+    }
+
+    /// Returns the location of the current static uniforms. They are only uploaded again if they
+    /// have been modified since the last call.
+    fn get_static_uniforms(&mut self, obj: &mut PooledObjectProvider) -> (vk::Buffer, vk::DeviceSize) {
+        let static_uniforms = &self.static_uniform_cache;
+        *self.static_uniform_allocation.get_or_insert_with(|| obj.allocate_uniform(bytes_of(static_uniforms)))
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Default)]
+struct PushConstants {
+    #[allow(unused)]
+    model_view_matrix: Mat4f32,
+
+    #[allow(unused)]
+    chunk_offset: Vec3f32,
+
+    _padding0: [u8; 4],
+}
+const_assert_eq!(std::mem::size_of::<PushConstants>(), 80);
+const_assert_eq!(std::mem::size_of::<PushConstants>() % 16, 0);
+
+unsafe impl Zeroable for PushConstants {}
+unsafe impl Pod for PushConstants {}
+
+/// Must match the `_McStaticUniforms` block in `mc_uniforms.glsl`.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct StaticUniforms {
+    #[allow(unused)]
+    projection_matrix: Mat4f32,
+
+    #[allow(unused)]
+    inverse_view_rotation_matrix: Mat4f32,
+
+    #[allow(unused)]
+    texture_matrix: Mat4f32,
+
+    #[allow(unused)]
+    fog_color: Vec4f32,
+
+    #[allow(unused)]
+    color_modulator: Vec4f32,
+
+    #[allow(unused)]
+    fog_range_and_game_time: Vec3f32,
+
+    #[allow(unused)]
+    fog_shape: u32,
+
+    #[allow(unused)]
+    light_0_direction: Vec3f32,
+
+    #[allow(unused)]
+    line_width: f32,
+
+    #[allow(unused)]
+    light_1_direction: Vec3f32,
+
+    _padding0: [u8; 4],
+
+    #[allow(unused)]
+    screen_size: Vec2f32,
+
+    _padding1: [u8; 8],
+}
+const_assert_eq!(std::mem::size_of::<StaticUniforms>(), 288);
+const_assert_eq!(std::mem::size_of::<StaticUniforms>() % 16, 0);
+
+unsafe impl Zeroable for StaticUniforms {}
+unsafe impl Pod for StaticUniforms {}
+
+pub(super) fn try_create_shader_module(device: &DeviceContext, data: &[u8], name: &str) -> Result<vk::ShaderModule, vk::Result> {
+    unsafe {
+        create_shader_from_bytes(device.get_functions(), data)
+    }.map_err(|err| {
+        log::error!("vkCreateShaderModule returned {:?} when creating module {:?}", err, name);
+        err
+    })
+}
+
+pub(super) const SHADER_ENTRY: &'static CStr = unsafe { CStr::from_bytes_with_nul_unchecked(b"main\0") }; // GOD I LOVE RUSTS FFI API IT IS SO NICE AND DEFINITELY NOT STUPID WITH WHICH FUNCTIONS ARE CONST AND WHICH AREN'T
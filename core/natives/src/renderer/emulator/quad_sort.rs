@@ -0,0 +1,180 @@
+//! Depth sorting of translucent quad meshes.
+//!
+//! Translucent geometry must be drawn back to front. Minecraft does this by sorting the quads of
+//! translucent chunk meshes on the cpu whenever the camera moves and uploading the mesh again. For
+//! sortable [`GlobalMesh`](crate::renderer::emulator::GlobalMesh)es we keep a copy of the vertex
+//! positions and the original quad indices so only the generated index data has to be rewritten.
+
+use std::borrow::Cow;
+
+use ash::vk;
+
+use crate::prelude::*;
+use crate::renderer::emulator::{MeshData, PrimitiveTopology};
+use crate::renderer::emulator::mc_shaders::VertexFormatEntry;
+use crate::renderer::emulator::topology::{EmulatedIndices, QuadPattern, read_quads};
+
+/// The data needed to sort the quads of a [`PrimitiveTopology::Quads`] mesh.
+///
+/// Sortable meshes always store their own uint32 triangle list index data, even if the quads are
+/// sequential, so that the draw order of the quads can be changed.
+pub(super) struct QuadSortState {
+    position: VertexFormatEntry,
+    vertex_stride: u32,
+
+    /// The model space position of every vertex.
+    positions: Vec<Vec3f32>,
+
+    /// The 4 vertex indices of every quad in submission order.
+    quads: Box<[[u32; 4]]>,
+}
+
+impl QuadSortState {
+    pub(super) fn new(data: &MeshData, position: &VertexFormatEntry) -> Self {
+        if data.primitive_topology != PrimitiveTopology::Quads {
+            log::error!("Sortable meshes must use the Quads topology but got {:?}", data.primitive_topology);
+            panic!()
+        }
+        if data.index_count % 4 != 0 {
+            log::warn!("Sortable mesh index count {:?} is not a multiple of 4. Trailing indices will be ignored", data.index_count);
+        }
+
+        let mut state = Self {
+            position: *position,
+            vertex_stride: data.vertex_stride,
+            positions: Vec::new(),
+            quads: read_quads(data).into_boxed_slice(),
+        };
+        state.positions = state.read_positions(data.vertex_data);
+
+        state
+    }
+
+    /// Creates a new sort state for new mesh data using the same position entry.
+    pub(super) fn recreate(&self, data: &MeshData) -> Self {
+        Self::new(data, &self.position)
+    }
+
+    /// Updates the positions of all vertices fully contained in a range of vertex data. The offset
+    /// is in bytes relative to the start of the vertex data.
+    pub(super) fn update_vertices(&mut self, offset: vk::DeviceSize, data: &[u8]) {
+        let stride = std::cmp::max(self.vertex_stride, 1) as vk::DeviceSize;
+        let first_vertex = (offset + stride - 1) / stride;
+        let data_offset = (first_vertex * stride - offset) as usize;
+        if data_offset >= data.len() {
+            return;
+        }
+
+        for (index, position) in self.read_positions(&data[data_offset..]).into_iter().enumerate() {
+            if let Some(dst) = self.positions.get_mut((first_vertex as usize) + index) {
+                *dst = position;
+            }
+        }
+    }
+
+    /// Returns the emulated index data of the mesh with the quads in submission order.
+    pub(super) fn make_indices(&self) -> EmulatedIndices<'static> {
+        let order: Vec<_> = (0..self.quads.len()).collect();
+        EmulatedIndices::Indexed {
+            data: Cow::Owned(self.write_indices(&order)),
+            index_type: vk::IndexType::UINT32,
+            index_count: (self.quads.len() * 6) as u32,
+        }
+    }
+
+    /// Returns the uint32 index data of the mesh with the quads sorted back to front as seen from
+    /// the camera position. The camera position must be in the model space of the mesh.
+    ///
+    /// Like minecraft the quads are sorted by the distance of their centroid to the camera.
+    pub(super) fn sort(&self, camera_position: Vec3f32) -> Vec<u8> {
+        let distances: Vec<f32> = self.quads.iter().map(|quad| {
+            let centroid = quad.iter()
+                .map(|index| self.positions.get(*index as usize).copied().unwrap_or_else(Vec3f32::zeros))
+                .fold(Vec3f32::zeros(), |sum, position| sum + position) * 0.25f32;
+            (centroid - camera_position).norm_squared()
+        }).collect();
+
+        let mut order: Vec<_> = (0..self.quads.len()).collect();
+        order.sort_by(|a, b| distances[*b].total_cmp(&distances[*a]));
+
+        self.write_indices(&order)
+    }
+
+    fn write_indices(&self, order: &[usize]) -> Vec<u8> {
+        let offsets = QuadPattern::Quads.get_offsets();
+
+        let mut data = Vec::with_capacity(order.len() * 6 * 4);
+        for quad in order {
+            let quad = &self.quads[*quad];
+            for offset in offsets {
+                data.extend_from_slice(&quad[offset as usize].to_ne_bytes());
+            }
+        }
+        data
+    }
+
+    fn read_positions(&self, vertex_data: &[u8]) -> Vec<Vec3f32> {
+        let stride = std::cmp::max(self.vertex_stride, 1) as usize;
+        let offset = self.position.offset as usize;
+
+        match self.position.format {
+            vk::Format::R32G32B32_SFLOAT | vk::Format::R32G32B32A32_SFLOAT => {},
+            _ => {
+                log::error!("Unsupported sortable mesh position format {:?}", self.position.format);
+                panic!()
+            }
+        }
+
+        let read_f32 = |bytes: &[u8]| f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+
+        vertex_data.chunks(stride).take_while(|vertex| vertex.len() >= offset + 12).map(|vertex| {
+            let position = &vertex[offset..(offset + 12)];
+            Vec3f32::new(read_f32(&position[0..4]), read_f32(&position[4..8]), read_f32(&position[8..12]))
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::cast_slice;
+
+    use super::*;
+
+    fn read_first_quad_indices(data: &[u8]) -> Vec<u32> {
+        data[0..24].chunks(4).map(|bytes| u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).collect()
+    }
+
+    #[test]
+    fn test_sort_back_to_front() {
+        // 2 quads. The first at z = 0 and the second at z = 10.
+        let vertices: [f32; 24] = [
+            0f32, 0f32, 0f32,   1f32, 0f32, 0f32,   1f32, 1f32, 0f32,   0f32, 1f32, 0f32,
+            0f32, 0f32, 10f32,  1f32, 0f32, 10f32,  1f32, 1f32, 10f32,  0f32, 1f32, 10f32,
+        ];
+        let data = MeshData {
+            vertex_data: cast_slice(&vertices),
+            index_data: None,
+            vertex_stride: 12,
+            index_count: 8,
+            index_type: vk::IndexType::UINT32,
+            primitive_topology: PrimitiveTopology::Quads,
+        };
+        let position = VertexFormatEntry {
+            offset: 0,
+            format: vk::Format::R32G32B32_SFLOAT,
+        };
+        let mut state = QuadSortState::new(&data, &position);
+
+        let sorted = state.sort(Vec3f32::new(0f32, 0f32, -5f32));
+        assert_eq!(read_first_quad_indices(&sorted), vec![4, 5, 6, 6, 7, 4]);
+
+        let sorted = state.sort(Vec3f32::new(0f32, 0f32, 15f32));
+        assert_eq!(read_first_quad_indices(&sorted), vec![0, 1, 2, 2, 3, 0]);
+
+        // Move the first quad behind the second one as seen from z = -5
+        let moved: [f32; 12] = [0f32, 0f32, -30f32, 1f32, 0f32, -30f32, 1f32, 1f32, -30f32, 0f32, 1f32, -30f32];
+        state.update_vertices(0, cast_slice(&moved));
+        let sorted = state.sort(Vec3f32::new(0f32, 0f32, -5f32));
+        assert_eq!(read_first_quad_indices(&sorted), vec![0, 1, 2, 2, 3, 0]);
+    }
+}
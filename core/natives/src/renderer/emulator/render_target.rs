@@ -0,0 +1,365 @@
+//! Offscreen render targets which can be rendered into by passes and sampled by later passes.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use ash::vk;
+
+use crate::allocator::Allocation;
+use crate::prelude::*;
+use crate::renderer::emulator::global_objects::{GlobalImage, GlobalObjectCreateError};
+use crate::renderer::emulator::PassId;
+use crate::renderer::emulator::share::Share;
+use crate::util::format::Format;
+use crate::util::vk::make_full_rect;
+
+/// A offscreen color and depth image pair which a pass can render into instead of the internal
+/// images of its pipeline.
+///
+/// The color image is a regular [`GlobalImage`] and can be passed to
+/// [`PassRecorder::update_texture`](crate::renderer::emulator::PassRecorder::update_texture) in
+/// any later pass. The color image uses a UNORM format for both rendering and sampling so later
+/// passes read back exactly the values written, like minecrafts post processing chains expect.
+///
+/// The contents of the color image are preserved between passes and are initially cleared to
+/// zero. The depth image is preserved between passes and cleared to 1.0 before its first use.
+pub struct RenderTarget {
+    share: Arc<Share>,
+    size: Vec2u32,
+
+    color_image: Arc<GlobalImage>,
+    color_view: vk::ImageView,
+
+    depth_image: vk::Image,
+    depth_allocation: Option<Allocation>,
+    depth_view: vk::ImageView,
+
+    /// Render pass used if the depth image has not been used before. Clears the depth image.
+    clear_render_pass: vk::RenderPass,
+    /// Render pass used for all other passes. Loads the previous depth contents.
+    load_render_pass: vk::RenderPass,
+    framebuffer: vk::Framebuffer,
+
+    depth_initialized: AtomicBool,
+}
+assert_impl_all!(RenderTarget: Send, Sync);
+
+impl RenderTarget {
+    /// The format used by the color attachment. Matches the color format of the
+    /// [`McPipeline`](crate::renderer::emulator::mc_pipeline::McPipeline) so its pipelines are
+    /// render pass compatible.
+    pub(super) const COLOR_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+
+    /// The format used by the depth attachment.
+    pub(super) const DEPTH_FORMAT: vk::Format = vk::Format::D32_SFLOAT;
+
+    pub(super) fn new(share: Arc<Share>, size: Vec2u32) -> Result<Arc<Self>, GlobalObjectCreateError> {
+        let color_image = GlobalImage::new_attachment(share.clone(), size, &Format::R8G8B8A8_UNORM)?;
+
+        let mut result = RenderTarget {
+            share,
+            size,
+
+            color_image,
+            color_view: vk::ImageView::null(),
+
+            depth_image: vk::Image::null(),
+            depth_allocation: None,
+            depth_view: vk::ImageView::null(),
+
+            clear_render_pass: vk::RenderPass::null(),
+            load_render_pass: vk::RenderPass::null(),
+            framebuffer: vk::Framebuffer::null(),
+
+            depth_initialized: AtomicBool::new(false),
+        };
+
+        // Any partially created objects are destroyed by our drop implementation
+        let device = result.share.get_device().clone();
+
+        result.color_view = Self::create_image_view(&device, result.color_image.get_image_handle(), Self::COLOR_FORMAT, vk::ImageAspectFlags::COLOR)?;
+
+        let (depth_image, depth_allocation) = Self::create_depth_image(&device, size)?;
+        result.depth_image = depth_image;
+        result.depth_allocation = Some(depth_allocation);
+
+        result.depth_view = Self::create_image_view(&device, depth_image, Self::DEPTH_FORMAT, vk::ImageAspectFlags::DEPTH)?;
+
+        result.clear_render_pass = Self::create_render_pass(&device, vk::AttachmentLoadOp::CLEAR)?;
+        result.load_render_pass = Self::create_render_pass(&device, vk::AttachmentLoadOp::LOAD)?;
+
+        result.framebuffer = Self::create_framebuffer(&device, size, result.depth_view, result.color_view, result.load_render_pass)?;
+
+        Ok(Arc::new(result))
+    }
+
+    pub fn get_size(&self) -> Vec2u32 {
+        self.size
+    }
+
+    /// Returns the color image of this render target. It can be used like any other
+    /// [`GlobalImage`].
+    pub fn get_color_image(&self) -> &Arc<GlobalImage> {
+        &self.color_image
+    }
+
+    /// Marks the render target as used by some pass.
+    pub(super) fn update_used_in(&self, pass: PassId) {
+        self.color_image.update_used_in(pass);
+    }
+
+    /// Records the barriers necessary to render into this render target and begins a render pass.
+    ///
+    /// The render pass is compatible with a render pass using [`RenderTarget::DEPTH_FORMAT`] as
+    /// attachment 0 and [`RenderTarget::COLOR_FORMAT`] as attachment 1 in a single subpass.
+    ///
+    /// Must only be called from the worker thread in pass order.
+    pub(super) fn begin_render_pass(&self, cmd: vk::CommandBuffer) {
+        let device = self.share.get_device();
+
+        let first_use = !self.depth_initialized.swap(true, Ordering::SeqCst);
+
+        let mut depth_barrier = vk::ImageMemoryBarrier2::builder()
+            .dst_stage_mask(vk::PipelineStageFlags2::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags2::LATE_FRAGMENT_TESTS)
+            .dst_access_mask(vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+            .image(self.depth_image)
+            .subresource_range(make_subresource_range(vk::ImageAspectFlags::DEPTH));
+        if first_use {
+            depth_barrier = depth_barrier
+                .src_stage_mask(vk::PipelineStageFlags2::NONE)
+                .src_access_mask(vk::AccessFlags2::NONE)
+                .old_layout(vk::ImageLayout::UNDEFINED);
+        } else {
+            depth_barrier = depth_barrier
+                .src_stage_mask(vk::PipelineStageFlags2::LATE_FRAGMENT_TESTS)
+                .src_access_mask(vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                .old_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+        }
+
+        let image_barriers = [
+            vk::ImageMemoryBarrier2::builder()
+                .src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                .src_access_mask(vk::AccessFlags2::MEMORY_WRITE)
+                .dst_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
+                .dst_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_READ | vk::AccessFlags2::COLOR_ATTACHMENT_WRITE)
+                .old_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .image(self.color_image.get_image_handle())
+                .subresource_range(make_subresource_range(vk::ImageAspectFlags::COLOR))
+                .build(),
+            depth_barrier.build(),
+        ];
+
+        let info = vk::DependencyInfo::builder()
+            .image_memory_barriers(&image_barriers);
+
+        let clear_values = [
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0
+                }
+            },
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0f32, 0f32, 0f32, 0f32],
+                }
+            },
+        ];
+        let render_pass = if first_use { self.clear_render_pass } else { self.load_render_pass };
+        let begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(render_pass)
+            .framebuffer(self.framebuffer)
+            .render_area(make_full_rect(self.size))
+            .clear_values(&clear_values);
+
+        unsafe {
+            device.synchronization_2_khr().cmd_pipeline_barrier2(cmd, &info);
+            device.vk().cmd_begin_render_pass(cmd, &begin_info, vk::SubpassContents::INLINE);
+        }
+    }
+
+    /// Ends the render pass started by [`RenderTarget::begin_render_pass`] and records the
+    /// barriers necessary for the color image to be used like any other [`GlobalImage`].
+    pub(super) fn end_render_pass(&self, cmd: vk::CommandBuffer) {
+        let device = self.share.get_device();
+
+        let image_barrier = vk::ImageMemoryBarrier2::builder()
+            .src_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+            .dst_access_mask(vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE)
+            .old_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .image(self.color_image.get_image_handle())
+            .subresource_range(make_subresource_range(vk::ImageAspectFlags::COLOR));
+
+        let info = vk::DependencyInfo::builder()
+            .image_memory_barriers(std::slice::from_ref(&image_barrier));
+
+        unsafe {
+            device.vk().cmd_end_render_pass(cmd);
+            device.synchronization_2_khr().cmd_pipeline_barrier2(cmd, &info);
+        }
+    }
+
+    fn create_depth_image(device: &DeviceContext, size: Vec2u32) -> Result<(vk::Image, Allocation), GlobalObjectCreateError> {
+        let info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(Self::DEPTH_FORMAT)
+            .extent(vk::Extent3D {
+                width: size[0],
+                height: size[1],
+                depth: 1
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+
+        unsafe {
+            device.get_allocator().create_gpu_image(&info, &format_args!("RenderTargetDepth"))
+        }.ok_or(GlobalObjectCreateError::Allocation)
+    }
+
+    fn create_image_view(device: &DeviceContext, image: vk::Image, format: vk::Format, aspect_mask: vk::ImageAspectFlags) -> Result<vk::ImageView, GlobalObjectCreateError> {
+        let info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .components(vk::ComponentMapping {
+                r: vk::ComponentSwizzle::IDENTITY,
+                g: vk::ComponentSwizzle::IDENTITY,
+                b: vk::ComponentSwizzle::IDENTITY,
+                a: vk::ComponentSwizzle::IDENTITY
+            })
+            .subresource_range(make_subresource_range(aspect_mask));
+
+        let image_view = unsafe {
+            device.vk().create_image_view(&info, None)
+        }.map_err(|err| {
+            log::error!("vkCreateImageView returned {:?} in RenderTarget::create_image_view", err);
+            err
+        })?;
+
+        Ok(image_view)
+    }
+
+    fn create_render_pass(device: &DeviceContext, depth_load_op: vk::AttachmentLoadOp) -> Result<vk::RenderPass, GlobalObjectCreateError> {
+        // Layout transitions into the attachment layouts are done by begin_render_pass
+        let attachments = [
+            vk::AttachmentDescription::builder()
+                .format(Self::DEPTH_FORMAT)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(depth_load_op)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .initial_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(Self::COLOR_FORMAT)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::LOAD)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .initial_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .build(),
+        ];
+
+        let depth = vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+        };
+
+        let color = [
+            vk::AttachmentReference {
+                attachment: 1,
+                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+            },
+        ];
+
+        let subpasses = [
+            vk::SubpassDescription::builder()
+                .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+                .color_attachments(&color)
+                .depth_stencil_attachment(&depth)
+                .build(),
+        ];
+
+        let info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses);
+
+        let render_pass = unsafe {
+            device.vk().create_render_pass(&info, None)
+        }.map_err(|err| {
+            log::error!("vkCreateRenderPass returned {:?} in RenderTarget::create_render_pass", err);
+            err
+        })?;
+
+        Ok(render_pass)
+    }
+
+    fn create_framebuffer(device: &DeviceContext, size: Vec2u32, depth_view: vk::ImageView, color_view: vk::ImageView, render_pass: vk::RenderPass) -> Result<vk::Framebuffer, GlobalObjectCreateError> {
+        let attachments = [
+            depth_view, color_view
+        ];
+
+        let info = vk::FramebufferCreateInfo::builder()
+            .render_pass(render_pass)
+            .attachments(&attachments)
+            .width(size[0])
+            .height(size[1])
+            .layers(1);
+
+        let framebuffer = unsafe {
+            device.vk().create_framebuffer(&info, None)
+        }.map_err(|err| {
+            log::error!("vkCreateFramebuffer returned {:?} in RenderTarget::create_framebuffer", err);
+            err
+        })?;
+
+        Ok(framebuffer)
+    }
+}
+
+impl Drop for RenderTarget {
+    fn drop(&mut self) {
+        let device = self.share.get_device();
+        unsafe {
+            if self.framebuffer != vk::Framebuffer::null() {
+                device.vk().destroy_framebuffer(self.framebuffer, None);
+            }
+            if self.load_render_pass != vk::RenderPass::null() {
+                device.vk().destroy_render_pass(self.load_render_pass, None);
+            }
+            if self.clear_render_pass != vk::RenderPass::null() {
+                device.vk().destroy_render_pass(self.clear_render_pass, None);
+            }
+            if self.depth_view != vk::ImageView::null() {
+                device.vk().destroy_image_view(self.depth_view, None);
+            }
+            if let Some(allocation) = self.depth_allocation.take() {
+                device.get_allocator().destroy_image(self.depth_image, allocation);
+            }
+            if self.color_view != vk::ImageView::null() {
+                device.vk().destroy_image_view(self.color_view, None);
+            }
+        }
+    }
+}
+
+#[inline]
+fn make_subresource_range(aspect_mask: vk::ImageAspectFlags) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1
+    }
+}
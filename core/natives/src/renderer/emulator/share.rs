@@ -5,13 +5,18 @@ use std::collections::{HashMap, VecDeque};
 use std::sync::atomic::AtomicU64;
 use ash::vk;
 
+use crate::renderer::emulator::cull::FrustumCullPipeline;
+use crate::renderer::emulator::deferred::{DeferredObject, DeferredQueue};
 use crate::renderer::emulator::descriptors::DescriptorPool;
 use crate::renderer::emulator::worker::WorkerTask;
 use crate::renderer::emulator::mc_shaders::{McUniform, Shader, ShaderId, VertexFormat};
 
 use crate::prelude::*;
 use crate::renderer::emulator::immediate::{ImmediateBuffer, ImmediatePool};
+use crate::renderer::emulator::mesh_arena::MeshArena;
 use crate::renderer::emulator::staging::StagingMemoryPool;
+use crate::renderer::emulator::topology::QuadIndexCache;
+use crate::renderer::emulator::PassId;
 
 pub(super) struct Share {
     id: UUID,
@@ -19,9 +24,13 @@ pub(super) struct Share {
     current_pass: AtomicU64,
 
     staging_memory: Mutex<StagingMemoryPool>,
+    mesh_arena: Mutex<MeshArena>,
     immediate_buffers: ImmediatePool,
     shader_database: Mutex<HashMap<ShaderId, Arc<Shader>>>,
     descriptors: Mutex<DescriptorPool>,
+    quad_indices: QuadIndexCache,
+    cull_pipeline: FrustumCullPipeline,
+    deferred_objects: Mutex<DeferredQueue<DeferredObject>>,
     channel: Mutex<Channel>,
     signal: Condvar,
 }
@@ -33,8 +42,10 @@ impl Share {
         let queue = device.get_main_queue();
 
         let staging_memory = StagingMemoryPool::new(device.clone());
+        let mesh_arena = MeshArena::new(device.clone());
         let immediate_buffers = ImmediatePool::new(device.clone());
         let descriptors = Mutex::new(DescriptorPool::new(device.clone()));
+        let cull_pipeline = FrustumCullPipeline::new(device.clone());
 
         Self {
             id: UUID::new(),
@@ -42,9 +53,13 @@ impl Share {
             current_pass: AtomicU64::new(0),
 
             staging_memory: Mutex::new(staging_memory),
+            mesh_arena: Mutex::new(mesh_arena),
             immediate_buffers,
             shader_database: Mutex::new(HashMap::new()),
             descriptors,
+            quad_indices: QuadIndexCache::new(),
+            cull_pipeline,
+            deferred_objects: Mutex::new(DeferredQueue::new()),
             channel: Mutex::new(Channel::new()),
             signal: Condvar::new(),
         }
@@ -58,8 +73,20 @@ impl Share {
         &self.staging_memory
     }
 
-    pub(super) fn create_shader(&self, vertex_format: &VertexFormat, used_uniforms: McUniform) -> ShaderId {
-        let shader = Shader::new(*vertex_format, used_uniforms);
+    pub(super) fn get_mesh_arena(&self) -> &Mutex<MeshArena> {
+        &self.mesh_arena
+    }
+
+    pub(super) fn get_quad_index_cache(&self) -> &QuadIndexCache {
+        &self.quad_indices
+    }
+
+    pub(super) fn get_cull_pipeline(&self) -> &FrustumCullPipeline {
+        &self.cull_pipeline
+    }
+
+    pub(super) fn create_shader(&self, vertex_format: &VertexFormat, used_uniforms: McUniform, alpha_cutoff: f32) -> ShaderId {
+        let shader = Shader::new(*vertex_format, used_uniforms, alpha_cutoff);
         let id = shader.get_id();
 
         let mut guard = self.shader_database.lock().unwrap();
@@ -87,6 +114,12 @@ impl Share {
         }
     }
 
+    /// Returns the id of the active pass or the id of the last pass if no pass is active.
+    pub(super) fn get_last_pass_id(&self) -> PassId {
+        let id = self.current_pass.load(std::sync::atomic::Ordering::Acquire);
+        PassId::from_raw(id & !Self::PASS_ID_ACTIVE_BIT)
+    }
+
     pub(super) fn try_start_pass_id(&self) -> Option<u64> {
         loop {
             let old_id = self.current_pass.load(std::sync::atomic::Ordering::Acquire);
@@ -132,6 +165,52 @@ impl Share {
         self.descriptors.lock().unwrap().allocate_uniform(data)
     }
 
+    /// Destroys the vulkan objects once the pass `used_in` has completed on the gpu.
+    pub(super) fn destroy_after_pass(&self, used_in: PassId, object: DeferredObject) {
+        let object = self.deferred_objects.lock().unwrap().push(used_in, object);
+        if let Some(object) = object {
+            unsafe { object.destroy(&self.device, &self.mesh_arena) };
+        }
+    }
+
+    #[cfg(any(test, feature = "__internal_doc_test"))]
+    pub(super) fn has_pending_deferred_objects(&self) -> bool {
+        !self.deferred_objects.lock().unwrap().is_empty()
+    }
+
+    /// Called by the worker once a pass has completed on the gpu. Destroys all deferred objects
+    /// which are no longer in use and compacts the mesh arena.
+    pub(super) fn on_pass_complete(&self, pass: PassId) {
+        let objects = self.deferred_objects.lock().unwrap().complete_pass(pass);
+        for object in objects {
+            unsafe { object.destroy(&self.device, &self.mesh_arena) };
+        }
+
+        self.compact_mesh_arena();
+    }
+
+    /// Relocates the meshes of a sparsely used mesh arena buffer into other buffers. The buffer is
+    /// destroyed once all old ranges have been freed.
+    fn compact_mesh_arena(&self) {
+        let ranges = match self.mesh_arena.lock().unwrap().find_sparse_block() {
+            Some(ranges) => ranges,
+            None => return,
+        };
+
+        // The arena lock must not be held while references to meshes are dropped
+        let meshes: Vec<_> = ranges.into_iter().filter_map(|(allocation, alignment, weak)| {
+            let mesh = weak.upgrade()?;
+            Some((mesh, allocation, alignment, weak))
+        }).collect();
+
+        for (mesh, allocation, alignment, weak) in &meshes {
+            let dst = self.mesh_arena.lock().unwrap().allocate_relocation(allocation, *alignment, weak);
+            if let Some(dst) = dst {
+                mesh.relocate(dst);
+            }
+        }
+    }
+
     pub(super) fn push_task(&self, task: WorkerTask) {
         self.channel.lock().unwrap().queue.push_back(task);
         self.signal.notify_one();
@@ -168,6 +247,19 @@ impl Share {
     }
 }
 
+impl Drop for Share {
+    fn drop(&mut self) {
+        // The share is only dropped once the worker and all global objects have been dropped
+        let objects = self.deferred_objects.get_mut().unwrap().drain();
+        if !objects.is_empty() {
+            unsafe { self.device.vk().device_wait_idle() }.unwrap();
+            for object in objects {
+                unsafe { object.destroy(&self.device, &self.mesh_arena) };
+            }
+        }
+    }
+}
+
 impl PartialEq for Share {
     fn eq(&self, other: &Self) -> bool {
         self.id.eq(&other.id)
@@ -52,7 +52,7 @@ impl StagingMemoryPool {
         } else {
             self.create_new_buffer(size);
             let (alloc, slot_id) = self.current_buffer.try_allocate(size, alignment).unwrap();
-            (alloc, StagingAllocationId{ buffer_id: 0, slot_id })
+            (alloc, StagingAllocationId{ buffer_id: self.current_buffer_id, slot_id })
         }
     }
 
@@ -76,6 +76,27 @@ impl StagingMemoryPool {
         }
     }
 
+    /// Makes data written by the device to a range of a staging allocation visible to the host.
+    /// The offset is relative to the start of the staging buffer.
+    pub(super) fn invalidate(&self, allocation: &StagingAllocationId, offset: vk::DeviceSize, size: vk::DeviceSize) {
+        let buffer = if allocation.buffer_id == self.current_buffer_id {
+            &self.current_buffer
+        } else {
+            self.old_buffers.iter().find(|(id, _)| *id == allocation.buffer_id).map(|(_, buffer)| buffer).unwrap_or_else(|| {
+                log::error!("Attempted to invalidate staging allocation of unknown buffer {:?}", allocation.buffer_id);
+                panic!()
+            })
+        };
+
+        // Staging buffers are bound to the start of their allocation
+        unsafe {
+            self.device.get_allocator().invalidate_allocation(buffer.allocation, offset, size)
+        }.unwrap_or_else(|err| {
+            log::error!("Failed to invalidate staging memory {:?}", err);
+            panic!()
+        });
+    }
+
     fn create_new_buffer(&mut self, additional_size: vk::DeviceSize) {
         let mut usage_sum = self.current_buffer.used_byte_count();
         for (_, old) in &self.old_buffers {
@@ -0,0 +1,559 @@
+//! Emulation of primitive topologies not natively supported by vulkan.
+//!
+//! Minecraft submits most of its geometry as quads and some as triangle fans. These are converted
+//! into triangle lists by rewriting the index data when a mesh is uploaded. Quad meshes with
+//! sequential indices (by far the most common case) do not store any index data at all and instead
+//! use a shared index buffer.
+//!
+//! Minecraft lines are drawn as screen space quads since wide lines are not supported on many
+//! devices. Every line vertex is submitted twice by minecraft and the vertex shader offsets the
+//! copies in opposite directions (see `mc_transform_line` in `mc_uniforms.glsl`).
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use ash::vk;
+
+use crate::renderer::emulator::{GlobalMesh, MeshData};
+use crate::renderer::emulator::share::Share;
+
+/// The primitive topology of a mesh.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum PrimitiveTopology {
+    PointList,
+    LineList,
+    LineStrip,
+    TriangleList,
+    TriangleStrip,
+    /// Emulated using a triangle list.
+    TriangleFan,
+    /// Emulated using a triangle list. Every 4 vertices form a quad.
+    Quads,
+    /// Minecraft lines. Every 4 vertices form a line segment with each end point submitted twice.
+    /// The normal attribute must contain the direction of the line. Emulated using a triangle list
+    /// with each segment expanded into a screen space quad of `LineWidth` pixels.
+    WideLineList,
+    /// Minecraft line strips. Every vertex is submitted twice with the normal attribute containing
+    /// the direction of the line. Emulated like [`PrimitiveTopology::WideLineList`].
+    WideLineStrip,
+}
+
+impl PrimitiveTopology {
+    /// The raw value used for [`PrimitiveTopology::Quads`]. All topologies not natively supported by
+    /// vulkan use raw values starting at 1000. All other topologies use the raw value of the
+    /// corresponding [`vk::PrimitiveTopology`].
+    pub const RAW_QUADS: i32 = 1000;
+    pub const RAW_WIDE_LINE_LIST: i32 = 1001;
+    pub const RAW_WIDE_LINE_STRIP: i32 = 1002;
+
+    pub fn from_raw(raw: i32) -> Option<Self> {
+        match raw {
+            Self::RAW_QUADS => Some(Self::Quads),
+            Self::RAW_WIDE_LINE_LIST => Some(Self::WideLineList),
+            Self::RAW_WIDE_LINE_STRIP => Some(Self::WideLineStrip),
+            _ => match vk::PrimitiveTopology::from_raw(raw) {
+                vk::PrimitiveTopology::POINT_LIST => Some(Self::PointList),
+                vk::PrimitiveTopology::LINE_LIST => Some(Self::LineList),
+                vk::PrimitiveTopology::LINE_STRIP => Some(Self::LineStrip),
+                vk::PrimitiveTopology::TRIANGLE_LIST => Some(Self::TriangleList),
+                vk::PrimitiveTopology::TRIANGLE_STRIP => Some(Self::TriangleStrip),
+                vk::PrimitiveTopology::TRIANGLE_FAN => Some(Self::TriangleFan),
+                _ => None,
+            }
+        }
+    }
+
+    /// Returns true if this topology is not passed to vulkan directly.
+    pub fn is_emulated(&self) -> bool {
+        match self {
+            Self::TriangleFan |
+            Self::Quads |
+            Self::WideLineList |
+            Self::WideLineStrip => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if draws using this topology must expand lines into screen space quads.
+    pub fn is_wide_line(&self) -> bool {
+        match self {
+            Self::WideLineList | Self::WideLineStrip => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the vulkan topology used to draw meshes of this topology.
+    pub fn get_native(&self) -> vk::PrimitiveTopology {
+        match self {
+            Self::PointList => vk::PrimitiveTopology::POINT_LIST,
+            Self::LineList => vk::PrimitiveTopology::LINE_LIST,
+            Self::LineStrip => vk::PrimitiveTopology::LINE_STRIP,
+            Self::TriangleList |
+            Self::TriangleFan |
+            Self::Quads |
+            Self::WideLineList |
+            Self::WideLineStrip => vk::PrimitiveTopology::TRIANGLE_LIST,
+            Self::TriangleStrip => vk::PrimitiveTopology::TRIANGLE_STRIP,
+        }
+    }
+}
+
+/// Returns the size in bytes of a single index of the index type.
+pub(super) fn get_index_type_size(index_type: vk::IndexType) -> u32 {
+    match index_type {
+        vk::IndexType::UINT8_EXT => 1u32,
+        vk::IndexType::UINT16 => 2u32,
+        vk::IndexType::UINT32 => 4u32,
+        _ => {
+            log::error!("Invalid index type");
+            panic!()
+        }
+    }
+}
+
+/// The index data of a mesh after topology emulation has been applied.
+pub(super) enum EmulatedIndices<'a> {
+    /// The mesh uses its own index data. If the topology is emulated the data has been rewritten or
+    /// generated.
+    Indexed {
+        data: Cow<'a, [u8]>,
+        index_type: vk::IndexType,
+        index_count: u32,
+    },
+
+    /// The mesh consists of quads with sequential indices and should use a shared quad index buffer.
+    SequentialQuads {
+        pattern: QuadPattern,
+        quad_count: u32,
+    },
+
+    /// The mesh has no index data and can be drawn without an index buffer.
+    NonIndexed {
+        vertex_count: u32,
+    }
+}
+
+impl<'a> EmulatedIndices<'a> {
+    /// Applies topology emulation to the index data of a mesh.
+    pub(super) fn new(data: &MeshData<'a>) -> Self {
+        let index_count = data.index_count as usize;
+        let (indices, index_type) = if let Some(index_data) = data.index_data {
+            let index_size = data.get_index_size() as usize;
+            if index_data.len() < index_count * index_size {
+                log::error!("Mesh index data is too small. Expected at least {:?} bytes but got {:?}", index_count * index_size, index_data.len());
+                panic!()
+            }
+            (IndexReader::new(Some(&index_data[..(index_count * index_size)]), index_size), data.index_type)
+        } else {
+            // Generated indices are always uint32
+            (IndexReader::new(None, 4), vk::IndexType::UINT32)
+        };
+        let index_size = indices.index_size;
+
+        match data.primitive_topology {
+            PrimitiveTopology::Quads |
+            PrimitiveTopology::WideLineList => {
+                let pattern = if data.primitive_topology == PrimitiveTopology::Quads {
+                    QuadPattern::Quads
+                } else {
+                    QuadPattern::Lines
+                };
+
+                if index_count % 4 != 0 {
+                    log::warn!("{:?} mesh index count {:?} is not a multiple of 4. Trailing indices will be ignored", data.primitive_topology, index_count);
+                }
+                let quad_count = index_count / 4;
+
+                if quad_count != 0 && indices.is_sequential(quad_count * 4) {
+                    return Self::SequentialQuads {
+                        pattern,
+                        quad_count: quad_count as u32,
+                    };
+                }
+
+                let mut writer = IndexWriter::new(quad_count * 6, index_size);
+                for quad in 0..quad_count {
+                    let base = quad * 4;
+                    for offset in pattern.get_offsets() {
+                        writer.push(indices.get(base + offset as usize));
+                    }
+                }
+
+                Self::Indexed {
+                    data: Cow::Owned(writer.data),
+                    index_type,
+                    index_count: (quad_count * 6) as u32,
+                }
+            }
+            PrimitiveTopology::WideLineStrip => {
+                // Every pair of consecutive (duplicated) points forms one line segment
+                if index_count % 2 != 0 {
+                    log::warn!("WideLineStrip mesh index count {:?} is not a multiple of 2. Trailing indices will be ignored", index_count);
+                }
+                let segment_count = (index_count / 2).saturating_sub(1);
+
+                let mut writer = IndexWriter::new(segment_count * 6, index_size);
+                for segment in 0..segment_count {
+                    let base = segment * 2;
+                    for offset in QuadPattern::Lines.get_offsets() {
+                        writer.push(indices.get(base + offset as usize));
+                    }
+                }
+
+                Self::Indexed {
+                    data: Cow::Owned(writer.data),
+                    index_type,
+                    index_count: (segment_count * 6) as u32,
+                }
+            }
+            PrimitiveTopology::TriangleFan => {
+                let triangle_count = index_count.saturating_sub(2);
+
+                let mut writer = IndexWriter::new(triangle_count * 3, index_size);
+                for triangle in 0..triangle_count {
+                    writer.push(indices.get(0));
+                    writer.push(indices.get(triangle + 1));
+                    writer.push(indices.get(triangle + 2));
+                }
+
+                Self::Indexed {
+                    data: Cow::Owned(writer.data),
+                    index_type,
+                    index_count: (triangle_count * 3) as u32,
+                }
+            }
+            _ => {
+                if let Some(index_data) = data.index_data {
+                    Self::Indexed {
+                        data: Cow::Borrowed(index_data),
+                        index_type,
+                        index_count: data.index_count,
+                    }
+                } else {
+                    Self::NonIndexed {
+                        vertex_count: data.index_count,
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the 4 vertex indices of every quad of a mesh. If the mesh has no index data the quads
+/// are sequential.
+pub(super) fn read_quads(data: &MeshData) -> Vec<[u32; 4]> {
+    let index_count = data.index_count as usize;
+    let indices = match data.index_data {
+        Some(index_data) => {
+            let index_size = data.get_index_size() as usize;
+            if index_data.len() < index_count * index_size {
+                log::error!("Mesh index data is too small. Expected at least {:?} bytes but got {:?}", index_count * index_size, index_data.len());
+                panic!()
+            }
+            IndexReader::new(Some(index_data), index_size)
+        }
+        None => IndexReader::new(None, 4),
+    };
+
+    (0..(index_count / 4)).map(|quad| {
+        let base = quad * 4;
+        [indices.get(base), indices.get(base + 1), indices.get(base + 2), indices.get(base + 3)]
+    }).collect()
+}
+
+/// Defines how the 4 vertices of a quad are used to form 2 triangles.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub(super) enum QuadPattern {
+    /// The vertices are in the order they appear around the quad.
+    Quads,
+    /// The vertices are the 2 copies of the start followed by the 2 copies of the end point of a
+    /// line segment.
+    Lines,
+}
+
+impl QuadPattern {
+    /// Returns the offsets of the vertices forming the 2 triangles. Matches minecrafts sequential
+    /// index buffers.
+    pub(super) fn get_offsets(&self) -> [u32; 6] {
+        match self {
+            Self::Quads => [0, 1, 2, 2, 3, 0],
+            Self::Lines => [0, 1, 2, 3, 2, 1],
+        }
+    }
+}
+
+/// Caches shared index buffers for sequential quad meshes.
+///
+/// Buffers are allocated in power of 2 sizes. Meshes using a buffer keep a strong reference to it
+/// so unused buffers are destroyed and recreated on demand.
+pub(super) struct QuadIndexCache {
+    buffers: Mutex<HashMap<(QuadPattern, u32), Weak<GlobalMesh>>>,
+}
+
+impl QuadIndexCache {
+    /// The minimum number of quads in a shared index buffer.
+    const MIN_QUAD_COUNT_LOG2: u32 = 12;
+
+    pub(super) fn new() -> Self {
+        Self {
+            buffers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a mesh containing uint32 indices for at least `quad_count` sequential quads.
+    pub(super) fn get_quad_indices(share: &Arc<Share>, pattern: QuadPattern, quad_count: u32) -> Arc<GlobalMesh> {
+        let size_log2 = Self::get_size_log2(quad_count);
+
+        let mut guard = share.get_quad_index_cache().buffers.lock().unwrap_or_else(|_| {
+            log::error!("Poisoned buffers mutex in QuadIndexCache::get_quad_indices");
+            panic!()
+        });
+
+        if let Some(mesh) = guard.get(&(pattern, size_log2)).and_then(Weak::upgrade) {
+            return mesh;
+        }
+
+        let quads = 1u64 << size_log2;
+        let index_data = Self::generate_indices(pattern, quads);
+
+        let data = MeshData {
+            vertex_data: &[],
+            index_data: Some(&index_data),
+            vertex_stride: 0,
+            index_count: (quads * 6) as u32,
+            index_type: vk::IndexType::UINT32,
+            primitive_topology: PrimitiveTopology::TriangleList,
+        };
+        let mesh = GlobalMesh::new_fixed(share.clone(), &data).unwrap_or_else(|err| {
+            log::error!("Failed to create shared quad index buffer {:?}", err);
+            panic!()
+        });
+
+        guard.insert((pattern, size_log2), Arc::downgrade(&mesh));
+        mesh
+    }
+
+    /// Returns the log2 of the number of quads in the shared index buffer used for a mesh of
+    /// `quad_count` quads.
+    fn get_size_log2(quad_count: u32) -> u32 {
+        std::cmp::max(quad_count.next_power_of_two().trailing_zeros(), Self::MIN_QUAD_COUNT_LOG2)
+    }
+
+    /// Generates uint32 indices for `quads` sequential quads.
+    fn generate_indices(pattern: QuadPattern, quads: u64) -> Vec<u8> {
+        let mut writer = IndexWriter::new((quads * 6) as usize, 4);
+        for quad in 0..quads {
+            let base = (quad * 4) as u32;
+            for offset in pattern.get_offsets() {
+                writer.push(base + offset);
+            }
+        }
+        writer.data
+    }
+}
+
+/// Reads index data of any size. If no index data is present sequential indices are returned.
+struct IndexReader<'a> {
+    data: Option<&'a [u8]>,
+    index_size: usize,
+}
+
+impl<'a> IndexReader<'a> {
+    fn new(data: Option<&'a [u8]>, index_size: usize) -> Self {
+        Self {
+            data,
+            index_size,
+        }
+    }
+
+    fn get(&self, index: usize) -> u32 {
+        let data = match self.data {
+            Some(data) => data,
+            None => return index as u32,
+        };
+
+        let bytes = &data[(index * self.index_size)..((index + 1) * self.index_size)];
+        match self.index_size {
+            1 => bytes[0] as u32,
+            2 => u16::from_ne_bytes([bytes[0], bytes[1]]) as u32,
+            _ => u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        }
+    }
+
+    /// Returns true if the first `count` indices are sequential starting at 0.
+    fn is_sequential(&self, count: usize) -> bool {
+        self.data.is_none() || (0..count).all(|i| self.get(i) == i as u32)
+    }
+}
+
+struct IndexWriter {
+    data: Vec<u8>,
+    index_size: usize,
+}
+
+impl IndexWriter {
+    fn new(index_count: usize, index_size: usize) -> Self {
+        Self {
+            data: Vec::with_capacity(index_count * index_size),
+            index_size,
+        }
+    }
+
+    fn push(&mut self, index: u32) {
+        match self.index_size {
+            1 => self.data.push(index as u8),
+            2 => self.data.extend_from_slice(&(index as u16).to_ne_bytes()),
+            _ => self.data.extend_from_slice(&index.to_ne_bytes()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::cast_slice;
+
+    use super::*;
+
+    fn make_mesh_data<'a>(primitive_topology: PrimitiveTopology, index_data: Option<&'a [u8]>, index_type: vk::IndexType, index_count: u32) -> MeshData<'a> {
+        MeshData {
+            vertex_data: &[],
+            index_data,
+            vertex_stride: 0,
+            index_count,
+            index_type,
+            primitive_topology,
+        }
+    }
+
+    fn read_indices(data: &[u8], index_type: vk::IndexType) -> Vec<u32> {
+        let index_size = get_index_type_size(index_type) as usize;
+        let reader = IndexReader::new(Some(data), index_size);
+        (0..(data.len() / index_size)).map(|index| reader.get(index)).collect()
+    }
+
+    /// Applies topology emulation and returns the generated indices. Panics if the result is not
+    /// [`EmulatedIndices::Indexed`].
+    fn emulate_indexed(data: &MeshData) -> (Vec<u32>, vk::IndexType) {
+        match EmulatedIndices::new(data) {
+            EmulatedIndices::Indexed { data, index_type, index_count } => {
+                let indices = read_indices(&data, index_type);
+                assert_eq!(indices.len(), index_count as usize);
+                (indices, index_type)
+            }
+            EmulatedIndices::SequentialQuads { .. } => panic!("Expected indexed result but got sequential quads"),
+            EmulatedIndices::NonIndexed { .. } => panic!("Expected indexed result but got non indexed"),
+        }
+    }
+
+    fn assert_sequential_quads(data: &MeshData, expected_pattern: QuadPattern, expected_quad_count: u32) {
+        match EmulatedIndices::new(data) {
+            EmulatedIndices::SequentialQuads { pattern, quad_count } => {
+                assert_eq!(pattern, expected_pattern);
+                assert_eq!(quad_count, expected_quad_count);
+            }
+            _ => panic!("Expected sequential quads"),
+        }
+    }
+
+    #[test]
+    fn test_quads() {
+        let indices: [u16; 8] = [4, 5, 6, 7, 0, 1, 2, 3];
+        let data = make_mesh_data(PrimitiveTopology::Quads, Some(cast_slice(&indices)), vk::IndexType::UINT16, 8);
+        assert_eq!(emulate_indexed(&data), (vec![4, 5, 6, 6, 7, 4, 0, 1, 2, 2, 3, 0], vk::IndexType::UINT16));
+
+        let indices: [u32; 4] = [3, 2, 1, 0];
+        let data = make_mesh_data(PrimitiveTopology::Quads, Some(cast_slice(&indices)), vk::IndexType::UINT32, 4);
+        assert_eq!(emulate_indexed(&data), (vec![3, 2, 1, 1, 0, 3], vk::IndexType::UINT32));
+    }
+
+    #[test]
+    fn test_quads_trailing_indices() {
+        let indices: [u32; 6] = [1, 2, 3, 4, 5, 6];
+        let data = make_mesh_data(PrimitiveTopology::Quads, Some(cast_slice(&indices)), vk::IndexType::UINT32, 6);
+        assert_eq!(emulate_indexed(&data), (vec![1, 2, 3, 3, 4, 1], vk::IndexType::UINT32));
+
+        let data = make_mesh_data(PrimitiveTopology::Quads, None, vk::IndexType::UINT32, 7);
+        assert_sequential_quads(&data, QuadPattern::Quads, 1);
+
+        // Less than a single quad
+        let data = make_mesh_data(PrimitiveTopology::Quads, None, vk::IndexType::UINT32, 3);
+        assert_eq!(emulate_indexed(&data), (vec![], vk::IndexType::UINT32));
+    }
+
+    #[test]
+    fn test_sequential_quads() {
+        let data = make_mesh_data(PrimitiveTopology::Quads, None, vk::IndexType::UINT16, 8);
+        assert_sequential_quads(&data, QuadPattern::Quads, 2);
+
+        let indices: [u16; 8] = [0, 1, 2, 3, 4, 5, 6, 7];
+        let data = make_mesh_data(PrimitiveTopology::Quads, Some(cast_slice(&indices)), vk::IndexType::UINT16, 8);
+        assert_sequential_quads(&data, QuadPattern::Quads, 2);
+
+        let data = make_mesh_data(PrimitiveTopology::WideLineList, None, vk::IndexType::UINT32, 8);
+        assert_sequential_quads(&data, QuadPattern::Lines, 2);
+    }
+
+    #[test]
+    fn test_wide_lines() {
+        let indices: [u32; 4] = [10, 11, 12, 13];
+        let data = make_mesh_data(PrimitiveTopology::WideLineList, Some(cast_slice(&indices)), vk::IndexType::UINT32, 4);
+        assert_eq!(emulate_indexed(&data), (vec![10, 11, 12, 13, 12, 11], vk::IndexType::UINT32));
+    }
+
+    #[test]
+    fn test_wide_line_strip() {
+        let data = make_mesh_data(PrimitiveTopology::WideLineStrip, None, vk::IndexType::UINT16, 6);
+        assert_eq!(emulate_indexed(&data), (vec![0, 1, 2, 3, 2, 1, 2, 3, 4, 5, 4, 3], vk::IndexType::UINT32));
+
+        let indices: [u16; 4] = [8, 9, 2, 3];
+        let data = make_mesh_data(PrimitiveTopology::WideLineStrip, Some(cast_slice(&indices)), vk::IndexType::UINT16, 4);
+        assert_eq!(emulate_indexed(&data), (vec![8, 9, 2, 3, 2, 9], vk::IndexType::UINT16));
+
+        // A single point does not form a segment
+        let data = make_mesh_data(PrimitiveTopology::WideLineStrip, None, vk::IndexType::UINT32, 2);
+        assert_eq!(emulate_indexed(&data), (vec![], vk::IndexType::UINT32));
+    }
+
+    #[test]
+    fn test_triangle_fan() {
+        let data = make_mesh_data(PrimitiveTopology::TriangleFan, None, vk::IndexType::UINT32, 5);
+        assert_eq!(emulate_indexed(&data), (vec![0, 1, 2, 0, 2, 3, 0, 3, 4], vk::IndexType::UINT32));
+
+        let indices: [u16; 4] = [7, 8, 9, 10];
+        let data = make_mesh_data(PrimitiveTopology::TriangleFan, Some(cast_slice(&indices)), vk::IndexType::UINT16, 4);
+        assert_eq!(emulate_indexed(&data), (vec![7, 8, 9, 7, 9, 10], vk::IndexType::UINT16));
+    }
+
+    #[test]
+    fn test_native_topologies() {
+        let indices: [u16; 4] = [3, 1, 2, 0];
+        let data = make_mesh_data(PrimitiveTopology::TriangleStrip, Some(cast_slice(&indices)), vk::IndexType::UINT16, 4);
+        assert_eq!(emulate_indexed(&data), (vec![3, 1, 2, 0], vk::IndexType::UINT16));
+
+        let data = make_mesh_data(PrimitiveTopology::LineStrip, None, vk::IndexType::UINT32, 5);
+        match EmulatedIndices::new(&data) {
+            EmulatedIndices::NonIndexed { vertex_count } => assert_eq!(vertex_count, 5),
+            _ => panic!("Expected non indexed result"),
+        }
+    }
+
+    #[test]
+    fn test_quad_index_cache_size() {
+        assert_eq!(QuadIndexCache::get_size_log2(0), QuadIndexCache::MIN_QUAD_COUNT_LOG2);
+        assert_eq!(QuadIndexCache::get_size_log2(1), QuadIndexCache::MIN_QUAD_COUNT_LOG2);
+        assert_eq!(QuadIndexCache::get_size_log2(1 << 12), 12);
+        assert_eq!(QuadIndexCache::get_size_log2((1 << 12) + 1), 13);
+        assert_eq!(QuadIndexCache::get_size_log2(1 << 13), 13);
+        assert_eq!(QuadIndexCache::get_size_log2((1 << 13) + 1), 14);
+    }
+
+    #[test]
+    fn test_quad_index_cache_indices() {
+        let indices = QuadIndexCache::generate_indices(QuadPattern::Quads, 2);
+        assert_eq!(read_indices(&indices, vk::IndexType::UINT32), vec![0, 1, 2, 2, 3, 0, 4, 5, 6, 6, 7, 4]);
+
+        let indices = QuadIndexCache::generate_indices(QuadPattern::Lines, 2);
+        assert_eq!(read_indices(&indices, vk::IndexType::UINT32), vec![0, 1, 2, 3, 2, 1, 4, 5, 6, 7, 6, 5]);
+    }
+}
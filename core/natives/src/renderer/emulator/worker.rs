@@ -16,23 +16,31 @@ use crate::renderer::emulator::immediate::ImmediateBuffer;
 use crate::renderer::emulator::pipeline::{EmulatorOutput, EmulatorPipeline, EmulatorPipelinePass, PipelineTask};
 
 use crate::prelude::*;
-use crate::renderer::emulator::global_objects::{GlobalImage, GlobalMesh};
+use crate::renderer::emulator::cull::CullTask;
+use crate::renderer::emulator::global_objects::{GlobalImage, GlobalMesh, ReadbackState};
+use crate::renderer::emulator::render_target::RenderTarget;
 use crate::renderer::emulator::mc_shaders::ShaderId;
+use crate::renderer::emulator::deferred::DeferredObject;
+use crate::renderer::emulator::mesh_arena::MeshArenaAllocation;
 use crate::renderer::emulator::share::{NextTaskResult, Share};
 use crate::renderer::emulator::staging::StagingAllocationId;
 
 pub(super) enum WorkerTask {
-    StartPass(PassId, Arc<dyn EmulatorPipeline>, Box<dyn EmulatorPipelinePass + Send>, Arc<GlobalImage>, vk::Sampler),
+    StartPass(PassId, Arc<dyn EmulatorPipeline>, Box<dyn EmulatorPipelinePass + Send>, Option<Arc<RenderTarget>>, Arc<GlobalImage>, vk::Sampler),
     EndPass(Box<ImmediateBuffer>),
     UseGlobalMesh(Arc<GlobalMesh>),
     UseGlobalImage(Arc<GlobalImage>),
     UseShader(ShaderId),
     UseOutput(Box<dyn EmulatorOutput + Send>),
     PipelineTask(PipelineTask),
+    CullBatch(CullTask),
     WriteGlobalMesh(GlobalMeshWrite, bool),
+    RelocateGlobalMesh(GlobalMeshRelocation),
     ClearGlobalImage(GlobalImageClear, bool),
     WriteGlobalImage(GlobalImageWrite),
     GenerateGlobalImageMipmaps(Arc<GlobalImage>, PassId),
+    ReadGlobalImage(GlobalImageRead),
+    CopyGlobalImage(GlobalImageCopy),
 }
 
 pub(super) struct GlobalMeshWrite {
@@ -41,9 +49,20 @@ pub(super) struct GlobalMeshWrite {
     pub(super) staging_range: (vk::DeviceSize, vk::DeviceSize),
     pub(super) staging_buffer: vk::Buffer,
     pub(super) dst_mesh: Arc<GlobalMesh>,
+    /// The range of the mesh at the time the write was queued. The regions are absolute offsets in its buffer.
+    pub(super) dst_range: MeshArenaAllocation,
     pub(super) regions: Box<[vk::BufferCopy]>,
 }
 
+/// Copies the data of a mesh to a new mesh arena range. The source range is freed once the pass the
+/// copy is submitted with has completed.
+pub(super) struct GlobalMeshRelocation {
+    pub(super) after_pass: PassId,
+    pub(super) mesh: Arc<GlobalMesh>,
+    pub(super) src: MeshArenaAllocation,
+    pub(super) dst: MeshArenaAllocation,
+}
+
 pub(super) struct GlobalImageWrite {
     pub(super) after_pass: PassId,
     pub(super) staging_allocation: StagingAllocationId,
@@ -53,6 +72,27 @@ pub(super) struct GlobalImageWrite {
     pub(super) regions: Box<[vk::BufferImageCopy]>,
 }
 
+pub(super) struct GlobalImageRead {
+    pub(super) after_pass: PassId,
+    pub(super) staging_range: (vk::DeviceSize, vk::DeviceSize),
+    pub(super) staging_buffer: vk::Buffer,
+    pub(super) src_image: Arc<GlobalImage>,
+    pub(super) region: vk::BufferImageCopy,
+    pub(super) readback: Arc<ReadbackState>,
+}
+
+pub(super) enum GlobalImageCopyRegion {
+    Copy(vk::ImageCopy),
+    Blit(vk::ImageBlit, vk::Filter),
+}
+
+pub(super) struct GlobalImageCopy {
+    pub(super) after_pass: PassId,
+    pub(super) src_image: Arc<GlobalImage>,
+    pub(super) dst_image: Arc<GlobalImage>,
+    pub(super) region: GlobalImageCopyRegion,
+}
+
 pub(super) struct GlobalImageClear {
     pub(super) after_pass: PassId,
     pub(super) clear_value: vk::ClearColorValue,
@@ -76,9 +116,20 @@ pub(super) fn run_worker(device: Arc<DeviceContext>, share: Arc<Share>) {
     let queue = device.get_main_queue();
 
     loop {
+        let mut completed_pass = None;
         old_frames.retain(|old: &PassState| {
-            !old.is_complete()
+            if old.is_complete() {
+                completed_pass = Some(old.pass_id);
+                false
+            } else {
+                true
+            }
         });
+        // The pass state must be dropped before deferred objects are destroyed as it may still hold
+        // references to global objects.
+        if let Some(pass) = completed_pass {
+            share.on_pass_complete(pass);
+        }
 
         let task = match share.try_get_next_task_timeout(Duration::from_micros(500)) {
             NextTaskResult::Ok(task) => task,
@@ -86,12 +137,12 @@ pub(super) fn run_worker(device: Arc<DeviceContext>, share: Arc<Share>) {
         };
 
         match task {
-            WorkerTask::StartPass(id, pipeline, pass, placeholder_image, placeholder_sampler) => {
+            WorkerTask::StartPass(id, pipeline, pass, render_target, placeholder_image, placeholder_sampler) => {
                 if current_pass.is_some() {
                     log::error!("Worker received WorkerTask::StartPass when a pass is already running");
                     panic!()
                 }
-                let state = PassState::new(id, pipeline, pass, device.clone(), &queue, share.clone(), pool.clone(), placeholder_image, placeholder_sampler);
+                let state = PassState::new(id, pipeline, pass, device.clone(), &queue, share.clone(), pool.clone(), render_target, placeholder_image, placeholder_sampler);
                 current_pass = Some(state);
                 current_global_recorder = next_global_recorder.take();
             }
@@ -152,6 +203,15 @@ pub(super) fn run_worker(device: Arc<DeviceContext>, share: Arc<Share>) {
                 }
             }
 
+            WorkerTask::CullBatch(task) => {
+                if let Some(pass) = &mut current_pass {
+                    pass.cull_tasks.push(task);
+                } else {
+                    log::error!("Worker received WorkerTask::CullBatch when no active pass exists");
+                    panic!()
+                }
+            }
+
             WorkerTask::WriteGlobalMesh(write, uninit) => {
                 if let Some(current_pass) = &current_pass {
                     if current_pass.pass_id > write.after_pass {
@@ -164,6 +224,18 @@ pub(super) fn run_worker(device: Arc<DeviceContext>, share: Arc<Share>) {
                 }
             }
 
+            WorkerTask::RelocateGlobalMesh(relocation) => {
+                if let Some(current_pass) = &mut current_pass {
+                    if current_pass.pass_id > relocation.after_pass {
+                        get_or_create_recorder(&mut current_global_recorder, &share, &pool).record_global_mesh_relocation(relocation);
+                    } else {
+                        get_or_create_recorder(&mut next_global_recorder, &share, &pool).record_global_mesh_relocation(relocation);
+                    }
+                } else {
+                    get_or_create_recorder(&mut next_global_recorder, &share, &pool).record_global_mesh_relocation(relocation);
+                }
+            }
+
             WorkerTask::ClearGlobalImage(clear, uninit) => {
                 if let Some(current_pass) = &current_pass {
                     if current_pass.pass_id > clear.after_pass {
@@ -188,6 +260,30 @@ pub(super) fn run_worker(device: Arc<DeviceContext>, share: Arc<Share>) {
                 }
             }
 
+            WorkerTask::ReadGlobalImage(read) => {
+                if let Some(current_pass) = &current_pass {
+                    if current_pass.pass_id > read.after_pass {
+                        get_or_create_recorder(&mut current_global_recorder, &share, &pool).record_global_image_read(read);
+                    } else {
+                        get_or_create_recorder(&mut next_global_recorder, &share, &pool).record_global_image_read(read);
+                    }
+                } else {
+                    get_or_create_recorder(&mut next_global_recorder, &share, &pool).record_global_image_read(read);
+                }
+            }
+
+            WorkerTask::CopyGlobalImage(copy) => {
+                if let Some(current_pass) = &current_pass {
+                    if current_pass.pass_id > copy.after_pass {
+                        get_or_create_recorder(&mut current_global_recorder, &share, &pool).record_global_image_copy(copy);
+                    } else {
+                        get_or_create_recorder(&mut next_global_recorder, &share, &pool).record_global_image_copy(copy);
+                    }
+                } else {
+                    get_or_create_recorder(&mut next_global_recorder, &share, &pool).record_global_image_copy(copy);
+                }
+            }
+
             WorkerTask::GenerateGlobalImageMipmaps(image, after_pass) => {
                 if let Some(current_pass) = &current_pass {
                     if current_pass.pass_id > after_pass {
@@ -374,6 +470,10 @@ struct PassState {
     global_images: Vec<Arc<GlobalImage>>,
     shaders: Vec<ShaderId>,
 
+    /// Culling is recorded once the immediate buffer has been uploaded since the draw commands
+    /// are stored in it.
+    cull_tasks: Vec<CullTask>,
+
     pre_cmd: vk::CommandBuffer,
     post_cmd: vk::CommandBuffer,
 
@@ -391,6 +491,7 @@ impl PassState {
         queue: &Queue,
         share: Arc<Share>,
         pool: Rc<RefCell<WorkerObjectPool>>,
+        render_target: Option<Arc<RenderTarget>>,
         placeholder_image: Arc<GlobalImage>,
         placeholder_sampler: vk::Sampler
     ) -> Self {
@@ -399,7 +500,7 @@ impl PassState {
         let pre_cmd = object_pool.get_begin_command_buffer().unwrap();
         let post_cmd = object_pool.get_begin_command_buffer().unwrap();
 
-        pass.init(queue, &mut object_pool, placeholder_image.get_sampler_view(), placeholder_sampler);
+        pass.init(queue, &mut object_pool, render_target, placeholder_image.get_sampler_view(), placeholder_sampler);
 
         Self {
             share,
@@ -417,6 +518,8 @@ impl PassState {
             global_images: vec![placeholder_image],
             shaders: Vec::new(),
 
+            cull_tasks: Vec::new(),
+
             pre_cmd,
             post_cmd,
 
@@ -433,6 +536,38 @@ impl PassState {
 
         immediate_buffer.generate_copy_commands(self.pre_cmd);
         self.immediate_buffer = Some(immediate_buffer);
+
+        self.record_culling();
+    }
+
+    fn record_culling(&mut self) {
+        if self.cull_tasks.is_empty() {
+            return;
+        }
+
+        let upload_barrier = vk::MemoryBarrier2::builder()
+            .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+            .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags2::COMPUTE_SHADER)
+            .dst_access_mask(vk::AccessFlags2::SHADER_STORAGE_READ | vk::AccessFlags2::SHADER_STORAGE_WRITE);
+        let info = vk::DependencyInfo::builder()
+            .memory_barriers(std::slice::from_ref(&upload_barrier));
+        unsafe {
+            self.device.synchronization_2_khr().cmd_pipeline_barrier2(self.pre_cmd, &info);
+        }
+
+        self.share.get_cull_pipeline().record(self.pre_cmd, &self.cull_tasks);
+
+        let cull_barrier = vk::MemoryBarrier2::builder()
+            .src_stage_mask(vk::PipelineStageFlags2::COMPUTE_SHADER)
+            .src_access_mask(vk::AccessFlags2::SHADER_STORAGE_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags2::DRAW_INDIRECT)
+            .dst_access_mask(vk::AccessFlags2::INDIRECT_COMMAND_READ);
+        let info = vk::DependencyInfo::builder()
+            .memory_barriers(std::slice::from_ref(&cull_barrier));
+        unsafe {
+            self.device.synchronization_2_khr().cmd_pipeline_barrier2(self.pre_cmd, &info);
+        }
     }
 
     fn use_output(&mut self, mut output: Box<dyn EmulatorOutput>) {
@@ -462,6 +597,7 @@ impl PassState {
 
         if let Some(mut gob) = gob {
             gob.record(&mut submit_recorder, &submit_alloc);
+            gob.free_relocated_ranges(self.pass_id);
             self.gob = Some(gob);
         }
 
@@ -529,7 +665,14 @@ struct GlobalObjectsRecorder {
 
     staging_barriers: Vec<vk::BufferMemoryBarrier2>,
 
-    used_global_meshes: HashMap<Arc<GlobalMesh>, gob::MeshState>,
+    /// Readbacks which complete once the recorded commands have finished execution.
+    readbacks: Vec<Arc<ReadbackState>>,
+
+    /// The state of all used mesh ranges. Keyed by the range since a relocated mesh uses two ranges.
+    used_global_meshes: HashMap<MeshArenaAllocation, (Arc<GlobalMesh>, gob::MeshState)>,
+
+    /// Ranges of relocated meshes which are freed once the copy to the new range has completed.
+    relocated_ranges: Vec<MeshArenaAllocation>,
     used_global_images: HashMap<Arc<GlobalImage>, gob::ImageState>,
 
     /// A [`vk::ImageMemoryBarrier2`] Vec which can be used locally inside functions to avoid new
@@ -559,7 +702,10 @@ impl GlobalObjectsRecorder {
             staging_allocations: Vec::new(),
             staging_barriers: Vec::new(),
 
+            readbacks: Vec::new(),
+
             used_global_meshes: HashMap::new(),
+            relocated_ranges: Vec::new(),
             used_global_images: HashMap::new(),
 
             tmp_image_barriers: Vec::new(),
@@ -568,10 +714,10 @@ impl GlobalObjectsRecorder {
     }
 
     fn record_global_buffer_write(&mut self, write: GlobalMeshWrite, is_uninit: bool) {
-        let dst_buffer = write.dst_mesh.get_buffer_handle();
+        let dst_buffer = write.dst_range.buffer;
 
         if !write.regions.is_empty() {
-            self.transition_mesh(write.dst_mesh, gob::MeshState::TransferWrite, is_uninit);
+            self.transition_mesh(write.dst_mesh, write.dst_range, gob::MeshState::TransferWrite, is_uninit);
 
             unsafe {
                 self.share.get_device().vk().cmd_copy_buffer(
@@ -586,6 +732,39 @@ impl GlobalObjectsRecorder {
         self.push_staging(write.staging_allocation, write.staging_buffer, write.staging_range.0, write.staging_range.1);
     }
 
+    fn record_global_mesh_relocation(&mut self, relocation: GlobalMeshRelocation) {
+        let src = relocation.src;
+        let dst = relocation.dst;
+
+        self.transition_mesh(relocation.mesh.clone(), src, gob::MeshState::TransferRead, false);
+        self.transition_mesh(relocation.mesh, dst, gob::MeshState::TransferWrite, true);
+
+        let region = vk::BufferCopy {
+            src_offset: src.offset,
+            dst_offset: dst.offset,
+            size: src.size
+        };
+
+        unsafe {
+            self.share.get_device().vk().cmd_copy_buffer(
+                self.cmd,
+                src.buffer,
+                dst.buffer,
+                std::slice::from_ref(&region)
+            );
+        }
+
+        self.relocated_ranges.push(src);
+    }
+
+    /// Frees the source ranges of all recorded relocations once the pass this recorder is
+    /// submitted with has completed.
+    fn free_relocated_ranges(&mut self, pass: PassId) {
+        for range in std::mem::replace(&mut self.relocated_ranges, Vec::new()) {
+            self.share.destroy_after_pass(pass, DeferredObject::MeshAllocation(range));
+        }
+    }
+
     fn record_global_image_clear(&mut self, clear: GlobalImageClear, is_uninit: bool) {
         let dst_image = clear.dst_image.get_image_handle();
 
@@ -628,12 +807,81 @@ impl GlobalObjectsRecorder {
         self.push_staging(write.staging_allocation, write.staging_buffer, write.staging_range.0, write.staging_range.1);
     }
 
+    fn record_global_image_read(&mut self, read: GlobalImageRead) {
+        let src_image = read.src_image.get_image_handle();
+
+        self.transition_image(read.src_image, gob::ImageState::TransferRead, false);
+
+        let device = self.share.get_device();
+        unsafe {
+            device.vk().cmd_copy_image_to_buffer(
+                self.cmd,
+                src_image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                read.staging_buffer,
+                std::slice::from_ref(&read.region)
+            );
+        }
+
+        let barrier = vk::BufferMemoryBarrier2::builder()
+            .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+            .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags2::HOST)
+            .dst_access_mask(vk::AccessFlags2::HOST_READ)
+            .buffer(read.staging_buffer)
+            .offset(read.staging_range.0)
+            .size(read.staging_range.1);
+
+        let info = vk::DependencyInfo::builder()
+            .buffer_memory_barriers(std::slice::from_ref(&barrier));
+
+        unsafe {
+            device.synchronization_2_khr().cmd_pipeline_barrier2(self.cmd, &info)
+        };
+
+        self.readbacks.push(read.readback);
+    }
+
+    fn record_global_image_copy(&mut self, copy: GlobalImageCopy) {
+        let src_image = copy.src_image.get_image_handle();
+        let dst_image = copy.dst_image.get_image_handle();
+
+        self.transition_image(copy.src_image, gob::ImageState::TransferRead, false);
+        self.transition_image(copy.dst_image, gob::ImageState::TransferWrite, false);
+
+        let device = self.share.get_device();
+        match &copy.region {
+            GlobalImageCopyRegion::Copy(region) => unsafe {
+                device.vk().cmd_copy_image(
+                    self.cmd,
+                    src_image,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    dst_image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    std::slice::from_ref(region)
+                );
+            },
+            GlobalImageCopyRegion::Blit(region, filter) => unsafe {
+                device.vk().cmd_blit_image(
+                    self.cmd,
+                    src_image,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    dst_image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    std::slice::from_ref(region),
+                    *filter
+                );
+            }
+        }
+    }
+
     fn record_global_image_generate_mipmaps(&mut self, image: Arc<GlobalImage>) {
         let mip_levels = image.get_mip_levels();
         if mip_levels > 1 {
             let handle = image.get_image_handle();
-            let src_size = image.get_size();
-            let mut src_size = Vec2i32::new(src_size[0] as i32, src_size[1] as i32);
+            let array_layers = image.get_array_layers();
+            let src_size = image.get_image_size().as_extent_3d();
+            let mut src_size = Vec3i32::new(src_size.width as i32, src_size.height as i32, src_size.depth as i32);
 
             self.transition_image(image, gob::ImageState::GenerateMipmaps, false);
 
@@ -653,7 +901,7 @@ impl GlobalObjectsRecorder {
                             base_mip_level: level - 1,
                             level_count: 1,
                             base_array_layer: 0,
-                            layer_count: 0
+                            layer_count: array_layers
                         });
 
                     let info = vk::DependencyInfo::builder()
@@ -664,25 +912,22 @@ impl GlobalObjectsRecorder {
                     }
                 }
 
-                let dst_size = Vec2i32::new(
-                    if src_size[0] > 1 { src_size[0] / 2 } else { 1 },
-                    if src_size[1] > 1 { src_size[1] / 2 } else { 1 }
-                );
+                let dst_size = src_size.map(|v| if v > 1 { v / 2 } else { 1 });
                 let blit = vk::ImageBlit::builder()
                     .src_subresource(vk::ImageSubresourceLayers {
                         aspect_mask: vk::ImageAspectFlags::COLOR,
                         mip_level: level - 1,
                         base_array_layer: 0,
-                        layer_count: 1
+                        layer_count: array_layers
                     })
-                    .src_offsets([vk::Offset3D { x: 0, y: 0, z: 0 }, vk::Offset3D { x: src_size[0], y: src_size[1], z: 1 }])
+                    .src_offsets([vk::Offset3D { x: 0, y: 0, z: 0 }, vk::Offset3D { x: src_size[0], y: src_size[1], z: src_size[2] }])
                     .dst_subresource(vk::ImageSubresourceLayers {
                         aspect_mask: vk::ImageAspectFlags::COLOR,
                         mip_level: level,
                         base_array_layer: 0,
-                        layer_count: 1
+                        layer_count: array_layers
                     })
-                    .dst_offsets([vk::Offset3D { x: 0, y: 0, z: 0 }, vk::Offset3D { x: dst_size[0], y: dst_size[1], z: 1 }]);
+                    .dst_offsets([vk::Offset3D { x: 0, y: 0, z: 0 }, vk::Offset3D { x: dst_size[0], y: dst_size[1], z: dst_size[2] }]);
 
                 unsafe {
                     device.vk().cmd_blit_image(
@@ -753,10 +998,8 @@ impl GlobalObjectsRecorder {
     fn generate_buffer_post_barriers(&mut self) -> Vec<vk::BufferMemoryBarrier2> {
         let mut barriers = std::mem::replace(&mut self.staging_barriers, Vec::new());
 
-        for (mesh, old_state) in &self.used_global_meshes {
-            let handle = mesh.get_buffer_handle();
-
-            gob::generate_mesh_barriers(*old_state, gob::MeshState::Ready, handle, &mut barriers);
+        for (range, (_, old_state)) in &self.used_global_meshes {
+            gob::generate_mesh_barriers(*old_state, gob::MeshState::Ready, range.buffer, range.offset, range.size, &mut barriers);
         }
 
         barriers
@@ -794,15 +1037,13 @@ impl GlobalObjectsRecorder {
         };
     }
 
-    /// Transitions a mesh to a new state and adds it to the used mesh list.
+    /// Transitions a range of a mesh to a new state and adds it to the used mesh list.
     ///
-    /// If the mesh is not in the used mesh list the mesh is currently either uninitialized or
-    /// ready. In that case if maybe_uninit is set the mesh is assumed to be uninitialized otherwise
+    /// If the range is not in the used mesh list the range is currently either uninitialized or
+    /// ready. In that case if maybe_uninit is set the range is assumed to be uninitialized otherwise
     /// it is assumed to be in the ready state.
-    fn transition_mesh(&mut self, mesh: Arc<GlobalMesh>, new_state: gob::MeshState, maybe_uninit: bool) {
-        let handle = mesh.get_buffer_handle();
-
-        let old_state = self.used_global_meshes.insert(mesh, new_state).unwrap_or_else(|| {
+    fn transition_mesh(&mut self, mesh: Arc<GlobalMesh>, range: MeshArenaAllocation, new_state: gob::MeshState, maybe_uninit: bool) {
+        let old_state = self.used_global_meshes.insert(range, (mesh, new_state)).map(|(_, state)| state).unwrap_or_else(|| {
             if maybe_uninit {
                 gob::MeshState::Uninitialized
             } else {
@@ -811,7 +1052,7 @@ impl GlobalObjectsRecorder {
         });
 
         self.tmp_buffer_barriers.clear();
-        gob::generate_mesh_barriers(old_state, new_state, handle, &mut self.tmp_buffer_barriers);
+        gob::generate_mesh_barriers(old_state, new_state, range.buffer, range.offset, range.size, &mut self.tmp_buffer_barriers);
 
         if !self.tmp_buffer_barriers.is_empty() {
             let info = vk::DependencyInfo::builder()
@@ -864,6 +1105,18 @@ impl Drop for GlobalObjectsRecorder {
         for allocation in std::mem::replace(&mut self.staging_allocations, Vec::new()) {
             guard.free(allocation);
         }
+
+        // The recorder is only dropped once all submitted commands have completed
+        let readbacks = std::mem::replace(&mut self.readbacks, Vec::new());
+        for readback in &readbacks {
+            readback.invalidate(&guard);
+        }
+        // Dropping the last reference to a readback frees its staging memory
+        drop(guard);
+
+        for readback in readbacks {
+            readback.mark_ready();
+        }
     }
 }
 
@@ -880,9 +1133,11 @@ mod gob {
         Ready,
         /// Mesh was previously written to
         TransferWrite,
+        /// Mesh was previously read by a transfer
+        TransferRead,
     }
 
-    pub(super) fn generate_mesh_barriers(old_state: MeshState, new_state: MeshState, buffer: vk::Buffer, barriers: &mut Vec<vk::BufferMemoryBarrier2>) {
+    pub(super) fn generate_mesh_barriers(old_state: MeshState, new_state: MeshState, buffer: vk::Buffer, offset: vk::DeviceSize, size: vk::DeviceSize, barriers: &mut Vec<vk::BufferMemoryBarrier2>) {
         match (old_state, new_state) {
             (MeshState::Uninitialized, _) => {
             },
@@ -896,17 +1151,19 @@ mod gob {
             (old, new) => {
                 let mut barrier = vk::BufferMemoryBarrier2::builder()
                     .buffer(buffer)
-                    .offset(0)
-                    .size(vk::WHOLE_SIZE);
+                    .offset(offset)
+                    .size(size);
                 barrier = match old {
                     MeshState::Uninitialized => panic!(), // Impossible
                     MeshState::Ready => MESH_READY_INFO().write_src(barrier),
-                    MeshState::TransferWrite => MESH_TRANSFER_WRITE_INFO.write_src(barrier)
+                    MeshState::TransferWrite => MESH_TRANSFER_WRITE_INFO.write_src(barrier),
+                    MeshState::TransferRead => MESH_TRANSFER_READ_INFO.write_src(barrier)
                 };
                 barrier = match new {
                     MeshState::Uninitialized => panic!(), // Impossible
                     MeshState::Ready => MESH_READY_INFO().write_dst(barrier),
-                    MeshState::TransferWrite => MESH_TRANSFER_WRITE_INFO.write_dst(barrier)
+                    MeshState::TransferWrite => MESH_TRANSFER_WRITE_INFO.write_dst(barrier),
+                    MeshState::TransferRead => MESH_TRANSFER_READ_INFO.write_dst(barrier)
                 };
 
                 barriers.push(barrier.build());
@@ -920,6 +1177,7 @@ mod gob {
         BufferAccessInfo::new(vk::PipelineStageFlags2::VERTEX_INPUT, vk::AccessFlags2::VERTEX_ATTRIBUTE_READ | vk::AccessFlags2::INDEX_READ)
     }
     const MESH_TRANSFER_WRITE_INFO: BufferAccessInfo = BufferAccessInfo::new(vk::PipelineStageFlags2::TRANSFER, vk::AccessFlags2::TRANSFER_WRITE);
+    const MESH_TRANSFER_READ_INFO: BufferAccessInfo = BufferAccessInfo::new(vk::PipelineStageFlags2::TRANSFER, vk::AccessFlags2::TRANSFER_READ);
 
     struct BufferAccessInfo {
         stage_mask: vk::PipelineStageFlags2,
@@ -958,6 +1216,8 @@ mod gob {
         Ready,
         /// Image was previously written to
         TransferWrite,
+        /// Image was previously read from
+        TransferRead,
         /// Image had previously generated its mipmaps
         GenerateMipmaps,
     }
@@ -1068,6 +1328,79 @@ mod gob {
 
                 barriers.push(barrier1.build());
             }
+            (ImageState::Ready, ImageState::TransferRead) => {
+                let mut barrier = vk::ImageMemoryBarrier2::builder()
+                    .image(image)
+                    .subresource_range(make_full_subresource_range(vk::ImageAspectFlags::COLOR));
+                barrier = IMAGE_READY_INFO.write_src(barrier);
+                barrier = IMAGE_TRANSFER_READ_INFO.write_dst(barrier);
+
+                barriers.push(barrier.build());
+            }
+            (ImageState::TransferWrite, ImageState::TransferRead) => {
+                let mut barrier = vk::ImageMemoryBarrier2::builder()
+                    .image(image)
+                    .subresource_range(make_full_subresource_range(vk::ImageAspectFlags::COLOR));
+                barrier = IMAGE_TRANSFER_WRITE_INFO.write_src(barrier);
+                barrier = IMAGE_TRANSFER_READ_INFO.write_dst(barrier);
+
+                barriers.push(barrier.build());
+            }
+            (ImageState::TransferRead, ImageState::Ready) => {
+                let mut barrier = vk::ImageMemoryBarrier2::builder()
+                    .image(image)
+                    .subresource_range(make_full_subresource_range(vk::ImageAspectFlags::COLOR));
+                barrier = IMAGE_TRANSFER_READ_INFO.write_src(barrier);
+                barrier = IMAGE_READY_INFO.write_dst(barrier);
+
+                barriers.push(barrier.build());
+            }
+            (ImageState::TransferRead, ImageState::TransferWrite) => {
+                let mut barrier = vk::ImageMemoryBarrier2::builder()
+                    .image(image)
+                    .subresource_range(make_full_subresource_range(vk::ImageAspectFlags::COLOR));
+                barrier = IMAGE_TRANSFER_READ_INFO.write_src(barrier);
+                barrier = IMAGE_TRANSFER_WRITE_INFO.write_dst(barrier);
+
+                barriers.push(barrier.build());
+            }
+            (ImageState::TransferRead, ImageState::TransferRead) => {
+                // Read after read does not need a barrier
+            }
+            (ImageState::TransferRead, ImageState::GenerateMipmaps) => {
+                let mut barrier0 = vk::ImageMemoryBarrier2::builder()
+                    .image(image)
+                    .subresource_range(make_first_mip_subresource_range(vk::ImageAspectFlags::COLOR));
+                barrier0 = IMAGE_TRANSFER_READ_INFO.write_src(barrier0);
+                barrier0 = IMAGE_GENERATE_MIPMAPS_0_INFO.write_dst(barrier0);
+
+                barriers.push(barrier0.build());
+
+                let mut barrier1 = vk::ImageMemoryBarrier2::builder()
+                    .image(image)
+                    .subresource_range(make_exclude_first_mips_subresource_range(vk::ImageAspectFlags::COLOR));
+                barrier1 = IMAGE_TRANSFER_READ_INFO.write_src(barrier1);
+                barrier1 = IMAGE_GENERATE_MIPMAPS_1_INFO.write_dst(barrier1);
+
+                barriers.push(barrier1.build());
+            }
+            (ImageState::GenerateMipmaps, ImageState::TransferRead) => {
+                let mut barrier0 = vk::ImageMemoryBarrier2::builder()
+                    .image(image)
+                    .subresource_range(make_exclude_last_mips_subresource_range(vk::ImageAspectFlags::COLOR, mip_levels));
+                barrier0 = IMAGE_GENERATE_MIPMAPS_0_INFO.write_src(barrier0);
+                barrier0 = IMAGE_TRANSFER_READ_INFO.write_dst(barrier0);
+
+                barriers.push(barrier0.build());
+
+                let mut barrier1 = vk::ImageMemoryBarrier2::builder()
+                    .image(image)
+                    .subresource_range(make_last_mip_subresource_range(vk::ImageAspectFlags::COLOR, mip_levels));
+                barrier1 = IMAGE_GENERATE_MIPMAPS_1_INFO.write_src(barrier1);
+                barrier1 = IMAGE_TRANSFER_READ_INFO.write_dst(barrier1);
+
+                barriers.push(barrier1.build());
+            }
             (ImageState::Ready, ImageState::Ready) => {
                 log::warn!("Transitioned image from ready to ready. Why?");
             }
@@ -1080,8 +1413,24 @@ mod gob {
                 panic!();
             }
             (ImageState::GenerateMipmaps, ImageState::GenerateMipmaps) => {
-                log::error!("Image cannot be transitioned from generate mipmaps to generate mipmaps");
-                panic!();
+                // The first mip level stays a transfer source. All other levels need to become a transfer destination again
+                if mip_levels > 2 {
+                    let mut barrier0 = vk::ImageMemoryBarrier2::builder()
+                        .image(image)
+                        .subresource_range(make_inner_mips_subresource_range(vk::ImageAspectFlags::COLOR, mip_levels));
+                    barrier0 = IMAGE_GENERATE_MIPMAPS_0_INFO.write_src(barrier0);
+                    barrier0 = IMAGE_GENERATE_MIPMAPS_1_INFO.write_dst(barrier0);
+
+                    barriers.push(barrier0.build());
+                }
+
+                let mut barrier1 = vk::ImageMemoryBarrier2::builder()
+                    .image(image)
+                    .subresource_range(make_last_mip_subresource_range(vk::ImageAspectFlags::COLOR, mip_levels));
+                barrier1 = IMAGE_GENERATE_MIPMAPS_1_INFO.write_src(barrier1);
+                barrier1 = IMAGE_GENERATE_MIPMAPS_1_INFO.write_dst(barrier1);
+
+                barriers.push(barrier1.build());
             }
         }
     }
@@ -1119,6 +1468,17 @@ mod gob {
         }
     }
 
+    #[inline]
+    fn make_inner_mips_subresource_range(aspect_mask: vk::ImageAspectFlags, mip_levels: u32) -> vk::ImageSubresourceRange {
+        vk::ImageSubresourceRange {
+            aspect_mask,
+            base_mip_level: 1,
+            level_count: mip_levels - 2,
+            base_array_layer: 0,
+            layer_count: vk::REMAINING_ARRAY_LAYERS
+        }
+    }
+
     #[inline]
     fn make_exclude_first_mips_subresource_range(aspect_mask: vk::ImageAspectFlags) -> vk::ImageSubresourceRange {
         vk::ImageSubresourceRange {
@@ -1142,8 +1502,10 @@ mod gob {
     }
 
     const IMAGE_UNINITIALIZED_INFO: ImageAccessInfo = ImageAccessInfo::new(vk::PipelineStageFlags2::NONE, vk::AccessFlags2::NONE, vk::ImageLayout::UNDEFINED);
-    const IMAGE_READY_INFO: ImageAccessInfo = ImageAccessInfo::new(vk::PipelineStageFlags2::FRAGMENT_SHADER, vk::AccessFlags2::SHADER_SAMPLED_READ, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+    // Images are sampled in the vertex shader as well (for example the lightmap and overlay in main.vert)
+    const IMAGE_READY_INFO: ImageAccessInfo = ImageAccessInfo::new(vk::PipelineStageFlags2::from_raw(vk::PipelineStageFlags2::VERTEX_SHADER.as_raw() | vk::PipelineStageFlags2::FRAGMENT_SHADER.as_raw()), vk::AccessFlags2::SHADER_SAMPLED_READ, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
     const IMAGE_TRANSFER_WRITE_INFO: ImageAccessInfo = ImageAccessInfo::new(vk::PipelineStageFlags2::TRANSFER, vk::AccessFlags2::TRANSFER_WRITE, vk::ImageLayout::TRANSFER_DST_OPTIMAL);
+    const IMAGE_TRANSFER_READ_INFO: ImageAccessInfo = ImageAccessInfo::new(vk::PipelineStageFlags2::TRANSFER, vk::AccessFlags2::TRANSFER_READ, vk::ImageLayout::TRANSFER_SRC_OPTIMAL);
     const IMAGE_GENERATE_MIPMAPS_0_INFO: ImageAccessInfo = ImageAccessInfo::new(vk::PipelineStageFlags2::TRANSFER, vk::AccessFlags2::TRANSFER_READ, vk::ImageLayout::TRANSFER_SRC_OPTIMAL);
     const IMAGE_GENERATE_MIPMAPS_1_INFO: ImageAccessInfo = ImageAccessInfo::new(vk::PipelineStageFlags2::TRANSFER, vk::AccessFlags2::TRANSFER_WRITE, vk::ImageLayout::TRANSFER_DST_OPTIMAL);
 
@@ -24,6 +24,24 @@ impl CompatibilityClass {
         self.name
     }
 
+    /// Returns the size of a single texel in bytes. Returns [`None`] for compressed and multi
+    /// planar classes.
+    pub fn get_texel_size(&self) -> Option<u32> {
+        match self.name {
+            "BIT8" => Some(1),
+            "BIT16" => Some(2),
+            "BIT24" => Some(3),
+            "BIT32" => Some(4),
+            "BIT48" => Some(6),
+            "BIT64" => Some(8),
+            "BIT96" => Some(12),
+            "BIT128" => Some(16),
+            "BIT192" => Some(24),
+            "BIT256" => Some(32),
+            _ => None,
+        }
+    }
+
     define_compatibility_class!(BIT8);
     define_compatibility_class!(BIT16);
     define_compatibility_class!(BIT24);
@@ -174,6 +192,12 @@ impl Format {
         self.clear_color_type
     }
 
+    /// Returns the size of a single texel in bytes. Returns [`None`] for compressed and multi
+    /// planar formats.
+    pub fn get_texel_size(&self) -> Option<u32> {
+        self.compatibility_class.get_texel_size()
+    }
+
     pub fn is_compatible_with(&self, other: &Format) -> bool {
         self.compatibility_class == other.compatibility_class
     }
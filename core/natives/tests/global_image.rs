@@ -0,0 +1,65 @@
+mod test_common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use ash::vk;
+use bytemuck::cast_slice;
+
+use b4d_core::prelude::*;
+use b4d_core::renderer::emulator::{ImageData, MeshData, PrimitiveTopology, SamplerInfo};
+use b4d_core::renderer::emulator::debug_pipeline::{DebugPipeline, DebugPipelineMode};
+use b4d_core::renderer::emulator::mc_shaders::{McUniform, VertexFormat, VertexFormatEntry};
+use b4d_core::renderer::emulator::pipeline::DrawConfig;
+use b4d_core::util::format::Format;
+
+use test_common::{make_headless_emulator, wait_for};
+
+#[test]
+fn test_destroy_after_update_texture() {
+    let (_instance, emulator) = make_headless_emulator();
+
+    let pipeline = DebugPipeline::new(emulator.clone(), DebugPipelineMode::Textured0, Vec2u32::new(64, 64)).unwrap();
+    let vertex_format = VertexFormat {
+        stride: 20,
+        position: VertexFormatEntry { offset: 0, format: vk::Format::R32G32B32_SFLOAT },
+        normal: None,
+        color: None,
+        uv0: Some(VertexFormatEntry { offset: 12, format: vk::Format::R32G32_SFLOAT }),
+        uv1: None,
+        uv2: None
+    };
+    let shader = emulator.create_shader(&vertex_format, McUniform::MODEL_VIEW_MATRIX | McUniform::PROJECTION_MATRIX, 0f32);
+
+    let vertices: [f32; 15] = [0f32, 0f32, 0f32, 0f32, 0f32, 1f32, 0f32, 0f32, 1f32, 0f32, 0f32, 1f32, 0f32, 0f32, 1f32];
+    let mesh = emulator.create_global_mesh(&MeshData {
+        vertex_data: cast_slice(&vertices),
+        index_data: None,
+        vertex_stride: 20,
+        index_count: 3,
+        index_type: vk::IndexType::UINT32,
+        primitive_topology: PrimitiveTopology::TriangleList,
+    });
+
+    let image = emulator.create_global_image(Vec2u32::new(16, 16), &Format::R8G8B8A8_UNORM);
+    let texels = [255u8; 16 * 16 * 4];
+    image.update_regions(&[ImageData::new_full(&texels, Vec2u32::new(16, 16))]);
+    let weak = Arc::downgrade(&image);
+
+    let sampler = SamplerInfo::new(vk::Filter::NEAREST, vk::Filter::NEAREST, vk::SamplerMipmapMode::NEAREST, vk::SamplerAddressMode::REPEAT, vk::SamplerAddressMode::REPEAT);
+
+    let mut pass = emulator.start_pass(pipeline);
+    pass.update_texture(0, &image, &sampler, shader);
+    pass.draw_global(mesh.clone(), shader, &DrawConfig::default());
+
+    // Drop the last reference owned by us while the pass is still being recorded
+    drop(image);
+    assert!(weak.upgrade().is_some());
+
+    // The image must only be destroyed once the pass has completed on the gpu
+    drop(pass);
+    assert!(wait_for(Duration::from_secs(5), || weak.upgrade().is_none() && !emulator.has_pending_deferred_objects()));
+
+    drop(mesh);
+    emulator.drop_shader(shader);
+}
@@ -0,0 +1,109 @@
+mod test_common;
+
+use std::time::Duration;
+
+use ash::vk;
+use bytemuck::cast_slice;
+
+use b4d_core::prelude::*;
+use b4d_core::renderer::emulator::{MeshData, PrimitiveTopology};
+use b4d_core::renderer::emulator::debug_pipeline::{DebugPipeline, DebugPipelineMode};
+use b4d_core::renderer::emulator::mc_shaders::{McUniform, VertexFormat, VertexFormatEntry};
+use b4d_core::renderer::emulator::pipeline::DrawConfig;
+
+use test_common::{make_headless_emulator, wait_for};
+
+#[test]
+fn test_destroy_after_draw() {
+    let (_instance, emulator) = make_headless_emulator();
+
+    let pipeline = DebugPipeline::new(emulator.clone(), DebugPipelineMode::Depth, Vec2u32::new(64, 64)).unwrap();
+    let vertex_format = VertexFormat {
+        stride: 12,
+        position: VertexFormatEntry { offset: 0, format: vk::Format::R32G32B32_SFLOAT },
+        normal: None,
+        color: None,
+        uv0: None,
+        uv1: None,
+        uv2: None
+    };
+    let shader = emulator.create_shader(&vertex_format, McUniform::MODEL_VIEW_MATRIX | McUniform::PROJECTION_MATRIX, 0f32);
+
+    let vertices: [f32; 9] = [0f32, 0f32, 0f32, 1f32, 0f32, 0f32, 0f32, 1f32, 0f32];
+    let mesh = emulator.create_global_mesh(&MeshData {
+        vertex_data: cast_slice(&vertices),
+        index_data: None,
+        vertex_stride: 12,
+        index_count: 3,
+        index_type: vk::IndexType::UINT32,
+        primitive_topology: PrimitiveTopology::TriangleList,
+    });
+    let range = emulator.get_mesh_arena_range(&mesh);
+
+    let mut pass = emulator.start_pass(pipeline);
+    pass.draw_global(mesh.clone(), shader, &DrawConfig::default());
+
+    // Drop the last reference owned by us while the pass is still being recorded
+    drop(mesh);
+    assert!(emulator.is_mesh_arena_range_allocated(range));
+
+    // The range must only be freed once the pass has completed on the gpu
+    drop(pass);
+    assert!(wait_for(Duration::from_secs(5), || !emulator.is_mesh_arena_range_allocated(range)));
+
+    emulator.drop_shader(shader);
+}
+
+#[test]
+fn test_relocate_sparse_block() {
+    let (_instance, emulator) = make_headless_emulator();
+
+    let pipeline = DebugPipeline::new(emulator.clone(), DebugPipelineMode::Depth, Vec2u32::new(64, 64)).unwrap();
+    let vertex_format = VertexFormat {
+        stride: 4,
+        position: VertexFormatEntry { offset: 0, format: vk::Format::R8G8B8A8_UNORM },
+        normal: None,
+        color: None,
+        uv0: None,
+        uv1: None,
+        uv2: None
+    };
+    let shader = emulator.create_shader(&vertex_format, McUniform::MODEL_VIEW_MATRIX | McUniform::PROJECTION_MATRIX, 0f32);
+
+    let make_mesh = |size: usize| {
+        let vertices = vec![0u8; size];
+        emulator.create_global_mesh(&MeshData {
+            vertex_data: &vertices,
+            index_data: None,
+            vertex_stride: 4,
+            index_count: 3,
+            index_type: vk::IndexType::UINT32,
+            primitive_topology: PrimitiveTopology::TriangleList,
+        })
+    };
+
+    // The first mesh fills most of the first arena buffer so the second one needs a new buffer
+    let large = make_mesh(56 << 20);
+    let small = make_mesh(12 << 20);
+    let old_range = emulator.get_mesh_arena_range(&small);
+
+    // Afterwards the second buffer is sparsely used and its mesh can be moved into the first one
+    drop(large);
+
+    // Relocation happens once a pass has completed. The mesh is drawn in every pass to make sure
+    // draws recorded around the relocation are valid.
+    assert!(wait_for(Duration::from_secs(5), || {
+        let mut pass = emulator.start_pass(pipeline.clone());
+        pass.draw_global(small.clone(), shader, &DrawConfig::default());
+        drop(pass);
+
+        !emulator.is_mesh_arena_range_allocated(old_range)
+    }));
+
+    let new_range = emulator.get_mesh_arena_range(&small);
+    assert_ne!(new_range, old_range);
+    assert!(emulator.is_mesh_arena_range_allocated(new_range));
+
+    drop(small);
+    emulator.drop_shader(shader);
+}
@@ -0,0 +1,39 @@
+//! Utilities shared by the integration tests.
+//!
+//! The tests require the `__internal_doc_test` feature to access the test utilities of the crate.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use b4d_core::prelude::*;
+use b4d_core::renderer::emulator::EmulatorRenderer;
+use b4d_core::vk::test::make_headless_instance_device;
+
+pub fn init_log() {
+    let _ = env_logger::builder().is_test(true).try_init();
+}
+
+/// Creates a headless device and a emulator renderer using it. The instance must be kept alive
+/// for as long as the emulator is used.
+pub fn make_headless_emulator() -> (Arc<InstanceContext>, Arc<EmulatorRenderer>) {
+    init_log();
+
+    let (instance, device) = make_headless_instance_device();
+    let emulator = Arc::new(EmulatorRenderer::new_for_test(device));
+
+    (instance, emulator)
+}
+
+/// Polls the condition until it returns true or the timeout expires. Returns the last result of
+/// the condition.
+#[allow(unused)]
+pub fn wait_for<F: FnMut() -> bool>(timeout: Duration, mut condition: F) -> bool {
+    let start = Instant::now();
+    while !condition() {
+        if start.elapsed() > timeout {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    true
+}
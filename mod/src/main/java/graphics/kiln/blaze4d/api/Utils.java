@@ -47,6 +47,24 @@ public class Utils {
         return hasPosition;
     }
 
+    /**
+     * Returns the alpha value below which the vanilla core shader with the given name discards fragments.
+     * Shaders which only discard fully transparent fragments use the smallest positive float.
+     */
+    public static float getAlphaCutoff(String shaderName) {
+        return switch (shaderName) {
+            case "rendertype_cutout_mipped" -> 0.5f;
+            case "rendertype_cutout", "rendertype_tripwire", "rendertype_crumbling", "particle", "position_tex_color_normal",
+                    "rendertype_entity_cutout", "rendertype_entity_cutout_no_cull", "rendertype_entity_cutout_no_cull_z_offset",
+                    "rendertype_entity_smooth_cutout", "rendertype_entity_translucent", "rendertype_entity_translucent_cull",
+                    "rendertype_item_entity_translucent_cull", "rendertype_entity_decal", "rendertype_entity_no_outline",
+                    "rendertype_armor_cutout_no_cull", "rendertype_text", "rendertype_text_see_through",
+                    "rendertype_text_intensity", "rendertype_text_intensity_see_through" -> 0.1f;
+            case "position_tex", "position_tex_color", "position_color_tex", "rendertype_outline" -> Float.MIN_NORMAL;
+            default -> 0.0f;
+        };
+    }
+
     public static int vulkanNormFormat(VertexFormatElement.Type type, int componentCount) {
         switch (type) {
             case FLOAT -> {
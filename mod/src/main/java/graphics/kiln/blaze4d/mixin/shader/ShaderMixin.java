@@ -42,7 +42,7 @@ public class ShaderMixin implements B4DShader {
             }
 
             if (Utils.convertVertexFormat(vertexFormat, nativeFormat)) {
-                this.b4dShaderId = Blaze4D.core.createShader(nativeFormat, usedUniforms);
+                this.b4dShaderId = Blaze4D.core.createShader(nativeFormat, usedUniforms, Utils.getAlphaCutoff(string));
             } else {
                 Blaze4D.LOGGER.warn("Shader vertex format did not contain position. Skipping!");
             }